@@ -0,0 +1,64 @@
+/*
+ * C interface of the opentools barcode extraction core.
+ *
+ * Link against libopentools.so (or .dylib), built by `cargo build --release`.
+ * Functions never take ownership of buffers; negative return values are the
+ * OPENTOOLS_ERR_* status codes.
+ */
+#ifndef OPENTOOLS_H
+#define OPENTOOLS_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <sys/types.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define OPENTOOLS_OK 0
+#define OPENTOOLS_ERR_NULL (-1)
+#define OPENTOOLS_ERR_POSITION (-2)
+#define OPENTOOLS_ERR_PATTERN (-3)
+#define OPENTOOLS_ERR_BASE (-4)
+#define OPENTOOLS_ERR_TOO_SHORT (-5)
+#define OPENTOOLS_ERR_MISMATCH (-6)
+#define OPENTOOLS_ERR_BUFFER (-7)
+
+/* Position of a sequence in a read, same fields as "read{1/2}:{+/-}:start-end".
+ * `start`/`end` count back from the 3' end of the read when their `_from_end` flag is set. */
+typedef struct {
+    bool read2;
+    bool revcomp;
+    size_t start;
+    size_t end;
+    bool start_from_end;
+    bool end_from_end;
+} OpentoolsPosition;
+
+/* Parse "read{1/2}:{+/-}:start-end" into `out`, OPENTOOLS_OK on success */
+int32_t opentools_position_parse(const char *text, OpentoolsPosition *out);
+
+/* Position and IUPAC pattern of the OpenST chemistry, either argument may be NULL.
+ * The pattern is a static string owned by the library. */
+void opentools_openst(OpentoolsPosition *pos, const char **pattern);
+
+/* 1 when `seq` matches the IUPAC `pattern` base by base, 0 when not.
+ * An `N` in `seq` mismatches every pattern base, `N` included. */
+int32_t opentools_pattern_match(const uint8_t *seq, size_t seq_len,
+                                const uint8_t *pattern, size_t pattern_len);
+
+/* Copy the bases and qualities at `pos` of one read into `out_seq`/`out_qual`,
+ * reverse complemented on the minus strand, and return the barcode length.
+ * `pattern` may be NULL to skip the pattern check, an `N` in the barcode fails any pattern. */
+ssize_t opentools_extract(const OpentoolsPosition *pos,
+                          const uint8_t *seq, const uint8_t *qual, size_t len,
+                          const uint8_t *pattern, size_t pattern_len,
+                          uint8_t *out_seq, uint8_t *out_qual, size_t out_cap);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* OPENTOOLS_H */
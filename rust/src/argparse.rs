@@ -2,12 +2,82 @@
 pub mod touchbarcode;
 pub mod dedupbarcode;
 pub mod tilesmatch;
+pub mod viewbarcode;
+pub mod barcoderank;
+pub mod spatialtag;
+pub mod count;
+pub mod demux;
+pub mod whitelist;
+pub mod qc;
+pub mod convert;
+pub mod segment;
+pub mod mergebam;
+pub mod indexbarcode;
+pub mod fqstat;
+pub mod validate;
+pub mod extract;
+pub mod pipeline;
+pub mod completions;
+pub mod tileimage;
+pub mod collide;
+pub mod adapterscan;
+pub mod splitlane;
+pub mod region;
+pub mod compare;
+pub mod stitch;
+pub mod register;
+pub mod bin;
+pub mod saturation;
+pub mod mask;
+pub mod report;
+pub mod interop;
+pub mod phix;
+pub mod errprofile;
+pub mod splitpool;
+pub mod config;
 
-use clap::{Parser, Subcommand};
+use std::fs;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use clap::{ArgAction, ArgMatches, CommandFactory, Parser, Subcommand, ValueEnum};
+use tracing::level_filters::LevelFilter;
+use crate::utils::spill::parse_memory_size;
 use self::{
     touchbarcode::TouchBarcodeArgs,
     dedupbarcode::DedupBarcodeArgs,
     tilesmatch::TilesMatchArgs,
+    viewbarcode::ViewBarcodeArgs,
+    barcoderank::BarcodeRankArgs,
+    spatialtag::SpatialTagArgs,
+    count::CountArgs,
+    demux::DemuxArgs,
+    whitelist::WhitelistArgs,
+    qc::QcArgs,
+    convert::ConvertArgs,
+    segment::SegmentArgs,
+    mergebam::MergeBamArgs,
+    indexbarcode::IndexBarcodeArgs,
+    fqstat::FqStatArgs,
+    validate::ValidateArgs,
+    extract::ExtractArgs,
+    pipeline::PipelineArgs,
+    completions::CompletionsArgs,
+    tileimage::TileImageArgs,
+    collide::CollideArgs,
+    adapterscan::AdapterScanArgs,
+    splitlane::SplitLaneArgs,
+    region::RegionArgs,
+    compare::CompareArgs,
+    stitch::StitchArgs,
+    register::RegisterArgs,
+    bin::BinArgs,
+    saturation::SaturationArgs,
+    mask::MaskArgs,
+    report::ReportArgs,
+    interop::InteropArgs,
+    phix::PhixArgs,
+    errprofile::ErrProfileArgs,
+    splitpool::SplitPoolArgs,
 };
 
 /// Command line arguments resolve the main structure
@@ -19,10 +89,139 @@ use self::{
 #[command(about = "OpenST toolbox", long_about = None)]
 #[command(next_line_help = true)]
 pub struct Cli {
+    /// TOML file with default options, one table per subcommand keyed by the long flag names
+    /// (e.g. `[tilesmatch]` and `threshold = 0.2`), options on the command line take precedence
+    #[arg(long, env = "OPENTOOLS_CONFIG", value_name = "TOML")]
+    pub config: Option<PathBuf>,
+
+    /// named defaults of the chemistry, thresholds and threads, built-in `openst-e00x` and `openst-novaseq`
+    /// or a `[profile.NAME]` table of the `--config` TOML
+    #[arg(long, env = "OPENTOOLS_PROFILE", value_name = "NAME")]
+    pub profile: Option<String>,
+
+    /// more log lines on stderr, `-vv` for trace output
+    #[arg(short, long, action = ArgAction::Count, conflicts_with = "quiet")]
+    pub verbose: u8,
+
+    /// only warnings on stderr, `-qq` for errors only
+    #[arg(short, long, action = ArgAction::Count)]
+    pub quiet: u8,
+
+    /// threads of every subcommand and of the rayon pool, a subcommand's own thread option takes precedence
+    #[arg(long, env = "OPENTOOLS_THREADS", value_name = "N")]
+    pub threads: Option<NonZeroUsize>,
+
+    /// threads decompressing gzip FASTQ and barcode inputs, BGZF blocks are inflated in parallel and
+    /// other gzip is decoded on one thread ahead of the parsing; decoded inline when unset
+    #[arg(long, env = "OPENTOOLS_DECOMPRESS_THREADS", value_name = "N")]
+    pub decompress_threads: Option<NonZeroUsize>,
+
+    /// read buffer of the FASTQ and text inputs, larger buffers suit network and parallel filesystems
+    /// such as Lustre or object storage (e.g. 4M) [default: 64K]
+    #[arg(long, env = "OPENTOOLS_READ_BUFFER_SIZE", value_parser = parse_memory_size, value_name = "SIZE")]
+    pub read_buffer_size: Option<u64>,
+
+    /// write buffer of the touchbarcode tile barcodes and the tilesmatch matched rows [default: 64K]
+    #[arg(long, env = "OPENTOOLS_WRITE_BUFFER_SIZE", value_parser = parse_memory_size, value_name = "SIZE")]
+    pub write_buffer_size: Option<u64>,
+
+    /// FASTQ record sets read ahead of the touchbarcode workers, twice the workers when unset
+    #[arg(long, env = "OPENTOOLS_QUEUE_SIZE", value_name = "N")]
+    pub queue_size: Option<NonZeroUsize>,
+
+    /// scratch directory of temporary files (touchbarcode tiles, sort and dedup spills), `$TMPDIR` when unset,
+    /// next to the outputs without either
+    #[arg(long, env = "OPENTOOLS_TMPDIR", value_name = "DIR")]
+    pub tmpdir: Option<PathBuf>,
+
+    /// plain output without colors or aligned tables, also set by a non-empty `NO_COLOR`
+    #[arg(long)]
+    pub no_color: bool,
+
+    /// seed of the sampling, e.g. the query subsamples of `tilesmatch --replicates`, for reruns with the same numbers
+    #[arg(long, env = "OPENTOOLS_SEED", value_name = "N")]
+    pub seed: Option<u64>,
+
+    /// structured progress events, `jsonl` writes one JSON object per finished tile or step
+    #[arg(long, env = "OPENTOOLS_PROGRESS", value_enum)]
+    pub progress: Option<ProgressFormat>,
+
+    /// write the progress events into this file or named pipe instead of stderr
+    #[arg(long, env = "OPENTOOLS_PROGRESS_FILE", value_name = "PATH", requires = "progress")]
+    pub progress_file: Option<PathBuf>,
+
+    /// overwrite existing outputs, without it a run stops before writing anything when an output file
+    /// exists or an output directory is not empty, `--resume` runs continue from theirs
+    #[arg(long)]
+    pub force: bool,
+
+    /// how a failure is reported on stderr, `json` writes one object with the error variant, message, path and line
+    #[arg(long, env = "OPENTOOLS_ERROR_FORMAT", value_enum, default_value_t = ErrorFormat::Text)]
+    pub error_format: ErrorFormat,
+
     #[command(subcommand)]
     pub command: Commands,
 }
 
+impl Cli {
+    /// Log level of `-v`/`-q`, informational messages by default
+    pub fn log_level(&self) -> LevelFilter {
+        match (self.verbose, self.quiet) {
+            (0, 0) => LevelFilter::INFO,
+            (1, _) => LevelFilter::DEBUG,
+            (_, 0) => LevelFilter::TRACE,
+            (_, 1) => LevelFilter::WARN,
+            _ => LevelFilter::ERROR,
+        }
+    }
+
+    /// Scratch directory of `--tmpdir`, falling back to `$TMPDIR`
+    pub fn tmpdir(&self) -> Option<PathBuf> {
+        self.tmpdir.clone().or_else(|| std::env::var_os("TMPDIR").map(PathBuf::from))
+    }
+}
+
+/// Help heading of the output options, which are checked against overwriting without `--force`
+pub const OUTPUTS: &str = "Outputs";
+
+/// Output files that exist and output directories that are not empty, among the `OUTPUTS` options
+/// of the parsed subcommand
+///
+/// A `--resume` run continues from its outputs and is never refused. touchbarcode reruns in its
+/// output directory to convert the missing tiles, only its barcode file counts there.
+pub fn existing_outputs(matches: &ArgMatches) -> Vec<PathBuf> {
+    let Some((name, sub_matches)) = matches.subcommand() else {
+        return Vec::new();
+    };
+    if let Ok(Some(true)) = sub_matches.try_get_one::<bool>("resume") {
+        return Vec::new();
+    }
+    let command = Cli::command();
+    let subcommand = command.find_subcommand(name).expect("subcommand was parsed");
+    subcommand.get_arguments()
+        .filter(|arg| arg.get_help_heading() == Some(OUTPUTS))
+        .filter_map(|arg| sub_matches.get_raw(arg.get_id().as_str()))
+        .flatten()
+        .map(PathBuf::from)
+        .map(|path| if name == "touchbarcode" { path.join(touchbarcode::BARCODE_FILE) } else { path })
+        .filter(|path| path.as_os_str() != "-" && match fs::read_dir(path) {
+            Ok(mut entries) => entries.next().is_some(),
+            Err(_) => path.exists(),
+        })
+        .collect()
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum ProgressFormat {
+    Jsonl,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum ErrorFormat {
+    Text,
+    Json,
+}
+
 /// Subcommand enumeration definitions
 /// 
 /// Each variant corresponds to a specific tool function
@@ -31,7 +230,71 @@ pub enum Commands {
     #[clap(name="touchbarcode")]
     TouchBarcode(TouchBarcodeArgs),
     #[clap(name="dedupbarcode")]
-    ViewBarcode(DedupBarcodeArgs),
+    DedupBarcode(DedupBarcodeArgs),
+    #[clap(name="viewbarcode")]
+    ViewBarcode(ViewBarcodeArgs),
     #[clap(name="tilesmatch")]
     TilesMatch(TilesMatchArgs),
+    #[clap(name="barcoderank")]
+    BarcodeRank(BarcodeRankArgs),
+    #[clap(name="spatialtag")]
+    SpatialTag(SpatialTagArgs),
+    #[clap(name="count")]
+    Count(CountArgs),
+    #[clap(name="demux")]
+    Demux(DemuxArgs),
+    #[clap(name="whitelist")]
+    Whitelist(WhitelistArgs),
+    #[clap(name="qc")]
+    Qc(QcArgs),
+    #[clap(name="convert")]
+    Convert(ConvertArgs),
+    #[clap(name="segment")]
+    Segment(SegmentArgs),
+    #[clap(name="mergebam")]
+    MergeBam(MergeBamArgs),
+    #[clap(name="indexbarcode")]
+    IndexBarcode(IndexBarcodeArgs),
+    #[clap(name="fqstat")]
+    FqStat(FqStatArgs),
+    #[clap(name="validate")]
+    Validate(ValidateArgs),
+    #[clap(name="extract")]
+    Extract(ExtractArgs),
+    #[clap(name="pipeline")]
+    Pipeline(PipelineArgs),
+    #[clap(name="completions")]
+    Completions(CompletionsArgs),
+    #[clap(name="tileimage")]
+    TileImage(TileImageArgs),
+    #[clap(name="collide")]
+    Collide(CollideArgs),
+    #[clap(name="adapterscan")]
+    AdapterScan(AdapterScanArgs),
+    #[clap(name="splitlane")]
+    SplitLane(SplitLaneArgs),
+    #[clap(name="region")]
+    Region(RegionArgs),
+    #[clap(name="compare")]
+    Compare(CompareArgs),
+    #[clap(name="stitch")]
+    Stitch(StitchArgs),
+    #[clap(name="register")]
+    Register(RegisterArgs),
+    #[clap(name="bin")]
+    Bin(BinArgs),
+    #[clap(name="saturation")]
+    Saturation(SaturationArgs),
+    #[clap(name="mask")]
+    Mask(MaskArgs),
+    #[clap(name="report")]
+    Report(ReportArgs),
+    #[clap(name="interop")]
+    Interop(InteropArgs),
+    #[clap(name="phix")]
+    Phix(PhixArgs),
+    #[clap(name="errprofile")]
+    ErrProfile(ErrProfileArgs),
+    #[clap(name="splitpool")]
+    SplitPool(SplitPoolArgs),
 }
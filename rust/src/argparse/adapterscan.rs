@@ -0,0 +1,237 @@
+use crate::utils::{
+    barcode_iter::validate_filepath_or_stdin,
+    fastqfile,
+    error::AppError,
+};
+use crate::argparse::convert::write_text;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use clap::Parser;
+use seq_io::fastq::Record;
+
+/// Adapters searched by default, the first `SEED` bases of each are matched exactly
+const KNOWN_ADAPTERS: [(&str, &str); 6] = [
+    ("illumina_universal", "AGATCGGAAGAGC"),
+    ("nextera", "CTGTCTCTTATACACATCT"),
+    ("small_rna", "TGGAATTCTCGG"),
+    ("tso", "AAGCAGTGGTATCAACGCAGAGTACATGGG"),
+    ("polya", "AAAAAAAAAAAAAAA"),
+    ("polyg", "GGGGGGGGGGGGGGG"),
+];
+
+/// Bases of an adapter matched exactly
+const SEED: usize = 12;
+
+/// k-mer length of the unexpected adapter detection
+const KMER: usize = 12;
+
+#[derive(Parser, Debug)]
+#[command(name = "adapterscan")]
+#[command(about = "Report adapter, TSO and homopolymer content of FASTQ reads per cycle", long_about = None)]
+#[command(next_line_help = true)]
+pub struct AdapterScanArgs {
+    /// FASTQ file (optionally gzipped), `-` for stdin
+    #[arg(short, long, value_parser = validate_filepath_or_stdin)]
+    input: PathBuf,
+
+    /// extra adapter to search as `name=SEQUENCE`, repeat it for several adapters
+    #[arg(long, value_parser = parse_adapter, value_name = "NAME=SEQ")]
+    adapter: Vec<(String, String)>,
+
+    /// scan only the first N reads, all reads with 0
+    #[arg(short = 'n', long, default_value_t = 1_000_000, value_name = "N")]
+    reads: u64,
+
+    /// reads of which k-mers are counted to detect unexpected adapters, reads holding a known adapter are skipped
+    #[arg(long, default_value_t = 200_000, value_name = "N")]
+    kmer_reads: u64,
+
+    /// number of overrepresented k-mers reported
+    #[arg(long, default_value_t = 5, value_name = "N")]
+    top: usize,
+
+    /// write the cumulative share of reads holding each adapter at every cycle into this TSV file
+    #[arg(short, long, help_heading = super::OUTPUTS)]
+    output: Option<PathBuf>,
+}
+
+fn parse_adapter(value: &str) -> Result<(String, String), String> {
+    let (name, sequence) = value.split_once('=')
+        .ok_or(format!("`{}` is not an adapter, expected 'name=SEQUENCE'", value))?;
+    let sequence = sequence.to_ascii_uppercase();
+    if name.is_empty() || sequence.is_empty() || !sequence.bytes().all(|b| matches!(b, b'A' | b'C' | b'G' | b'T')) {
+        return Err(format!("`{}` is not an adapter, expected 'name=SEQUENCE' of ACGT", value));
+    }
+    Ok((name.to_string(), sequence))
+}
+
+/// 2-bit packed k-mer, `None` when it holds a base other than ACGT
+#[inline]
+fn pack(kmer: &[u8]) -> Option<u32> {
+    kmer.iter().try_fold(0u32, |packed, base| {
+        let bits = match base {
+            b'A' => 0,
+            b'C' => 1,
+            b'G' => 2,
+            b'T' => 3,
+            _ => return None,
+        };
+        Some(packed << 2 | bits)
+    })
+}
+
+fn unpack(packed: u32) -> String {
+    (0..KMER).rev().map(|i| b"ACGT"[(packed >> (2 * i) & 3) as usize] as char).collect()
+}
+
+/// Adapter with the reads holding its seed first at every cycle
+struct AdapterHits {
+    name: String,
+    seed: Vec<u8>,
+    /// full adapter sequence, k-mers inside it are not reported as unexpected
+    sequence: String,
+    starts: Vec<u64>,
+}
+
+impl AdapterHits {
+    fn new(name: &str, sequence: &str) -> Self {
+        let seed = sequence.as_bytes()[..sequence.len().min(SEED)].to_vec();
+        Self { name: name.to_string(), seed, sequence: sequence.to_string(), starts: Vec::new() }
+    }
+
+    /// Count the first start of the adapter in the read, whether it holds the adapter
+    fn scan(&mut self, seq: &[u8]) -> bool {
+        let Some(start) = seq.windows(self.seed.len()).position(|window| window == self.seed.as_slice()) else {
+            return false;
+        };
+        if self.starts.len() <= start {
+            self.starts.resize(start + 1, 0);
+        }
+        self.starts[start] += 1;
+        true
+    }
+
+    /// Reads holding the adapter at or before every cycle, up to `cycles`
+    fn cumulative(&self, cycles: usize) -> Vec<u64> {
+        let mut total = 0;
+        (0..cycles).map(|cycle| {
+            total += self.starts.get(cycle).copied().unwrap_or(0);
+            total
+        }).collect()
+    }
+}
+
+impl AdapterScanArgs {
+    pub fn scan(self) -> Result<AdapterScanReport, AppError> {
+        let mut adapters: Vec<AdapterHits> = KNOWN_ADAPTERS.iter()
+            .map(|(name, sequence)| AdapterHits::new(name, sequence))
+            .chain(self.adapter.iter().map(|(name, sequence)| AdapterHits::new(name, sequence)))
+            .collect();
+        let mut kmers: HashMap<u32, u64> = HashMap::new();
+        let mut read_kmers = HashSet::new();
+        let mut reader = fastqfile::open(&self.input)?;
+        let (mut reads, mut cycles) = (0u64, 0usize);
+        while let Some(record) = reader.next() {
+            let record = record?;
+            if self.reads > 0 && reads >= self.reads {
+                break;
+            }
+            let seq = record.seq();
+            reads += 1;
+            cycles = cycles.max(seq.len());
+            let mut explained = false;
+            for adapter in adapters.iter_mut() {
+                explained |= adapter.scan(seq);
+            }
+            // k-mers of reads without a known adapter, every k-mer counted once per read
+            if reads <= self.kmer_reads && !explained {
+                read_kmers.clear();
+                read_kmers.extend(seq.windows(KMER).filter_map(pack));
+                for &kmer in &read_kmers {
+                    *kmers.entry(kmer).or_default() += 1;
+                }
+            }
+        }
+
+        let known: Vec<&str> = adapters.iter().map(|adapter| adapter.sequence.as_str()).collect();
+        let mut overrepresented: Vec<(String, u64)> = kmers.into_iter()
+            .map(|(kmer, count)| (unpack(kmer), count))
+            // homopolymers are reported by the poly adapters, low complexity k-mers are not adapters
+            .filter(|(kmer, _)| kmer.bytes().collect::<HashSet<_>>().len() > 2)
+            .filter(|(kmer, _)| !known.iter().any(|sequence| sequence.contains(kmer.as_str())))
+            .collect();
+        overrepresented.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        // k-mers shifted along the same adapter are reported once
+        let mut reported: Vec<(String, u64)> = Vec::new();
+        for (kmer, count) in overrepresented {
+            if reported.len() == self.top {
+                break;
+            }
+            if !reported.iter().any(|(other, _)| overlaps(other, &kmer)) {
+                reported.push((kmer, count));
+            }
+        }
+
+        if let Some(path) = &self.output {
+            let columns: Vec<Vec<u64>> = adapters.iter().map(|adapter| adapter.cumulative(cycles)).collect();
+            write_text(path, |writer| {
+                write!(writer, "cycle")?;
+                for adapter in &adapters {
+                    write!(writer, "\t{}", adapter.name)?;
+                }
+                for cycle in 0..cycles {
+                    write!(writer, "\n{}", cycle + 1)?;
+                    for column in &columns {
+                        write!(writer, "\t{:.4}", percent(column[cycle], reads))?;
+                    }
+                }
+                writeln!(writer)
+            })?;
+        }
+
+        Ok(AdapterScanReport {
+            reads,
+            kmer_reads: reads.min(self.kmer_reads),
+            adapters: adapters.iter().map(|adapter| {
+                let hits = adapter.starts.iter().sum::<u64>();
+                let first = adapter.starts.iter().position(|&count| count > 0);
+                (adapter.name.clone(), hits, first)
+            }).collect(),
+            overrepresented: reported,
+        })
+    }
+}
+
+/// Whether the k-mers overlap by at least half of their bases, in either order
+fn overlaps(a: &str, b: &str) -> bool {
+    (0..=KMER / 2).any(|shift| a[shift..] == b[..KMER - shift] || b[shift..] == a[..KMER - shift])
+}
+
+#[inline]
+fn percent(part: u64, total: u64) -> f64 {
+    if total == 0 { 0.0 } else { part as f64 * 100.0 / total as f64 }
+}
+
+/// Reads holding each adapter and the most frequent unexplained k-mers
+pub struct AdapterScanReport {
+    reads: u64,
+    kmer_reads: u64,
+    /// name, reads holding it and first cycle it starts at
+    adapters: Vec<(String, u64, Option<usize>)>,
+    overrepresented: Vec<(String, u64)>,
+}
+
+impl std::fmt::Display for AdapterScanReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Reads={}\nAdapter\tReads\tPercent\tFirst cycle", self.reads)?;
+        for (name, hits, first) in &self.adapters {
+            let first = first.map_or("-".to_string(), |cycle| (cycle + 1).to_string());
+            write!(f, "\n{}\t{}\t{:.2}\t{}", name, hits, percent(*hits, self.reads), first)?;
+        }
+        write!(f, "\nOverrepresented k-mer\tReads\tPercent")?;
+        for (kmer, count) in &self.overrepresented {
+            write!(f, "\n{}\t{}\t{:.2}", kmer, count, percent(*count, self.kmer_reads))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,232 @@
+use crate::utils::{
+    hts,
+    barcode_iter::{validate_absolute_dirpath, validate_filepath_or_stdin},
+    fastqfile::open_text,
+    plot::LinePlot,
+    error::AppError,
+};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use clap::{Parser, ValueEnum};
+use rayon::prelude::*;
+use rust_htslib::bam::{self, Read, record::Aux};
+
+/// Parse a two character SAM tag like `CB`
+pub fn parse_bam_tag(value: &str) -> Result<String, String> {
+    let bytes = value.as_bytes();
+    if bytes.len() != 2 || !bytes[0].is_ascii_alphabetic() || !bytes[1].is_ascii_alphanumeric() {
+        return Err(format!("`{}` is not a valid SAM tag, expected two characters like CB", value));
+    }
+    Ok(value.to_string())
+}
+
+/// Reads of every barcode stored in the `tag` of a BAM file, secondary and supplementary alignments skipped
+pub fn count_bam_tag(path: &Path, tag: &str) -> Result<HashMap<String, u64>, AppError> {
+    let mut reader = hts::open_bam(path)?;
+    let mut counts: HashMap<String, u64> = HashMap::new();
+    let mut record = bam::Record::new();
+    while let Some(result) = reader.read(&mut record) {
+        result?;
+        if record.is_secondary() || record.is_supplementary() {
+            continue;
+        }
+        if let Ok(Aux::String(barcode)) = record.aux(tag.as_bytes()) {
+            match counts.get_mut(barcode) {
+                Some(count) => *count += 1,
+                None => {
+                    counts.insert(barcode.to_string(), 1);
+                }
+            }
+        }
+    }
+    Ok(counts)
+}
+
+/// Reads of every barcode from `barcode\tcount` lines, a non-numeric first line is taken as header
+pub fn read_count_table(path: &Path) -> Result<HashMap<String, u64>, AppError> {
+    let mut counts: HashMap<String, u64> = HashMap::new();
+    for (index, line) in open_text(path)?.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let (Some(barcode), Some(count)) = (fields.next(), fields.next()) else {
+            return Err(AppError::IoError(io::Error::new(
+                io::ErrorKind::InvalidData, format!("Invalid count table line {}: {}", index + 1, line)
+            )));
+        };
+        let count: u64 = match count.parse() {
+            Ok(count) => count,
+            Err(_) if index == 0 => continue,
+            Err(_) => return Err(AppError::IoError(io::Error::new(
+                io::ErrorKind::InvalidData, format!("Invalid count on line {}: {}", index + 1, count)
+            ))),
+        };
+        *counts.entry(barcode.to_string()).or_default() += count;
+    }
+    Ok(counts)
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum RankInput {
+    /// `.bam`, `.sam` and `.cram` are read as alignments, anything else as count table
+    Auto,
+    /// alignments with the barcode in a tag
+    Bam,
+    /// `barcode\tcount` per line, optionally gzipped, a non-numeric first line is taken as header
+    Table,
+}
+
+#[derive(Parser, Debug)]
+#[command(name = "barcoderank")]
+#[command(about = "Barcode rank curve with knee and inflection points", long_about = None)]
+#[command(next_line_help = true)]
+pub struct BarcodeRankArgs {
+    /// tagged BAM or barcode count table (`-` for a table from stdin)
+    #[arg(short, long, value_parser = validate_filepath_or_stdin)]
+    input: PathBuf,
+
+    /// how to read the input
+    #[arg(long, value_enum, default_value_t = RankInput::Auto)]
+    input_format: RankInput,
+
+    /// SAM tag holding the barcode (only effective for BAM input)
+    #[arg(long, default_value = "CB", value_parser = parse_bam_tag)]
+    tag: String,
+
+    /// barcodes with fewer reads are ignored when searching the knee and inflection
+    #[arg(long, default_value_t = 100)]
+    lower: u64,
+
+    /// write `barcode_rank.tsv` and `barcode_rank.svg` into this directory
+    #[arg(short, long, value_parser = validate_absolute_dirpath, help_heading = super::OUTPUTS)]
+    output_dir: PathBuf,
+}
+
+impl BarcodeRankArgs {
+    fn is_bam(&self) -> bool {
+        match self.input_format {
+            RankInput::Bam => true,
+            RankInput::Table => false,
+            RankInput::Auto => self.input.extension()
+                .is_some_and(|ext| ext == "bam" || ext == "sam" || ext == "cram"),
+        }
+    }
+
+    pub fn rank(self) -> Result<RankReport, AppError> {
+        let counts = if self.is_bam() {
+            count_bam_tag(&self.input, &self.tag)?
+        } else {
+            read_count_table(&self.input)?
+        };
+        let mut ranked: Vec<(String, u64)> = counts.into_iter().collect();
+        // ties broken by barcode so output is stable
+        ranked.par_sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+        let curve = rank_curve(&ranked);
+        let searched: Vec<(f64, f64)> = curve.iter()
+            .copied()
+            .filter(|&(_, count)| count >= self.lower as f64)
+            .collect();
+        let report = RankReport {
+            barcodes: ranked.len() as u64,
+            reads: ranked.iter().map(|(_, count)| count).sum(),
+            knee: find_knee(&searched),
+            inflection: find_inflection(&searched),
+        };
+
+        let mut writer = BufWriter::new(fs::File::create(self.output_dir.join("barcode_rank.tsv"))?);
+        writeln!(writer, "rank\tbarcode\tcount")?;
+        for (rank, (barcode, count)) in ranked.iter().enumerate() {
+            writeln!(writer, "{}\t{}\t{}", rank + 1, barcode, count)?;
+        }
+        writer.flush()?;
+
+        let mut plot = LinePlot::new("Barcode rank", "Rank", "Reads").log_scale(true, true);
+        plot.add_series(curve, "steelblue");
+        if let Some((rank, count)) = report.knee {
+            plot.add_marker(&format!("knee {rank}"), rank as f64, count as f64);
+        }
+        if let Some((rank, count)) = report.inflection {
+            plot.add_marker(&format!("inflection {rank}"), rank as f64, count as f64);
+        }
+        plot.write(&self.output_dir.join("barcode_rank.svg"))?;
+        Ok(report)
+    }
+}
+
+/// Collapse barcodes of equal count into one point at their mid rank, like DropletUtils
+pub fn rank_curve(ranked: &[(String, u64)]) -> Vec<(f64, f64)> {
+    let mut curve = Vec::new();
+    let mut start = 0;
+    while start < ranked.len() {
+        let count = ranked[start].1;
+        let end = start + ranked[start..].iter().take_while(|(_, c)| *c == count).count();
+        curve.push(((start + 1 + end) as f64 / 2.0, count as f64));
+        start = end;
+    }
+    curve
+}
+
+/// Point of the log-log curve farthest above the chord between its ends
+pub fn find_knee(curve: &[(f64, f64)]) -> Option<(u64, u64)> {
+    if curve.len() < 3 {
+        return None;
+    }
+    let log = |&(x, y): &(f64, f64)| (x.log10(), y.log10());
+    let (x0, y0) = log(&curve[0]);
+    let (x1, y1) = log(&curve[curve.len() - 1]);
+    let (dx, dy) = (x1 - x0, y1 - y0);
+    curve.iter()
+        .map(|point| {
+            let (x, y) = log(point);
+            // signed distance, positive above the chord
+            ((dx * (y - y0) - dy * (x - x0)) / dx.hypot(dy), point)
+        })
+        .max_by(|a, b| a.0.total_cmp(&b.0))
+        .map(|(_, &(rank, count))| (rank.round() as u64, count as u64))
+}
+
+/// Point of the steepest descent of the log-log curve
+fn find_inflection(curve: &[(f64, f64)]) -> Option<(u64, u64)> {
+    if curve.len() < 3 {
+        return None;
+    }
+    curve.windows(2)
+        .map(|pair| {
+            let slope = (pair[1].1.log10() - pair[0].1.log10()) / (pair[1].0.log10() - pair[0].0.log10());
+            (slope, pair[1])
+        })
+        .min_by(|a, b| a.0.total_cmp(&b.0))
+        .map(|(_, (rank, count))| (rank.round() as u64, count as u64))
+}
+
+/// Knee and inflection of the barcode rank curve
+pub struct RankReport {
+    barcodes: u64,
+    reads: u64,
+    /// (rank, count)
+    knee: Option<(u64, u64)>,
+    /// (rank, count)
+    inflection: Option<(u64, u64)>,
+}
+
+impl std::fmt::Display for RankReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let point = |point: Option<(u64, u64)>| match point {
+            Some((rank, count)) => format!("rank={rank}, count={count}"),
+            None => "not found".to_string(),
+        };
+        write!(
+            f,
+            "Barcodes={}, Reads={}\nKnee: {}\nInflection: {}",
+            self.barcodes,
+            self.reads,
+            point(self.knee),
+            point(self.inflection),
+        )
+    }
+}
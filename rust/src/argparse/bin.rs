@@ -0,0 +1,404 @@
+use crate::utils::{
+    barcode_iter::{validate_absolute_dirpath, validate_absolute_filepath},
+    fastqfile::open_text,
+    error::AppError,
+};
+use crate::argparse::{
+    convert::write_text,
+    register::find_column,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+use clap::{Parser, ValueEnum};
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum BinShape {
+    /// squares of --size wide
+    Square,
+    /// pointy top hexagons with --size between neighbouring centers of a row, like Visium spots
+    Hex,
+}
+
+#[derive(Parser, Debug)]
+#[command(name = "bin")]
+#[command(about = "Aggregate barcodes and their counts into square or hexagonal bins", long_about = None)]
+#[command(next_line_help = true)]
+pub struct BinArgs {
+    /// coordinates with a header line naming the columns, e.g. a barcode file or stitch output
+    ///
+    /// `x_pos`/`y_pos` of a barcode file are binned per tile, `x_um`/`y_um` (or `x`/`y`) as one plane
+    #[arg(short, long, value_parser = validate_absolute_filepath)]
+    input: PathBuf,
+
+    /// bin size in units of the coordinates
+    #[arg(short, long)]
+    size: f64,
+
+    /// bin shape
+    #[arg(long, value_enum, default_value_t = BinShape::Square)]
+    shape: BinShape,
+
+    /// count output of `count` (`matrix.mtx.gz`, `features.tsv.gz` and `barcodes.tsv.gz`) summed into bins
+    #[arg(long, value_parser = validate_absolute_dirpath)]
+    counts: Option<PathBuf>,
+
+    /// write `bins.tsv`, `barcode_bins.tsv.gz` and with --counts the bin x gene matrix into `counts/`
+    #[arg(short, long, value_parser = validate_absolute_dirpath, help_heading = super::OUTPUTS)]
+    output_dir: PathBuf,
+}
+
+/// Tile of per tile coordinates, column and row of the bin
+type BinKey = (Option<u64>, i64, i64);
+
+/// Bin of a position and its center
+struct Grid {
+    size: f64,
+    shape: BinShape,
+}
+
+impl Grid {
+    /// Rows of hexagons are `size * sqrt(3) / 2` apart, odd rows shifted by half a bin
+    #[inline]
+    fn row_height(&self) -> f64 {
+        match self.shape {
+            BinShape::Square => self.size,
+            BinShape::Hex => self.size * 3f64.sqrt() / 2.0,
+        }
+    }
+
+    #[inline]
+    fn center(&self, column: i64, row: i64) -> (f64, f64) {
+        match self.shape {
+            BinShape::Square => ((column as f64 + 0.5) * self.size, (row as f64 + 0.5) * self.size),
+            BinShape::Hex => {
+                let shift = if row.rem_euclid(2) == 1 { self.size / 2.0 } else { 0.0 };
+                (column as f64 * self.size + shift, row as f64 * self.row_height())
+            }
+        }
+    }
+
+    /// Column and row of the bin holding the position, the nearest hexagon center for hexagons
+    fn locate(&self, x: f64, y: f64) -> (i64, i64) {
+        match self.shape {
+            BinShape::Square => ((x / self.size).floor() as i64, (y / self.size).floor() as i64),
+            BinShape::Hex => {
+                // the nearest center lies in one of the two rows around the position
+                let below = (y / self.row_height()).floor() as i64;
+                [below, below + 1].into_iter().map(|row| {
+                    let shift = if row.rem_euclid(2) == 1 { self.size / 2.0 } else { 0.0 };
+                    let column = ((x - shift) / self.size).round() as i64;
+                    let (center_x, center_y) = self.center(column, row);
+                    ((x - center_x).hypot(y - center_y), column, row)
+                }).min_by(|a, b| a.0.total_cmp(&b.0)).map(|(_, column, row)| (column, row)).unwrap()
+            }
+        }
+    }
+}
+
+fn bin_name((tile, column, row): BinKey) -> String {
+    match tile {
+        Some(tile) => format!("{tile}_{column}_{row}"),
+        None => format!("{column}_{row}"),
+    }
+}
+
+fn invalid_data(message: String) -> AppError {
+    AppError::IoError(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+impl BinArgs {
+    pub fn bin(self) -> Result<BinReport, AppError> {
+        if self.size.is_nan() || self.size <= 0.0 {
+            return Err(invalid_data(format!("bin size {} must be positive", self.size)));
+        }
+        let grid = Grid { size: self.size, shape: self.shape };
+        let mut lines = open_text(&self.input)?.lines();
+        let header = lines.next().transpose()?.unwrap_or_default();
+        let columns: Vec<&str> = header.split('\t').collect();
+        let (Some(x_column), Some(y_column), Some(barcode_column)) = (
+            find_column(&columns, &["x_um", "x_pos", "x"]),
+            find_column(&columns, &["y_um", "y_pos", "y"]),
+            find_column(&columns, &["barcode", "cell_bc"]),
+        ) else {
+            return Err(invalid_data(format!("No barcode, x and y columns in the header of {}", self.input.display())));
+        };
+        // pixel positions are relative to their tile
+        let tile_column = (columns[x_column].trim_start_matches('#') == "x_pos")
+            .then(|| find_column(&columns, &["tile_id", "tile"]))
+            .flatten();
+
+        let mut report = BinReport::default();
+        let mut bins: BTreeMap<BinKey, u64> = BTreeMap::new();
+        let mut members: HashMap<String, BinKey> = HashMap::new();
+        for (index, line) in lines.enumerate() {
+            let line = line?;
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || invalid_data(format!("Invalid line {} in {}", index + 2, self.input.display()));
+            let fields: Vec<&str> = line.split('\t').collect();
+            let number = |column: usize| fields.get(column).and_then(|value| value.parse::<f64>().ok());
+            let (Some(x), Some(y), Some(barcode)) = (number(x_column), number(y_column), fields.get(barcode_column)) else {
+                return Err(invalid());
+            };
+            let tile = match tile_column {
+                Some(column) => Some(fields.get(column).and_then(|value| value.parse::<u64>().ok()).ok_or_else(invalid)?),
+                None => None,
+            };
+            let (column, row) = grid.locate(x, y);
+            let key = (tile, column, row);
+            report.barcodes += 1;
+            match members.get(*barcode) {
+                // a barcode at two positions only counts into the first bin
+                Some(first) if *first != key => report.ambiguous += 1,
+                Some(_) => {}
+                None => {
+                    members.insert(barcode.to_string(), key);
+                    *bins.entry(key).or_default() += 1;
+                }
+            }
+        }
+        report.bins = bins.len() as u64;
+
+        let counts = match &self.counts {
+            Some(dir) => Some(self.bin_counts(dir, &bins, &members, &mut report)?),
+            None => None,
+        };
+        write_text(&self.output_dir.join("bins.tsv"), |writer| {
+            write!(writer, "bin\tx\ty\tbarcodes")?;
+            if counts.is_some() {
+                write!(writer, "\tumis")?;
+            }
+            writeln!(writer)?;
+            for (index, (&key, barcodes)) in bins.iter().enumerate() {
+                let (x, y) = grid.center(key.1, key.2);
+                write!(writer, "{}\t{:.2}\t{:.2}\t{}", bin_name(key), x, y, barcodes)?;
+                if let Some(umis) = &counts {
+                    write!(writer, "\t{}", umis[index])?;
+                }
+                writeln!(writer)?;
+            }
+            Ok(())
+        })?;
+        let mut members: Vec<(String, BinKey)> = members.into_iter().collect();
+        members.sort_unstable_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+        write_text(&self.output_dir.join("barcode_bins.tsv.gz"), |writer| {
+            writeln!(writer, "barcode\tbin")?;
+            for (barcode, key) in &members {
+                writeln!(writer, "{}\t{}", barcode, bin_name(*key))?;
+            }
+            Ok(())
+        })?;
+        Ok(report)
+    }
+
+    /// Sum the gene counts of the barcodes into their bins and write the bin x gene matrix,
+    /// the UMIs of every bin in `bins` order are handed back
+    fn bin_counts(
+        &self,
+        dir: &Path,
+        bins: &BTreeMap<BinKey, u64>,
+        members: &HashMap<String, BinKey>,
+        report: &mut BinReport,
+    ) -> Result<Vec<u64>, AppError> {
+        let columns: HashMap<BinKey, usize> = bins.keys().enumerate().map(|(index, &key)| (key, index)).collect();
+        // matrix column of every barcode, `None` for barcodes without a position
+        let mut barcode_bins = Vec::new();
+        for line in open_text(dir.join("barcodes.tsv.gz"))?.lines() {
+            let line = line?;
+            let barcode = line.strip_suffix("-1").unwrap_or(&line);
+            barcode_bins.push(members.get(barcode).map(|key| columns[key]));
+        }
+
+        let matrix_path = dir.join("matrix.mtx.gz");
+        let invalid = || invalid_data(format!("Invalid MatrixMarket file {}", matrix_path.display()));
+        let mut lines = open_text(&matrix_path)?.lines();
+        // genes of the dimension line, the first after the comments
+        let mut dimensions: Option<usize> = None;
+        let mut entries: BTreeMap<(usize, usize), u64> = BTreeMap::new();
+        let mut umis = vec![0u64; bins.len()];
+        while let Some(line) = lines.next().transpose()? {
+            if line.starts_with('%') {
+                continue;
+            }
+            let numbers: Vec<usize> = line.split_whitespace().map(|value| value.parse().map_err(|_| invalid()))
+                .collect::<Result<_, _>>()?;
+            let [gene, barcode, count] = numbers[..] else {
+                return Err(invalid());
+            };
+            if dimensions.is_none() {
+                dimensions = Some(gene);
+                continue;
+            }
+            match barcode_bins.get(barcode.wrapping_sub(1)).ok_or_else(invalid)? {
+                Some(bin) => {
+                    *entries.entry((*bin, gene)).or_default() += count as u64;
+                    umis[*bin] += count as u64;
+                }
+                None => report.unplaced_umis += count as u64,
+            }
+        }
+
+        let genes = dimensions.ok_or_else(invalid)?;
+
+        let output = self.output_dir.join("counts");
+        fs::create_dir_all(&output)?;
+        fs::copy(dir.join("features.tsv.gz"), output.join("features.tsv.gz"))?;
+        write_text(&output.join("barcodes.tsv.gz"), |writer| {
+            for &key in bins.keys() {
+                writeln!(writer, "{}", bin_name(key))?;
+            }
+            Ok(())
+        })?;
+        write_text(&output.join("matrix.mtx.gz"), |writer| {
+            writeln!(writer, "%%MatrixMarket matrix coordinate integer general")?;
+            writeln!(writer, "{} {} {}", genes, bins.len(), entries.len())?;
+            for ((bin, gene), count) in &entries {
+                writeln!(writer, "{} {} {}", gene, bin + 1, count)?;
+            }
+            Ok(())
+        })?;
+        report.umis = umis.iter().sum();
+        Ok(umis)
+    }
+}
+
+/// Barcodes and UMIs placed into bins
+#[derive(Default)]
+pub struct BinReport {
+    barcodes: u64,
+    /// barcodes found again in another bin
+    ambiguous: u64,
+    bins: u64,
+    umis: u64,
+    /// UMIs of barcodes without a position
+    unplaced_umis: u64,
+}
+
+impl std::fmt::Display for BinReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Barcodes={}, Ambiguous={}, Bins={}", self.barcodes, self.ambiguous, self.bins)?;
+        if self.umis + self.unplaced_umis > 0 {
+            write!(f, ", UMIs={}, Unplaced UMIs={}", self.umis, self.unplaced_umis)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufReader, Write};
+    use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+
+    /// Bin of the nearest center among all bins around the position
+    fn nearest(grid: &Grid, x: f64, y: f64) -> (i64, i64) {
+        let (column, row) = ((x / grid.size) as i64, (y / grid.row_height()) as i64);
+        let mut bins: Vec<(i64, i64)> = (column - 3..=column + 3)
+            .flat_map(|column| (row - 3..=row + 3).map(move |row| (column, row)))
+            .collect();
+        bins.sort_by(|a, b| {
+            let distance = |&(column, row): &(i64, i64)| {
+                let (center_x, center_y) = grid.center(column, row);
+                (x - center_x).hypot(y - center_y)
+            };
+            distance(a).total_cmp(&distance(b))
+        });
+        bins[0]
+    }
+
+    #[test]
+    fn test_locate_square() {
+        let grid = Grid { size: 10.0, shape: BinShape::Square };
+        assert_eq!(grid.locate(0.0, 0.0), (0, 0));
+        assert_eq!(grid.locate(9.99, 10.0), (0, 1));
+        assert_eq!(grid.locate(-0.01, -10.0), (-1, -1));
+        assert_eq!(grid.locate(-10.01, 25.0), (-2, 2));
+        assert_eq!(grid.center(-1, 2), (-5.0, 25.0));
+    }
+
+    #[test]
+    fn test_locate_hex() {
+        let grid = Grid { size: 10.0, shape: BinShape::Hex };
+        let height = grid.row_height();
+        // odd rows are shifted by half a bin, also below zero
+        assert_eq!(grid.center(1, 1), (15.0, height));
+        assert_eq!(grid.center(0, -1), (5.0, -height));
+        for (column, row) in [(0, 0), (3, 1), (-2, -1), (-1, -2), (4, -3)] {
+            let (x, y) = grid.center(column, row);
+            assert_eq!(grid.locate(x, y), (column, row));
+        }
+        // either side of the edge between (0, 0) and (1, 0)
+        assert_eq!(grid.locate(4.99, 0.0), (0, 0));
+        assert_eq!(grid.locate(5.01, 0.0), (1, 0));
+        // either side of the edge between (0, 0) and (0, 1), whose center is at (5, height)
+        let (edge_x, edge_y) = (2.5, height / 2.0);
+        assert_eq!(grid.locate(edge_x - 0.01, edge_y - 0.01), (0, 0));
+        assert_eq!(grid.locate(edge_x + 0.01, edge_y + 0.01), (0, 1));
+        // and of the one between (0, 0) and (-1, -1), whose center is at (-5, -height)
+        assert_eq!(grid.locate(-edge_x + 0.01, -edge_y + 0.01), (0, 0));
+        assert_eq!(grid.locate(-edge_x - 0.01, -edge_y - 0.01), (-1, -1));
+
+        for step in 0..2000 {
+            let (x, y) = (-53.3 + 0.37 * (step % 50) as f64, -41.9 + 2.11 * (step / 50) as f64);
+            assert_eq!(grid.locate(x, y), nearest(&grid, x, y), "({x}, {y})");
+        }
+    }
+
+    fn write_gz(path: &Path, text: &str) {
+        let mut writer = GzEncoder::new(fs::File::create(path).unwrap(), Compression::default());
+        writer.write_all(text.as_bytes()).unwrap();
+        writer.finish().unwrap();
+    }
+
+    fn read_gz(path: &Path) -> String {
+        io::read_to_string(BufReader::new(GzDecoder::new(fs::File::open(path).unwrap()))).unwrap()
+    }
+
+    #[test]
+    fn test_bin_counts() {
+        let dir = std::env::temp_dir().join(format!("opentools-test-bin-{}", std::process::id()));
+        let counts = dir.join("counts_in");
+        fs::create_dir_all(&counts).unwrap();
+        let input = dir.join("coordinates.tsv");
+        fs::write(&input, "barcode\tx_um\ty_um\nAAA\t1\t1\nCCC\t5\t9\nGGG\t-3\t4\nTTT\t25\t12\nAAA\t50\t50\n").unwrap();
+        write_gz(&counts.join("features.tsv.gz"), "G1\tAlpha\tGene Expression\nG2\tBeta\tGene Expression\n");
+        write_gz(&counts.join("barcodes.tsv.gz"), "AAA-1\nCCC-1\nGGG-1\nNNN-1\n");
+        write_gz(
+            &counts.join("matrix.mtx.gz"),
+            "%%MatrixMarket matrix coordinate integer general\n% by count\n2 4 5\n1 1 3\n2 1 1\n1 2 2\n2 3 4\n1 4 7\n",
+        );
+        let args = || BinArgs::try_parse_from([
+            "bin", "-i", input.to_str().unwrap(), "-s", "10", "--counts", counts.to_str().unwrap(), "-o", dir.to_str().unwrap(),
+        ]).unwrap();
+
+        let report = args().bin().unwrap();
+        assert_eq!(
+            (report.barcodes, report.ambiguous, report.bins, report.umis, report.unplaced_umis),
+            (5, 1, 3, 10, 7)
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("bins.tsv")).unwrap(),
+            "bin\tx\ty\tbarcodes\tumis\n-1_0\t-5.00\t5.00\t1\t4\n0_0\t5.00\t5.00\t2\t6\n2_1\t25.00\t15.00\t1\t0\n"
+        );
+        assert_eq!(read_gz(&dir.join("barcode_bins.tsv.gz")), "barcode\tbin\nGGG\t-1_0\nAAA\t0_0\nCCC\t0_0\nTTT\t2_1\n");
+        assert_eq!(read_gz(&dir.join("counts/barcodes.tsv.gz")), "-1_0\n0_0\n2_1\n");
+        assert_eq!(
+            read_gz(&dir.join("counts/matrix.mtx.gz")),
+            "%%MatrixMarket matrix coordinate integer general\n2 3 3\n2 1 4\n1 2 5\n2 2 1\n"
+        );
+        assert_eq!(read_gz(&dir.join("counts/features.tsv.gz")), read_gz(&counts.join("features.tsv.gz")));
+
+        // a matrix without genes still has its dimension line
+        write_gz(&counts.join("features.tsv.gz"), "");
+        write_gz(&counts.join("matrix.mtx.gz"), "%%MatrixMarket matrix coordinate integer general\n0 4 0\n");
+        assert_eq!(args().bin().unwrap().umis, 0);
+        assert_eq!(read_gz(&dir.join("counts/matrix.mtx.gz")), "%%MatrixMarket matrix coordinate integer general\n0 3 0\n");
+        // and a matrix without one is refused
+        write_gz(&counts.join("matrix.mtx.gz"), "%%MatrixMarket matrix coordinate integer general\n");
+        assert!(args().bin().is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,175 @@
+use crate::utils::{
+    barcode_file::BarcodeRecord,
+    barcode_iter::{validate_absolute_dirpath, validate_absolute_filepath},
+    coordinate::tile_distance,
+    fastqfile::open_text,
+    error::AppError,
+};
+use crate::argparse::{
+    convert::write_text,
+    tilesmatch::is_valid_tile_id,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufRead};
+use std::path::PathBuf;
+use clap::Parser;
+
+#[derive(Parser, Debug)]
+#[command(name = "collide")]
+#[command(about = "Count barcodes shared between tiles by tile distance", long_about = None)]
+#[command(next_line_help = true)]
+pub struct CollideArgs {
+    /// The path to the barcode file
+    #[arg(short = 'I', long, value_parser = validate_absolute_filepath)]
+    barcode_file: PathBuf,
+
+    /// the tile id list to scan, all tiles in the barcode file by default
+    #[arg(
+        long,
+        value_delimiter = ' ',
+        num_args = 1..,
+        value_parser = is_valid_tile_id,
+    )]
+    tile_list: Vec<u64>,
+
+    /// write `collision_matrix.tsv` and `collision_distance.tsv` into this directory
+    ///
+    /// the matrix holds the barcodes shared by every pair of tiles, its diagonal the barcodes repeated within a tile
+    #[arg(short, long, value_parser = validate_absolute_dirpath, help_heading = super::OUTPUTS)]
+    output_dir: PathBuf,
+}
+
+/// Shared barcodes of all tile pairs at one distance
+#[derive(Default, Clone, Copy)]
+struct DistanceRow {
+    tile_pairs: u64,
+    shared: u64,
+}
+
+impl CollideArgs {
+    pub fn collide(self) -> Result<CollideReport, AppError> {
+        let invalid = |line: usize| AppError::IoError(io::Error::new(
+            io::ErrorKind::InvalidData, format!("Invalid barcode file line {line} in {}", self.barcode_file.display())
+        ));
+        let mut tiles: Vec<u64> = Vec::new();
+        let mut tile_index: HashMap<u64, usize> = HashMap::new();
+        // barcode to the tiles holding it, a tile listed twice when the barcode repeats within it
+        let mut barcodes: HashMap<String, Vec<u32>> = HashMap::new();
+        let mut report = CollideReport::default();
+        for (index, line) in open_text(&self.barcode_file)?.lines().enumerate() {
+            let line = line?;
+            if line.is_empty() || line.starts_with('#') || line.starts_with("tile_id") {
+                continue;
+            }
+            let record = BarcodeRecord::parse(&line)?;
+            let tile_id: u64 = record.tile_id.parse().map_err(|_| invalid(index + 1))?;
+            if !self.tile_list.is_empty() && !self.tile_list.contains(&tile_id) {
+                continue;
+            }
+            report.rows += 1;
+            let tile = *tile_index.entry(tile_id).or_insert_with(|| {
+                tiles.push(tile_id);
+                tiles.len() - 1
+            });
+            let holders = barcodes.entry(record.barcode.to_string()).or_default();
+            // a barcode repeated within its tile is counted once on the diagonal
+            if holders.iter().filter(|&&holder| holder == tile as u32).count() < 2 {
+                holders.push(tile as u32);
+            }
+        }
+
+        let n = tiles.len();
+        let mut matrix = vec![0u64; n * n];
+        for holders in barcodes.values_mut() {
+            holders.sort_unstable();
+            let mut distinct = holders.clone();
+            distinct.dedup();
+            if distinct.len() < holders.len() {
+                for window in holders.windows(2).filter(|window| window[0] == window[1]) {
+                    matrix[window[0] as usize * n + window[0] as usize] += 1;
+                }
+            }
+            if distinct.len() > 1 {
+                report.colliding += 1;
+            }
+            for (i, &a) in distinct.iter().enumerate() {
+                for &b in &distinct[i + 1..] {
+                    matrix[a as usize * n + b as usize] += 1;
+                    matrix[b as usize * n + a as usize] += 1;
+                }
+            }
+        }
+        report.barcodes = barcodes.len() as u64;
+        report.repeated_within = (0..n).map(|i| matrix[i * n + i]).sum();
+        drop(barcodes);
+
+        // distance `None` collects tile pairs of different lane surfaces
+        let mut order: Vec<usize> = (0..n).collect();
+        order.sort_by_key(|&i| tiles[i]);
+        for (position, &a) in order.iter().enumerate() {
+            for &b in &order[position + 1..] {
+                let row = report.distances.entry(tile_distance(tiles[a], tiles[b])).or_default();
+                row.tile_pairs += 1;
+                row.shared += matrix[a * n + b];
+            }
+        }
+
+        write_text(&self.output_dir.join("collision_matrix.tsv"), |writer| {
+            write!(writer, "tile_id")?;
+            for &i in &order {
+                write!(writer, "\t{}", tiles[i])?;
+            }
+            for &i in &order {
+                write!(writer, "\n{}", tiles[i])?;
+                for &j in &order {
+                    write!(writer, "\t{}", matrix[i * n + j])?;
+                }
+            }
+            writeln!(writer)
+        })?;
+        write_text(&self.output_dir.join("collision_distance.tsv"), |writer| {
+            writeln!(writer, "{}", DISTANCE_HEADER)?;
+            for (distance, row) in &report.distances {
+                writeln!(writer, "{}", distance_line(*distance, row))?;
+            }
+            Ok(())
+        })?;
+        report.tiles = n as u64;
+        Ok(report)
+    }
+}
+
+const DISTANCE_HEADER: &str = "distance\ttile_pairs\tshared_barcodes\tshared_per_pair";
+
+fn distance_line(distance: Option<u64>, row: &DistanceRow) -> String {
+    let per_pair = if row.tile_pairs == 0 { 0.0 } else { row.shared as f64 / row.tile_pairs as f64 };
+    let distance = distance.map_or("other_surface".to_string(), |distance| distance.to_string());
+    format!("{}\t{}\t{}\t{:.2}", distance, row.tile_pairs, row.shared, per_pair)
+}
+
+/// Barcodes shared between tiles, summarized by distance
+#[derive(Default)]
+pub struct CollideReport {
+    rows: u64,
+    tiles: u64,
+    barcodes: u64,
+    /// barcodes found on more than one tile
+    colliding: u64,
+    /// barcodes repeated within one tile, counted per tile
+    repeated_within: u64,
+    distances: BTreeMap<Option<u64>, DistanceRow>,
+}
+
+impl std::fmt::Display for CollideReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Rows={}, Tiles={}, Barcodes={}, Colliding={}, Repeated within tile={}\n{}",
+            self.rows, self.tiles, self.barcodes, self.colliding, self.repeated_within, DISTANCE_HEADER,
+        )?;
+        for (distance, row) in &self.distances {
+            write!(f, "\n{}", distance_line(*distance, row))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,199 @@
+use crate::utils::{
+    barcode_file::BarcodeRecord,
+    barcode_iter::validate_absolute_filepath,
+    fastqfile::open_text,
+    error::AppError,
+};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use clap::Parser;
+
+#[derive(Parser, Debug)]
+#[command(name = "compare")]
+#[command(about = "Compare the barcodes of two barcode files or whitelists, overall and per tile", long_about = None)]
+#[command(next_line_help = true)]
+pub struct CompareArgs {
+    /// first barcode file or whitelist (optionally gzipped)
+    ///
+    /// rows of `tile_id\tx_pos\ty_pos\tbarcode` are compared per tile, other rows by their first column,
+    /// the `-1` suffix of 10x whitelists is dropped
+    #[arg(short = 'a', long, value_parser = validate_absolute_filepath)]
+    first: PathBuf,
+
+    /// second barcode file or whitelist (optionally gzipped)
+    #[arg(short = 'b', long, value_parser = validate_absolute_filepath)]
+    second: PathBuf,
+
+    /// mismatches allowed between two barcodes of the same length counted as shared
+    #[arg(short, long, default_value_t = 0, value_parser = clap::value_parser!(u32).range(0..=3))]
+    mismatches: u32,
+}
+
+/// Distinct barcodes of a file, overall and per tile
+#[derive(Default)]
+pub struct BarcodeSets {
+    pub all: HashSet<String>,
+    pub tiles: BTreeMap<u64, HashSet<String>>,
+}
+
+impl BarcodeSets {
+    /// Barcodes of a barcode file, or of a whitelist with one barcode in the first column per line
+    pub fn load(path: &Path) -> Result<Self, AppError> {
+        let mut sets = Self::default();
+        for line in open_text(path)?.lines() {
+            let line = line?;
+            if line.is_empty() || line.starts_with('#') || line.starts_with("tile_id") {
+                continue;
+            }
+            let tiled = BarcodeRecord::parse(&line).ok()
+                .and_then(|record| Some((record.tile_id.parse::<u64>().ok()?, record.barcode)));
+            let barcode = match tiled {
+                Some((tile_id, barcode)) => {
+                    sets.tiles.entry(tile_id).or_default().insert(barcode.to_string());
+                    barcode
+                }
+                None => {
+                    let first = line.split('\t').next().unwrap_or_default();
+                    first.strip_suffix("-1").unwrap_or(first)
+                }
+            };
+            sets.all.insert(barcode.to_string());
+        }
+        Ok(sets)
+    }
+}
+
+/// Barcodes looked up within a number of mismatches
+///
+/// Barcodes within `k` mismatches share at least one of `k + 1` segments exactly,
+/// candidates of a shared segment are verified by Hamming distance
+struct BarcodeIndex<'a> {
+    barcodes: &'a HashSet<String>,
+    mismatches: usize,
+    segments: HashMap<(usize, &'a [u8]), Vec<&'a [u8]>>,
+}
+
+/// Byte ranges splitting a barcode into `parts` segments
+fn segment_ranges(len: usize, parts: usize) -> impl Iterator<Item = std::ops::Range<usize>> {
+    (0..parts).map(move |i| i * len / parts..(i + 1) * len / parts)
+}
+
+impl<'a> BarcodeIndex<'a> {
+    fn new(barcodes: &'a HashSet<String>, mismatches: usize) -> Self {
+        let mut segments: HashMap<(usize, &[u8]), Vec<&[u8]>> = HashMap::new();
+        if mismatches > 0 {
+            for barcode in barcodes {
+                let bytes = barcode.as_bytes();
+                for (i, range) in segment_ranges(bytes.len(), mismatches + 1).enumerate() {
+                    segments.entry((i, &bytes[range])).or_default().push(bytes);
+                }
+            }
+        }
+        Self { barcodes, mismatches, segments }
+    }
+
+    fn contains(&self, barcode: &str) -> bool {
+        if self.barcodes.contains(barcode) {
+            return true;
+        }
+        // segments are only indexed with mismatches allowed
+        let bytes = barcode.as_bytes();
+        segment_ranges(bytes.len(), self.mismatches + 1).enumerate()
+            .filter_map(|(i, range)| self.segments.get(&(i, &bytes[range])))
+            .flatten()
+            .any(|candidate| {
+                candidate.len() == bytes.len()
+                    && candidate.iter().zip(bytes).filter(|(a, b)| a != b).count() <= self.mismatches
+            })
+    }
+}
+
+/// Barcodes of both sets and how many of each are found in the other
+#[derive(Default, Clone, Copy)]
+struct Overlap {
+    first: u64,
+    second: u64,
+    first_in_second: u64,
+    second_in_first: u64,
+}
+
+impl Overlap {
+    fn new(first: &HashSet<String>, second: &HashSet<String>, mismatches: usize) -> Self {
+        let count = |query: &HashSet<String>, target: &HashSet<String>| {
+            let index = BarcodeIndex::new(target, mismatches);
+            query.iter().filter(|barcode| index.contains(barcode)).count() as u64
+        };
+        Self {
+            first: first.len() as u64,
+            second: second.len() as u64,
+            first_in_second: count(first, second),
+            second_in_first: count(second, first),
+        }
+    }
+
+    /// Shared barcodes over the barcodes of either set, the smaller of the two shared counts
+    /// is taken when mismatches make them differ
+    fn jaccard(&self) -> f64 {
+        let shared = self.first_in_second.min(self.second_in_first);
+        let union = self.first + self.second - shared;
+        if union == 0 { 0.0 } else { shared as f64 / union as f64 }
+    }
+
+    /// Shared barcodes over the barcodes of the smaller set
+    fn overlap_coefficient(&self) -> f64 {
+        let smaller = self.first.min(self.second);
+        let shared = self.first_in_second.max(self.second_in_first).min(smaller);
+        if smaller == 0 { 0.0 } else { shared as f64 / smaller as f64 }
+    }
+}
+
+impl CompareArgs {
+    pub fn compare(self) -> Result<CompareReport, AppError> {
+        let first = BarcodeSets::load(&self.first)?;
+        let second = BarcodeSets::load(&self.second)?;
+        let mismatches = self.mismatches as usize;
+        let overall = Overlap::new(&first.all, &second.all, mismatches);
+
+        // tiles are compared only when both files carry them
+        let mut tiles = BTreeMap::new();
+        if !first.tiles.is_empty() && !second.tiles.is_empty() {
+            let empty = HashSet::new();
+            let tile_ids: BTreeSet<u64> = first.tiles.keys().chain(second.tiles.keys()).copied().collect();
+            for tile_id in tile_ids {
+                let overlap = Overlap::new(
+                    first.tiles.get(&tile_id).unwrap_or(&empty),
+                    second.tiles.get(&tile_id).unwrap_or(&empty),
+                    mismatches,
+                );
+                tiles.insert(tile_id, overlap);
+            }
+        }
+        Ok(CompareReport { overall, tiles })
+    }
+}
+
+/// Overlap of the two files, overall and per tile
+pub struct CompareReport {
+    overall: Overlap,
+    tiles: BTreeMap<u64, Overlap>,
+}
+
+fn overlap_line(name: &str, overlap: &Overlap) -> String {
+    format!(
+        "{}\t{}\t{}\t{}\t{}\t{:.4}\t{:.4}",
+        name, overlap.first, overlap.second, overlap.first_in_second, overlap.second_in_first,
+        overlap.jaccard(), overlap.overlap_coefficient(),
+    )
+}
+
+impl std::fmt::Display for CompareReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Tile\tFirst\tSecond\tFirst in second\tSecond in first\tJaccard\tOverlap")?;
+        write!(f, "\n{}", overlap_line("all", &self.overall))?;
+        for (tile_id, overlap) in &self.tiles {
+            write!(f, "\n{}", overlap_line(&tile_id.to_string(), overlap))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,29 @@
+use crate::argparse::Cli;
+use crate::utils::error::AppError;
+use std::io::{self, Write};
+use clap::{CommandFactory, Parser};
+use clap_complete::{generate, Shell};
+
+#[derive(Parser, Debug)]
+#[command(name = "completions")]
+#[command(about = "Print the shell completion script of opentools", long_about = None)]
+#[command(next_line_help = true)]
+#[command(after_help = "e.g. `opentools completions bash > ~/.local/share/bash-completion/completions/opentools`\n\
+    or `opentools completions zsh > ~/.zfunc/_opentools`\n\
+    or `opentools completions fish > ~/.config/fish/completions/opentools.fish`")]
+pub struct CompletionsArgs {
+    /// shell to complete in
+    #[arg(value_enum)]
+    shell: Shell,
+}
+
+impl CompletionsArgs {
+    pub fn print(self) -> Result<(), AppError> {
+        let mut command = Cli::command();
+        let name = command.get_name().to_string();
+        let mut script = Vec::new();
+        generate(self.shell, &mut command, name, &mut script);
+        io::stdout().write_all(&script)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,166 @@
+use crate::utils::{chemistry, error::AppError};
+use crate::argparse::Cli;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use clap::{ArgMatches, Command, CommandFactory, parser::ValueSource};
+
+/// Command line options of one config entry, `key = value` as `--key value`
+///
+/// `true` is a bare flag, `false` adds nothing and arrays repeat the flag for every value
+pub(crate) fn toml_options(section: &str, key: &str, value: &toml::Value) -> Result<Vec<String>, AppError> {
+    let invalid = |message: &str| AppError::IoError(io::Error::new(
+        io::ErrorKind::InvalidInput, format!("[{}] {}: {}", section, key, message)
+    ));
+    let flag = format!("--{}", key.replace('_', "-"));
+    let values = match value {
+        toml::Value::Boolean(true) => return Ok(vec![flag]),
+        toml::Value::Boolean(false) => return Ok(Vec::new()),
+        toml::Value::Array(values) => values.iter().collect(),
+        value => vec![value],
+    };
+    let mut args = Vec::new();
+    for value in values {
+        let value = match value {
+            toml::Value::String(value) => value.clone(),
+            toml::Value::Integer(value) => value.to_string(),
+            toml::Value::Float(value) => value.to_string(),
+            _ => return Err(invalid("expected strings, numbers or booleans")),
+        };
+        args.push(flag.clone());
+        args.push(value);
+    }
+    Ok(args)
+}
+
+/// Built-in profiles, a table per profile with the top level options as keys and one table per subcommand
+const BUILTIN_PROFILES: &str = r#"
+[openst-e00x]
+threads = 12
+
+[openst-e00x.touchbarcode]
+mode = "openst"
+
+[openst-e00x.tilesmatch]
+mode = "openst"
+threshold = 0.1
+min_matched = 100
+
+[openst-novaseq]
+threads = 24
+
+[openst-novaseq.touchbarcode]
+mode = "openst"
+
+[openst-novaseq.tilesmatch]
+mode = "openst"
+threshold = 0.05
+min_matched = 1000
+"#;
+
+/// Profile of this name, a `[profile.NAME]` table of the config before the built-in ones
+fn profile(name: &str, config: Option<&toml::Table>) -> Result<toml::Table, AppError> {
+    let user = config
+        .and_then(|config| config.get("profile"))
+        .and_then(toml::Value::as_table)
+        .and_then(|profiles| profiles.get(name));
+    let builtin: toml::Table = toml::from_str(BUILTIN_PROFILES).expect("built-in profiles are valid TOML");
+    match user.or_else(|| builtin.get(name)) {
+        Some(toml::Value::Table(table)) => Ok(table.clone()),
+        Some(_) => Err(AppError::IoError(io::Error::new(
+            io::ErrorKind::InvalidData, format!("profile `{}` is not a table", name)
+        ))),
+        None => Err(AppError::IoError(io::Error::new(
+            io::ErrorKind::InvalidInput, format!("unknown profile `{}`, built-in: {}", name, builtin.keys().cloned().collect::<Vec<_>>().join(", "))
+        ))),
+    }
+}
+
+/// Options of `table` for `command`, leaving out the ones already given on the command line
+fn command_options(command: &Command, matches: &ArgMatches, section: &str, table: &toml::Table) -> Result<Vec<OsString>, AppError> {
+    let mut options = Vec::new();
+    for (key, value) in table {
+        let long = key.replace('_', "-");
+        let Some(arg) = command.get_arguments().find(|arg| arg.get_long() == Some(long.as_str())) else {
+            return Err(AppError::IoError(io::Error::new(
+                io::ErrorKind::InvalidInput, format!("[{}] {}: no such option", section, key)
+            )));
+        };
+        if matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+        options.extend(toml_options(section, key, value)?.into_iter().map(OsString::from));
+    }
+    Ok(options)
+}
+
+/// Command line with the defaults of `opentools --config <TOML> --profile <NAME> <subcommand> ...` inserted
+///
+/// The config has one table per subcommand with the long flag names as keys, e.g.
+/// `[tilesmatch]` followed by `threshold = 0.2`. Options given on the command line are left out of
+/// the config values, so the command line always wins. `[chemistry.NAME]` tables register
+/// chemistries for `--mode NAME`.
+///
+/// A profile bundles top level options such as `threads` with a table per subcommand, the
+/// `[profile.NAME]` tables of the config add to or replace the built-in `openst-e00x` and
+/// `openst-novaseq`. The subcommand tables of the config take precedence over the profile.
+pub fn with_config(mut args: Vec<OsString>) -> Result<Vec<OsString>, AppError> {
+    // a lenient parse finds the config and the options already on the command line,
+    // the real parse reports any errors afterwards
+    let Ok(matches) = Cli::command().ignore_errors(true).try_get_matches_from(&args) else {
+        return Ok(args);
+    };
+    let Some((name, sub_matches)) = matches.subcommand() else {
+        return Ok(args);
+    };
+    let path = matches.get_one::<PathBuf>("config");
+    let config: Option<toml::Table> = match path {
+        Some(path) => Some(toml::from_str(&fs::read_to_string(path)?).map_err(|err| AppError::IoError(io::Error::new(
+            io::ErrorKind::InvalidData, format!("{}: {}", path.display(), err)
+        )))?),
+        None => None,
+    };
+    if let Some(chemistries) = config.as_ref().and_then(|config| config.get("chemistry")).and_then(toml::Value::as_table) {
+        chemistry::register_table(chemistries)?;
+    }
+    let mut profile = match matches.get_one::<String>("profile") {
+        Some(profile_name) => profile(profile_name, config.as_ref())?,
+        None => toml::Table::new(),
+    };
+
+    // the subcommand table of the profile, with the keys of the config table replacing its values
+    let mut table = match profile.remove(name) {
+        Some(toml::Value::Table(table)) => table,
+        Some(_) => return Err(AppError::IoError(io::Error::new(
+            io::ErrorKind::InvalidData, format!("profile: `{}` is not a table", name)
+        ))),
+        None => toml::Table::new(),
+    };
+    match config.as_ref().and_then(|config| config.get(name)) {
+        Some(toml::Value::Table(config_table)) => table.extend(config_table.clone()),
+        Some(_) => return Err(AppError::IoError(io::Error::new(
+            io::ErrorKind::InvalidData, format!("{}: `{}` is not a table", path.expect("config was read").display(), name)
+        ))),
+        None => (),
+    }
+    // the tables left in the profile belong to other subcommands
+    profile.retain(|_, value| !value.is_table());
+
+    let command = Cli::command();
+    let subcommand = command.find_subcommand(name).expect("subcommand was parsed");
+    let options = command_options(subcommand, sub_matches, name, &table)?;
+    let top_options = command_options(&command, &matches, "profile", &profile)?;
+
+    // the subcommand is the first argument that is neither a top level option nor its value
+    let mut index = 1;
+    while index < args.len() && args[index] != name {
+        index += if args[index] == "--config" || args[index] == "--profile" { 2 } else { 1 };
+    }
+    if index >= args.len() {
+        return Ok(args);
+    }
+    args.splice(index + 1..index + 1, options);
+    args.splice(1..1, top_options);
+    Ok(args)
+}
@@ -0,0 +1,366 @@
+use crate::utils::{
+    barcode_file::{build_tabix_index, create_bgzf, BarcodeRecord, BARCODE_FILE_HEADER},
+    barcode_iter::validate_absolute_filepath,
+    coordinate::{PuckTransform, TileSize},
+    fastqfile::open_text,
+    atomic_file::{persist, persist_indexed, temp_path},
+    error::AppError,
+};
+use std::fs;
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use clap::{Parser, ValueEnum};
+use flate2::{Compression, write::GzEncoder};
+use parquet::{
+    data_type::{ByteArray, ByteArrayType, FloatType, Int32Type, Int64Type},
+    file::{
+        properties::WriterProperties,
+        reader::{FileReader, SerializedFileReader},
+        writer::SerializedFileWriter,
+    },
+    record::Field,
+    schema::parser::parse_message_type,
+};
+
+/// Schema of the parquet barcode map, same columns as the native barcode file
+const PARQUET_SCHEMA: &str = "message barcode_map {
+    REQUIRED INT64 tile_id;
+    REQUIRED INT32 x_pos;
+    REQUIRED INT32 y_pos;
+    REQUIRED BYTE_ARRAY barcode (UTF8);
+    OPTIONAL FLOAT quality;
+}";
+
+/// Header of spaceranger `tissue_positions.csv`
+const SPACERANGER_HEADER: &str = "barcode,in_tissue,array_row,array_col,pxl_row_in_fullres,pxl_col_in_fullres";
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum MapFormat {
+    /// `tile_id\tx_pos\ty_pos\tbarcode[\tquality]`, bgzipped and tabix indexed when the path ends with `.gz`
+    Native,
+    /// spaceranger `tissue_positions.csv`, array row/col are the pixel in the tile, full resolution pixels
+    /// are the pixel on the lane surface
+    Spaceranger,
+    /// `barcode\tx_um\ty_um[\ttile]` like Slide-seq pucks and puck_collection.tsv.gz of dedupbarcode
+    Puck,
+    /// parquet with the columns of the native format
+    Parquet,
+}
+
+#[derive(Parser, Debug)]
+#[command(name = "convert")]
+#[command(about = "Convert barcode maps between native, spaceranger, puck and parquet formats", long_about = None)]
+#[command(next_line_help = true)]
+pub struct ConvertArgs {
+    /// input barcode map (optionally gzipped unless parquet)
+    #[arg(short, long, value_parser = validate_absolute_filepath)]
+    input: PathBuf,
+
+    /// format of the input
+    #[arg(long, value_enum)]
+    from: MapFormat,
+
+    /// output barcode map, gzipped when the path ends with `.gz` unless parquet
+    #[arg(short, long, help_heading = super::OUTPUTS)]
+    output: PathBuf,
+
+    /// format of the output
+    #[arg(long, value_enum)]
+    to: MapFormat,
+
+    /// µm per pixel of puck coordinates
+    #[arg(long, default_value_t = 0.6, value_name = "UM")]
+    um_per_pixel: f64,
+
+    /// tile size in pixels used to offset tiles onto the lane surface
+    #[arg(long, default_value_t = TileSize { width: 33000.0, height: 37100.0 }, value_name = "WIDTH,HEIGHT")]
+    tile_size: TileSize,
+
+    /// lane * 10 + surface of the tiles, used when the input has no tile column (spaceranger or puck)
+    #[arg(long, default_value_t = 11, value_name = "N")]
+    lane_surface: u64,
+}
+
+/// One barcode of the map, at a pixel of a tile
+struct Spot {
+    tile_id: u64,
+    x_pos: i32,
+    y_pos: i32,
+    barcode: String,
+    /// only carried by the native and parquet formats
+    quality: Option<f32>,
+}
+
+fn invalid_data(message: String) -> AppError {
+    AppError::IoError(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str) -> Result<T, AppError> {
+    value.trim().parse().map_err(|_| invalid_data(format!("Invalid number: {value}")))
+}
+
+#[inline]
+fn is_gz(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "gz")
+}
+
+impl ConvertArgs {
+    pub fn convert(self) -> Result<ConvertReport, AppError> {
+        let spots = match self.from {
+            MapFormat::Native => read_native(&self.input)?,
+            MapFormat::Spaceranger => self.read_spaceranger()?,
+            MapFormat::Puck => self.read_puck()?,
+            MapFormat::Parquet => read_parquet(&self.input)?,
+        };
+        let dropped_quality = spots.iter().any(|spot| spot.quality.is_some())
+            && matches!(self.to, MapFormat::Spaceranger | MapFormat::Puck);
+        match self.to {
+            MapFormat::Native => write_native(&self.output, spots.as_slice())?,
+            MapFormat::Spaceranger => self.write_spaceranger(&spots)?,
+            MapFormat::Puck => self.write_puck(&spots)?,
+            MapFormat::Parquet => write_parquet(&self.output, &spots)?,
+        }
+        Ok(ConvertReport { spots: spots.len() as u64, dropped_quality })
+    }
+
+    /// Spots in µm (puck) or in pixels (spaceranger) of the lane surface
+    #[inline]
+    fn transform(&self, um_per_pixel: f64) -> PuckTransform {
+        PuckTransform::new(um_per_pixel, self.tile_size)
+    }
+
+    fn locate(&self, um_per_pixel: f64, barcode: &str, x: f64, y: f64) -> Spot {
+        let (tile_id, x_pos, y_pos) = self.transform(um_per_pixel).locate(self.lane_surface, x, y);
+        Spot { tile_id, x_pos: x_pos.round() as i32, y_pos: y_pos.round() as i32, barcode: barcode.to_string(), quality: None }
+    }
+
+    fn read_spaceranger(&self) -> Result<Vec<Spot>, AppError> {
+        let mut spots = Vec::new();
+        for line in open_text(&self.input)?.lines() {
+            let line = line?;
+            if line.is_empty() || line.starts_with("barcode") {
+                continue;
+            }
+            let fields: Vec<&str> = line.split(',').collect();
+            let [barcode, _, _, _, pxl_row, pxl_col] = fields[..] else {
+                return Err(invalid_data(format!("Invalid tissue positions line: {line}")));
+            };
+            spots.push(self.locate(1.0, barcode, parse_number(pxl_col)?, parse_number(pxl_row)?));
+        }
+        Ok(spots)
+    }
+
+    fn read_puck(&self) -> Result<Vec<Spot>, AppError> {
+        let mut spots = Vec::new();
+        for (index, line) in open_text(&self.input)?.lines().enumerate() {
+            let line = line?;
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            let (barcode, x_um, y_um) = match fields[..] {
+                [barcode, x_um, y_um, ..] => (barcode, x_um, y_um),
+                _ => return Err(invalid_data(format!("Invalid puck line: {line}"))),
+            };
+            // a non-numeric first line is the header
+            if index == 0 && x_um.parse::<f64>().is_err() {
+                continue;
+            }
+            let (x_um, y_um): (f64, f64) = (parse_number(x_um)?, parse_number(y_um)?);
+            match fields.get(3) {
+                Some(tile_id) => {
+                    let tile_id = parse_number(tile_id)?;
+                    let (x_offset, y_offset) = self.transform(self.um_per_pixel).apply(tile_id, 0.0, 0.0);
+                    spots.push(Spot {
+                        tile_id,
+                        x_pos: ((x_um - x_offset) / self.um_per_pixel).round() as i32,
+                        y_pos: ((y_um - y_offset) / self.um_per_pixel).round() as i32,
+                        barcode: barcode.to_string(),
+                        quality: None,
+                    });
+                }
+                None => spots.push(self.locate(self.um_per_pixel, barcode, x_um, y_um)),
+            }
+        }
+        Ok(spots)
+    }
+
+    fn write_spaceranger(&self, spots: &[Spot]) -> Result<(), AppError> {
+        let transform = self.transform(1.0);
+        write_text(&self.output, |writer| {
+            writeln!(writer, "{SPACERANGER_HEADER}")?;
+            for spot in spots {
+                let (x, y) = transform.apply(spot.tile_id, spot.x_pos as f64, spot.y_pos as f64);
+                writeln!(writer, "{},1,{},{},{},{}", spot.barcode, spot.y_pos, spot.x_pos, y, x)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn write_puck(&self, spots: &[Spot]) -> Result<(), AppError> {
+        let transform = self.transform(self.um_per_pixel);
+        write_text(&self.output, |writer| {
+            writeln!(writer, "barcode\tx_um\ty_um\ttile")?;
+            for spot in spots {
+                let (x_um, y_um) = transform.apply(spot.tile_id, spot.x_pos as f64, spot.y_pos as f64);
+                writeln!(writer, "{}\t{:.2}\t{:.2}\t{}", spot.barcode, x_um, y_um, spot.tile_id)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Write a text output through its temporary file, gzipped when the path ends with `.gz`
+pub fn write_text<F>(path: &Path, write: F) -> Result<(), AppError>
+where
+    F: FnOnce(&mut dyn Write) -> io::Result<()>
+{
+    let file = BufWriter::new(fs::File::create(temp_path(path))?);
+    if is_gz(path) {
+        let mut writer = GzEncoder::new(file, Compression::default());
+        write(&mut writer)?;
+        writer.finish()?.flush()?;
+    } else {
+        let mut writer = file;
+        write(&mut writer)?;
+        writer.flush()?;
+    }
+    persist(path)?;
+    Ok(())
+}
+
+fn read_native(path: &Path) -> Result<Vec<Spot>, AppError> {
+    let mut spots = Vec::new();
+    for line in open_text(path)?.lines() {
+        let line = line?;
+        if line.is_empty() || line.starts_with('#') || line.starts_with("tile_id") {
+            continue;
+        }
+        let record = BarcodeRecord::parse(&line)?;
+        spots.push(Spot {
+            tile_id: parse_number(record.tile_id)?,
+            x_pos: parse_number(record.x_pos)?,
+            y_pos: parse_number(record.y_pos)?,
+            barcode: record.barcode.to_string(),
+            quality: record.quality.map(parse_number).transpose()?,
+        });
+    }
+    Ok(spots)
+}
+
+/// Write the native format, a `.gz` output is sorted by tile and y position for its tabix index
+fn write_native(path: &Path, spots: &[Spot]) -> Result<(), AppError> {
+    let row = |spot: &Spot| match spot.quality {
+        Some(quality) => format!("{}\t{}\t{}\t{}\t{:.1}", spot.tile_id, spot.x_pos, spot.y_pos, spot.barcode, quality),
+        None => format!("{}\t{}\t{}\t{}", spot.tile_id, spot.x_pos, spot.y_pos, spot.barcode),
+    };
+    if !is_gz(path) {
+        return write_text(path, |writer| {
+            writeln!(writer, "{BARCODE_FILE_HEADER}")?;
+            for spot in spots {
+                writeln!(writer, "{}", row(spot))?;
+            }
+            Ok(())
+        });
+    }
+    let mut sorted: Vec<&Spot> = spots.iter().collect();
+    sorted.sort_by_key(|spot| (spot.tile_id, spot.y_pos, spot.x_pos));
+    let mut writer = create_bgzf(&temp_path(path))?;
+    writeln!(writer, "{BARCODE_FILE_HEADER}")?;
+    for spot in sorted {
+        writeln!(writer, "{}", row(spot))?;
+    }
+    writer.flush()?;
+    drop(writer);
+    build_tabix_index(&temp_path(path))?;
+    persist_indexed(path)?;
+    Ok(())
+}
+
+/// Read a parquet barcode map by column name, other columns are ignored
+fn read_parquet(path: &Path) -> Result<Vec<Spot>, AppError> {
+    let reader = SerializedFileReader::new(fs::File::open(path)?)?;
+    let mut spots = Vec::new();
+    for row in reader.get_row_iter(None)? {
+        let row = row?;
+        let (mut tile_id, mut x_pos, mut y_pos, mut barcode, mut quality) = (None, None, None, None, None);
+        for (name, field) in row.get_column_iter() {
+            let integer = match field {
+                Field::Long(value) => Some(*value),
+                Field::Int(value) => Some(*value as i64),
+                _ => None,
+            };
+            match (name.as_str(), field) {
+                ("tile_id", _) => tile_id = integer.map(|value| value as u64),
+                ("x_pos", _) => x_pos = integer.map(|value| value as i32),
+                ("y_pos", _) => y_pos = integer.map(|value| value as i32),
+                ("barcode", Field::Str(value)) => barcode = Some(value.clone()),
+                ("quality", Field::Float(value)) => quality = Some(*value),
+                ("quality", Field::Double(value)) => quality = Some(*value as f32),
+                _ => {}
+            }
+        }
+        let (Some(tile_id), Some(x_pos), Some(y_pos), Some(barcode)) = (tile_id, x_pos, y_pos, barcode) else {
+            return Err(invalid_data(format!(
+                "{} needs the columns tile_id, x_pos, y_pos and barcode", path.display()
+            )));
+        };
+        spots.push(Spot { tile_id, x_pos, y_pos, barcode, quality });
+    }
+    Ok(spots)
+}
+
+fn write_parquet(path: &Path, spots: &[Spot]) -> Result<(), AppError> {
+    let schema = Arc::new(parse_message_type(PARQUET_SCHEMA)?);
+    let properties = Arc::new(WriterProperties::builder().build());
+    let mut writer = SerializedFileWriter::new(fs::File::create(temp_path(path))?, schema, properties)?;
+    let mut row_group = writer.next_row_group()?;
+    let mut index = 0;
+    while let Some(mut column) = row_group.next_column()? {
+        match index {
+            0 => {
+                let values: Vec<i64> = spots.iter().map(|spot| spot.tile_id as i64).collect();
+                column.typed::<Int64Type>().write_batch(&values, None, None)?;
+            }
+            1 | 2 => {
+                let values: Vec<i32> = spots.iter()
+                    .map(|spot| if index == 1 { spot.x_pos } else { spot.y_pos })
+                    .collect();
+                column.typed::<Int32Type>().write_batch(&values, None, None)?;
+            }
+            3 => {
+                let values: Vec<ByteArray> = spots.iter().map(|spot| ByteArray::from(spot.barcode.as_str())).collect();
+                column.typed::<ByteArrayType>().write_batch(&values, None, None)?;
+            }
+            _ => {
+                let values: Vec<f32> = spots.iter().filter_map(|spot| spot.quality).collect();
+                let levels: Vec<i16> = spots.iter().map(|spot| spot.quality.is_some() as i16).collect();
+                column.typed::<FloatType>().write_batch(&values, Some(&levels), None)?;
+            }
+        }
+        column.close()?;
+        index += 1;
+    }
+    row_group.close()?;
+    writer.close()?;
+    persist(path)?;
+    Ok(())
+}
+
+/// Barcodes converted
+pub struct ConvertReport {
+    spots: u64,
+    /// the input has qualities the output format can not hold
+    dropped_quality: bool,
+}
+
+impl std::fmt::Display for ConvertReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Converted={}", self.spots)?;
+        if self.dropped_quality {
+            write!(f, ", quality column dropped")?;
+        }
+        Ok(())
+    }
+}
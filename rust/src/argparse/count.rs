@@ -0,0 +1,289 @@
+use crate::utils::{
+    threads,
+    hts,
+    barcode_iter::{validate_absolute_dirpath, validate_absolute_filepath},
+    gtf::GeneIndex,
+    atomic_file::{persist, temp_path},
+    error::AppError,
+};
+use crate::argparse::barcoderank::parse_bam_tag;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use clap::{Parser, ValueEnum};
+use flate2::{Compression, write::GzEncoder};
+use rust_htslib::bam::{self, Read, ext::BamRecordExtensions, record::Aux};
+
+/// Which strand of the gene a read has to come from
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Strandedness {
+    /// reads on the gene strand, e.g. 3' OpenST and 10x libraries
+    Forward,
+    /// reads on the opposite strand
+    Reverse,
+    /// reads on either strand
+    Unstranded,
+}
+
+#[derive(Parser, Debug)]
+#[command(name = "count")]
+#[command(about = "Count UMIs of every gene per spatial barcode", long_about = None)]
+#[command(next_line_help = true)]
+pub struct CountArgs {
+    /// aligned BAM with barcode and UMI tags
+    #[arg(short, long, value_parser = validate_absolute_filepath)]
+    input: PathBuf,
+
+    /// gene annotation, exons are used for gene assignment (optionally gzipped)
+    #[arg(short, long, value_parser = validate_absolute_filepath)]
+    gtf: PathBuf,
+
+    /// write `matrix.mtx.gz`, `features.tsv.gz` and `barcodes.tsv.gz` into this directory
+    #[arg(short, long, value_parser = validate_absolute_dirpath, help_heading = super::OUTPUTS)]
+    output_dir: PathBuf,
+
+    /// SAM tag holding the barcode
+    #[arg(long, default_value = "CB", value_parser = parse_bam_tag)]
+    barcode_tag: String,
+
+    /// SAM tag holding the UMI
+    #[arg(long, default_value = "UB", value_parser = parse_bam_tag)]
+    umi_tag: String,
+
+    /// strand of the reads relative to the gene
+    #[arg(long, value_enum, default_value_t = Strandedness::Forward)]
+    strand: Strandedness,
+
+    /// reads with lower mapping quality are not counted
+    #[arg(long, default_value_t = 0)]
+    min_mapq: u8,
+
+    /// BAM decompression threads, the global `--threads` or 4 by default
+    #[arg(short = '@', long)]
+    threads: Option<usize>,
+}
+
+impl CountArgs {
+    pub fn count(self) -> Result<CountReport, AppError> {
+        let index = GeneIndex::from_gtf(&self.gtf)?;
+        let mut reader = hts::open_bam(&self.input)?;
+        reader.set_threads(threads::resolve(self.threads, 4))?;
+        let chroms: Vec<String> = reader.header().target_names().iter()
+            .map(|name| String::from_utf8_lossy(name).into_owned())
+            .collect();
+
+        let mut report = CountReport::default();
+        let mut barcodes: HashMap<String, u32> = HashMap::new();
+        // distinct UMIs of every (gene, barcode)
+        let mut umis: HashMap<(u32, u32), HashSet<Vec<u8>>> = HashMap::new();
+        let mut hits = Vec::new();
+        let mut record = bam::Record::new();
+        while let Some(result) = reader.read(&mut record) {
+            result?;
+            if record.is_secondary() || record.is_supplementary() || record.is_unmapped() {
+                continue;
+            }
+            report.reads += 1;
+            if record.mapq() < self.min_mapq {
+                report.low_mapq += 1;
+                continue;
+            }
+            let (Ok(Aux::String(barcode)), Ok(Aux::String(umi))) = (
+                record.aux(self.barcode_tag.as_bytes()), record.aux(self.umi_tag.as_bytes())
+            ) else {
+                report.no_barcode += 1;
+                continue;
+            };
+            let reverse = match self.strand {
+                Strandedness::Forward => Some(record.is_reverse()),
+                Strandedness::Reverse => Some(!record.is_reverse()),
+                Strandedness::Unstranded => None,
+            };
+            hits.clear();
+            let chrom = &chroms[record.tid() as usize];
+            for [start, end] in record.aligned_blocks() {
+                index.overlapping(chrom, start as u64, end as u64, reverse, &mut hits);
+            }
+            hits.sort_unstable();
+            hits.dedup();
+            let gene = match hits[..] {
+                [gene] => gene,
+                [] => {
+                    report.no_gene += 1;
+                    continue;
+                }
+                _ => {
+                    report.ambiguous += 1;
+                    continue;
+                }
+            };
+            report.assigned += 1;
+            let next = barcodes.len() as u32;
+            let barcode = match barcodes.get(barcode) {
+                Some(&barcode) => barcode,
+                None => *barcodes.entry(barcode.to_string()).or_insert(next),
+            };
+            umis.entry((gene, barcode)).or_default().insert(umi.as_bytes().to_vec());
+        }
+
+        let mut entries: Vec<(u32, u32, u64)> = umis.into_iter()
+            .map(|((gene, barcode), umis)| (gene, barcode, umis.len() as u64))
+            .collect();
+        entries.sort_unstable();
+        let mut barcodes: Vec<(String, u32)> = barcodes.into_iter().collect();
+        barcodes.sort_unstable_by_key(|(_, column)| *column);
+
+        report.barcodes = barcodes.len() as u64;
+        report.genes = entries.iter().map(|(gene, ..)| gene).collect::<HashSet<_>>().len() as u64;
+        report.umis = entries.iter().map(|(.., count)| count).sum();
+
+        write_features(&self.output_dir.join("features.tsv.gz"), &index)?;
+        let barcodes_path = self.output_dir.join("barcodes.tsv.gz");
+        let mut writer = gz_writer(&barcodes_path)?;
+        for (barcode, _) in &barcodes {
+            writeln!(writer, "{barcode}")?;
+        }
+        writer.finish()?.flush()?;
+        persist(&barcodes_path)?;
+        write_matrix(&self.output_dir.join("matrix.mtx.gz"), index.genes().len(), barcodes.len(), &entries)?;
+        Ok(report)
+    }
+}
+
+/// Gzip writer of the temporary file of `path`, moved into place by `persist` once finished
+fn gz_writer(path: &Path) -> Result<GzEncoder<BufWriter<fs::File>>, AppError> {
+    Ok(GzEncoder::new(BufWriter::new(fs::File::create(temp_path(path))?), Compression::default()))
+}
+
+fn write_features(path: &Path, index: &GeneIndex) -> Result<(), AppError> {
+    let mut writer = gz_writer(path)?;
+    for gene in index.genes() {
+        writeln!(writer, "{}\t{}\tGene Expression", gene.id, gene.name)?;
+    }
+    writer.finish()?.flush()?;
+    persist(path)?;
+    Ok(())
+}
+
+/// Write a gene x barcode MatrixMarket coordinate matrix, 1-based like cellranger
+fn write_matrix(path: &Path, genes: usize, barcodes: usize, entries: &[(u32, u32, u64)]) -> Result<(), AppError> {
+    let mut writer = gz_writer(path)?;
+    writeln!(writer, "%%MatrixMarket matrix coordinate integer general")?;
+    writeln!(writer, "{} {} {}", genes, barcodes, entries.len())?;
+    for (gene, barcode, count) in entries {
+        writeln!(writer, "{} {} {}", gene + 1, barcode + 1, count)?;
+    }
+    writer.finish()?.flush()?;
+    persist(path)?;
+    Ok(())
+}
+
+/// Reads assigned to genes and the size of the matrix
+#[derive(Default)]
+pub struct CountReport {
+    /// primary mapped reads
+    reads: u64,
+    low_mapq: u64,
+    no_barcode: u64,
+    no_gene: u64,
+    /// reads overlapping exons of several genes
+    ambiguous: u64,
+    assigned: u64,
+    barcodes: u64,
+    genes: u64,
+    umis: u64,
+}
+
+impl std::fmt::Display for CountReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Reads={}, Assigned={}, Low MAPQ={}, No barcode/UMI={}, No gene={}, Ambiguous={}",
+            self.reads, self.assigned, self.low_mapq, self.no_barcode, self.no_gene, self.ambiguous,
+        )?;
+        write!(f, "Barcodes={}, Genes={}, UMIs={}", self.barcodes, self.genes, self.umis)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use flate2::read::GzDecoder;
+
+    const GTF: &str = "\
+chr1\ttest\texon\t101\t200\t.\t+\t.\tgene_id \"GA\"; gene_name \"Alpha\";
+chr1\ttest\texon\t301\t400\t.\t-\t.\tgene_id \"GB\";
+chr1\ttest\texon\t351\t450\t.\t+\t.\tgene_id \"GC\";
+chr1\ttest\texon\t501\t600\t.\t+\t.\tgene_id \"GD\";
+chr1\ttest\texon\t551\t650\t.\t+\t.\tgene_id \"GE\";
+";
+
+    /// Forward reads hit GA, GC, both GD and GE or no gene, the reverse one at 361 hits GB
+    const SAM: [&str; 11] = [
+        "r1\t0\tchr1\t121\t60\t20M\t*\t0\t0\t*\t*\tCB:Z:AAA\tUB:Z:U1",
+        "r2\t0\tchr1\t131\t60\t20M\t*\t0\t0\t*\t*\tCB:Z:AAA\tUB:Z:U1",
+        "r3\t0\tchr1\t141\t60\t20M\t*\t0\t0\t*\t*\tCB:Z:AAA\tUB:Z:U2",
+        "r3\t256\tchr1\t141\t60\t20M\t*\t0\t0\t*\t*\tCB:Z:AAA\tUB:Z:U9",
+        // spliced over the intron, its second block is in no exon
+        "r4\t0\tchr1\t181\t60\t10M120N10M\t*\t0\t0\t*\t*\tCB:Z:CCC\tUB:Z:U5",
+        "r5\t0\tchr1\t181\t60\t20M\t*\t0\t0\t*\t*",
+        "r6\t16\tchr1\t361\t60\t20M\t*\t0\t0\t*\t*\tCB:Z:CCC\tUB:Z:U1",
+        "r7\t0\tchr1\t361\t60\t20M\t*\t0\t0\t*\t*\tCB:Z:CCC\tUB:Z:U3",
+        "r8\t0\tchr1\t561\t60\t20M\t*\t0\t0\t*\t*\tCB:Z:AAA\tUB:Z:U4",
+        "r9\t0\tchr1\t801\t60\t20M\t*\t0\t0\t*\t*\tCB:Z:AAA\tUB:Z:U6",
+        "r10\t4\t*\t0\t0\t*\t*\t0\t0\t*\t*\tCB:Z:AAA\tUB:Z:U7",
+    ];
+
+    fn gz_lines(path: &Path) -> Vec<String> {
+        BufReader::new(GzDecoder::new(fs::File::open(path).unwrap())).lines().map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn test_count() {
+        let dir = std::env::temp_dir().join(format!("opentools-test-count-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let gtf = dir.join("genes.gtf");
+        fs::write(&gtf, GTF).unwrap();
+        let input = dir.join("reads.bam");
+        let view = bam::HeaderView::from_bytes(b"@HD\tVN:1.6\tSO:unsorted\n@SQ\tSN:chr1\tLN:10000\n");
+        let mut writer = bam::Writer::from_path(&input, &bam::Header::from_template(&view), bam::Format::Bam).unwrap();
+        for line in SAM {
+            writer.write(&bam::Record::from_sam(&view, line.as_bytes()).unwrap()).unwrap();
+        }
+        drop(writer);
+
+        let count = |strand: &str| CountArgs::try_parse_from([
+            "count", "-i", input.to_str().unwrap(), "-g", gtf.to_str().unwrap(), "-o", dir.to_str().unwrap(), "--strand", strand,
+        ]).unwrap().count().unwrap();
+
+        let report = count("forward");
+        assert_eq!(
+            (report.reads, report.assigned, report.low_mapq, report.no_barcode, report.no_gene, report.ambiguous),
+            (9, 6, 0, 1, 1, 1)
+        );
+        assert_eq!((report.barcodes, report.genes, report.umis), (2, 3, 5));
+        assert_eq!(gz_lines(&dir.join("features.tsv.gz")), [
+            "GA\tAlpha\tGene Expression", "GB\tGB\tGene Expression", "GC\tGC\tGene Expression",
+            "GD\tGD\tGene Expression", "GE\tGE\tGene Expression",
+        ]);
+        assert_eq!(gz_lines(&dir.join("barcodes.tsv.gz")), ["AAA", "CCC"]);
+        // GA has U1 and U2 in AAA, r2 repeats U1
+        assert_eq!(gz_lines(&dir.join("matrix.mtx.gz")), [
+            "%%MatrixMarket matrix coordinate integer general", "5 2 4", "1 1 2", "1 2 1", "2 2 1", "3 2 1",
+        ]);
+
+        // the reads at 361 overlap GB and GC on either strand, the second block of r4 reaches GB
+        let report = count("unstranded");
+        assert_eq!((report.assigned, report.no_gene, report.ambiguous), (3, 1, 4));
+        assert_eq!(gz_lines(&dir.join("matrix.mtx.gz"))[1..], ["5 1 1", "1 1 2"]);
+
+        // forward reads count for the reverse GB, the reverse r6 for GC
+        let report = count("reverse");
+        assert_eq!((report.assigned, report.no_gene, report.ambiguous), (3, 5, 0));
+        assert_eq!(gz_lines(&dir.join("barcodes.tsv.gz")), ["CCC"]);
+        assert_eq!(gz_lines(&dir.join("matrix.mtx.gz"))[1..], ["5 1 2", "2 1 2", "3 1 1"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,16 +1,94 @@
 
 use crate::utils::{
+    threads,
+    barcode_file::{build_tabix_index, create_bgzf, list_tiles, BarcodeRecord, TabixPool},
+    barcode_index::BarcodeIndex,
     barcode_iter::{validate_absolute_filepath, validate_absolute_dirpath},
+    coordinate::{PuckTransform, TileSize},
+    progress::Tracker,
+    spill::{parse_memory_size, scratch_dir, ScratchGuard, SortedRuns},
+    atomic_file::{persist, persist_indexed, temp_path},
     error::AppError,
+    packed::PackedBarcode,
 };
 use crate::argparse::tilesmatch::is_valid_tile_id;
+use std::collections::{BTreeMap, HashMap, HashSet, btree_map};
+use std::sync::{Mutex, atomic::{AtomicUsize, Ordering}};
 use std::fs;
-use std::io::{self, Write, BufWriter};
-use std::path::PathBuf;
-use clap::Parser;
-use dashmap::DashSet;
-use rayon::prelude::*;
-use rust_htslib::tbx::{self, Read};
+use std::num::NonZeroUsize;
+use std::io::{self, BufRead, Write, BufWriter};
+use std::path::{Path, PathBuf};
+use clap::{Parser, ValueEnum};
+use dashmap::DashMap;
+use flate2::{Compression, write::GzEncoder};
+use crossbeam::channel::Sender;
+use rayon::{ThreadPoolBuilder, prelude::*};
+use rust_htslib::tbx::Read;
+use serde::Serialize;
+use tracing::{debug, warn};
+
+/// How to resolve a barcode observed more than once
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum DedupStrategy {
+    /// keep the first occurrence in tile list order
+    KeepFirst,
+    /// drop every barcode observed more than once
+    DropAll,
+    /// keep the occurrence with the highest mean barcode base quality (5th column), first on ties or without qualities
+    KeepBest,
+    /// keep the occurrence in the tile holding most rows of the barcode, first on ties
+    KeepMostReads,
+}
+
+/// Naming, compression and content of the barcode whitelist
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum WhitelistFormat {
+    /// barcode_whitelist.txt, one barcode per line
+    Plain,
+    /// whitelist.txt for STARsolo `--soloCBwhitelist`
+    Starsolo,
+    /// permit_list.txt for alevin-fry `--unfiltered-pl`
+    Alevin,
+    /// barcodes.tsv.gz, gzipped with the 10x `-1` suffix
+    #[value(name = "10x-gz")]
+    TenxGz,
+}
+
+impl WhitelistFormat {
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            WhitelistFormat::Plain => "barcode_whitelist.txt",
+            WhitelistFormat::Starsolo => "whitelist.txt",
+            WhitelistFormat::Alevin => "permit_list.txt",
+            WhitelistFormat::TenxGz => "barcodes.tsv.gz",
+        }
+    }
+
+    #[inline]
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            WhitelistFormat::TenxGz => "-1",
+            _ => "",
+        }
+    }
+
+    pub fn create(&self, path: &Path) -> io::Result<Box<dyn Write>> {
+        let file = BufWriter::new(fs::File::create(path)?);
+        Ok(match self {
+            WhitelistFormat::TenxGz => Box::new(GzEncoder::new(file, Compression::default())),
+            _ => Box::new(file),
+        })
+    }
+}
+
+/// Where the deduplicated barcodes are written
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    /// per-tile text files and the tabix indexed barcode mapping
+    Text,
+    /// a single indexed SQLite database `barcodes.db` with tables barcodes, tiles and collisions
+    Db,
+}
 
 #[derive(Parser, Debug)]
 #[command(name = "dedupbarcode")]
@@ -24,7 +102,7 @@ pub struct DedupBarcodeArgs {
     )]
     barcode_file: PathBuf,
 
-    /// the tile id list to query
+    /// the tile id list to query, all tiles in the barcode file by default
     #[arg(
         long, 
         value_delimiter = ' ',
@@ -39,8 +117,330 @@ pub struct DedupBarcodeArgs {
         long,
         required = true,
         value_parser = validate_absolute_dirpath,
+        help_heading = super::OUTPUTS,
     )]
     output_dir: PathBuf,
+
+    /// duplicate resolution strategy, the decision of every kept row is recorded in the barcode mapping
+    #[arg(long, value_enum, default_value_t = DedupStrategy::KeepFirst)]
+    strategy: DedupStrategy,
+
+    /// write duplicate statistics into this file as JSON
+    #[arg(long, value_name = "FILE", help_heading = super::OUTPUTS)]
+    stats_json: Option<PathBuf>,
+
+    /// write `{tile_id}.metrics.json` of every tile and the merged `run_summary.json` into this directory
+    #[arg(long, value_parser = validate_absolute_dirpath, value_name = "DIR", help_heading = super::OUTPUTS)]
+    metrics_dir: Option<PathBuf>,
+
+    /// write `collisions.tsv.gz` listing every barcode observed in more than one tile with all its locations
+    #[arg(long)]
+    collisions: bool,
+
+    /// naming, compression and content of the whitelist, ready for the aligner
+    #[arg(long, value_enum, default_value_t = WhitelistFormat::Plain)]
+    whitelist_format: WhitelistFormat,
+
+    /// path of the whitelist, named by --whitelist-format inside the output directory by default
+    #[arg(long, value_name = "FILE", conflicts_with = "no_whitelist", help_heading = super::OUTPUTS)]
+    whitelist_out: Option<PathBuf>,
+
+    /// do not write the whitelist
+    #[arg(long)]
+    no_whitelist: bool,
+
+    /// path of the bgzf compressed and tabix indexed barcode mapping, `barcode_mapping.txt.gz` inside the output directory by default
+    #[arg(long, value_name = "FILE", conflicts_with = "no_mapping", help_heading = super::OUTPUTS)]
+    mapping_out: Option<PathBuf>,
+
+    /// do not write the barcode mapping
+    #[arg(long)]
+    no_mapping: bool,
+
+    /// directory of the per-tile `{tile_id}.txt` files, the output directory by default
+    #[arg(long, value_parser = validate_absolute_dirpath, value_name = "DIR", conflicts_with = "no_per_tile", help_heading = super::OUTPUTS)]
+    per_tile_dir: Option<PathBuf>,
+
+    /// do not write the per-tile files
+    #[arg(long)]
+    no_per_tile: bool,
+
+    /// write `puck_collection.tsv.gz` (barcode, x_um, y_um, tile) for the openst python package
+    #[arg(long)]
+    puck_coordinates: bool,
+
+    /// µm per pixel of the barcode positions (only effective with --puck-coordinates)
+    #[arg(long, default_value_t = 0.6, value_name = "UM")]
+    um_per_pixel: f64,
+
+    /// tile size in pixels used to offset tiles into one puck (only effective with --puck-coordinates)
+    #[arg(long, default_value_t = TileSize { width: 33000.0, height: 37100.0 }, value_name = "WIDTH,HEIGHT")]
+    tile_size: TileSize,
+
+    /// bound the memory of deduplication by spilling barcodes into temporary files (e.g. 4G)
+    /// 
+    /// rows are sorted in runs of this size spilled to disk, then merged to resolve one barcode at a time,
+    /// trading time for memory; nothing is spilled when all rows fit
+    #[arg(long, value_parser = parse_memory_size, value_name = "SIZE")]
+    max_memory: Option<u64>,
+
+    /// only write the whitelist, skipping the mapping, per-tile files and the second pass over the barcode file
+    /// 
+    /// per-tile statistics are not collected, the second pass is still needed together with --max-memory
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "mapping_out", "per_tile_dir", "no_whitelist", "puck_coordinates", "collisions", "metrics_dir", "output_format",
+        ],
+    )]
+    whitelist_only: bool,
+
+    /// keep the per-tile files of tiles listed in `dedup_manifest.txt` by an interrupted run with the same options
+    /// 
+    /// every output is written to `{name}.tmp` and renamed into place once complete, 
+    /// the manifest lists the tiles whose per-tile file is complete
+    #[arg(long)]
+    resume: bool,
+
+    /// number of worker threads, the global `--threads` or all available cores by default
+    #[arg(short = 't', long, value_name = "N")]
+    threads: Option<NonZeroUsize>,
+
+    /// output format of the deduplicated barcodes, the whitelist is always written
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    output_format: OutputFormat,
+}
+
+/// The occurrence of a barcode to keep, merged over all tiles
+#[derive(Clone, Copy, Debug)]
+struct Candidate {
+    /// index into the tile list
+    tile_index: usize,
+    /// record index inside the tile
+    row: u64,
+    /// rows of the barcode in the tile of the candidate
+    tile_count: u64,
+    score: f32,
+    /// rows of the barcode in all tiles
+    occurrences: u64,
+    /// tiles the barcode observed in
+    tiles: u64,
+}
+
+impl Candidate {
+    #[inline]
+    fn new(tile_index: usize, row: u64, score: f32) -> Self {
+        Self { tile_index, row, tile_count: 1, score, occurrences: 1, tiles: 1 }
+    }
+
+    /// Whether `self` should be kept instead of `other`
+    fn beats(&self, other: &Candidate, strategy: DedupStrategy) -> bool {
+        let first = (self.tile_index, self.row) < (other.tile_index, other.row);
+        match strategy {
+            DedupStrategy::KeepFirst | DedupStrategy::DropAll => first,
+            DedupStrategy::KeepBest => {
+                self.score > other.score || (self.score == other.score && first)
+            }
+            DedupStrategy::KeepMostReads => {
+                self.tile_count > other.tile_count || (self.tile_count == other.tile_count && first)
+            }
+        }
+    }
+
+    /// Whether the row at `tile_index` and `row` is the one to keep
+    #[inline]
+    fn keeps(&self, tile_index: usize, row: u64, strategy: DedupStrategy) -> bool {
+        self.tile_index == tile_index && self.row == row
+            && (strategy != DedupStrategy::DropAll || self.occurrences == 1)
+    }
+
+    /// Count another row of the barcode in the tile of `self`, keeping the better row
+    #[inline]
+    fn add_row(&mut self, other: Candidate, strategy: DedupStrategy) {
+        self.occurrences += 1;
+        if other.beats(self, strategy) {
+            self.row = other.row;
+            self.score = other.score;
+        }
+    }
+
+    fn merge(&mut self, other: Candidate, strategy: DedupStrategy) {
+        let occurrences = self.occurrences + other.occurrences;
+        let tiles = self.tiles + other.tiles;
+        if other.beats(self, strategy) {
+            *self = other;
+        }
+        self.occurrences = occurrences;
+        self.tiles = tiles;
+    }
+}
+
+/// What the second pass does with a row
+#[derive(Clone, Copy, Debug, Default)]
+struct Decision {
+    keep: bool,
+    /// the barcode is observed in more than one tile
+    collided: bool,
+    /// rows of the barcode in all tiles, zero for dropped rows under --max-memory
+    occurrences: u64,
+    /// tiles the barcode observed in, zero for dropped rows under --max-memory
+    tiles: u64,
+}
+
+impl Decision {
+    /// `unique`, or `{strategy}:{occurrences}:{tiles}` for the kept row of a duplicated barcode
+    fn label(&self, strategy: DedupStrategy) -> String {
+        if self.occurrences <= 1 {
+            return "unique".to_string();
+        }
+        let strategy = strategy.to_possible_value().expect("no skipped strategy");
+        format!("{}:{}:{}", strategy.get_name(), self.occurrences, self.tiles)
+    }
+}
+
+/// Resolved duplicates, looked up by the second pass
+enum Decisions {
+    /// Candidate of every barcode, held in memory
+    Memory(DashMap<PackedBarcode, Candidate>, DedupStrategy),
+    /// Kept and collided rows of every tile in `{dir}/tiles/tile_{tile_index}.tsv`, sorted by row
+    Disk { dir: ScratchGuard },
+}
+
+impl Decisions {
+    /// Decisions of one tile, asked row by row in file order
+    fn tile(&self, tile_index: usize) -> Result<TileDecisions<'_>, AppError> {
+        match self {
+            Decisions::Memory(candidates, strategy) => Ok(TileDecisions::Memory { candidates, strategy: *strategy, tile_index }),
+            Decisions::Disk { dir } => {
+                // a tile without kept or collided rows has no file
+                let lines = match fs::File::open(Self::tile_path(dir, tile_index)) {
+                    Ok(file) => Some(io::BufReader::new(file).lines()),
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+                    Err(err) => return Err(err.into()),
+                };
+                Ok(TileDecisions::Disk { lines, next: None })
+            }
+        }
+    }
+
+    #[inline]
+    fn tile_path(dir: &Path, tile_index: usize) -> PathBuf {
+        dir.join("tiles").join(format!("tile_{tile_index:06}.tsv"))
+    }
+
+    /// Remove the decision files of the disk resolution
+    fn remove(self) -> Result<(), AppError> {
+        if let Decisions::Disk { dir } = self {
+            dir.remove()?;
+        }
+        Ok(())
+    }
+}
+
+/// Decisions of the rows of one tile
+enum TileDecisions<'a> {
+    Memory { candidates: &'a DashMap<PackedBarcode, Candidate>, strategy: DedupStrategy, tile_index: usize },
+    /// `{row}\t{keep}\t{collided}\t{occurrences}\t{tiles}` lines of the tile, read ahead by one
+    Disk { lines: Option<io::Lines<io::BufReader<fs::File>>>, next: Option<(u64, Decision)> },
+}
+
+impl TileDecisions<'_> {
+    /// Decision of `row`, rows are asked in increasing order
+    fn decide(&mut self, row: u64, barcode: &str) -> Result<Decision, AppError> {
+        match self {
+            TileDecisions::Memory { candidates, strategy, tile_index } => {
+                Ok(candidates.get(&PackedBarcode::from(barcode)).map(|kept| Decision {
+                    keep: kept.keeps(*tile_index, row, *strategy),
+                    collided: kept.tiles > 1,
+                    occurrences: kept.occurrences,
+                    tiles: kept.tiles,
+                }).unwrap_or_default())
+            }
+            TileDecisions::Disk { lines, next } => {
+                loop {
+                    if next.is_none() {
+                        let Some(line) = lines.as_mut().and_then(Iterator::next).transpose()? else {
+                            return Ok(Decision::default());
+                        };
+                        *next = Some(parse_decision_line(&line)?);
+                    }
+                    match *next {
+                        Some((next_row, decision)) if next_row == row => {
+                            *next = None;
+                            return Ok(decision);
+                        }
+                        Some((next_row, _)) if next_row > row => return Ok(Decision::default()),
+                        _ => *next = None,
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Row and decision of a `{row}\t{keep}\t{collided}\t{occurrences}\t{tiles}` line
+fn parse_decision_line(line: &str) -> Result<(u64, Decision), AppError> {
+    let invalid = || AppError::IoError(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid decision row `{line}`")));
+    let mut fields = line.split('\t').map(|field| field.parse::<u64>().map_err(|_| invalid()));
+    let mut field = || fields.next().unwrap_or_else(|| Err(invalid()));
+    let row = field()?;
+    let decision = Decision { keep: field()? == 1, collided: field()? == 1, occurrences: field()?, tiles: field()? };
+    Ok((row, decision))
+}
+
+/// Decisions of `Decisions::Disk` collected barcode by barcode, sorted into runs by tile and row
+struct DiskResolution<'a> {
+    stats: DedupStats,
+    runs: &'a SortedRuns,
+    /// `{tile_index}\t{row}\t{keep}\t{collided}\t{occurrences}\t{tiles}` lines not yet pushed to the runs
+    lines: Vec<String>,
+}
+
+impl DiskResolution<'_> {
+    /// Resolve one barcode from all its `(tile_index, row, score)` rows
+    fn add_barcode(&mut self, rows: &[(usize, u64, f32)], strategy: DedupStrategy) -> io::Result<()> {
+        // tile_count of a candidate needs rows grouped by tile first
+        let mut per_tile: BTreeMap<usize, Candidate> = BTreeMap::new();
+        for &(tile_index, row, score) in rows {
+            let candidate = Candidate::new(tile_index, row, score);
+            match per_tile.entry(tile_index) {
+                btree_map::Entry::Occupied(mut entry) => entry.get_mut().add_row(candidate, strategy),
+                btree_map::Entry::Vacant(entry) => {
+                    entry.insert(candidate);
+                }
+            }
+        }
+        let Some(candidate) = per_tile.into_values().map(|mut candidate| {
+            candidate.tile_count = candidate.occurrences;
+            candidate
+        }).reduce(|mut kept, candidate| {
+            kept.merge(candidate, strategy);
+            kept
+        }) else {
+            return Ok(());
+        };
+        self.stats.add_candidate(&candidate);
+        let collided = candidate.tiles > 1;
+        for &(tile_index, row, _) in rows {
+            let keep = candidate.keeps(tile_index, row, strategy);
+            if !keep && !collided {
+                continue;
+            }
+            let (occurrences, tiles) = if keep { (candidate.occurrences, candidate.tiles) } else { (0, 0) };
+            // zero padded so the text order of the runs is the numeric order
+            self.lines.push(format!("{tile_index:010}\t{row:020}\t{}\t{}\t{occurrences}\t{tiles}", keep as u8, collided as u8));
+        }
+        if self.lines.len() >= SPILL_BATCH_ROWS {
+            self.runs.push(std::mem::take(&mut self.lines))?;
+        }
+        Ok(())
+    }
+
+    /// Push the remaining lines and return the stats
+    fn finish(self) -> io::Result<DedupStats> {
+        self.runs.push(self.lines)?;
+        Ok(self.stats)
+    }
 }
 
 impl DedupBarcodeArgs {
@@ -49,65 +449,862 @@ impl DedupBarcodeArgs {
         &self.tile_list
     }
 
-    pub fn dedup(self) -> Result<(), AppError> {
-        let barcode_set = DashSet::new();
+    /// Resolve duplicates in memory, or partition by partition on disk under `--max-memory`
+    fn resolve(&self, readers: &TabixPool) -> Result<(Decisions, DedupStats), AppError> {
+        match self.max_memory {
+            None => {
+                let candidates = self.collect_candidates(readers)?;
+                let mut stats = DedupStats::default();
+                candidates.iter().for_each(|candidate| stats.add_candidate(&candidate));
+                Ok((Decisions::Memory(candidates, self.strategy), stats))
+            }
+            Some(max_memory) => self.resolve_on_disk(max_memory, readers),
+        }
+    }
 
-        // use for STAR to generate whitelist
-        let barcode_whitelist = self.output_dir.join(format!("barcode_whitelist.txt"));
-        let mut total_writer = BufWriter::new(
-            fs::OpenOptions::new().create(true).write(true).open(barcode_whitelist)?
-        );
-
-        // use for map barcode to tile id
-        let barcode_mapping = self.output_dir.join(format!("barcode_mapping.txt"));
-        let mut map_writer = BufWriter::new(
-            fs::OpenOptions::new().create(true).write(true).open(barcode_mapping)?
-        );
-
-        let (sender, receiver) = crossbeam::channel::unbounded();
-    
-        let producer_handle = std::thread::spawn(
-            move || {
-                self.tile_list.par_iter().try_for_each(|&tile_id| {
-                    let tile_file = self.output_dir.join(format!("{tile_id}.txt"));
-                    let mut writer = BufWriter::new(
-                        fs::OpenOptions::new().create(true).write(true).open(tile_file)?
-                    );
-        
-                    let mut reader = tbx::Reader::from_path(&self.barcode_file)?;
-                    let tid = reader.tid(&tile_id.to_string())?;
-                    reader.fetch(tid, 1000, 37100)?;
+    /// Merge the candidate of a barcode row into `local`
+    fn add_row(&self, local: &mut HashMap<PackedBarcode, Candidate>, barcode: PackedBarcode, candidate: Candidate) {
+        match local.get_mut(&barcode) {
+            Some(kept) => kept.add_row(candidate, self.strategy),
+            None => {
+                local.insert(barcode, candidate);
+            }
+        }
+    }
 
-                    writeln!(writer, "tile_id\tx_pos\ty_pos\tbarcode")?;
-                    for record in reader.records() {
+    /// Sort `barcode\ttile_index\trow\tscore` rows in runs spilled past `max_memory`, then resolve
+    /// the barcodes one at a time while merging the runs
+    ///
+    /// The decisions are sorted by tile and row the same way and split into one file per tile, which
+    /// the second pass reads along with the tile, so no pass holds more than `max_memory` of rows.
+    fn resolve_on_disk(&self, max_memory: u64, readers: &TabixPool) -> Result<(Decisions, DedupStats), AppError> {
+        // removed on every error below, the decisions take it over on success
+        let spill_dir = ScratchGuard::new(scratch_dir(self.output_dir.join("dedup_spill"), "dedupbarcode"));
+        let runs = SortedRuns::new(&spill_dir.join("rows"), max_memory);
+
+        self.tile_list.par_iter().enumerate().try_for_each(|(tile_index, &tile_id)| {
+            let mut reader = readers.fetch_tile(tile_id)?;
+            let mut lines = Vec::with_capacity(SPILL_BATCH_ROWS);
+            for (row, record) in reader.records().enumerate() {
+                let record = record?;
+                let record = String::from_utf8_lossy(&record);
+                let record = BarcodeRecord::parse(&record)?;
+                let score = record.quality.unwrap_or("0");
+                lines.push(format!("{}\t{}\t{}\t{}", record.barcode, tile_index, row, score));
+                if lines.len() == SPILL_BATCH_ROWS {
+                    runs.push(std::mem::replace(&mut lines, Vec::with_capacity(SPILL_BATCH_ROWS)))?;
+                }
+            }
+            runs.push(lines)?;
+            Ok::<(), AppError>(())
+        })?;
+
+        let decision_runs = SortedRuns::new(&spill_dir.join("decisions"), max_memory);
+        let mut resolved = DiskResolution { stats: DedupStats::default(), runs: &decision_runs, lines: Vec::new() };
+        let merged = runs.finish()?;
+        debug!("Deduplicating from {} sorted runs", merged.runs());
+        // rows of one barcode are adjacent once sorted
+        let mut barcode = String::new();
+        let mut rows: Vec<(usize, u64, f32)> = Vec::new();
+        for line in merged {
+            let line = line?;
+            let mut fields = line.split('\t');
+            let (Some(line_barcode), Some(tile_index), Some(row), Some(score)) =
+                (fields.next(), fields.next(), fields.next(), fields.next()) else {
+                return Err(AppError::IoError(io::Error::new(
+                    io::ErrorKind::InvalidData, format!("Invalid spill row in {}", spill_dir.display())
+                )));
+            };
+            let invalid = |_| io::Error::new(io::ErrorKind::InvalidData, "Invalid spill row");
+            let tile_index: usize = tile_index.parse().map_err(invalid)?;
+            let row: u64 = row.parse().map_err(invalid)?;
+            if line_barcode != barcode {
+                resolved.add_barcode(&rows, self.strategy)?;
+                rows.clear();
+                barcode = line_barcode.to_string();
+            }
+            rows.push((tile_index, row, score.parse().unwrap_or(0.0)));
+        }
+        resolved.add_barcode(&rows, self.strategy)?;
+        let stats = resolved.finish()?;
+        let rows_dir = spill_dir.join("rows");
+        if rows_dir.exists() {
+            fs::remove_dir_all(&rows_dir)?;
+        }
+
+        // lines of one tile are adjacent once sorted, each goes to the file of its tile without the tile index
+        fs::create_dir_all(spill_dir.join("tiles"))?;
+        let mut current: Option<(usize, BufWriter<fs::File>)> = None;
+        for line in decision_runs.finish()? {
+            let line = line?;
+            let (tile_index, decision) = line.split_once('\t')
+                .and_then(|(tile_index, decision)| Some((tile_index.parse::<usize>().ok()?, decision)))
+                .ok_or_else(|| AppError::IoError(io::Error::new(
+                    io::ErrorKind::InvalidData, format!("Invalid decision row in {}", spill_dir.display())
+                )))?;
+            if current.as_ref().is_none_or(|(index, _)| *index != tile_index) {
+                if let Some((_, mut writer)) = current.take() {
+                    writer.flush()?;
+                }
+                current = Some((tile_index, BufWriter::new(fs::File::create(Decisions::tile_path(&spill_dir, tile_index))?)));
+            }
+            if let Some((_, writer)) = current.as_mut() {
+                writeln!(writer, "{decision}")?;
+            }
+        }
+        if let Some((_, mut writer)) = current {
+            writer.flush()?;
+        }
+        let decisions_dir = spill_dir.join("decisions");
+        if decisions_dir.exists() {
+            fs::remove_dir_all(&decisions_dir)?;
+        }
+        Ok((Decisions::Disk { dir: spill_dir }, stats))
+    }
+
+    /// First pass: find the occurrence to keep for every barcode, read from the binary index when there is one
+    fn collect_candidates(&self, readers: &TabixPool) -> Result<DashMap<PackedBarcode, Candidate>, AppError> {
+        let candidates: DashMap<PackedBarcode, Candidate> = DashMap::new();
+        let index = BarcodeIndex::open(&self.barcode_file);
+        self.tile_list.par_iter().enumerate().try_for_each(|(tile_index, &tile_id)| {
+            let mut local: HashMap<PackedBarcode, Candidate> = HashMap::new();
+            match index.as_ref().and_then(|index| index.tile(tile_id)) {
+                Some(entries) => for entry in entries.iter() {
+                    self.add_row(&mut local, entry.barcode(), Candidate::new(tile_index, entry.row as u64, entry.score));
+                },
+                None => {
+                    let mut reader = readers.fetch_tile(tile_id)?;
+                    for (row, record) in reader.records().enumerate() {
                         let record = record?;
-                        let record = unsafe { String::from_utf8_unchecked(record) };
-                        let barcode = record.splitn(4, '\t').nth(3).ok_or(AppError::IoError(
-                            io::Error::new(io::ErrorKind::InvalidData, "Invalid tile's barcode file format")
-                        ))?;
-
-                        if barcode_set.insert(barcode.to_string()) {
-                            writeln!(writer, "{}", record)?;
-                            sender.send((record.to_owned(), barcode.to_string())).map_err(|_| AppError::ChannelError)?;
-                        }
+                        let record = String::from_utf8_lossy(&record);
+                        let record = BarcodeRecord::parse(&record)?;
+                        let score = record.quality.and_then(|q| q.parse().ok()).unwrap_or(0.0);
+                        self.add_row(&mut local, PackedBarcode::from(record.barcode), Candidate::new(tile_index, row as u64, score));
                     }
-                    Ok::<(), AppError>(())
-                })
+                }
+            }
+            for (barcode, mut candidate) in local {
+                candidate.tile_count = candidate.occurrences;
+                candidates.entry(barcode)
+                    .and_modify(|kept| kept.merge(candidate, self.strategy))
+                    .or_insert(candidate);
             }
-        );
+            Ok::<(), AppError>(())
+        })?;
+        Ok(candidates)
+    }
 
-        crossbeam::scope(|s| {
-            s.spawn(|_| {
-                for (record, barcode) in receiver {
-                    writeln!(total_writer, "{}", barcode)?;
-                    writeln!(map_writer, "{}", record)?;
+    /// Use the tiles of the barcode file, filtered by `--tile-list` when given
+    fn resolve_tile_list(&mut self) -> Result<(), AppError> {
+        let tiles = list_tiles(&self.barcode_file)?;
+        if self.tile_list.is_empty() {
+            self.tile_list = tiles;
+        } else {
+            let present: HashSet<u64> = tiles.into_iter().collect();
+            for tile_id in self.tile_list.iter().filter(|tile_id| !present.contains(tile_id)) {
+                warn!("Tile {tile_id} is not in the barcode file, skipped");
+            }
+            self.tile_list.retain(|tile_id| present.contains(tile_id));
+        }
+        if self.tile_list.is_empty() {
+            return Err(AppError::EmptyTileIDsList(self.barcode_file.clone()));
+        }
+        Ok(())
+    }
+
+    fn whitelist_path(&self) -> Option<PathBuf> {
+        if self.no_whitelist {
+            return None;
+        }
+        Some(self.whitelist_out.clone().unwrap_or_else(|| self.output_dir.join(self.whitelist_format.file_name())))
+    }
+
+    /// The mapping and per-tile files only exist for text output
+    fn mapping_path(&self) -> Option<PathBuf> {
+        if self.no_mapping || self.whitelist_only || self.output_format != OutputFormat::Text {
+            return None;
+        }
+        Some(self.mapping_out.clone().unwrap_or_else(|| self.output_dir.join("barcode_mapping.txt.gz")))
+    }
+
+    fn per_tile_path(&self) -> Option<PathBuf> {
+        if self.no_per_tile || self.whitelist_only || self.output_format != OutputFormat::Text {
+            return None;
+        }
+        Some(self.per_tile_dir.clone().unwrap_or_else(|| self.output_dir.clone()))
+    }
+
+    /// Dedup one tile, sending kept rows in batches and returning its stats and collided rows
+    fn dedup_tile(
+        &self, 
+        tile_index: usize, 
+        context: &TileContext, 
+        sender: &Sender<TileMessage>,
+    ) -> Result<(TileDedupStats, Vec<(String, String)>), AppError> {
+        let tile_id = self.tile_list[tile_index];
+        let tile_file = context.per_tile_dir
+            .map(|dir| dir.join(format!("{tile_id}.txt")))
+            .filter(|path| !(context.done.contains(&tile_id) && path.exists()));
+        let mut writer = match &tile_file {
+            Some(path) => {
+                let mut writer = BufWriter::new(fs::File::create(temp_path(path))?);
+                writeln!(writer, "tile_id\tx_pos\ty_pos\tbarcode")?;
+                Some(writer)
+            }
+            None => None,
+        };
+
+        let mut reader = context.readers.fetch_tile(tile_id)?;
+
+        let mut tile_stats = TileDedupStats::new(tile_id);
+        let mut collisions = Vec::new();
+        let mut batch = RowBatch::default();
+        let mut decisions = context.decisions.tile(tile_index)?;
+        for (row, record) in reader.records().enumerate() {
+            tile_stats.rows += 1;
+            let record = record?;
+            let record = String::from_utf8_lossy(&record).into_owned();
+            let parsed = BarcodeRecord::parse(&record)?;
+            let barcode = parsed.barcode;
+            let decision = decisions.decide(row as u64, barcode)?;
+            if context.collect_collisions && decision.collided {
+                collisions.push((
+                    barcode.to_string(), 
+                    format!("{},{},{}", parsed.tile_id, parsed.x_pos, parsed.y_pos)
+                ));
+            }
+            if !decision.keep {
+                if decision.collided {
+                    tile_stats.cross_tile_lost += 1;
+                } else {
+                    tile_stats.within_tile_lost += 1;
+                }
+            } else {
+                tile_stats.kept += 1;
+                if let Some(writer) = writer.as_mut() {
+                    writeln!(writer, "{}", record)?;
+                }
+                if let Some(suffix) = context.whitelist_suffix {
+                    writeln!(batch.whitelist, "{}{}", barcode, suffix)?;
+                }
+                if context.mapping {
+                    writeln!(
+                        batch.mapping,
+                        "{}\t{}\t{}\t{}\t{}\t{}",
+                        parsed.tile_id, 
+                        parsed.x_pos, 
+                        parsed.y_pos, 
+                        barcode, 
+                        parsed.quality.unwrap_or("."), 
+                        decision.label(self.strategy),
+                    )?;
                 }
-                Ok::<(), AppError>(())
-            }).join().unwrap()
-        }).unwrap()?;
+                if let Some(transform) = context.transform {
+                    writeln!(batch.puck, "{}", puck_row(&transform, tile_id, &parsed)?)?;
+                }
+                if context.database {
+                    batch.records.push(record);
+                }
+                batch.rows += 1;
+                if batch.rows == BATCH_ROWS {
+                    let full = std::mem::take(&mut batch);
+                    sender.send(TileMessage::Rows(full)).map_err(|_| AppError::ChannelError)?;
+                }
+            }
+        }
+        if let (Some(mut writer), Some(path)) = (writer, tile_file) {
+            writer.flush()?;
+            drop(writer);
+            persist(&path)?;
+            if let Some(manifest) = context.manifest {
+                writeln!(manifest.lock().unwrap(), "{tile_id}")?;
+            }
+        }
+        if batch.rows > 0 {
+            sender.send(TileMessage::Rows(batch)).map_err(|_| AppError::ChannelError)?;
+        }
+        Ok((tile_stats, collisions))
+    }
 
-        producer_handle.join().unwrap()?;
+    /// Write the kept barcodes straight from the candidates in tile list order, returns the number kept
+    fn write_whitelist(&self, candidates: &DashMap<PackedBarcode, Candidate>, strategy: DedupStrategy) -> Result<u64, AppError> {
+        let mut kept: Vec<(usize, u64, PackedBarcode)> = candidates.iter()
+            .filter(|entry| entry.keeps(entry.tile_index, entry.row, strategy))
+            .map(|entry| (entry.tile_index, entry.row, entry.key().clone()))
+            .collect();
+        kept.par_sort_unstable_by_key(|&(tile_index, row, _)| (tile_index, row));
+        let count = kept.len() as u64;
+        if let Some(path) = self.whitelist_path() {
+            let mut writer = self.whitelist_format.create(&temp_path(&path))?;
+            for (_, _, barcode) in kept {
+                writeln!(writer, "{}{}", barcode, self.whitelist_format.suffix())?;
+            }
+            writer.flush()?;
+            drop(writer);
+            persist(&path)?;
+        }
+        Ok(count)
+    }
+
+    pub fn dedup(mut self) -> Result<DedupStats, AppError> {
+        self.resolve_tile_list()?;
+        let threads = threads::resolve(self.threads.map(NonZeroUsize::get), threads::available());
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .expect("Build thread pool failed");
+        // both passes fetch their tiles through the same readers
+        let readers = TabixPool::new(&self.barcode_file);
+        let (decisions, mut stats) = pool.install(|| self.resolve(&readers))?;
+        if let (true, Decisions::Memory(candidates, strategy)) = (self.whitelist_only, &decisions) {
+            stats.kept = self.write_whitelist(candidates, *strategy)?;
+            if let Some(path) = &self.stats_json {
+                write_json(path, &stats)?;
+            }
+            return Ok(stats);
+        }
+        let stats_json = self.stats_json.clone();
+        let metrics_dir = self.metrics_dir.clone();
+        let text_output = self.output_format == OutputFormat::Text;
+        let collect_collisions = self.collisions || !text_output;
+        let collisions_file = (self.collisions && text_output).then(|| self.output_dir.join("collisions.tsv.gz"));
+        let database_file = (self.output_format == OutputFormat::Db).then(|| self.output_dir.join("barcodes.db"));
+        let mut database = match &database_file {
+            Some(path) => Some(BarcodeDb::create(&temp_path(path))?),
+            None => None,
+        };
+        let puck_file = self.puck_coordinates.then(|| self.output_dir.join("puck_collection.tsv.gz"));
+        let transform = self.puck_coordinates.then(|| PuckTransform::new(self.um_per_pixel, self.tile_size));
+
+        // use for STAR to generate whitelist
+        let whitelist_format = self.whitelist_format;
+        let barcode_whitelist = self.whitelist_path();
+        let mut total_writer = match &barcode_whitelist {
+            Some(path) => Some(whitelist_format.create(&temp_path(path))?),
+            None => None,
+        };
+
+        // use for map barcode to tile id, bgzf compressed and tabix indexed like the input
+        let barcode_mapping = self.mapping_path();
+        let mut map_writer = match &barcode_mapping {
+            Some(path) => Some(create_bgzf(&temp_path(path))?),
+            None => None,
+        };
+        let per_tile_dir = self.per_tile_path();
+
+        // tiles whose per-tile file is complete, appended as they finish
+        let manifest_file = self.output_dir.join("dedup_manifest.txt");
+        let done: HashSet<u64> = if self.resume && manifest_file.exists() {
+            fs::read_to_string(&manifest_file)?.lines().filter_map(|line| line.trim().parse().ok()).collect()
+        } else {
+            HashSet::new()
+        };
+        let manifest = match per_tile_dir {
+            Some(_) => {
+                let file = fs::OpenOptions::new().create(true).append(true).open(&manifest_file)?;
+                if !self.resume {
+                    file.set_len(0)?;
+                }
+                Some(Mutex::new(file))
+            }
+            None => None,
+        };
+
+        // bgzf writer is not Send, drain the channels on the current thread
+        let mut puck_writer = match &puck_file {
+            Some(path) => {
+                let mut writer = GzEncoder::new(BufWriter::new(fs::File::create(temp_path(path))?), Compression::default());
+                writeln!(writer, "barcode\tx_um\ty_um\ttile")?;
+                Some(writer)
+            }
+            None => None,
+        };
+        if let Some(writer) = map_writer.as_mut() {
+            writeln!(writer, "{}", MAPPING_FILE_HEADER)?;
+        }
+        // one bounded channel per tile, each holding at most CHANNEL_BATCHES batches
+        let tile_count = self.tile_list.len();
+        let (senders, receivers): (Vec<_>, Vec<_>) = (0..tile_count)
+            .map(|_| {
+                let (sender, receiver) = crossbeam::channel::bounded(CHANNEL_BATCHES);
+                (Mutex::new(Some(sender)), receiver)
+            })
+            .unzip();
+        let next_tile = AtomicUsize::new(0);
+        let context = TileContext {
+            readers: &readers, 
+            decisions: &decisions, 
+            whitelist_suffix: barcode_whitelist.is_some().then(|| whitelist_format.suffix()),
+            mapping: barcode_mapping.is_some(),
+            database: database_file.is_some(),
+            per_tile_dir: per_tile_dir.as_deref(), 
+            transform, 
+            collect_collisions, 
+            done: &done, 
+            manifest: manifest.as_ref(),
+        };
+
+        // Second pass: write the kept occurrence of every barcode
+        let tiles = std::thread::scope(|scope| {
+            // workers claim tiles in list order, so the tile drained below is always in progress
+            for _ in 0..threads.min(tile_count) {
+                scope.spawn(|| loop {
+                    let tile_index = next_tile.fetch_add(1, Ordering::Relaxed);
+                    if tile_index >= tile_count {
+                        break;
+                    }
+                    let Some(sender) = senders[tile_index].lock().unwrap().take() else { break };
+                    let result = self.dedup_tile(tile_index, &context, &sender);
+                    if sender.send(TileMessage::Done(result)).is_err() {
+                        break;
+                    }
+                });
+            }
+
+            // drain tiles in list order, rows of a tile stay contiguous for tabix
+            let mut tiles = Vec::with_capacity(tile_count);
+            let tracker = Tracker::new("dedupbarcode", tile_count);
+            for receiver in receivers {
+                loop {
+                    match receiver.recv().map_err(|_| AppError::ChannelError)? {
+                        TileMessage::Rows(batch) => {
+                            if let Some(writer) = total_writer.as_mut() {
+                                writer.write_all(&batch.whitelist)?;
+                            }
+                            if let Some(writer) = map_writer.as_mut() {
+                                writer.write_all(&batch.mapping)?;
+                            }
+                            if let Some(database) = database.as_mut() {
+                                for record in &batch.records {
+                                    database.insert_barcode(record)?;
+                                }
+                            }
+                            if let Some(writer) = puck_writer.as_mut() {
+                                writer.write_all(&batch.puck)?;
+                            }
+                        }
+                        TileMessage::Done(result) => {
+                            let (tile, collisions) = result?;
+                            tracker.finish(Some(&tile.tile_id.to_string()), Some(tile.rows));
+                            tiles.push((tile, collisions));
+                            break;
+                        }
+                    }
+                }
+            }
+            Ok::<_, AppError>(tiles)
+        })?;
+        decisions.remove()?;
+        if let (Some(writer), Some(path)) = (puck_writer, &puck_file) {
+            writer.finish()?.flush()?;
+            persist(path)?;
+        }
+        if let (Some(mut writer), Some(path)) = (total_writer, &barcode_whitelist) {
+            writer.flush()?;
+            drop(writer);
+            persist(path)?;
+        }
+
+        let (tiles, collisions): (Vec<_>, Vec<_>) = tiles.into_iter().unzip();
+        if let (Some(mut writer), Some(path)) = (map_writer, barcode_mapping) {
+            writer.flush()?;
+            drop(writer);
+            build_tabix_index(&temp_path(&path))?;
+            persist_indexed(&path)?;
+        }
+        stats.set_tiles(tiles);
+        if let (Some(database), Some(path)) = (database, &database_file) {
+            database.finish(&stats.tiles, &collisions)?;
+            persist(path)?;
+        }
+        if let Some(path) = collisions_file {
+            write_collisions(&path, collisions)?;
+        }
+        if let Some(path) = stats_json {
+            write_json(&path, &stats)?;
+        }
+        if let Some(dir) = metrics_dir {
+            for tile in &stats.tiles {
+                write_json(&dir.join(format!("{}.metrics.json", tile.tile_id)), tile)?;
+            }
+            write_json(&dir.join("run_summary.json"), &stats)?;
+        }
         
+        Ok(stats)
+    }
+}
+
+/// SQLite output of dedup, rows are inserted in one transaction and indexed at the end
+struct BarcodeDb {
+    connection: rusqlite::Connection,
+}
+
+impl BarcodeDb {
+    fn create(path: &Path) -> Result<Self, AppError> {
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        let connection = rusqlite::Connection::open(path)?;
+        connection.execute_batch(
+            "PRAGMA journal_mode = OFF;
+            PRAGMA synchronous = OFF;
+            CREATE TABLE barcodes (barcode TEXT NOT NULL, tile_id INTEGER NOT NULL, x_pos INTEGER NOT NULL, y_pos INTEGER NOT NULL);
+            CREATE TABLE tiles (tile_id INTEGER PRIMARY KEY, rows INTEGER NOT NULL, kept INTEGER NOT NULL, retention REAL NOT NULL);
+            CREATE TABLE collisions (barcode TEXT NOT NULL, tile_id INTEGER NOT NULL, x_pos INTEGER NOT NULL, y_pos INTEGER NOT NULL);
+            BEGIN;"
+        )?;
+        Ok(Self { connection })
+    }
+
+    fn insert_barcode(&mut self, record: &str) -> Result<(), AppError> {
+        let record = BarcodeRecord::parse(record)?;
+        self.connection.prepare_cached(
+            "INSERT INTO barcodes (barcode, tile_id, x_pos, y_pos) VALUES (?1, ?2, ?3, ?4)"
+        )?.execute((record.barcode, record.tile_id, record.x_pos, record.y_pos))?;
         Ok(())
     }
-}
\ No newline at end of file
+
+    fn finish(self, tiles: &[TileDedupStats], collisions: &[Vec<(String, String)>]) -> Result<(), AppError> {
+        {
+            let mut insert_tile = self.connection.prepare_cached(
+                "INSERT INTO tiles (tile_id, rows, kept, retention) VALUES (?1, ?2, ?3, ?4)"
+            )?;
+            for tile in tiles {
+                insert_tile.execute((tile.tile_id, tile.rows, tile.kept, tile.retention))?;
+            }
+            let mut insert_collision = self.connection.prepare_cached(
+                "INSERT INTO collisions (barcode, tile_id, x_pos, y_pos) VALUES (?1, ?2, ?3, ?4)"
+            )?;
+            for (barcode, location) in collisions.iter().flatten() {
+                let mut fields = location.split(',');
+                if let (Some(tile_id), Some(x_pos), Some(y_pos)) = (fields.next(), fields.next(), fields.next()) {
+                    insert_collision.execute((barcode, tile_id, x_pos, y_pos))?;
+                }
+            }
+        }
+        self.connection.execute_batch(
+            "CREATE UNIQUE INDEX barcodes_barcode ON barcodes (barcode);
+            CREATE INDEX barcodes_position ON barcodes (tile_id, y_pos, x_pos);
+            CREATE INDEX collisions_barcode ON collisions (barcode);
+            COMMIT;"
+        )?;
+        Ok(())
+    }
+}
+
+/// Header of the barcode mapping, quality is `.` for barcode files without qualities
+const MAPPING_FILE_HEADER: &str = "#tile_id\tx_pos\ty_pos\tbarcode\tquality\tdecision";
+
+/// Rows of kept barcodes per message
+const BATCH_ROWS: usize = 4096;
+/// Rows a tile worker buffers before handing them to the sorted runs under --max-memory
+const SPILL_BATCH_ROWS: usize = 65536;
+/// Messages buffered per tile before the worker blocks
+const CHANNEL_BATCHES: usize = 4;
+
+/// What the second pass of a tile reads besides the tile itself
+struct TileContext<'a> {
+    readers: &'a TabixPool,
+    decisions: &'a Decisions,
+    /// the outputs the rows are formatted for, the suffix of the whitelist lines when it is written
+    whitelist_suffix: Option<&'static str>,
+    mapping: bool,
+    database: bool,
+    per_tile_dir: Option<&'a Path>,
+    transform: Option<PuckTransform>,
+    collect_collisions: bool,
+    /// tiles of the manifest kept from an interrupted run
+    done: &'a HashSet<u64>,
+    manifest: Option<&'a Mutex<fs::File>>,
+}
+
+/// Sent from a tile worker to the writer, `Done` closes the tile
+enum TileMessage {
+    Rows(RowBatch),
+    Done(Result<(TileDedupStats, Vec<(String, String)>), AppError>),
+}
+
+/// Rows kept by dedup, formatted by the tile worker into one buffer per output so the writer
+/// thread only copies bytes
+#[derive(Default)]
+struct RowBatch {
+    rows: usize,
+    whitelist: Vec<u8>,
+    /// rows of the barcode mapping, the record with its quality and dedup decision
+    mapping: Vec<u8>,
+    /// rows of puck_collection.tsv.gz
+    puck: Vec<u8>,
+    /// records of the SQLite output, inserted one by one
+    records: Vec<String>,
+}
+
+fn puck_row(transform: &PuckTransform, tile_id: u64, record: &BarcodeRecord) -> Result<String, AppError> {
+    let parse = |value: &str| value.parse::<f64>().map_err(|_| AppError::IoError(io::Error::new(
+        io::ErrorKind::InvalidData, format!("Invalid position: {value}")
+    )));
+    let (x_um, y_um) = transform.apply(tile_id, parse(record.x_pos)?, parse(record.y_pos)?);
+    Ok(format!("{}\t{:.2}\t{:.2}\t{}", record.barcode, x_um, y_um, tile_id))
+}
+
+/// Write `barcode\ttile count\ttile,x,y;...` rows sorted by barcode, locations follow tile list order
+fn write_collisions(path: &Path, collisions: Vec<Vec<(String, String)>>) -> Result<(), AppError> {
+    let mut locations: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (barcode, location) in collisions.into_iter().flatten() {
+        locations.entry(barcode).or_default().push(location);
+    }
+    let mut writer = GzEncoder::new(BufWriter::new(fs::File::create(temp_path(path))?), Compression::default());
+    writeln!(writer, "#barcode\tn_tiles\tlocations")?;
+    for (barcode, locations) in locations {
+        let n_tiles = locations.iter()
+            .map(|location| location.split(',').next().unwrap_or_default())
+            .collect::<HashSet<&str>>()
+            .len();
+        writeln!(writer, "{}\t{}\t{}", barcode, n_tiles, locations.join(";"))?;
+    }
+    writer.finish()?.flush()?;
+    persist(path)?;
+    Ok(())
+}
+
+fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<(), AppError> {
+    let mut writer = BufWriter::new(fs::File::create(temp_path(path))?);
+    serde_json::to_writer_pretty(&mut writer, value).map_err(io::Error::from)?;
+    writer.flush()?;
+    drop(writer);
+    persist(path)?;
+    Ok(())
+}
+
+/// Rows scanned and kept of one tile
+#[derive(Serialize)]
+pub struct TileDedupStats {
+    tile_id: u64,
+    rows: u64,
+    kept: u64,
+    /// rows dropped as duplicates inside the tile
+    within_tile_lost: u64,
+    /// rows dropped because the barcode is also observed in other tiles
+    cross_tile_lost: u64,
+    retention: f64,
+}
+
+impl TileDedupStats {
+    #[inline]
+    fn new(tile_id: u64) -> Self {
+        Self { tile_id, rows: 0, kept: 0, within_tile_lost: 0, cross_tile_lost: 0, retention: 0.0 }
+    }
+}
+
+/// Duplicate statistics of a dedup run
+#[derive(Serialize, Default)]
+pub struct DedupStats {
+    total_rows: u64,
+    unique_barcodes: u64,
+    /// barcodes observed more than once inside one tile
+    within_tile_duplicated: u64,
+    /// barcodes observed in more than one tile
+    across_tile_duplicated: u64,
+    kept: u64,
+    tiles: Vec<TileDedupStats>,
+}
+
+impl DedupStats {
+    fn add_candidate(&mut self, candidate: &Candidate) {
+        self.unique_barcodes += 1;
+        self.total_rows += candidate.occurrences;
+        if candidate.occurrences > candidate.tiles {
+            self.within_tile_duplicated += 1;
+        }
+        if candidate.tiles > 1 {
+            self.across_tile_duplicated += 1;
+        }
+    }
+
+    fn set_tiles(&mut self, mut tiles: Vec<TileDedupStats>) {
+        for tile in tiles.iter_mut() {
+            tile.retention = if tile.rows == 0 { 0.0 } else { tile.kept as f64 / tile.rows as f64 };
+        }
+        self.kept = tiles.iter().map(|tile| tile.kept).sum();
+        self.tiles = tiles;
+    }
+}
+
+impl std::fmt::Display for DedupStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Total={}, Unique={}, Duplicated (within tile={}, across tiles={}), Kept={}",
+            self.total_rows,
+            self.unique_barcodes,
+            self.within_tile_duplicated,
+            self.across_tile_duplicated,
+            self.kept,
+        )?;
+        if self.tiles.is_empty() {
+            return Ok(());
+        }
+        write!(f, "\nTile id\tRows\tKept\tWithin tile lost\tCross tile lost\tRetention")?;
+        for tile in &self.tiles {
+            write!(
+                f, 
+                "\n{:<7}\t{}\t{}\t{}\t{}\t{:.5}", 
+                tile.tile_id, tile.rows, tile.kept, tile.within_tile_lost, tile.cross_tile_lost, tile.retention
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::barcode_file::{build_tabix_index, create_bgzf, BARCODE_FILE_HEADER};
+
+    /// `(tile_index, row, score)` rows of one barcode per case, in tile and row order
+    const CASES: [&[(usize, u64, f32)]; 6] = [
+        &[(0, 5, 30.0)],
+        &[(0, 1, 20.0), (0, 7, 35.0)],
+        &[(0, 3, 30.0), (1, 0, 30.0), (1, 4, 30.0), (2, 2, 10.0)],
+        &[(0, 2, 20.0), (2, 9, 38.0)],
+        &[(0, 1, 30.0), (1, 2, 10.0), (1, 3, 10.0)],
+        &[(1, 6, 25.0), (3, 0, 25.0), (3, 1, 36.0), (3, 8, 12.0), (10, 4, 36.0)],
+    ];
+
+    const STRATEGIES: [DedupStrategy; 4] =
+        [DedupStrategy::KeepFirst, DedupStrategy::DropAll, DedupStrategy::KeepBest, DedupStrategy::KeepMostReads];
+
+    /// Candidate of `collect_candidates`: rows merged per tile, then the tiles merged in `tile_order`
+    fn memory_candidate(rows: &[(usize, u64, f32)], strategy: DedupStrategy, tile_order: &[usize]) -> Candidate {
+        let mut per_tile: HashMap<usize, Candidate> = HashMap::new();
+        for &(tile_index, row, score) in rows {
+            let candidate = Candidate::new(tile_index, row, score);
+            per_tile.entry(tile_index)
+                .and_modify(|kept| kept.add_row(candidate, strategy))
+                .or_insert(candidate);
+        }
+        tile_order.iter()
+            .filter_map(|tile_index| per_tile.remove(tile_index))
+            .map(|mut candidate| {
+                candidate.tile_count = candidate.occurrences;
+                candidate
+            })
+            .reduce(|mut kept, candidate| {
+                kept.merge(candidate, strategy);
+                kept
+            })
+            .expect("rows of at least one tile")
+    }
+
+    #[test]
+    fn test_disk_resolution_matches_memory() {
+        let dir = std::env::temp_dir().join(format!("opentools-test-disk-resolution-{}", std::process::id()));
+        for strategy in STRATEGIES {
+            for rows in CASES {
+                let mut tiles: Vec<usize> = rows.iter().map(|&(tile_index, ..)| tile_index).collect();
+                tiles.dedup();
+                let candidate = memory_candidate(rows, strategy, &tiles);
+                // the merge of the parallel tiles happens in any order
+                tiles.reverse();
+                let reversed = memory_candidate(rows, strategy, &tiles);
+                assert_eq!((candidate.tile_index, candidate.row), (reversed.tile_index, reversed.row), "{strategy:?} {rows:?}");
+
+                let runs = SortedRuns::new(&dir, u64::MAX);
+                let mut resolution = DiskResolution { stats: DedupStats::default(), runs: &runs, lines: Vec::new() };
+                // merged runs order rows by the text of the tile index, not by tile
+                let mut shuffled = rows.to_vec();
+                shuffled.sort_by_key(|&(tile_index, row, _)| (tile_index.to_string(), row.to_string()));
+                resolution.add_barcode(&shuffled, strategy).unwrap();
+                let stats = resolution.finish().unwrap();
+
+                let mut expected_stats = DedupStats::default();
+                expected_stats.add_candidate(&candidate);
+                assert_eq!(serde_json::to_value(&stats).unwrap(), serde_json::to_value(&expected_stats).unwrap());
+
+                let mut decisions: HashMap<(usize, u64), Decision> = HashMap::new();
+                for line in runs.finish().unwrap() {
+                    let line = line.unwrap();
+                    let (tile_index, decision) = line.split_once('\t').unwrap();
+                    let (row, decision) = parse_decision_line(decision).unwrap();
+                    decisions.insert((tile_index.parse().unwrap(), row), decision);
+                }
+                for &(tile_index, row, _) in rows {
+                    let keep = candidate.keeps(tile_index, row, strategy);
+                    let collided = candidate.tiles > 1;
+                    let decision = decisions.get(&(tile_index, row)).copied().unwrap_or_default();
+                    let context = format!("{strategy:?} {rows:?} row {tile_index}:{row}");
+                    assert_eq!(decision.keep, keep, "{context}");
+                    assert_eq!(decision.collided, collided, "{context}");
+                    if keep {
+                        assert_eq!((decision.occurrences, decision.tiles), (candidate.occurrences, candidate.tiles), "{context}");
+                    }
+                }
+                assert_eq!(decisions.values().filter(|decision| decision.keep).count(), usize::from(candidate.keeps(candidate.tile_index, candidate.row, strategy)));
+            }
+        }
+        assert!(!dir.exists());
+    }
+
+    /// Barcode of every row of three tiles, duplicated inside and across tiles, with its quality
+    fn tile_rows() -> Vec<Vec<(&'static str, f32)>> {
+        const BARCODES: [&str; 7] = ["AAAA", "CCCC", "GGGG", "TTTT", "ACGT", "TGCA", "ACNT"];
+        let mut state: u64 = 17;
+        let mut next = move |n: u64| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 33) % n
+        };
+        (0..3).map(|_| (0..40).map(|_| (BARCODES[next(7) as usize], [10.0, 25.0, 36.0][next(3) as usize])).collect()).collect()
+    }
+
+    #[test]
+    fn test_resolve_on_disk_matches_memory() {
+        let dir = std::env::temp_dir().join(format!("opentools-test-resolve-on-disk-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let barcode_file = dir.join("barcodes.txt.gz");
+        let tiles = tile_rows();
+        let mut writer = create_bgzf(&barcode_file).unwrap();
+        writeln!(writer, "{BARCODE_FILE_HEADER}").unwrap();
+        for (tile, rows) in tiles.iter().enumerate() {
+            for (row, (barcode, quality)) in rows.iter().enumerate() {
+                writeln!(writer, "{}\t{}\t{}\t{barcode}\t{quality:.1}", 11101 + tile, 5 * row, 1000 + row).unwrap();
+            }
+        }
+        writer.flush().unwrap();
+        drop(writer);
+        build_tabix_index(&barcode_file).unwrap();
+        let readers = TabixPool::new(&barcode_file);
+
+        for strategy in STRATEGIES {
+            let resolve = |max_memory: &[&str]| {
+                let mut args = DedupBarcodeArgs::try_parse_from(
+                    ["dedupbarcode", "-I", barcode_file.to_str().unwrap(), "-o", dir.to_str().unwrap(),
+                        "--strategy", strategy.to_possible_value().unwrap().get_name()].iter().chain(max_memory)
+                ).unwrap();
+                args.resolve_tile_list().unwrap();
+                args.resolve(&readers).unwrap()
+            };
+            let (memory, memory_stats) = resolve(&[]);
+            // a few rows per run, so the resolution merges many runs
+            let (disk, disk_stats) = resolve(&["--max-memory", "1K"]);
+            assert!(matches!(disk, Decisions::Disk { .. }));
+            assert_eq!(serde_json::to_value(&disk_stats).unwrap(), serde_json::to_value(&memory_stats).unwrap(), "{strategy:?}");
+
+            for (tile_index, rows) in tiles.iter().enumerate() {
+                let (mut memory_tile, mut disk_tile) = (memory.tile(tile_index).unwrap(), disk.tile(tile_index).unwrap());
+                for (row, (barcode, _)) in rows.iter().enumerate() {
+                    let expected = memory_tile.decide(row as u64, barcode).unwrap();
+                    let decision = disk_tile.decide(row as u64, barcode).unwrap();
+                    let context = format!("{strategy:?} tile {tile_index} row {row} {barcode}");
+                    assert_eq!((decision.keep, decision.collided), (expected.keep, expected.collided), "{context}");
+                    if expected.keep {
+                        assert_eq!((decision.occurrences, decision.tiles), (expected.occurrences, expected.tiles), "{context}");
+                    }
+                }
+            }
+            let spill_dir = dir.join("dedup_spill");
+            assert!(spill_dir.exists());
+            disk.remove().unwrap();
+            assert!(!spill_dir.exists());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
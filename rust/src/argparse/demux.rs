@@ -0,0 +1,301 @@
+use crate::utils::{
+    hts,
+    barcode_iter::{validate_absolute_dirpath, validate_absolute_filepath},
+    coordinate::{load_regions, Region},
+    fastqfile::{self, complement},
+    position::Position,
+    chemistry::{Chemistry, OpenSt},
+    atomic_file::{persist, temp_path},
+    error::AppError,
+};
+use crate::argparse::{
+    barcoderank::parse_bam_tag,
+    spatialtag::{load_barcode_map, lookup, BarcodeMap},
+};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use clap::{ArgGroup, Parser};
+use flate2::{Compression, write::GzEncoder};
+use rust_htslib::bam::{self, Read as _, record::Aux};
+use seq_io::fastq::Record;
+
+/// Name of the group of reads without a known position
+const UNASSIGNED: &str = "unassigned";
+
+#[derive(Parser, Debug)]
+#[command(name = "demux")]
+#[command(about = "Split reads by tile or spatial region of their barcode", long_about = None)]
+#[command(next_line_help = true)]
+#[command(group(ArgGroup::new("input").required(true).args(["read1", "bam"])))]
+pub struct DemuxArgs {
+    /// barcode map with `tile_id\tx_pos\ty_pos\tbarcode` rows, e.g. barcode_mapping.txt.gz from dedupbarcode
+    #[arg(short, long, value_parser = validate_absolute_filepath)]
+    barcode_map: PathBuf,
+
+    /// FASTQ holding the barcode read
+    #[arg(short = '1', long, requires = "read2", value_parser = validate_absolute_filepath)]
+    read1: Option<PathBuf>,
+
+    /// FASTQ mate of --read1
+    #[arg(short = '2', long, requires = "read1", value_parser = validate_absolute_filepath)]
+    read2: Option<PathBuf>,
+
+    /// BAM with the barcode in a tag instead of FASTQ
+    #[arg(long, value_parser = validate_absolute_filepath)]
+    bam: Option<PathBuf>,
+
+    /// SAM tag holding the barcode (only effective with --bam)
+    #[arg(long, default_value = "CB", value_parser = parse_bam_tag)]
+    tag: String,
+
+    /// barcode position in the FASTQ pair, the OpenST position by default
+    ///
+    /// Format: "read{1/2}:{+/-}:start-end" (e.g. "read1:+:2-30")
+    #[arg(long, value_parser = clap::value_parser!(Position), value_name = "BARCODE_POS")]
+    barcode_pos: Option<Position>,
+
+    /// split by the regions of this file instead of by tile
+    ///
+    /// rows of `name\ttile_id\tx_start\tx_end\ty_start\ty_end` with inclusive pixel ranges,
+    /// `*` as tile id for every tile, the first region containing a read wins
+    #[arg(long, value_parser = validate_absolute_filepath)]
+    regions: Option<PathBuf>,
+
+    /// do not write reads without a known position into the `unassigned` group
+    #[arg(long)]
+    drop_unassigned: bool,
+
+    /// output files open at once, below the file descriptor limit
+    ///
+    /// FASTQ groups past it are closed least recently used first and appended to when their reads
+    /// come back, BAM outputs cannot be appended to so more BAM groups than this are refused
+    #[arg(long, default_value_t = 512, value_name = "N")]
+    max_open_files: usize,
+
+    /// write `{group}_R1.fastq.gz`/`{group}_R2.fastq.gz` or `{group}.bam` into this directory
+    #[arg(short, long, value_parser = validate_absolute_dirpath, help_heading = super::OUTPUTS)]
+    output_dir: PathBuf,
+}
+
+type FastqWriter = GzEncoder<BufWriter<fs::File>>;
+
+/// FASTQ pair writers of the groups, at most `max_pairs` of them open at once
+///
+/// A closed writer finishes its gzip member, reopening the group appends a new member, which
+/// gzip readers decode as one stream.
+struct FastqGroups<'a> {
+    output_dir: &'a Path,
+    max_pairs: usize,
+    /// writers and the read count when the group was last written
+    open: HashMap<String, (FastqWriter, FastqWriter, u64)>,
+    closed: HashSet<String>,
+    reads: u64,
+}
+
+impl<'a> FastqGroups<'a> {
+    fn new(output_dir: &'a Path, max_open_files: usize) -> Self {
+        Self { output_dir, max_pairs: (max_open_files / 2).max(1), open: HashMap::new(), closed: HashSet::new(), reads: 0 }
+    }
+
+    /// Writer of the temporary file of a group, appending when it was closed earlier
+    fn writer(&self, group: &str, mate: u8) -> io::Result<FastqWriter> {
+        let temp = temp_path(&self.output_dir.join(format!("{group}_R{mate}.fastq.gz")));
+        let file = match self.closed.contains(group) {
+            true => fs::OpenOptions::new().append(true).open(temp)?,
+            false => fs::File::create(temp)?,
+        };
+        Ok(GzEncoder::new(BufWriter::new(file), Compression::fast()))
+    }
+
+    fn get(&mut self, group: &str) -> io::Result<(&mut FastqWriter, &mut FastqWriter)> {
+        self.reads += 1;
+        if !self.open.contains_key(group) {
+            if self.open.len() >= self.max_pairs {
+                let oldest = self.open.iter().min_by_key(|(_, (.., last))| *last).map(|(group, _)| group.clone());
+                if let Some((oldest, (writer1, writer2, _))) = oldest.and_then(|group| self.open.remove_entry(&group)) {
+                    writer1.finish()?.flush()?;
+                    writer2.finish()?.flush()?;
+                    self.closed.insert(oldest);
+                }
+            }
+            let writers = (self.writer(group, 1)?, self.writer(group, 2)?, 0);
+            self.open.insert(group.to_string(), writers);
+        }
+        let (writer1, writer2, last) = self.open.get_mut(group).expect("opened above");
+        *last = self.reads;
+        Ok((writer1, writer2))
+    }
+
+    /// Close every writer and move the files of all groups into place
+    fn finish(self) -> Result<(), AppError> {
+        let Self { output_dir, open, closed: mut groups, .. } = self;
+        for (group, (writer1, writer2, _)) in open {
+            writer1.finish()?.flush()?;
+            writer2.finish()?.flush()?;
+            groups.insert(group);
+        }
+        for group in groups {
+            persist(&output_dir.join(format!("{group}_R1.fastq.gz")))?;
+            persist(&output_dir.join(format!("{group}_R2.fastq.gz")))?;
+        }
+        Ok(())
+    }
+}
+
+impl DemuxArgs {
+    /// Group of a read from the position of its barcode, `None` to drop it
+    fn group(&self, regions: &[Region], position: Option<(i32, i32, i32)>) -> Option<String> {
+        let group = match (position, regions.is_empty()) {
+            (Some((tile_id, ..)), true) => Some(tile_id.to_string()),
+            (Some(position), false) => regions.iter()
+                .find(|region| region.contains(position))
+                .map(|region| region.name.clone()),
+            (None, _) => None,
+        };
+        match group {
+            Some(group) => Some(group),
+            None if self.drop_unassigned => None,
+            None => Some(UNASSIGNED.to_string()),
+        }
+    }
+
+    #[inline]
+    fn position(map: &BarcodeMap, barcode: &[u8]) -> Option<(i32, i32, i32)> {
+        lookup(map, barcode).copied().flatten()
+    }
+
+    pub fn demux(self) -> Result<DemuxReport, AppError> {
+        let map = load_barcode_map(&self.barcode_map)?;
+        let regions = match &self.regions {
+            Some(path) => load_regions(path)?,
+            None => Vec::new(),
+        };
+        if regions.iter().any(|region| region.name == UNASSIGNED) {
+            return Err(AppError::IoError(io::Error::new(
+                io::ErrorKind::InvalidInput, format!("`{UNASSIGNED}` is reserved for reads without a region")
+            )));
+        }
+        match (&self.read1, &self.read2, &self.bam) {
+            (Some(read1), Some(read2), None) => self.demux_fastq(&map, &regions, read1, read2),
+            (None, None, Some(bam)) => self.demux_bam(&map, &regions, bam),
+            _ => unreachable!("clap parse the error is impossible."),
+        }
+    }
+
+    fn demux_fastq(
+        &self,
+        map: &BarcodeMap,
+        regions: &[Region],
+        read1: &Path,
+        read2: &Path,
+    ) -> Result<DemuxReport, AppError> {
+        let pos = self.barcode_pos.unwrap_or_else(|| OpenSt.library_barcode().0);
+        let mut reader1 = fastqfile::open(read1)?;
+        let mut reader2 = fastqfile::open(read2)?;
+        let mut writers = FastqGroups::new(&self.output_dir, self.max_open_files);
+        let mut report = DemuxReport::default();
+        let mut barcode = Vec::with_capacity(pos.len());
+        loop {
+            let (record1, record2) = match (reader1.next(), reader2.next()) {
+                (Some(record1), Some(record2)) => (record1?, record2?),
+                (None, None) => break,
+                _ => return Err(AppError::IoError(io::Error::new(
+                    io::ErrorKind::UnexpectedEof, "FASTQ pair has different numbers of reads"
+                ))),
+            };
+            let seq = if pos.is_read2() { record2.seq() } else { record1.seq() };
+            let seq = pos.safe_slice(seq);
+            barcode.clear();
+            if pos.is_revcomp() {
+                barcode.extend(seq.iter().rev().map(complement));
+            } else {
+                barcode.extend_from_slice(seq);
+            }
+            let Some(group) = report.count(self.group(regions, Self::position(map, &barcode))) else {
+                continue;
+            };
+            let (writer1, writer2) = writers.get(&group)?;
+            record1.write_unchanged(&mut *writer1)?;
+            record2.write_unchanged(&mut *writer2)?;
+        }
+        writers.finish()?;
+        Ok(report)
+    }
+
+    fn demux_bam(&self, map: &BarcodeMap, regions: &[Region], path: &Path) -> Result<DemuxReport, AppError> {
+        // every group keeps its BAM open to the end, refuse before reading what would run out of files
+        let groups = match regions.is_empty() {
+            true => map.values().flatten().map(|&(tile_id, ..)| tile_id).collect::<HashSet<_>>().len(),
+            false => regions.len(),
+        } + !self.drop_unassigned as usize;
+        if groups > self.max_open_files {
+            return Err(AppError::IoError(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                "up to {groups} BAM groups exceed --max-open-files {}, raise it along with the file descriptor limit", self.max_open_files
+            ))));
+        }
+        let mut reader = hts::open_bam(path)?;
+        let header = bam::Header::from_template(reader.header());
+        let mut writers: HashMap<String, bam::Writer> = HashMap::new();
+        let mut report = DemuxReport::default();
+        let mut record = bam::Record::new();
+        while let Some(result) = reader.read(&mut record) {
+            result?;
+            let position = match record.aux(self.tag.as_bytes()) {
+                Ok(Aux::String(barcode)) => Self::position(map, barcode.as_bytes()),
+                _ => None,
+            };
+            let Some(group) = report.count(self.group(regions, position)) else {
+                continue;
+            };
+            let writer = match writers.get_mut(&group) {
+                Some(writer) => writer,
+                None => {
+                    let path = self.output_dir.join(format!("{group}.bam"));
+                    let writer = bam::Writer::from_path(temp_path(&path), &header, bam::Format::Bam)?;
+                    writers.entry(group).or_insert(writer)
+                }
+            };
+            writer.write(&record)?;
+        }
+        for (group, writer) in writers {
+            // dropping the writer flushes and closes the BAM
+            drop(writer);
+            persist(&self.output_dir.join(format!("{group}.bam")))?;
+        }
+        Ok(report)
+    }
+}
+
+/// Reads written per group
+#[derive(Default)]
+pub struct DemuxReport {
+    total: u64,
+    dropped: u64,
+    groups: HashMap<String, u64>,
+}
+
+impl DemuxReport {
+    /// Count a read into its group and hand the group back
+    fn count(&mut self, group: Option<String>) -> Option<String> {
+        self.total += 1;
+        match &group {
+            Some(name) => *self.groups.entry(name.clone()).or_default() += 1,
+            None => self.dropped += 1,
+        }
+        group
+    }
+}
+
+impl std::fmt::Display for DemuxReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Total={}, Dropped={}\nGroup\tReads", self.total, self.dropped)?;
+        let mut groups: Vec<_> = self.groups.iter().collect();
+        groups.sort();
+        for (group, reads) in groups {
+            write!(f, "\n{group}\t{reads}")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,396 @@
+use crate::utils::{
+    hts,
+    barcode_iter::{validate_absolute_dirpath, validate_absolute_filepath},
+    fastqfile::{self, cut},
+    position::Position,
+    chemistry::{Chemistry, OpenSt},
+    atomic_file::{persist, temp_path},
+    error::AppError,
+};
+use crate::argparse::{
+    barcoderank::parse_bam_tag,
+    compare::BarcodeSets,
+    convert::write_text,
+};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use clap::{ArgGroup, Parser};
+use rust_htslib::bam::{self, Read as _, record::Aux};
+use seq_io::fastq::Record;
+use serde::Serialize;
+
+const BASES: [u8; 4] = *b"ACGT";
+
+#[derive(Parser, Debug)]
+#[command(name = "errprofile")]
+#[command(about = "Estimate per position substitution and indel rates of barcode reads against a whitelist", long_about = None)]
+#[command(next_line_help = true)]
+#[command(group(ArgGroup::new("input").required(true).args(["read1", "bam"])))]
+pub struct ErrProfileArgs {
+    /// barcode file or whitelist with one barcode in the first column per line (optionally gzipped)
+    #[arg(short, long, value_parser = validate_absolute_filepath)]
+    whitelist: PathBuf,
+
+    /// FASTQ holding the barcode read
+    #[arg(short = '1', long, value_parser = validate_absolute_filepath)]
+    read1: Option<PathBuf>,
+
+    /// FASTQ mate of --read1, only needed when --barcode-pos is on read2
+    #[arg(short = '2', long, requires = "read1", value_parser = validate_absolute_filepath)]
+    read2: Option<PathBuf>,
+
+    /// BAM with the raw barcode in a tag instead of FASTQ
+    #[arg(long, value_parser = validate_absolute_filepath)]
+    bam: Option<PathBuf>,
+
+    /// SAM tag holding the uncorrected barcode (only effective with --bam)
+    #[arg(long, default_value = "CR", value_parser = parse_bam_tag)]
+    tag: String,
+
+    /// SAM tag holding the barcode qualities (only effective with --bam)
+    #[arg(long, default_value = "CY", value_parser = parse_bam_tag)]
+    qual_tag: String,
+
+    /// barcode position in the FASTQ reads, the OpenST position by default
+    ///
+    /// Format: "read{1/2}:{+/-}:start-end" (e.g. "read1:+:2-30")
+    #[arg(long, value_parser = clap::value_parser!(Position), value_name = "BARCODE_POS")]
+    barcode_pos: Option<Position>,
+
+    /// profile only the first N reads, all reads with 0
+    #[arg(short = 'n', long, default_value_t = 1_000_000, value_name = "N")]
+    reads: u64,
+
+    /// write `errprofile.tsv` and the `errprofile.json` profile into this directory
+    #[arg(short, long, value_parser = validate_absolute_dirpath, help_heading = super::OUTPUTS)]
+    output_dir: PathBuf,
+}
+
+/// Single edit explaining an observed barcode, positions 0-based along the whitelist barcode
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Edit {
+    /// whitelist base replaced by the observed one
+    Substitution(usize, u8, u8),
+    /// extra base read before the whitelist position
+    Insertion(usize),
+    /// whitelist base missing from the read
+    Deletion(usize),
+}
+
+/// How an observed barcode relates to the whitelist
+enum Call {
+    Exact,
+    Corrected(Edit),
+    /// more than one whitelist barcode is a single edit away
+    Ambiguous,
+    Unmatched,
+}
+
+/// Whitelist barcodes looked up by every single edit
+struct Whitelist {
+    barcodes: HashSet<Vec<u8>>,
+}
+
+impl Whitelist {
+    fn call(&self, observed: &[u8]) -> Call {
+        if self.barcodes.contains(observed) {
+            return Call::Exact;
+        }
+        let len = observed.len();
+        // whitelist barcode of every edit explaining the observed one, the leftmost position of a homopolymer kept
+        let mut found: Vec<(Vec<u8>, Edit)> = Vec::new();
+        let mut candidate = observed.to_vec();
+        let check = |candidate: &[u8], edit: Edit, found: &mut Vec<(Vec<u8>, Edit)>| {
+            if self.barcodes.contains(candidate) && !found.iter().any(|(barcode, _)| barcode == candidate) {
+                found.push((candidate.to_vec(), edit));
+            }
+        };
+        for i in 0..len {
+            for &base in &BASES {
+                if base != observed[i] {
+                    candidate[i] = base;
+                    check(&candidate, Edit::Substitution(i, base, observed[i]), &mut found);
+                }
+            }
+            candidate[i] = observed[i];
+        }
+        for i in 0..len {
+            for &base in &BASES {
+                // base deleted at i, the read shows the next whitelist base in its place
+                let deleted: Vec<u8> = observed[..i].iter().chain([&base]).chain(&observed[i..len - 1]).copied().collect();
+                check(&deleted, Edit::Deletion(i), &mut found);
+                // base inserted at i, the last whitelist base is pushed out of the read
+                let inserted: Vec<u8> = observed[..i].iter().chain(&observed[i + 1..]).chain([&base]).copied().collect();
+                check(&inserted, Edit::Insertion(i), &mut found);
+            }
+        }
+        match found[..] {
+            [] => Call::Unmatched,
+            [(_, edit)] => Call::Corrected(edit),
+            _ => Call::Ambiguous,
+        }
+    }
+}
+
+/// Errors at one barcode position
+#[derive(Default, Clone)]
+struct PositionErrors {
+    bases: u64,
+    /// (whitelist base, read base) to count
+    substitutions: BTreeMap<(u8, u8), u64>,
+    insertions: u64,
+    deletions: u64,
+}
+
+impl PositionErrors {
+    fn substitution_count(&self) -> u64 {
+        self.substitutions.values().sum()
+    }
+}
+
+/// Bases and substitutions by Phred quality
+#[derive(Default, Clone, Copy)]
+struct QualityErrors {
+    bases: u64,
+    errors: u64,
+}
+
+#[derive(Default)]
+struct Profile {
+    reads: u64,
+    exact: u64,
+    corrected: u64,
+    ambiguous: u64,
+    unmatched: u64,
+    positions: Vec<PositionErrors>,
+    qualities: BTreeMap<u8, QualityErrors>,
+}
+
+impl Profile {
+    /// Count a barcode with its Phred+33 qualities, empty when unknown
+    fn add(&mut self, whitelist: &Whitelist, barcode: &[u8], qual: &[u8]) {
+        self.reads += 1;
+        let edit = match whitelist.call(barcode) {
+            Call::Exact => {
+                self.exact += 1;
+                None
+            }
+            Call::Corrected(edit) => {
+                self.corrected += 1;
+                Some(edit)
+            }
+            Call::Ambiguous => {
+                self.ambiguous += 1;
+                return;
+            }
+            Call::Unmatched => {
+                self.unmatched += 1;
+                return;
+            }
+        };
+        if self.positions.len() < barcode.len() {
+            self.positions.resize(barcode.len(), PositionErrors::default());
+        }
+        for position in &mut self.positions[..barcode.len()] {
+            position.bases += 1;
+        }
+        let substituted = match edit {
+            Some(Edit::Substitution(i, from, to)) => {
+                *self.positions[i].substitutions.entry((from, to)).or_default() += 1;
+                Some(i)
+            }
+            Some(Edit::Insertion(i)) => {
+                self.positions[i].insertions += 1;
+                None
+            }
+            Some(Edit::Deletion(i)) => {
+                self.positions[i].deletions += 1;
+                None
+            }
+            None => None,
+        };
+        // qualities only line up with the whitelist bases without indels
+        if matches!(edit, None | Some(Edit::Substitution(..))) {
+            for (i, &q) in qual.iter().enumerate().take(barcode.len()) {
+                let quality = self.qualities.entry(q.saturating_sub(33)).or_default();
+                quality.bases += 1;
+                quality.errors += (substituted == Some(i)) as u64;
+            }
+        }
+    }
+}
+
+#[inline]
+fn rate(count: u64, total: u64) -> f64 {
+    if total == 0 { 0.0 } else { count as f64 / total as f64 }
+}
+
+/// Profile read by the barcode correction, positions 1-based
+#[derive(Serialize)]
+struct ProfileJson {
+    reads: u64,
+    exact: u64,
+    corrected: u64,
+    ambiguous: u64,
+    unmatched: u64,
+    positions: Vec<PositionJson>,
+    qualities: Vec<QualityJson>,
+}
+
+#[derive(Serialize)]
+struct PositionJson {
+    position: usize,
+    bases: u64,
+    substitution_rate: f64,
+    insertion_rate: f64,
+    deletion_rate: f64,
+    /// `A>C` like keys, whitelist base first, to the rate among the bases of the position
+    substitutions: BTreeMap<String, f64>,
+}
+
+#[derive(Serialize)]
+struct QualityJson {
+    quality: u8,
+    bases: u64,
+    errors: u64,
+    error_rate: f64,
+}
+
+impl ErrProfileArgs {
+    fn profile_fastq(&self, whitelist: &Whitelist, read1: &Path) -> Result<Profile, AppError> {
+        let pos = self.barcode_pos.unwrap_or_else(|| OpenSt.library_barcode().0);
+        let path = match (&self.read2, pos.is_read2()) {
+            (Some(read2), true) => read2.as_path(),
+            (None, true) => return Err(AppError::IoError(io::Error::new(
+                io::ErrorKind::InvalidInput, "--barcode-pos on read2 needs --read2"
+            ))),
+            (_, false) => read1,
+        };
+        let mut reader = fastqfile::open(path)?;
+        let mut profile = Profile::default();
+        while let Some(record) = reader.next() {
+            if self.reads > 0 && profile.reads >= self.reads {
+                break;
+            }
+            let record = record?;
+            let (barcode, qual) = cut(&pos, record.seq(), record.qual());
+            if pos.fits(record.seq().len()) {
+                profile.add(whitelist, &barcode, &qual);
+            }
+        }
+        Ok(profile)
+    }
+
+    fn profile_bam(&self, whitelist: &Whitelist, path: &Path) -> Result<Profile, AppError> {
+        let mut reader = hts::open_bam(path)?;
+        let mut profile = Profile::default();
+        let mut record = bam::Record::new();
+        while let Some(result) = reader.read(&mut record) {
+            if self.reads > 0 && profile.reads >= self.reads {
+                break;
+            }
+            result?;
+            if record.is_secondary() || record.is_supplementary() {
+                continue;
+            }
+            if let Ok(Aux::String(barcode)) = record.aux(self.tag.as_bytes()) {
+                let qual = match record.aux(self.qual_tag.as_bytes()) {
+                    Ok(Aux::String(qual)) if qual.len() == barcode.len() => qual.as_bytes(),
+                    _ => &[],
+                };
+                profile.add(whitelist, barcode.as_bytes(), qual);
+            }
+        }
+        Ok(profile)
+    }
+
+    pub fn estimate(self) -> Result<ErrProfileReport, AppError> {
+        let whitelist = Whitelist {
+            barcodes: BarcodeSets::load(&self.whitelist)?.all.into_iter().map(String::into_bytes).collect(),
+        };
+        let profile = match (&self.read1, &self.bam) {
+            (Some(read1), None) => self.profile_fastq(&whitelist, read1)?,
+            (None, Some(bam)) => self.profile_bam(&whitelist, bam)?,
+            _ => unreachable!("clap parse the error is impossible."),
+        };
+
+        write_text(&self.output_dir.join("errprofile.tsv"), |writer| {
+            writeln!(writer, "position\tbases\tsubstitutions\tinsertions\tdeletions\tsubstitution_rate\tinsertion_rate\tdeletion_rate")?;
+            for (i, position) in profile.positions.iter().enumerate() {
+                let substitutions = position.substitution_count();
+                writeln!(
+                    writer, "{}\t{}\t{}\t{}\t{}\t{:.6}\t{:.6}\t{:.6}",
+                    i + 1, position.bases, substitutions, position.insertions, position.deletions,
+                    rate(substitutions, position.bases), rate(position.insertions, position.bases),
+                    rate(position.deletions, position.bases),
+                )?;
+            }
+            Ok(())
+        })?;
+
+        let json = ProfileJson {
+            reads: profile.reads,
+            exact: profile.exact,
+            corrected: profile.corrected,
+            ambiguous: profile.ambiguous,
+            unmatched: profile.unmatched,
+            positions: profile.positions.iter().enumerate().map(|(i, position)| PositionJson {
+                position: i + 1,
+                bases: position.bases,
+                substitution_rate: rate(position.substitution_count(), position.bases),
+                insertion_rate: rate(position.insertions, position.bases),
+                deletion_rate: rate(position.deletions, position.bases),
+                substitutions: position.substitutions.iter()
+                    .map(|(&(from, to), &count)| (format!("{}>{}", from as char, to as char), rate(count, position.bases)))
+                    .collect(),
+            }).collect(),
+            qualities: profile.qualities.iter().map(|(&quality, errors)| QualityJson {
+                quality,
+                bases: errors.bases,
+                errors: errors.errors,
+                error_rate: rate(errors.errors, errors.bases),
+            }).collect(),
+        };
+        let path = self.output_dir.join("errprofile.json");
+        let mut writer = BufWriter::new(fs::File::create(temp_path(&path))?);
+        serde_json::to_writer_pretty(&mut writer, &json).map_err(io::Error::from)?;
+        writer.flush()?;
+        drop(writer);
+        persist(&path)?;
+
+        let bases: u64 = profile.positions.iter().map(|position| position.bases).sum();
+        Ok(ErrProfileReport {
+            reads: profile.reads,
+            exact: profile.exact,
+            corrected: profile.corrected,
+            ambiguous: profile.ambiguous,
+            unmatched: profile.unmatched,
+            substitution_rate: rate(profile.positions.iter().map(PositionErrors::substitution_count).sum(), bases),
+            indel_rate: rate(profile.positions.iter().map(|position| position.insertions + position.deletions).sum(), bases),
+        })
+    }
+}
+
+/// Barcodes by their whitelist call and the overall error rates
+pub struct ErrProfileReport {
+    reads: u64,
+    exact: u64,
+    corrected: u64,
+    ambiguous: u64,
+    unmatched: u64,
+    substitution_rate: f64,
+    indel_rate: f64,
+}
+
+impl std::fmt::Display for ErrProfileReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Reads={}, Exact={}, Corrected={}, Ambiguous={}, Unmatched={}, Substitution Rate={:.4}%, Indel Rate={:.4}%",
+            self.reads, self.exact, self.corrected, self.ambiguous, self.unmatched,
+            self.substitution_rate * 100.0, self.indel_rate * 100.0,
+        )
+    }
+}
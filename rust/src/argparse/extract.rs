@@ -0,0 +1,362 @@
+use crate::utils::{
+    barcode_iter::validate_absolute_filepath,
+    fastqfile::{self, check_base_match, cut, too_short},
+    position::Position,
+    chemistry::{Chemistry, OpenSt},
+    atomic_file::{persist, temp_path},
+    error::AppError,
+};
+use crate::argparse::touchbarcode::validate_barcode_pattern;
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use clap::{Parser, ValueEnum};
+use flate2::{Compression, write::GzEncoder};
+use seq_io::fastq::{self, Record};
+
+#[derive(Parser, Debug)]
+#[command(name = "extract")]
+#[command(about = "Move barcode and UMI bases of FASTQ reads into their read names", long_about = None)]
+#[command(next_line_help = true)]
+pub struct ExtractArgs {
+    /// FASTQ read 1
+    #[arg(short = '1', long, value_parser = validate_absolute_filepath)]
+    read1: PathBuf,
+
+    /// FASTQ read 2, required when a position is on read 2
+    #[arg(short = '2', long, requires = "out2", value_parser = validate_absolute_filepath)]
+    read2: Option<PathBuf>,
+
+    /// output of read 1, gzipped when ending with `.gz`
+    #[arg(long, help_heading = super::OUTPUTS)]
+    out1: PathBuf,
+
+    /// output of read 2, gzipped when ending with `.gz`
+    #[arg(long, requires = "read2", help_heading = super::OUTPUTS)]
+    out2: Option<PathBuf>,
+
+    /// barcode position, the OpenST position and pattern by default
+    ///
+    /// Format: "read{1/2}:{+/-}:start-end" (e.g. "read1:+:2-30")
+    #[arg(long, value_parser = clap::value_parser!(Position), value_name = "BARCODE_POS")]
+    barcode_pos: Option<Position>,
+
+    /// drop reads whose barcode does not match this pattern, before reverse complement
+    ///
+    /// Regex: ^[ATGCNRYMKSWHBVD]+$
+    #[arg(long, requires = "barcode_pos", value_parser = validate_barcode_pattern, value_name = "BARCODE_PATTERN")]
+    barcode_pattern: Option<String>,
+
+    /// UMI position, no UMI by default
+    ///
+    /// Format: "read{1/2}:{+/-}:start-end" (e.g. "read2:+:0-9", or "read2:+:end-12-end" for the last 12 bases)
+    #[arg(long, value_parser = clap::value_parser!(Position), value_name = "UMI_POS")]
+    umi_pos: Option<Position>,
+
+    /// how barcode and UMI are added to the read names
+    #[arg(long, value_enum, default_value_t = NameFormat::UmiTools)]
+    name_format: NameFormat,
+
+    /// keep the extracted bases in the reads instead of removing them
+    #[arg(long)]
+    keep_bases: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum NameFormat {
+    /// `@{name}_{barcode}_{umi}`, same as `umi_tools extract`
+    UmiTools,
+    /// `@{name} CR:Z:{barcode}\tCY:Z:{quality}\tUR:Z:{umi}\tUY:Z:{quality}`, SAM tags for `bwa mem -C` or `samtools import -T`
+    Tags,
+}
+
+/// Plain or gzipped output written to its temporary path
+enum FastqOutput {
+    Plain(BufWriter<fs::File>),
+    Gz(Box<GzEncoder<BufWriter<fs::File>>>),
+}
+
+impl FastqOutput {
+    fn create(path: &Path) -> io::Result<Self> {
+        let file = BufWriter::new(fs::File::create(temp_path(path))?);
+        if path.extension().is_some_and(|ext| ext == "gz") {
+            Ok(Self::Gz(Box::new(GzEncoder::new(file, Compression::fast()))))
+        } else {
+            Ok(Self::Plain(file))
+        }
+    }
+
+    fn finish(self, path: &Path) -> io::Result<()> {
+        match self {
+            Self::Plain(mut writer) => writer.flush()?,
+            Self::Gz(writer) => (*writer).finish()?.flush()?,
+        }
+        persist(path)
+    }
+}
+
+impl Write for FastqOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(writer) => writer.write(buf),
+            Self::Gz(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(writer) => writer.flush(),
+            Self::Gz(writer) => writer.flush(),
+        }
+    }
+}
+
+/// Copy of `data` without the ranges
+fn remove_ranges(data: &[u8], ranges: &[Range<usize>]) -> Vec<u8> {
+    data.iter()
+        .enumerate()
+        .filter(|(index, _)| !ranges.iter().any(|range| range.contains(index)))
+        .map(|(_, &byte)| byte)
+        .collect()
+}
+
+impl ExtractArgs {
+    /// New read name from the original head line
+    fn head(&self, head: &[u8], barcode: &(Vec<u8>, Vec<u8>), umi: Option<&(Vec<u8>, Vec<u8>)>) -> Vec<u8> {
+        let id_end = head.iter().position(|&b| b == b' ' || b == b'\t').unwrap_or(head.len());
+        let mut name = head[..id_end].to_vec();
+        match self.name_format {
+            NameFormat::UmiTools => {
+                name.push(b'_');
+                name.extend_from_slice(&barcode.0);
+                if let Some((umi, _)) = umi {
+                    name.push(b'_');
+                    name.extend_from_slice(umi);
+                }
+                name.extend_from_slice(&head[id_end..]);
+            }
+            NameFormat::Tags => {
+                name.extend_from_slice(b" CR:Z:");
+                name.extend_from_slice(&barcode.0);
+                name.extend_from_slice(b"\tCY:Z:");
+                name.extend_from_slice(&barcode.1);
+                if let Some((umi, qual)) = umi {
+                    name.extend_from_slice(b"\tUR:Z:");
+                    name.extend_from_slice(umi);
+                    name.extend_from_slice(b"\tUY:Z:");
+                    name.extend_from_slice(qual);
+                }
+            }
+        }
+        name
+    }
+
+    pub fn extract(self) -> Result<ExtractReport, AppError> {
+        let (pos, pattern) = match (self.barcode_pos, &self.barcode_pattern) {
+            (None, _) => OpenSt.library_barcode(),
+            (Some(pos), pattern) => (pos, pattern.clone().unwrap_or_default()),
+        };
+        let on_read2 = pos.is_read2() || self.umi_pos.is_some_and(|umi| umi.is_read2());
+        if on_read2 && self.read2.is_none() {
+            return Err(AppError::IoError(io::Error::new(
+                io::ErrorKind::InvalidInput, "--read2 is required by a position on read 2"
+            )));
+        }
+        // positions removed from read 1 and read 2, resolved against each read
+        let mut removed: [Vec<Position>; 2] = [Vec::new(), Vec::new()];
+        if !self.keep_bases {
+            for position in std::iter::once(pos).chain(self.umi_pos) {
+                removed[position.is_read2() as usize].push(position);
+            }
+        }
+
+        let mut reader1 = fastqfile::open(&self.read1)?;
+        let mut reader2 = self.read2.as_ref().map(fastqfile::open).transpose()?;
+        let mut writer1 = FastqOutput::create(&self.out1)?;
+        let mut writer2 = self.out2.as_ref().map(|path| FastqOutput::create(path)).transpose()?;
+        let mut report = ExtractReport::default();
+        loop {
+            let (record1, record2) = match (reader1.next(), reader2.as_mut().map(|reader| reader.next())) {
+                (Some(record1), None) => (record1?, None),
+                (Some(record1), Some(Some(record2))) => (record1?, Some(record2?)),
+                (None, None | Some(None)) => break,
+                _ => return Err(AppError::IoError(io::Error::new(
+                    io::ErrorKind::UnexpectedEof, "FASTQ pair has different numbers of reads"
+                ))),
+            };
+            report.total += 1;
+            let records = [Some(&record1), record2.as_ref()];
+            let source = |position: &Position| records[position.is_read2() as usize].expect("read 2 is checked above");
+
+            let record = source(&pos);
+            if too_short(&pos, record.seq().len()) {
+                report.too_short += 1;
+                continue;
+            }
+            if pos.safe_slice(record.seq()).iter().zip(pattern.bytes()).any(|(&b, p)| check_base_match(b, p)) {
+                report.failed_pattern += 1;
+                continue;
+            }
+            let barcode = cut(&pos, record.seq(), record.qual());
+            let umi = match &self.umi_pos {
+                Some(umi_pos) if too_short(umi_pos, source(umi_pos).seq().len()) => {
+                    report.too_short += 1;
+                    continue;
+                }
+                Some(umi_pos) => Some(cut(umi_pos, source(umi_pos).seq(), source(umi_pos).qual())),
+                None => None,
+            };
+
+            for (index, record) in records.into_iter().enumerate() {
+                let Some(record) = record else { continue };
+                let writer = match index {
+                    0 => &mut writer1,
+                    _ => writer2.as_mut().expect("--out2 is required by --read2"),
+                };
+                let head = self.head(record.head(), &barcode, umi.as_ref());
+                let ranges: Vec<Range<usize>> = removed[index].iter()
+                    .map(|position| position.resolve(record.seq().len()))
+                    .collect();
+                if ranges.is_empty() {
+                    fastq::write_to(&mut *writer, &head, record.seq(), record.qual())?;
+                } else {
+                    let seq = remove_ranges(record.seq(), &ranges);
+                    let qual = remove_ranges(record.qual(), &ranges);
+                    fastq::write_to(&mut *writer, &head, &seq, &qual)?;
+                }
+            }
+            report.written += 1;
+        }
+        writer1.finish(&self.out1)?;
+        if let (Some(writer), Some(path)) = (writer2, &self.out2) {
+            writer.finish(path)?;
+        }
+        Ok(report)
+    }
+}
+
+/// Barcode and UMI extraction for library use, set up by its builder methods instead of command line arguments
+///
+/// ```no_run
+/// use opentools::{BarcodeExtractor, NameFormat};
+///
+/// let report = BarcodeExtractor::builder()
+///     .read1("/data/R1.fastq.gz", "/data/R1.extracted.fastq.gz")
+///     .read2("/data/R2.fastq.gz", "/data/R2.extracted.fastq.gz")
+///     .umi_pos("read2:+:0-9".parse()?)
+///     .name_format(NameFormat::Tags)
+///     .run()?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub struct BarcodeExtractor {
+    read1: Option<(PathBuf, PathBuf)>,
+    read2: Option<(PathBuf, PathBuf)>,
+    barcode: Option<(Position, Option<String>)>,
+    umi_pos: Option<Position>,
+    name_format: NameFormat,
+    keep_bases: bool,
+}
+
+impl BarcodeExtractor {
+    /// Extract the OpenST barcode without UMI into umi_tools read names
+    pub fn builder() -> Self {
+        Self {
+            read1: None,
+            read2: None,
+            barcode: None,
+            umi_pos: None,
+            name_format: NameFormat::UmiTools,
+            keep_bases: false,
+        }
+    }
+
+    /// FASTQ read 1 and its output, gzipped when ending with `.gz`
+    pub fn read1(mut self, input: impl Into<PathBuf>, output: impl Into<PathBuf>) -> Self {
+        self.read1 = Some((input.into(), output.into()));
+        self
+    }
+
+    /// FASTQ read 2 and its output, gzipped when ending with `.gz`
+    pub fn read2(mut self, input: impl Into<PathBuf>, output: impl Into<PathBuf>) -> Self {
+        self.read2 = Some((input.into(), output.into()));
+        self
+    }
+
+    /// Custom barcode position, reads not matching the pattern are dropped when one is given
+    pub fn barcode(mut self, pos: Position, pattern: Option<&str>) -> Self {
+        self.barcode = Some((pos, pattern.map(str::to_string)));
+        self
+    }
+
+    pub fn umi_pos(mut self, pos: Position) -> Self {
+        self.umi_pos = Some(pos);
+        self
+    }
+
+    pub fn name_format(mut self, name_format: NameFormat) -> Self {
+        self.name_format = name_format;
+        self
+    }
+
+    pub fn keep_bases(mut self, keep_bases: bool) -> Self {
+        self.keep_bases = keep_bases;
+        self
+    }
+
+    pub fn run(self) -> Result<ExtractReport, AppError> {
+        let (read1, out1) = self.read1.ok_or_else(|| AppError::IoError(io::Error::new(
+            io::ErrorKind::InvalidInput, "read 1 is required"
+        )))?;
+        let (read2, out2) = self.read2.unzip();
+        let (barcode_pos, barcode_pattern) = self.barcode.unzip();
+        let barcode_pattern = barcode_pattern.flatten()
+            .map(|pattern| validate_barcode_pattern(&pattern).map_err(AppError::InvalidBarcodePattern))
+            .transpose()?;
+        ExtractArgs {
+            read1,
+            read2,
+            out1,
+            out2,
+            barcode_pos,
+            barcode_pattern,
+            umi_pos: self.umi_pos,
+            name_format: self.name_format,
+            keep_bases: self.keep_bases,
+        }.extract()
+    }
+}
+
+/// Reads written and why the others are dropped
+#[derive(Default)]
+pub struct ExtractReport {
+    total: u64,
+    /// reads ending before the barcode or UMI position
+    too_short: u64,
+    failed_pattern: u64,
+    written: u64,
+}
+
+impl ExtractReport {
+    #[inline]
+    pub fn total(&self) -> u64 { self.total }
+
+    #[inline]
+    pub fn too_short(&self) -> u64 { self.too_short }
+
+    #[inline]
+    pub fn failed_pattern(&self) -> u64 { self.failed_pattern }
+
+    #[inline]
+    pub fn written(&self) -> u64 { self.written }
+}
+
+impl std::fmt::Display for ExtractReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Total={}, Too short={}, Failed pattern={}, Written={}",
+            self.total, self.too_short, self.failed_pattern, self.written,
+        )
+    }
+}
@@ -0,0 +1,240 @@
+use crate::utils::{
+    threads,
+    barcode_iter::validate_filepath_or_stdin,
+    fastqfile,
+    error::AppError,
+};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{self, BufWriter, Write};
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use clap::Parser;
+use crossbeam::channel;
+use seq_io::fastq::Record;
+use serde::Serialize;
+
+/// Reads handed to a worker at once
+const BATCH_READS: usize = 4096;
+
+#[derive(Parser, Debug)]
+#[command(name = "fqstat")]
+#[command(about = "Streaming FASTQ statistics: lengths, per-cycle quality, GC, N rate and duplication", long_about = None)]
+#[command(next_line_help = true)]
+pub struct FqStatArgs {
+    /// FASTQ file (optionally gzipped), repeat it for several files, `-` for stdin
+    #[arg(short, long, required = true, value_parser = validate_filepath_or_stdin)]
+    input: Vec<PathBuf>,
+
+    /// write the full statistics of all files, including length distribution and per-cycle quality, as JSON
+    #[arg(long, value_name = "FILE", help_heading = super::OUTPUTS)]
+    json: Option<PathBuf>,
+
+    /// reads at the start of each file used to estimate duplication
+    #[arg(long, default_value_t = 1_000_000, value_name = "N")]
+    dup_reads: u64,
+
+    /// number of worker threads, the global `--threads` or all available cores by default
+    #[arg(short, long)]
+    threads: Option<NonZeroUsize>,
+}
+
+/// Statistics of one FASTQ file
+#[derive(Serialize, Default)]
+pub struct FastqStats {
+    file: PathBuf,
+    reads: u64,
+    bases: u64,
+    min_length: u64,
+    max_length: u64,
+    mean_length: f64,
+    gc_content: f64,
+    n_rate: f64,
+    mean_quality: f64,
+    q30_rate: f64,
+    /// fraction of duplicated sequences among the first `--dup-reads` reads
+    duplication: f64,
+    /// read length to number of reads
+    length_distribution: BTreeMap<u64, u64>,
+    /// mean Phred quality of every cycle
+    cycle_quality: Vec<f64>,
+    #[serde(skip)]
+    gc_bases: u64,
+    #[serde(skip)]
+    n_bases: u64,
+    #[serde(skip)]
+    q30_bases: u64,
+    #[serde(skip)]
+    quality_sum: u64,
+    /// (quality sum, bases) of every cycle
+    #[serde(skip)]
+    cycles: Vec<(u64, u64)>,
+}
+
+impl FastqStats {
+    fn add(&mut self, seq: &[u8], qual: &[u8]) {
+        let length = seq.len() as u64;
+        if self.reads == 0 || length < self.min_length {
+            self.min_length = length;
+        }
+        self.max_length = self.max_length.max(length);
+        self.reads += 1;
+        self.bases += length;
+        *self.length_distribution.entry(length).or_default() += 1;
+        for &base in seq {
+            match base {
+                b'G' | b'C' | b'g' | b'c' => self.gc_bases += 1,
+                b'N' | b'n' => self.n_bases += 1,
+                _ => {}
+            }
+        }
+        if self.cycles.len() < qual.len() {
+            self.cycles.resize(qual.len(), (0, 0));
+        }
+        for (cycle, &q) in qual.iter().enumerate() {
+            let q = q.saturating_sub(33) as u64;
+            self.quality_sum += q;
+            if q >= 30 {
+                self.q30_bases += 1;
+            }
+            self.cycles[cycle].0 += q;
+            self.cycles[cycle].1 += 1;
+        }
+    }
+
+    fn merge(&mut self, other: FastqStats) {
+        if other.reads == 0 {
+            return;
+        }
+        if self.reads == 0 || other.min_length < self.min_length {
+            self.min_length = other.min_length;
+        }
+        self.max_length = self.max_length.max(other.max_length);
+        self.reads += other.reads;
+        self.bases += other.bases;
+        self.gc_bases += other.gc_bases;
+        self.n_bases += other.n_bases;
+        self.q30_bases += other.q30_bases;
+        self.quality_sum += other.quality_sum;
+        for (length, reads) in other.length_distribution {
+            *self.length_distribution.entry(length).or_default() += reads;
+        }
+        if self.cycles.len() < other.cycles.len() {
+            self.cycles.resize(other.cycles.len(), (0, 0));
+        }
+        for (cycle, (sum, bases)) in other.cycles.into_iter().enumerate() {
+            self.cycles[cycle].0 += sum;
+            self.cycles[cycle].1 += bases;
+        }
+    }
+
+    fn finish(&mut self) {
+        let ratio = |part: u64, total: u64| if total == 0 { 0.0 } else { part as f64 / total as f64 };
+        self.mean_length = ratio(self.bases, self.reads);
+        self.gc_content = ratio(self.gc_bases, self.bases - self.n_bases);
+        self.n_rate = ratio(self.n_bases, self.bases);
+        self.mean_quality = ratio(self.quality_sum, self.bases);
+        self.q30_rate = ratio(self.q30_bases, self.bases);
+        self.cycle_quality = self.cycles.iter().map(|&(sum, bases)| ratio(sum, bases)).collect();
+    }
+}
+
+impl FqStatArgs {
+    /// Parse on the current thread, count batches of reads on `threads` workers
+    fn stats_of(&self, path: &PathBuf, threads: usize) -> Result<FastqStats, AppError> {
+        let mut reader = fastqfile::open(path)?;
+        let (sender, receiver) = channel::bounded::<Vec<(Vec<u8>, Vec<u8>)>>(threads * 2);
+        let (mut stats, sampled, unique) = std::thread::scope(|scope| -> Result<_, AppError> {
+            let workers: Vec<_> = (0..threads)
+                .map(|_| {
+                    let receiver = receiver.clone();
+                    scope.spawn(move || {
+                        let mut stats = FastqStats::default();
+                        for batch in receiver {
+                            for (seq, qual) in &batch {
+                                stats.add(seq, qual);
+                            }
+                        }
+                        stats
+                    })
+                })
+                .collect();
+            drop(receiver);
+
+            let mut hashes: HashSet<u64> = HashSet::new();
+            let mut sampled = 0;
+            let mut batch = Vec::with_capacity(BATCH_READS);
+            while let Some(record) = reader.next() {
+                let record = record?;
+                if sampled < self.dup_reads {
+                    let mut hasher = DefaultHasher::new();
+                    record.seq().hash(&mut hasher);
+                    hashes.insert(hasher.finish());
+                    sampled += 1;
+                }
+                batch.push((record.seq().to_vec(), record.qual().to_vec()));
+                if batch.len() == BATCH_READS {
+                    sender.send(std::mem::replace(&mut batch, Vec::with_capacity(BATCH_READS)))
+                        .map_err(|_| AppError::ChannelError)?;
+                }
+            }
+            if !batch.is_empty() {
+                sender.send(batch).map_err(|_| AppError::ChannelError)?;
+            }
+            drop(sender);
+
+            let mut stats = FastqStats::default();
+            for worker in workers {
+                stats.merge(worker.join().map_err(|_| AppError::ChannelError)?);
+            }
+            Ok((stats, sampled, hashes.len() as u64))
+        })?;
+        stats.file = path.clone();
+        stats.duplication = if sampled == 0 { 0.0 } else { 1.0 - unique as f64 / sampled as f64 };
+        stats.finish();
+        Ok(stats)
+    }
+
+    pub fn stat(self) -> Result<FqStatReport, AppError> {
+        let threads = threads::resolve(self.threads.map(NonZeroUsize::get), threads::available());
+        let files = self.input.iter()
+            .map(|path| self.stats_of(path, threads))
+            .collect::<Result<Vec<_>, _>>()?;
+        if let Some(path) = &self.json {
+            let mut writer = BufWriter::new(fs::File::create(path)?);
+            serde_json::to_writer_pretty(&mut writer, &files).map_err(io::Error::from)?;
+            writer.flush()?;
+        }
+        Ok(FqStatReport { files })
+    }
+}
+
+/// One summary row per file
+pub struct FqStatReport {
+    files: Vec<FastqStats>,
+}
+
+impl std::fmt::Display for FqStatReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "file\treads\tbases\tmin_len\tmean_len\tmax_len\tgc\tn_rate\tmean_q\tq30\tduplication")?;
+        for stats in &self.files {
+            write!(
+                f,
+                "\n{}\t{}\t{}\t{}\t{:.2}\t{}\t{:.4}\t{:.5}\t{:.2}\t{:.4}\t{:.4}",
+                stats.file.display(),
+                stats.reads,
+                stats.bases,
+                stats.min_length,
+                stats.mean_length,
+                stats.max_length,
+                stats.gc_content,
+                stats.n_rate,
+                stats.mean_quality,
+                stats.q30_rate,
+                stats.duplication,
+            )?;
+        }
+        Ok(())
+    }
+}
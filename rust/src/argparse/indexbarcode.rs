@@ -0,0 +1,184 @@
+use crate::utils::{
+    barcode_file::{build_tabix_index, create_bgzf, BarcodeRecord, TILE_FETCH_END, TILE_FETCH_START},
+    barcode_index,
+    barcode_iter::validate_absolute_filepath,
+    fastqfile::open_text,
+    atomic_file::{persist_indexed, temp_path},
+    error::AppError,
+};
+use std::collections::HashSet;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use clap::Parser;
+
+#[derive(Parser, Debug)]
+#[command(name = "indexbarcode")]
+#[command(about = "Validate a barcode file, then bgzip and tabix index it", long_about = None)]
+#[command(next_line_help = true)]
+pub struct IndexBarcodeArgs {
+    /// barcode file with `tile_id\tx_pos\ty_pos\tbarcode[\tquality]` rows (plain, gzip or bgzf)
+    #[arg(short = 'I', long, value_parser = validate_absolute_filepath)]
+    barcode_file: PathBuf,
+
+    /// indexed output, the input itself when it ends with `.gz`, `{input}.gz` otherwise
+    #[arg(short, long, help_heading = super::OUTPUTS)]
+    output: Option<PathBuf>,
+
+    /// sort rows by tile and position instead of failing on unsorted input, the file is loaded into memory
+    #[arg(long)]
+    sort: bool,
+
+    /// also write the binary index `{output}.bix` of packed barcodes, memory-mapped by tilesmatch and dedupbarcode
+    #[arg(long)]
+    binary_index: bool,
+}
+
+/// Position of a row for the tabix sort order
+struct SortedRow {
+    tile_id: u64,
+    y_pos: i64,
+    x_pos: i64,
+    line: String,
+}
+
+impl IndexBarcodeArgs {
+    fn output(&self) -> PathBuf {
+        match &self.output {
+            Some(path) => path.clone(),
+            None if self.barcode_file.extension().is_some_and(|ext| ext == "gz") => self.barcode_file.clone(),
+            None => {
+                let mut name = self.barcode_file.as_os_str().to_owned();
+                name.push(".gz");
+                PathBuf::from(name)
+            }
+        }
+    }
+
+    /// Check every row and the sort order tabix needs, tiles contiguous and y positions ascending
+    fn validate(&self, report: &mut IndexReport, mut keep: impl FnMut(SortedRow)) -> Result<Vec<String>, AppError> {
+        let invalid = |line: usize, message: &str| AppError::IoError(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} line {}: {}", self.barcode_file.display(), line, message),
+        ));
+        let mut header = Vec::new();
+        let mut seen_tiles: HashSet<u64> = HashSet::new();
+        let mut last: Option<(u64, i64)> = None;
+        for (index, line) in open_text(&self.barcode_file)?.lines().enumerate() {
+            let line = line?;
+            if line.starts_with('#') {
+                if report.rows == 0 {
+                    header.push(line);
+                }
+                continue;
+            }
+            if line.is_empty() {
+                continue;
+            }
+            let record = BarcodeRecord::parse(&line).map_err(|_| invalid(index + 1, "expected 4 or 5 columns"))?;
+            let tile_id: u64 = record.tile_id.parse().map_err(|_| invalid(index + 1, "invalid tile id"))?;
+            let x_pos: i64 = record.x_pos.parse().map_err(|_| invalid(index + 1, "invalid x position"))?;
+            let y_pos: i64 = record.y_pos.parse().map_err(|_| invalid(index + 1, "invalid y position"))?;
+            if x_pos < 0 || y_pos < 0 {
+                return Err(invalid(index + 1, "negative position"));
+            }
+            report.rows += 1;
+            if !(TILE_FETCH_START as i64..TILE_FETCH_END as i64).contains(&y_pos) {
+                report.outside_fetch += 1;
+            }
+            match last {
+                Some((last_tile, last_y)) if last_tile == tile_id => {
+                    if y_pos < last_y && report.unsorted_at.is_none() {
+                        report.unsorted_at = Some(index + 1);
+                    }
+                }
+                _ => {
+                    if !seen_tiles.insert(tile_id) && report.unsorted_at.is_none() {
+                        report.unsorted_at = Some(index + 1);
+                    }
+                }
+            }
+            last = Some((tile_id, y_pos));
+            keep(SortedRow { tile_id, y_pos, x_pos, line });
+        }
+        report.tiles = seen_tiles.len() as u64;
+        Ok(header)
+    }
+
+    pub fn index(self) -> Result<IndexReport, AppError> {
+        let output = self.output();
+        let mut report = IndexReport::default();
+        let mut rows = Vec::new();
+        let header = self.validate(&mut report, |row| if self.sort { rows.push(row) })?;
+        if let (Some(line), false) = (report.unsorted_at, self.sort) {
+            return Err(AppError::IoError(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not sorted by tile and y position from line {}, rerun with --sort", self.barcode_file.display(), line),
+            )));
+        }
+
+        let temp = temp_path(&output);
+        let mut writer = create_bgzf(&temp)?;
+        for line in &header {
+            writeln!(writer, "{line}")?;
+        }
+        if self.sort {
+            rows.sort_by_key(|row| (row.tile_id, row.y_pos, row.x_pos));
+            for row in rows {
+                writeln!(writer, "{}", row.line)?;
+            }
+        } else {
+            copy_rows(&self.barcode_file, &mut writer)?;
+        }
+        writer.flush()?;
+        drop(writer);
+        build_tabix_index(&temp)?;
+        persist_indexed(&output)?;
+        if self.binary_index && barcode_index::build(&output)? {
+            report.binary_index = Some(barcode_index::path_of(&output));
+        }
+        report.output = output;
+        Ok(report)
+    }
+}
+
+/// Copy the data rows of a validated file, header lines are written separately
+fn copy_rows(path: &Path, writer: &mut impl Write) -> Result<(), AppError> {
+    for line in open_text(path)?.lines() {
+        let line = line?;
+        if !line.is_empty() && !line.starts_with('#') {
+            writeln!(writer, "{line}")?;
+        }
+    }
+    Ok(())
+}
+
+/// Rows indexed and the problems found
+#[derive(Default)]
+pub struct IndexReport {
+    rows: u64,
+    tiles: u64,
+    /// first line breaking the sort order
+    unsorted_at: Option<usize>,
+    /// rows with y position outside the range fetched per tile, invisible to tilesmatch and dedupbarcode
+    outside_fetch: u64,
+    output: PathBuf,
+    binary_index: Option<PathBuf>,
+}
+
+impl std::fmt::Display for IndexReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Rows={}, Tiles={}, Re-sorted={}, Outside fetch range={}\nIndexed {}",
+            self.rows,
+            self.tiles,
+            if self.unsorted_at.is_some() { "yes" } else { "no" },
+            self.outside_fetch,
+            self.output.display(),
+        )?;
+        if let Some(path) = &self.binary_index {
+            write!(f, "\nBinary index {}", path.display())?;
+        }
+        Ok(())
+    }
+}
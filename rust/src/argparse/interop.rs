@@ -0,0 +1,231 @@
+use crate::utils::{
+    barcode_iter::validate_absolute_dirpath,
+    interop::{read_error_metrics, read_q_metrics, read_tile_metrics, tile_id, CycleMap, CycleQuality, TileMetricsMap},
+    atomic_file::{persist, temp_path},
+    error::AppError,
+};
+use crate::argparse::convert::write_text;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use clap::Parser;
+use serde::Serialize;
+
+/// Quality score clusters have to reach to count as Q30
+const Q30: u8 = 30;
+
+#[derive(Parser, Debug)]
+#[command(name = "interop")]
+#[command(about = "Dump per tile and per cycle metrics of the Illumina InterOp files of a run", long_about = None)]
+#[command(next_line_help = true)]
+pub struct InteropArgs {
+    /// run folder holding `InterOp/` with TileMetricsOut.bin, QMetricsOut.bin and ErrorMetricsOut.bin
+    ///
+    /// missing files leave their columns empty
+    #[arg(short, long, value_parser = validate_absolute_dirpath)]
+    run_dir: PathBuf,
+
+    /// write `interop_tiles.tsv` and `interop_cycles.tsv` into this directory
+    #[arg(short, long, value_parser = validate_absolute_dirpath, help_heading = super::OUTPUTS)]
+    output_dir: PathBuf,
+
+    /// also write both tables into `interop.json`
+    #[arg(long)]
+    json: bool,
+}
+
+/// Metrics of one tile, quality and error rate over all of its cycles
+#[derive(Serialize)]
+struct TileRow {
+    /// tile id of the barcode file
+    tile_id: u64,
+    lane: u16,
+    tile: u32,
+    density: Option<f64>,
+    pf_density: Option<f64>,
+    clusters: Option<f64>,
+    pf_clusters: Option<f64>,
+    percent_pf: Option<f64>,
+    mean_quality: Option<f64>,
+    percent_q30: Option<f64>,
+    error_rate: Option<f64>,
+}
+
+/// Metrics of one cycle of a lane over all of its tiles
+#[derive(Serialize)]
+struct CycleRow {
+    lane: u16,
+    cycle: u16,
+    mean_quality: Option<f64>,
+    percent_q30: Option<f64>,
+    error_rate: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct InteropJson<'a> {
+    tiles: &'a [TileRow],
+    cycles: &'a [CycleRow],
+}
+
+/// Quality histograms and error rates summed into groups of cycles
+#[derive(Default)]
+struct Summary {
+    quality: CycleQuality,
+    error_sum: f64,
+    error_cycles: u64,
+}
+
+impl Summary {
+    fn add_quality(&mut self, quality: &CycleQuality) {
+        self.quality.histogram.extend_from_slice(&quality.histogram);
+    }
+
+    fn add_error(&mut self, rate: f64) {
+        self.error_sum += rate;
+        self.error_cycles += 1;
+    }
+
+    fn error_rate(&self) -> Option<f64> {
+        (self.error_cycles > 0).then(|| self.error_sum / self.error_cycles as f64)
+    }
+}
+
+/// Read an InterOp file if the run has it
+fn read_optional<T: Default>(
+    path: &Path,
+    read: fn(&Path) -> Result<T, AppError>,
+    files: &mut Vec<String>,
+) -> Result<T, AppError> {
+    if !path.exists() {
+        return Ok(T::default());
+    }
+    files.push(path.file_name().unwrap_or_default().to_string_lossy().into_owned());
+    read(path)
+}
+
+fn cell(value: Option<f64>) -> String {
+    value.map_or(String::new(), |value| format!("{value:.3}"))
+}
+
+impl InteropArgs {
+    pub fn dump(self) -> Result<InteropReport, AppError> {
+        let dir = self.run_dir.join("InterOp");
+        let mut files = Vec::new();
+        let tiles: TileMetricsMap = read_optional(&dir.join("TileMetricsOut.bin"), read_tile_metrics, &mut files)?;
+        let quality: CycleMap<CycleQuality> = read_optional(&dir.join("QMetricsOut.bin"), read_q_metrics, &mut files)?;
+        let errors: CycleMap<f64> = read_optional(&dir.join("ErrorMetricsOut.bin"), read_error_metrics, &mut files)?;
+        if files.is_empty() {
+            return Err(AppError::IoError(io::Error::new(
+                io::ErrorKind::NotFound, format!("No InterOp metrics found in {}", dir.display())
+            )));
+        }
+
+        let mut per_tile: BTreeMap<(u16, u32), Summary> = BTreeMap::new();
+        let mut per_cycle: BTreeMap<(u16, u16), Summary> = BTreeMap::new();
+        for (&(lane, tile, cycle), histogram) in &quality {
+            per_tile.entry((lane, tile)).or_default().add_quality(histogram);
+            per_cycle.entry((lane, cycle)).or_default().add_quality(histogram);
+        }
+        for (&(lane, tile, cycle), &rate) in &errors {
+            per_tile.entry((lane, tile)).or_default().add_error(rate);
+            per_cycle.entry((lane, cycle)).or_default().add_error(rate);
+        }
+        for &key in tiles.keys() {
+            per_tile.entry(key).or_default();
+        }
+
+        let tile_rows: Vec<TileRow> = per_tile.iter().map(|(&(lane, tile), summary)| {
+            let metrics = tiles.get(&(lane, tile)).copied().unwrap_or_default();
+            TileRow {
+                tile_id: tile_id(lane, tile),
+                lane,
+                tile,
+                density: metrics.density,
+                pf_density: metrics.pf_density,
+                clusters: metrics.clusters,
+                pf_clusters: metrics.pf_clusters,
+                percent_pf: metrics.percent_pf(),
+                mean_quality: summary.quality.mean(),
+                percent_q30: summary.quality.percent_at_least(Q30),
+                error_rate: summary.error_rate(),
+            }
+        }).collect();
+        let cycle_rows: Vec<CycleRow> = per_cycle.iter().map(|(&(lane, cycle), summary)| CycleRow {
+            lane,
+            cycle,
+            mean_quality: summary.quality.mean(),
+            percent_q30: summary.quality.percent_at_least(Q30),
+            error_rate: summary.error_rate(),
+        }).collect();
+
+        write_text(&self.output_dir.join("interop_tiles.tsv"), |writer| {
+            writeln!(
+                writer,
+                "tile_id\tlane\ttile\tdensity\tpf_density\tclusters\tpf_clusters\tpercent_pf\tmean_quality\tpercent_q30\terror_rate",
+            )?;
+            for row in &tile_rows {
+                writeln!(
+                    writer, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    row.tile_id, row.lane, row.tile, cell(row.density), cell(row.pf_density), cell(row.clusters),
+                    cell(row.pf_clusters), cell(row.percent_pf), cell(row.mean_quality), cell(row.percent_q30),
+                    cell(row.error_rate),
+                )?;
+            }
+            Ok(())
+        })?;
+        write_text(&self.output_dir.join("interop_cycles.tsv"), |writer| {
+            writeln!(writer, "lane\tcycle\tmean_quality\tpercent_q30\terror_rate")?;
+            for row in &cycle_rows {
+                writeln!(
+                    writer, "{}\t{}\t{}\t{}\t{}",
+                    row.lane, row.cycle, cell(row.mean_quality), cell(row.percent_q30), cell(row.error_rate),
+                )?;
+            }
+            Ok(())
+        })?;
+        if self.json {
+            let path = self.output_dir.join("interop.json");
+            let mut writer = BufWriter::new(fs::File::create(temp_path(&path))?);
+            serde_json::to_writer_pretty(&mut writer, &InteropJson { tiles: &tile_rows, cycles: &cycle_rows })
+                .map_err(io::Error::from)?;
+            writer.flush()?;
+            drop(writer);
+            persist(&path)?;
+        }
+
+        let mut overall = Summary::default();
+        quality.values().for_each(|histogram| overall.add_quality(histogram));
+        Ok(InteropReport {
+            files,
+            tiles: tile_rows.len() as u64,
+            cycles: cycle_rows.iter().map(|row| row.cycle).max().unwrap_or(0),
+            percent_q30: overall.quality.percent_at_least(Q30),
+            percent_pf: {
+                let (clusters, pf) = tiles.values()
+                    .filter_map(|metrics| Some((metrics.clusters?, metrics.pf_clusters?)))
+                    .fold((0.0, 0.0), |sum, (clusters, pf)| (sum.0 + clusters, sum.1 + pf));
+                (clusters > 0.0).then(|| pf * 100.0 / clusters)
+            },
+        })
+    }
+}
+
+/// InterOp files read and the run level metrics
+pub struct InteropReport {
+    files: Vec<String>,
+    tiles: u64,
+    cycles: u16,
+    percent_q30: Option<f64>,
+    percent_pf: Option<f64>,
+}
+
+impl std::fmt::Display for InteropReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let percent = |value: Option<f64>| value.map_or("-".to_string(), |value| format!("{value:.2}%"));
+        write!(
+            f, "Files={}, Tiles={}, Cycles={}, Q30={}, PF={}",
+            self.files.join(","), self.tiles, self.cycles, percent(self.percent_q30), percent(self.percent_pf),
+        )
+    }
+}
@@ -0,0 +1,146 @@
+use crate::utils::{
+    barcode_file::{build_tabix_index, create_bgzf, BarcodeRecord, BARCODE_FILE_HEADER},
+    barcode_iter::validate_absolute_filepath,
+    coordinate::{load_polygons, load_regions},
+    fastqfile::open_text,
+    atomic_file::{persist_indexed, temp_path},
+    error::AppError,
+};
+use crate::argparse::{
+    convert::write_text,
+    tileimage::load_barcodes,
+};
+use std::collections::{BTreeMap, HashSet};
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use clap::{ArgGroup, Parser};
+
+/// Removal reason of barcodes found in --blacklist
+const BLACKLIST: &str = "blacklist";
+
+#[derive(Parser, Debug)]
+#[command(name = "mask")]
+#[command(about = "Remove barcodes inside masked regions or on a blacklist from a barcode file", long_about = None)]
+#[command(next_line_help = true)]
+#[command(group(ArgGroup::new("masks").required(true).multiple(true).args(["regions", "polygons", "blacklist"])))]
+pub struct MaskArgs {
+    /// The path to the barcode file
+    #[arg(short = 'I', long, value_parser = validate_absolute_filepath)]
+    barcode_file: PathBuf,
+
+    /// remove barcodes inside any of these rectangles
+    ///
+    /// rows of `name\ttile_id\tx_start\tx_end\ty_start\ty_end` with inclusive pixel ranges,
+    /// `*` as tile id for every tile
+    #[arg(long, value_parser = validate_absolute_filepath)]
+    regions: Option<PathBuf>,
+
+    /// remove barcodes inside any of these polygons
+    ///
+    /// rows of `name\ttile_id\tx1,y1 x2,y2 x3,y3 ...` with pixel vertices, `*` as tile id for every tile
+    #[arg(long, value_parser = validate_absolute_filepath)]
+    polygons: Option<PathBuf>,
+
+    /// remove these barcodes wherever they are, one barcode in the first column per line
+    #[arg(long, value_parser = validate_absolute_filepath)]
+    blacklist: Option<PathBuf>,
+
+    /// output barcode file, bgzf compressed and tabix indexed like the input (e.g. masked.txt.gz)
+    #[arg(short, long, help_heading = super::OUTPUTS)]
+    output: PathBuf,
+
+    /// also write the removed rows with the name of the mask removing them as last column
+    #[arg(long, help_heading = super::OUTPUTS)]
+    removed: Option<PathBuf>,
+}
+
+impl MaskArgs {
+    pub fn mask(self) -> Result<MaskReport, AppError> {
+        let regions = self.regions.as_deref().map(load_regions).transpose()?.unwrap_or_default();
+        let polygons = self.polygons.as_deref().map(load_polygons).transpose()?.unwrap_or_default();
+        let blacklist = self.blacklist.as_deref().map(load_barcodes).transpose()?.unwrap_or_default();
+        let invalid = |value: &str| AppError::IoError(io::Error::new(
+            io::ErrorKind::InvalidData, format!("Invalid barcode file value: {value}")
+        ));
+
+        let mut report = MaskReport::default();
+        let mut writer = create_bgzf(&temp_path(&self.output))?;
+        writeln!(writer, "{}", BARCODE_FILE_HEADER)?;
+        let mut removed: Vec<(String, &str)> = Vec::new();
+        for line in open_text(&self.barcode_file)?.lines() {
+            let line = line?;
+            if line.is_empty() || line.starts_with('#') || line.starts_with("tile_id") {
+                continue;
+            }
+            let record = BarcodeRecord::parse(&line)?;
+            let tile_id: i32 = record.tile_id.parse().map_err(|_| invalid(record.tile_id))?;
+            let x: f64 = record.x_pos.parse().map_err(|_| invalid(record.x_pos))?;
+            let y: f64 = record.y_pos.parse().map_err(|_| invalid(record.y_pos))?;
+            report.rows += 1;
+            let reason = if blacklist.contains(record.barcode) {
+                Some(BLACKLIST)
+            } else {
+                regions.iter()
+                    .find(|region| region.contains((tile_id, x.floor() as i32, y.floor() as i32)))
+                    .map(|region| region.name.as_str())
+                    .or_else(|| polygons.iter().find(|polygon| polygon.contains((tile_id, x, y))).map(|polygon| polygon.name.as_str()))
+            };
+            match reason {
+                Some(reason) => {
+                    *report.removed.entry(reason.to_string()).or_default() += 1;
+                    if self.removed.is_some() {
+                        removed.push((line, reason));
+                    }
+                }
+                None => {
+                    writeln!(writer, "{}", line)?;
+                    report.kept += 1;
+                }
+            }
+        }
+        writer.flush()?;
+        drop(writer);
+        build_tabix_index(&temp_path(&self.output))?;
+        persist_indexed(&self.output)?;
+
+        if let Some(path) = &self.removed {
+            write_text(path, |writer| {
+                writeln!(writer, "{}\tmask", BARCODE_FILE_HEADER)?;
+                for (line, reason) in &removed {
+                    writeln!(writer, "{}\t{}", line, reason)?;
+                }
+                Ok(())
+            })?;
+        }
+        // masks removing nothing are still listed
+        let names: HashSet<&str> = regions.iter().map(|region| region.name.as_str())
+            .chain(polygons.iter().map(|polygon| polygon.name.as_str()))
+            .chain(self.blacklist.as_ref().map(|_| BLACKLIST))
+            .collect();
+        for name in names {
+            report.removed.entry(name.to_string()).or_default();
+        }
+        Ok(report)
+    }
+}
+
+/// Rows kept and removed by every mask
+#[derive(Default)]
+pub struct MaskReport {
+    rows: u64,
+    kept: u64,
+    removed: BTreeMap<String, u64>,
+}
+
+impl std::fmt::Display for MaskReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f, "Rows={}, Kept={}, Removed={}\nMask\tRemoved",
+            self.rows, self.kept, self.removed.values().sum::<u64>(),
+        )?;
+        for (name, removed) in &self.removed {
+            write!(f, "\n{}\t{}", name, removed)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,363 @@
+use crate::utils::{
+    threads,
+    hts,
+    barcode_iter::validate_absolute_filepath,
+    spill::{parse_memory_size, scratch_dir},
+    atomic_file::{persist, temp_path},
+    error::AppError,
+};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet, hash_map::Entry};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use clap::{Parser, ValueEnum};
+use rust_htslib::bam::{self, Read, record::Aux, header::HeaderRecord};
+
+/// Order of the merged records
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum MergeSort {
+    /// inputs one after another, records keep their order
+    None,
+    /// by read name, read 1 before read 2, like `samtools sort -n` with lexicographical names
+    Queryname,
+    /// by reference and position, unmapped reads last
+    Coordinate,
+}
+
+#[derive(Parser, Debug)]
+#[command(name = "mergebam")]
+#[command(about = "Merge tagged BAM files with read group and program header merging", long_about = None)]
+#[command(next_line_help = true)]
+pub struct MergeBamArgs {
+    /// input BAM, repeat it for every shard or lane (e.g. "-i L001.bam -i L002.bam")
+    #[arg(short, long, required = true, value_parser = validate_absolute_filepath)]
+    input: Vec<PathBuf>,
+
+    /// output BAM
+    #[arg(short, long, help_heading = super::OUTPUTS)]
+    output: PathBuf,
+
+    /// order of the merged records
+    #[arg(long, value_enum, default_value_t = MergeSort::None)]
+    sort: MergeSort,
+
+    /// memory of records sorted at once before they are spilled next to the output or into `--tmpdir` (e.g. 2G)
+    #[arg(long, default_value = "1G", value_parser = parse_memory_size, value_name = "SIZE")]
+    max_memory: u64,
+
+    /// BAM compression threads, the global `--threads` or 4 by default
+    #[arg(short = '@', long)]
+    threads: Option<usize>,
+}
+
+/// Value of `tag` in a tab separated header line
+fn header_tag<'a>(line: &'a str, tag: &str) -> Option<&'a str> {
+    line.split('\t').skip(1).find_map(|field| field.strip_prefix(tag)?.strip_prefix(':'))
+}
+
+/// Replace the value of `tag` in a header line, appending it when missing
+fn set_header_tag(line: &str, tag: &str, value: &str) -> String {
+    let mut fields: Vec<String> = line.split('\t').map(str::to_string).collect();
+    let field = format!("{tag}:{value}");
+    match fields.iter().position(|field| field.starts_with(&format!("{tag}:"))) {
+        Some(index) => fields[index] = field,
+        None => fields.push(field),
+    }
+    fields.join("\t")
+}
+
+/// Header lines of all inputs merged, with the ids renamed per input where they clash
+struct MergedHeader {
+    lines: Vec<String>,
+    /// renamed `RG` then `PG` ids of every input
+    renames: Vec<[HashMap<String, String>; 2]>,
+}
+
+impl MergedHeader {
+    fn merge(headers: &[String], sort: MergeSort) -> Result<Self, AppError> {
+        let mut hd = None;
+        let mut sq: Option<Vec<&str>> = None;
+        let mut kept: [Vec<String>; 2] = [Vec::new(), Vec::new()];
+        let mut ids: [HashMap<String, String>; 2] = [HashMap::new(), HashMap::new()];
+        let mut comments: Vec<&str> = Vec::new();
+        let mut others: Vec<&str> = Vec::new();
+        let mut renames = Vec::with_capacity(headers.len());
+        for (index, header) in headers.iter().enumerate() {
+            let lines: Vec<&str> = header.lines().filter(|line| !line.is_empty()).collect();
+            let input_sq: Vec<&str> = lines.iter().copied().filter(|line| line.starts_with("@SQ")).collect();
+            match &sq {
+                None => sq = Some(input_sq),
+                Some(sq) if *sq == input_sq => {}
+                Some(_) => return Err(AppError::IoError(io::Error::new(
+                    io::ErrorKind::InvalidData, format!("Input {} has different @SQ lines than the first input", index + 1)
+                ))),
+            }
+            let mut input_renames: [HashMap<String, String>; 2] = [HashMap::new(), HashMap::new()];
+            for (kind, prefix) in ["@RG", "@PG"].into_iter().enumerate() {
+                for line in lines.iter().filter(|line| line.starts_with(prefix)) {
+                    let Some(id) = header_tag(line, "ID") else {
+                        continue;
+                    };
+                    match ids[kind].get(id) {
+                        None => {}
+                        Some(existing) if existing == line => continue,
+                        Some(_) => {
+                            let renamed = (1..)
+                                .map(|n| format!("{id}-{n}"))
+                                .find(|renamed| !ids[kind].contains_key(renamed))
+                                .expect("unbounded suffixes");
+                            input_renames[kind].insert(id.to_string(), renamed);
+                        }
+                    }
+                }
+            }
+            for (kind, prefix) in ["@RG", "@PG"].into_iter().enumerate() {
+                for line in lines.iter().filter(|line| line.starts_with(prefix)) {
+                    let Some(id) = header_tag(line, "ID") else {
+                        continue;
+                    };
+                    let mut line = line.to_string();
+                    if let Some(renamed) = input_renames[kind].get(id) {
+                        line = set_header_tag(&line, "ID", renamed);
+                    }
+                    // keep program chains of this input pointing at their renamed ids
+                    if let Some(renamed) = header_tag(&line, "PP").and_then(|pp| input_renames[1].get(pp)) {
+                        line = set_header_tag(&line, "PP", renamed);
+                    }
+                    let id = header_tag(&line, "ID").unwrap_or_default().to_string();
+                    if let Entry::Vacant(entry) = ids[kind].entry(id) {
+                        entry.insert(line.clone());
+                        kept[kind].push(line);
+                    }
+                }
+            }
+            for &line in &lines {
+                if line.starts_with("@HD") {
+                    hd.get_or_insert(line);
+                } else if line.starts_with("@CO") {
+                    if !comments.contains(&line) {
+                        comments.push(line);
+                    }
+                } else if index == 0 && !["@SQ", "@RG", "@PG"].iter().any(|prefix| line.starts_with(prefix)) {
+                    others.push(line);
+                }
+            }
+            renames.push(input_renames);
+        }
+
+        let hd = hd.unwrap_or("@HD\tVN:1.6");
+        let hd = match sort {
+            MergeSort::None => set_header_tag(hd, "SO", "unsorted"),
+            MergeSort::Queryname => set_header_tag(
+                &set_header_tag(hd, "SO", "queryname"), "SS", "queryname:lexicographical"
+            ),
+            MergeSort::Coordinate => set_header_tag(hd, "SO", "coordinate"),
+        };
+        let [rg, pg] = kept;
+        let lines = std::iter::once(hd)
+            .chain(sq.unwrap_or_default().into_iter().map(str::to_string))
+            .chain(rg)
+            .chain(pg)
+            .chain(others.into_iter().map(str::to_string))
+            .chain(comments.into_iter().map(str::to_string))
+            .collect();
+        Ok(Self { lines, renames })
+    }
+
+    fn to_header(&self) -> bam::Header {
+        let text = self.lines.join("\n") + "\n";
+        let mut header = bam::Header::from_template(&bam::HeaderView::from_bytes(text.as_bytes()));
+        header.push_record(
+            HeaderRecord::new(b"PG")
+                .push_tag(b"ID", "opentools-mergebam")
+                .push_tag(b"PN", "opentools")
+                .push_tag(b"VN", env!("CARGO_PKG_VERSION")),
+        );
+        header
+    }
+}
+
+/// Comparable position of a record in the merged order
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum SortKey {
+    /// read name, then read 1 before read 2
+    Name(Vec<u8>, u16),
+    /// reference (unmapped last), position, strand
+    Coordinate(u32, i64, bool),
+}
+
+impl MergeSort {
+    #[inline]
+    fn key(&self, record: &bam::Record) -> SortKey {
+        match self {
+            MergeSort::Coordinate => SortKey::Coordinate(record.tid() as u32, record.pos(), record.is_reverse()),
+            _ => SortKey::Name(record.qname().to_vec(), record.flags() & 0xC0),
+        }
+    }
+}
+
+/// Rename `RG` and `PG` tags of a record after its header ids were renamed
+fn rename_tags(record: &mut bam::Record, renames: &[HashMap<String, String>; 2]) -> Result<bool, AppError> {
+    let mut renamed = false;
+    for (tag, renames) in [b"RG", b"PG"].into_iter().zip(renames) {
+        if renames.is_empty() {
+            continue;
+        }
+        let new = match record.aux(tag) {
+            Ok(Aux::String(id)) => renames.get(id).cloned(),
+            _ => None,
+        };
+        if let Some(new) = new {
+            record.remove_aux(tag)?;
+            record.push_aux(tag, Aux::String(&new))?;
+            renamed = true;
+        }
+    }
+    Ok(renamed)
+}
+
+impl MergeBamArgs {
+    pub fn merge(self) -> Result<MergeBamReport, AppError> {
+        let mut readers = self.input.iter()
+            .map(hts::open_bam)
+            .collect::<Result<Vec<_>, _>>()?;
+        let headers: Vec<String> = readers.iter()
+            .map(|reader| String::from_utf8_lossy(reader.header().as_bytes()).into_owned())
+            .collect();
+        let merged = MergedHeader::merge(&headers, self.sort)?;
+        let header = merged.to_header();
+
+        let mut report = MergeBamReport {
+            inputs: readers.len() as u64,
+            renamed_ids: merged.renames.iter().map(|[rg, pg]| (rg.len() + pg.len()) as u64).sum(),
+            ..Default::default()
+        };
+        let temp = temp_path(&self.output);
+        let threads = threads::resolve(self.threads, 4);
+        let mut writer = bam::Writer::from_path(&temp, &header, bam::Format::Bam)?;
+        writer.set_threads(threads)?;
+        let spill_dir = self.spill_dir();
+        let mut chunks = Vec::new();
+        let mut buffer: Vec<(SortKey, bam::Record)> = Vec::new();
+        let mut buffered = 0;
+        for (reader, renames) in readers.iter_mut().zip(&merged.renames) {
+            reader.set_threads(threads)?;
+            for record in reader.records() {
+                let mut record = record?;
+                report.records += 1;
+                if rename_tags(&mut record, renames)? {
+                    report.retagged += 1;
+                }
+                if self.sort == MergeSort::None {
+                    writer.write(&record)?;
+                    continue;
+                }
+                buffered += record.inner().l_data as u64 + size_of::<bam::Record>() as u64;
+                buffer.push((self.sort.key(&record), record));
+                if buffered >= self.max_memory {
+                    fs::create_dir_all(&spill_dir)?;
+                    chunks.push(self.write_chunk(&spill_dir, chunks.len(), &header, &mut buffer)?);
+                    buffered = 0;
+                }
+            }
+        }
+        if self.sort != MergeSort::None {
+            buffer.sort_by(|a, b| a.0.cmp(&b.0));
+            if chunks.is_empty() {
+                for (_, record) in &buffer {
+                    writer.write(record)?;
+                }
+            } else {
+                if !buffer.is_empty() {
+                    chunks.push(self.write_chunk(&spill_dir, chunks.len(), &header, &mut buffer)?);
+                }
+                report.chunks = chunks.len() as u64;
+                self.merge_chunks(&chunks, &mut writer)?;
+                fs::remove_dir_all(&spill_dir)?;
+            }
+        }
+        drop(writer);
+        persist(&self.output)?;
+        report.read_groups = merged.lines.iter()
+            .filter(|line| line.starts_with("@RG"))
+            .filter_map(|line| header_tag(line, "ID"))
+            .collect::<HashSet<_>>()
+            .len() as u64;
+        Ok(report)
+    }
+
+    #[inline]
+    fn spill_dir(&self) -> PathBuf {
+        let mut name = self.output.as_os_str().to_owned();
+        name.push(".sort_spill");
+        scratch_dir(PathBuf::from(name), "mergebam")
+    }
+
+    /// Sort the buffered records and write them into an uncompressed chunk
+    fn write_chunk(
+        &self,
+        dir: &Path,
+        index: usize,
+        header: &bam::Header,
+        buffer: &mut Vec<(SortKey, bam::Record)>,
+    ) -> Result<PathBuf, AppError> {
+        buffer.sort_by(|a, b| a.0.cmp(&b.0));
+        let path = dir.join(format!("chunk_{index:04}.bam"));
+        let mut writer = bam::Writer::from_path(&path, header, bam::Format::Bam)?;
+        writer.set_compression_level(bam::CompressionLevel::Uncompressed)?;
+        for (_, record) in buffer.drain(..) {
+            writer.write(&record)?;
+        }
+        Ok(path)
+    }
+
+    /// K-way merge of sorted chunks, ties resolved by chunk order so the sort stays stable
+    fn merge_chunks(&self, chunks: &[PathBuf], writer: &mut bam::Writer) -> Result<(), AppError> {
+        let mut readers = chunks.iter()
+            .map(hts::open_bam)
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut heads: Vec<bam::Record> = Vec::with_capacity(readers.len());
+        let mut heap = BinaryHeap::new();
+        for (index, reader) in readers.iter_mut().enumerate() {
+            let mut record = bam::Record::new();
+            if let Some(result) = reader.read(&mut record) {
+                result?;
+                heap.push(Reverse((self.sort.key(&record), index)));
+            }
+            heads.push(record);
+        }
+        while let Some(Reverse((_, index))) = heap.pop() {
+            writer.write(&heads[index])?;
+            if let Some(result) = readers[index].read(&mut heads[index]) {
+                result?;
+                heap.push(Reverse((self.sort.key(&heads[index]), index)));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Records merged and header ids renamed
+#[derive(Default)]
+pub struct MergeBamReport {
+    inputs: u64,
+    records: u64,
+    read_groups: u64,
+    /// `RG`/`PG` header ids renamed because another input used them for different content
+    renamed_ids: u64,
+    /// records whose `RG`/`PG` tag followed a renamed id
+    retagged: u64,
+    /// sorted chunks spilled to disk
+    chunks: u64,
+}
+
+impl std::fmt::Display for MergeBamReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Inputs={}, Records={}, Read groups={}, Renamed ids={}, Retagged records={}, Spilled chunks={}",
+            self.inputs, self.records, self.read_groups, self.renamed_ids, self.retagged, self.chunks,
+        )
+    }
+}
@@ -0,0 +1,263 @@
+use crate::utils::{
+    threads,
+    hts,
+    barcode_iter::{validate_absolute_dirpath, validate_absolute_filepath},
+    fastqfile,
+    atomic_file::{persist, temp_path},
+    error::AppError,
+};
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use clap::Parser;
+use rust_htslib::{bam::{self, Read as _}, bgzf, tpool::ThreadPool};
+use seq_io::{fasta, fastq::{self, Record as _}};
+
+#[derive(Parser, Debug)]
+#[command(name = "phix")]
+#[command(about = "Detect PhiX spike-in reads by k-mer matches against the PhiX genome and optionally remove them", long_about = None)]
+#[command(next_line_help = true)]
+pub struct PhixArgs {
+    /// FASTQ (optionally gzipped) or unaligned BAM file
+    ///
+    /// repeat it for the mate files of paired FASTQ (e.g. R1 and R2), which are read in lockstep,
+    /// BAM is told by the `.bam` extension and holds mates as consecutive records of the same name
+    #[arg(short, long, required = true, value_parser = validate_absolute_filepath)]
+    input: Vec<PathBuf>,
+
+    /// PhiX genome FASTA (e.g. PhiX/Illumina/RTA/Sequence/WholeGenomeFasta/genome.fa of iGenomes)
+    #[arg(short, long, value_parser = validate_absolute_filepath)]
+    reference: PathBuf,
+
+    /// k-mer length, both strands of the genome are indexed
+    #[arg(short, long, default_value_t = 25, value_parser = clap::value_parser!(u8).range(11..=32))]
+    kmer: u8,
+
+    /// genome k-mers a read needs to be taken as PhiX, over all mates of a read
+    #[arg(long, default_value_t = 3)]
+    min_kmers: usize,
+
+    /// write the inputs without the PhiX reads as `{input file name}` into this directory, FASTQ bgzf compressed
+    #[arg(short, long, value_parser = validate_absolute_dirpath, help_heading = super::OUTPUTS)]
+    output_dir: Option<PathBuf>,
+
+    /// compression and decompression threads, the global `--threads` or 4 by default
+    #[arg(short = '@', long)]
+    threads: Option<usize>,
+}
+
+#[inline]
+fn is_bam(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "bam")
+}
+
+/// 2-bit code of a base, `None` for anything other than ACGT
+#[inline]
+fn base_bits(base: u8) -> Option<u64> {
+    match base.to_ascii_uppercase() {
+        b'A' => Some(0),
+        b'C' => Some(1),
+        b'G' => Some(2),
+        b'T' => Some(3),
+        _ => None,
+    }
+}
+
+/// Call `f` with every canonical k-mer of the sequence, windows holding other bases than ACGT are skipped
+fn for_each_kmer(seq: &[u8], k: usize, mut f: impl FnMut(u64)) {
+    let mask = if k == 32 { u64::MAX } else { (1 << (2 * k)) - 1 };
+    let shift = 2 * (k - 1);
+    let (mut forward, mut reverse, mut len) = (0u64, 0u64, 0);
+    for &base in seq {
+        let Some(bits) = base_bits(base) else {
+            len = 0;
+            continue;
+        };
+        forward = (forward << 2 | bits) & mask;
+        reverse = reverse >> 2 | (3 - bits) << shift;
+        len += 1;
+        if len >= k {
+            f(forward.min(reverse));
+        }
+    }
+}
+
+/// Canonical k-mers of a genome
+struct KmerIndex {
+    k: usize,
+    kmers: HashSet<u64>,
+}
+
+impl KmerIndex {
+    fn from_fasta(path: &Path, k: usize) -> Result<Self, AppError> {
+        let mut index = Self { k, kmers: HashSet::new() };
+        let mut reader = fasta::Reader::new(fastqfile::open_text(path)?);
+        while let Some(record) = reader.next() {
+            let record = record.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            let seq = record.owned_seq();
+            // the genome is circular, k-mers over its end are read by wrapping around
+            let wrapped: Vec<u8> = seq.iter().chain(seq.iter().take(k - 1)).copied().collect();
+            for_each_kmer(&wrapped, k, |kmer| { index.kmers.insert(kmer); });
+        }
+        if index.kmers.is_empty() {
+            return Err(AppError::IoError(io::Error::new(
+                io::ErrorKind::InvalidData, format!("{} holds no sequence of {} bases", path.display(), k)
+            )));
+        }
+        Ok(index)
+    }
+
+    /// Genome k-mers in the sequence
+    fn hits(&self, seq: &[u8]) -> usize {
+        let mut hits = 0;
+        for_each_kmer(seq, self.k, |kmer| hits += self.kmers.contains(&kmer) as usize);
+        hits
+    }
+}
+
+impl PhixArgs {
+    /// Output path of an input, FASTQ always gets the `.gz` extension
+    fn output_path(&self, input: &Path) -> Option<PathBuf> {
+        let mut name: OsString = input.file_name().unwrap_or_default().to_os_string();
+        if !is_bam(input) && input.extension().is_none_or(|ext| ext != "gz") {
+            name.push(".gz");
+        }
+        self.output_dir.as_ref().map(|dir| dir.join(name))
+    }
+
+    fn is_phix(&self, index: &KmerIndex, seqs: impl Iterator<Item = impl AsRef<[u8]>>) -> bool {
+        let mut hits = 0;
+        for seq in seqs {
+            hits += index.hits(seq.as_ref());
+            if hits >= self.min_kmers {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn filter_fastq(&self, index: &KmerIndex, pool: &ThreadPool) -> Result<PhixReport, AppError> {
+        let mut readers: Vec<fastqfile::FastqReader> = self.input.iter().map(fastqfile::open).collect::<Result<_, _>>()?;
+        let mut writers: Vec<(PathBuf, bgzf::Writer)> = Vec::new();
+        for input in &self.input {
+            if let Some(path) = self.output_path(input) {
+                let mut writer = bgzf::Writer::from_path(temp_path(&path))?;
+                writer.set_thread_pool(pool)?;
+                writers.push((path, writer));
+            }
+        }
+        let mut report = PhixReport::default();
+        loop {
+            let records: Vec<Option<fastq::RefRecord>> = readers.iter_mut()
+                .map(|reader| reader.next().transpose())
+                .collect::<Result<_, _>>()?;
+            if records.iter().all(Option::is_none) {
+                break;
+            }
+            let Some(records) = records.into_iter().collect::<Option<Vec<_>>>() else {
+                return Err(AppError::IoError(io::Error::new(
+                    io::ErrorKind::InvalidData, "mate files hold different numbers of reads"
+                )));
+            };
+            report.reads += 1;
+            if self.is_phix(index, records.iter().map(|record| record.seq())) {
+                report.phix += 1;
+                continue;
+            }
+            for (record, (_, writer)) in records.iter().zip(writers.iter_mut()) {
+                record.write_unchanged(&mut *writer)?;
+            }
+        }
+        for (path, mut writer) in writers {
+            writer.flush()?;
+            drop(writer);
+            persist(&path)?;
+        }
+        Ok(report)
+    }
+
+    fn filter_bam(&self, index: &KmerIndex, pool: &ThreadPool) -> Result<PhixReport, AppError> {
+        let input = &self.input[0];
+        let mut reader = hts::open_bam(input)?;
+        reader.set_thread_pool(pool)?;
+        let mut writer = match self.output_path(input) {
+            Some(path) => {
+                let header = bam::Header::from_template(reader.header());
+                let mut writer = bam::Writer::from_path(temp_path(&path), &header, bam::Format::Bam)?;
+                writer.set_thread_pool(pool)?;
+                Some((path, writer))
+            }
+            None => None,
+        };
+        let mut report = PhixReport::default();
+        // records of one read name, the mates of a pair
+        let mut mates: Vec<bam::Record> = Vec::new();
+        let mut flush = |mates: &mut Vec<bam::Record>| -> Result<(), AppError> {
+            if mates.is_empty() {
+                return Ok(());
+            }
+            report.reads += 1;
+            if self.is_phix(index, mates.iter().map(|record| record.seq().as_bytes())) {
+                report.phix += 1;
+            } else if let Some((_, writer)) = writer.as_mut() {
+                for record in mates.iter() {
+                    writer.write(record)?;
+                }
+            }
+            mates.clear();
+            Ok(())
+        };
+        let mut record = bam::Record::new();
+        while let Some(result) = reader.read(&mut record) {
+            result?;
+            if mates.first().is_some_and(|mate| mate.qname() != record.qname()) {
+                flush(&mut mates)?;
+            }
+            mates.push(record.clone());
+        }
+        flush(&mut mates)?;
+        if let Some((path, writer)) = writer {
+            drop(writer);
+            persist(&path)?;
+        }
+        Ok(report)
+    }
+
+    pub fn filter(self) -> Result<PhixReport, AppError> {
+        if self.input.iter().any(|input| is_bam(input)) && self.input.len() > 1 {
+            return Err(AppError::IoError(io::Error::new(
+                io::ErrorKind::InvalidInput, "BAM input is read as a single file holding both mates"
+            )));
+        }
+        let index = KmerIndex::from_fasta(&self.reference, self.kmer as usize)?;
+        let pool = ThreadPool::new(threads::resolve(self.threads, 4) as u32)?;
+        let mut report = if is_bam(&self.input[0]) {
+            self.filter_bam(&index, &pool)?
+        } else {
+            self.filter_fastq(&index, &pool)?
+        };
+        report.removed = self.output_dir.is_some();
+        Ok(report)
+    }
+}
+
+/// Reads taken as PhiX
+#[derive(Default)]
+pub struct PhixReport {
+    reads: u64,
+    phix: u64,
+    /// whether the PhiX reads were left out of the written files
+    removed: bool,
+}
+
+impl std::fmt::Display for PhixReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let percent = if self.reads == 0 { 0.0 } else { self.phix as f64 * 100.0 / self.reads as f64 };
+        write!(f, "Reads={}, PhiX={}, PhiX Percent={:.3}%", self.reads, self.phix, percent)?;
+        if self.removed {
+            write!(f, ", Kept={}", self.reads - self.phix)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,302 @@
+use crate::utils::{
+    barcode_iter::validate_absolute_filepath,
+    atomic_file::{persist, temp_path},
+    error::AppError,
+    progress::{self, Event},
+};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Instant;
+use clap::{Parser, ValueEnum};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use crate::argparse::config::toml_options;
+
+#[derive(Parser, Debug)]
+#[command(name = "pipeline")]
+#[command(about = "Run touchbarcode, tilesmatch, dedupbarcode and qc of one chip from a TOML config", long_about = None)]
+#[command(next_line_help = true)]
+pub struct PipelineArgs {
+    /// TOML config with the shared settings and one table of options per step
+    ///
+    /// top level: `output_dir`, optional `name`, `threads`, `tmpdir` and `barcode_file` (skips touchbarcode);
+    /// tables `[touchbarcode]`, `[tilesmatch]`, `[dedupbarcode]` and `[qc]` hold the options of each step
+    /// with the long flag names as keys, e.g. `bcl_dir = "/data/bcl"` or `threshold = 0.2`
+    #[arg(short, long, value_parser = validate_absolute_filepath)]
+    config: PathBuf,
+
+    /// rerun this step and the following ones even when checkpointed
+    #[arg(long, value_enum)]
+    from: Option<Step>,
+
+    /// rerun every step
+    #[arg(long, conflicts_with = "from")]
+    force: bool,
+
+    /// print the commands of the steps without running them
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(ValueEnum, Serialize, Clone, Copy, Debug, PartialEq, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+pub enum Step {
+    Touchbarcode,
+    Tilesmatch,
+    Dedupbarcode,
+    Qc,
+}
+
+impl Step {
+    fn name(&self) -> &'static str {
+        match self {
+            Step::Touchbarcode => "touchbarcode",
+            Step::Tilesmatch => "tilesmatch",
+            Step::Dedupbarcode => "dedupbarcode",
+            Step::Qc => "qc",
+        }
+    }
+
+    /// Options set by the pipeline to chain the steps, not allowed in the config
+    fn reserved(&self) -> &'static [&'static str] {
+        match self {
+            Step::Touchbarcode => &["output"],
+            Step::Tilesmatch => &["barcode_file", "passed_out", "json", "quiet"],
+            Step::Dedupbarcode => &["barcode_file", "tile_list", "output_dir", "metrics_dir"],
+            Step::Qc => &["name", "tilesmatch_json", "dedup_summary", "output_dir"],
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct PipelineConfig {
+    output_dir: PathBuf,
+    name: Option<String>,
+    /// global `--threads` of every step
+    threads: Option<usize>,
+    /// `TMPDIR` of the steps
+    tmpdir: Option<PathBuf>,
+    /// existing barcode file of the chip, touchbarcode is skipped when set
+    barcode_file: Option<PathBuf>,
+    #[serde(default)]
+    touchbarcode: toml::Table,
+    #[serde(default)]
+    tilesmatch: toml::Table,
+    #[serde(default)]
+    dedupbarcode: toml::Table,
+    #[serde(default)]
+    qc: toml::Table,
+}
+
+impl PipelineConfig {
+    fn load(path: &Path) -> Result<Self, AppError> {
+        toml::from_str(&fs::read_to_string(path)?).map_err(|err| AppError::IoError(io::Error::new(
+            io::ErrorKind::InvalidData, format!("{}: {}", path.display(), err)
+        )))
+    }
+
+    fn table(&self, step: Step) -> &toml::Table {
+        match step {
+            Step::Touchbarcode => &self.touchbarcode,
+            Step::Tilesmatch => &self.tilesmatch,
+            Step::Dedupbarcode => &self.dedupbarcode,
+            Step::Qc => &self.qc,
+        }
+    }
+
+    /// Command line options of a config table, `key = value` as `--key value`
+    fn options(&self, step: Step) -> Result<Vec<String>, AppError> {
+        let mut args = Vec::new();
+        for (key, value) in self.table(step) {
+            if step.reserved().contains(&key.as_str()) {
+                return Err(AppError::IoError(io::Error::new(
+                    io::ErrorKind::InvalidInput, format!("[{}] {}: set by the pipeline", step.name(), key)
+                )));
+            }
+            args.extend(toml_options(step.name(), key, value)?);
+        }
+        Ok(args)
+    }
+}
+
+/// Outcome of one step
+#[derive(Serialize)]
+struct StepReport {
+    step: Step,
+    /// `ran`, `checkpointed` or `planned` for a dry run
+    status: &'static str,
+    seconds: f64,
+    command: Vec<String>,
+    log: Option<PathBuf>,
+}
+
+struct Pipeline {
+    args: PipelineArgs,
+    config: PipelineConfig,
+    executable: PathBuf,
+    reports: Vec<StepReport>,
+}
+
+impl Pipeline {
+    fn dir(&self, name: &str) -> Result<PathBuf, AppError> {
+        let dir = self.config.output_dir.join(name);
+        if !self.args.dry_run {
+            fs::create_dir_all(&dir)?;
+        }
+        Ok(dir)
+    }
+
+    /// Run a step unless its checkpoint records the same command
+    fn run(&mut self, step: Step, mut command: Vec<String>) -> Result<(), AppError> {
+        command.extend(self.config.options(step)?);
+        let checkpoint = self.dir(".checkpoints")?.join(format!("{}.done", step.name()));
+        let log = self.dir("logs")?.join(format!("{}.log", step.name()));
+        let rerun = self.args.force || self.args.from.is_some_and(|from| from <= step);
+        let recorded = fs::read_to_string(&checkpoint).ok();
+        let threads: Vec<String> = self.config.threads.map_or_else(Vec::new, |threads| vec!["--threads".into(), threads.to_string()]);
+        let status = if self.args.dry_run {
+            let global = threads.iter().map(|arg| format!("{arg} ")).collect::<String>();
+            println!("{} {global}{} {}", self.executable.display(), step.name(), command.join(" "));
+            "planned"
+        } else if !rerun && recorded.as_deref() == Some(command.join("\n").as_str()) {
+            info!("Step {} checkpointed, skipped", step.name());
+            "checkpointed"
+        } else {
+            // a changed step invalidates the checkpoints of the following ones
+            self.args.from = Some(self.args.from.map_or(step, |from| if from < step { from } else { step }));
+            let _ = fs::remove_file(&checkpoint);
+            info!("Step {} running, log in {}", step.name(), log.display());
+            let start = Instant::now();
+            let log_file = fs::File::create(&log)?;
+            let mut process = Command::new(&self.executable);
+            // checkpoints decide what reruns, a rerun step replaces its earlier outputs
+            process.arg("--force")
+                .args(&threads)
+                .arg(step.name())
+                .args(&command)
+                .stdout(Stdio::from(log_file.try_clone()?))
+                .stderr(Stdio::from(log_file));
+            if let Some(tmpdir) = &self.config.tmpdir {
+                process.env("TMPDIR", tmpdir);
+            }
+            // the stderr of a step goes to its log, only a progress file is shared with it
+            if let Some(file) = progress::file() {
+                process.env("OPENTOOLS_PROGRESS", "jsonl").env("OPENTOOLS_PROGRESS_FILE", file);
+            }
+            if !process.status()?.success() {
+                return Err(AppError::CommandError(format!("step {} failed, see {}", step.name(), log.display())));
+            }
+            fs::write(temp_path(&checkpoint), command.join("\n"))?;
+            persist(&checkpoint)?;
+            self.step_done(step);
+            self.reports.push(StepReport {
+                step,
+                status: "ran",
+                seconds: start.elapsed().as_secs_f64(),
+                command,
+                log: Some(log),
+            });
+            return Ok(());
+        };
+        self.reports.push(StepReport { step, status, seconds: 0.0, command, log: None });
+        self.step_done(step);
+        Ok(())
+    }
+
+    /// Progress event of a finished or skipped step
+    fn step_done(&self, step: Step) {
+        if !self.args.dry_run {
+            let mut event = Event::new(step.name());
+            event.percent = Some(100.0);
+            event.emit();
+        }
+    }
+}
+
+impl PipelineArgs {
+    pub fn run(self) -> Result<PipelineReport, AppError> {
+        let config = PipelineConfig::load(&self.config)?;
+        let mut pipeline = Pipeline { args: self, config, executable: std::env::current_exe()?, reports: Vec::new() };
+        let output_dir = pipeline.config.output_dir.clone();
+        let path_arg = |path: &Path| path.display().to_string();
+
+        let barcode_file = match pipeline.config.barcode_file.clone() {
+            Some(path) => path,
+            None => {
+                let chip_dir = pipeline.dir("chip")?;
+                pipeline.run(Step::Touchbarcode, vec!["--output".into(), path_arg(&chip_dir)])?;
+                chip_dir.join(super::touchbarcode::BARCODE_FILE)
+            }
+        };
+
+        let tilesmatch_dir = pipeline.dir("tilesmatch")?;
+        let passed_tiles = tilesmatch_dir.join("passed_tiles.txt");
+        let tilesmatch_json = tilesmatch_dir.join("tilesmatch.json");
+        pipeline.run(Step::Tilesmatch, vec![
+            "--barcode-file".into(), path_arg(&barcode_file),
+            "--passed-out".into(), path_arg(&passed_tiles),
+            "--json".into(), path_arg(&tilesmatch_json),
+        ])?;
+
+        let dedup_dir = pipeline.dir("dedup")?;
+        let metrics_dir = pipeline.dir("dedup/metrics")?;
+        let mut command = vec![
+            "--barcode-file".into(), path_arg(&barcode_file),
+            "--output-dir".into(), path_arg(&dedup_dir),
+            "--metrics-dir".into(), path_arg(&metrics_dir),
+        ];
+        if !pipeline.args.dry_run {
+            let tiles: Vec<String> = fs::read_to_string(&passed_tiles)?.split_whitespace().map(String::from).collect();
+            if tiles.is_empty() {
+                return Err(AppError::EmptyTileIDsList(passed_tiles));
+            }
+            command.push("--tile-list".into());
+            command.extend(tiles);
+        }
+        pipeline.run(Step::Dedupbarcode, command)?;
+
+        let report_dir = pipeline.dir("report")?;
+        let name = pipeline.config.name.clone().unwrap_or_else(|| {
+            output_dir.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_else(|| "run".into())
+        });
+        pipeline.run(Step::Qc, vec![
+            "--name".into(), name,
+            "--tilesmatch-json".into(), path_arg(&tilesmatch_json),
+            "--dedup-summary".into(), path_arg(&metrics_dir.join("run_summary.json")),
+            "--output-dir".into(), path_arg(&report_dir),
+        ])?;
+
+        let report = PipelineReport {
+            json: output_dir.join("pipeline_report.json"),
+            html: report_dir.join("qc_report.html"),
+            steps: pipeline.reports,
+        };
+        if !pipeline.args.dry_run {
+            let mut writer = fs::File::create(temp_path(&report.json))?;
+            serde_json::to_writer_pretty(&mut writer, &report.steps).map_err(io::Error::from)?;
+            writer.flush()?;
+            persist(&report.json)?;
+        }
+        Ok(report)
+    }
+}
+
+/// Steps run or skipped, and where the reports are
+pub struct PipelineReport {
+    steps: Vec<StepReport>,
+    json: PathBuf,
+    html: PathBuf,
+}
+
+impl std::fmt::Display for PipelineReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Step\tStatus\tSeconds")?;
+        for report in &self.steps {
+            write!(f, "\n{}\t{}\t{:.1}", report.step.name(), report.status, report.seconds)?;
+        }
+        write!(f, "\nReport {}\nSteps {}", self.html.display(), self.json.display())
+    }
+}
@@ -0,0 +1,434 @@
+use crate::utils::{
+    barcode_file::BarcodeRecord,
+    barcode_iter::{validate_absolute_dirpath, validate_absolute_filepath},
+    fastqfile::open_text,
+    plot::{escape, LinePlot},
+    atomic_file::{persist, temp_path},
+    error::AppError,
+};
+use crate::argparse::barcoderank::{find_knee, rank_curve};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use clap::{ArgGroup, Parser};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+#[derive(Parser, Debug)]
+#[command(name = "qc")]
+#[command(about = "Collect the metrics of a run into one HTML report and a JSON summary", long_about = None)]
+#[command(next_line_help = true)]
+#[command(group(
+    ArgGroup::new("metrics").required(true).multiple(true)
+        .args(["barcode_file", "tilesmatch_json", "dedup_summary", "barcode_rank"])
+))]
+pub struct QcArgs {
+    /// run name shown in the report
+    #[arg(long, default_value = "run")]
+    name: String,
+
+    /// barcode file written by touchbarcode
+    #[arg(short = 'I', long, value_parser = validate_absolute_filepath)]
+    barcode_file: Option<PathBuf>,
+
+    /// report written by `tilesmatch --json`
+    #[arg(long, value_parser = validate_absolute_filepath)]
+    tilesmatch_json: Option<PathBuf>,
+
+    /// run_summary.json written by `dedupbarcode --metrics-dir`
+    #[arg(long, value_parser = validate_absolute_filepath)]
+    dedup_summary: Option<PathBuf>,
+
+    /// barcode_rank.tsv written by barcoderank
+    #[arg(long, value_parser = validate_absolute_filepath)]
+    barcode_rank: Option<PathBuf>,
+
+    /// write `qc_report.html` and `qc_report.json` into this directory
+    #[arg(short, long, value_parser = validate_absolute_dirpath, help_heading = super::OUTPUTS)]
+    output_dir: PathBuf,
+}
+
+/// Barcode rows and quality of one tile in the barcode file
+#[derive(Serialize, Default)]
+struct TileBarcodeQc {
+    tile_id: u64,
+    rows: u64,
+    /// mean of the barcode mean qualities, missing for files without a quality column
+    mean_quality: Option<f64>,
+    #[serde(skip)]
+    quality_sum: f64,
+    #[serde(skip)]
+    quality_rows: u64,
+}
+
+#[derive(Serialize)]
+struct BarcodeFileQc {
+    rows: u64,
+    tiles: Vec<TileBarcodeQc>,
+    /// (rounded mean quality, rows)
+    quality_histogram: Vec<(u32, u64)>,
+}
+
+/// Fields of the tilesmatch JSON report shown in the QC report
+#[derive(Serialize, Deserialize)]
+struct TileMatchQc {
+    tile_id: u64,
+    passed_num: u64,
+    total_num: u64,
+    percent: f64,
+    selected: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TilesMatchQc {
+    barcode_file: PathBuf,
+    reports: Vec<TileMatchQc>,
+}
+
+/// Fields of the dedupbarcode run summary shown in the QC report
+#[derive(Serialize, Deserialize)]
+struct TileDedupQc {
+    tile_id: u64,
+    rows: u64,
+    kept: u64,
+    within_tile_lost: u64,
+    cross_tile_lost: u64,
+    retention: f64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DedupQc {
+    total_rows: u64,
+    unique_barcodes: u64,
+    within_tile_duplicated: u64,
+    across_tile_duplicated: u64,
+    kept: u64,
+    tiles: Vec<TileDedupQc>,
+}
+
+#[derive(Serialize)]
+struct BarcodeRankQc {
+    barcodes: u64,
+    reads: u64,
+    /// (rank, count)
+    knee: Option<(u64, u64)>,
+    #[serde(skip)]
+    curve: Vec<(f64, f64)>,
+}
+
+/// Everything collected for one run, serialized as `qc_report.json`
+#[derive(Serialize)]
+pub struct QcReport {
+    run: String,
+    version: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    touchbarcode: Option<BarcodeFileQc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tilesmatch: Option<Vec<TilesMatchQc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dedupbarcode: Option<DedupQc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    barcoderank: Option<BarcodeRankQc>,
+}
+
+fn invalid_data(message: String) -> AppError {
+    AppError::IoError(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+fn read_json<T: for<'de> Deserialize<'de>>(path: &Path) -> Result<T, AppError> {
+    serde_json::from_reader(open_text(path)?)
+        .map_err(|err| invalid_data(format!("Invalid JSON in {}: {}", path.display(), err)))
+}
+
+fn read_barcode_file(path: &Path) -> Result<BarcodeFileQc, AppError> {
+    let mut tiles: BTreeMap<u64, TileBarcodeQc> = BTreeMap::new();
+    let mut histogram: BTreeMap<u32, u64> = BTreeMap::new();
+    let mut rows = 0;
+    for line in open_text(path)?.lines() {
+        let line = line?;
+        if line.is_empty() || line.starts_with('#') || line.starts_with("tile_id") {
+            continue;
+        }
+        let record = BarcodeRecord::parse(&line)?;
+        let tile_id: u64 = record.tile_id.parse()
+            .map_err(|_| invalid_data(format!("Invalid tile id: {}", record.tile_id)))?;
+        let tile = tiles.entry(tile_id).or_insert_with(|| TileBarcodeQc { tile_id, ..Default::default() });
+        tile.rows += 1;
+        rows += 1;
+        if let Some(quality) = record.quality.and_then(|quality| quality.parse::<f64>().ok()) {
+            tile.quality_sum += quality;
+            tile.quality_rows += 1;
+            *histogram.entry(quality.round() as u32).or_default() += 1;
+        }
+    }
+    let tiles = tiles.into_values()
+        .map(|mut tile| {
+            tile.mean_quality = (tile.quality_rows > 0).then(|| tile.quality_sum / tile.quality_rows as f64);
+            tile
+        })
+        .collect();
+    Ok(BarcodeFileQc { rows, tiles, quality_histogram: histogram.into_iter().collect() })
+}
+
+fn read_barcode_rank(path: &Path) -> Result<BarcodeRankQc, AppError> {
+    let mut ranked = Vec::new();
+    for line in open_text(path)?.lines().skip(1) {
+        let line = line?;
+        let mut fields = line.split('\t').skip(1);
+        let (Some(barcode), Some(count)) = (fields.next(), fields.next()) else {
+            return Err(invalid_data(format!("Invalid barcode rank line: {line}")));
+        };
+        let count: u64 = count.parse().map_err(|_| invalid_data(format!("Invalid barcode rank count: {count}")))?;
+        ranked.push((barcode.to_string(), count));
+    }
+    let curve = rank_curve(&ranked);
+    Ok(BarcodeRankQc {
+        barcodes: ranked.len() as u64,
+        reads: ranked.iter().map(|(_, count)| count).sum(),
+        knee: find_knee(&curve),
+        curve,
+    })
+}
+
+/// HTML table of `headers` and `rows`, cells are escaped
+fn html_table(html: &mut String, headers: &[&str], rows: impl Iterator<Item = Vec<String>>) {
+    html.push_str("<table>\n<tr>");
+    for header in headers {
+        let _ = write!(html, "<th>{}</th>", escape(header));
+    }
+    html.push_str("</tr>\n");
+    for row in rows {
+        html.push_str("<tr>");
+        for cell in row {
+            let _ = write!(html, "<td>{}</td>", escape(&cell));
+        }
+        html.push_str("</tr>\n");
+    }
+    html.push_str("</table>\n");
+}
+
+impl QcArgs {
+    pub fn qc(self) -> Result<QcReport, AppError> {
+        let report = QcReport::collect(
+            self.name,
+            self.barcode_file.as_deref(),
+            self.tilesmatch_json.as_deref(),
+            self.dedup_summary.as_deref(),
+            self.barcode_rank.as_deref(),
+        )?;
+
+        let path = self.output_dir.join("qc_report.json");
+        let mut writer = BufWriter::new(fs::File::create(temp_path(&path))?);
+        serde_json::to_writer_pretty(&mut writer, &report).map_err(io::Error::from)?;
+        writer.flush()?;
+        drop(writer);
+        persist(&path)?;
+
+        let path = self.output_dir.join("qc_report.html");
+        fs::write(temp_path(&path), report.to_html())?;
+        persist(&path)?;
+        Ok(report)
+    }
+}
+
+impl QcReport {
+    /// Read the metrics written by the subcommands of one run
+    pub fn collect(
+        run: String,
+        barcode_file: Option<&Path>,
+        tilesmatch_json: Option<&Path>,
+        dedup_summary: Option<&Path>,
+        barcode_rank: Option<&Path>,
+    ) -> Result<Self, AppError> {
+        Ok(Self {
+            run,
+            version: env!("CARGO_PKG_VERSION"),
+            touchbarcode: barcode_file.map(read_barcode_file).transpose()?,
+            tilesmatch: tilesmatch_json.map(read_json).transpose()?,
+            dedupbarcode: dedup_summary.map(read_json).transpose()?,
+            barcoderank: barcode_rank.map(read_barcode_rank).transpose()?,
+        })
+    }
+
+    /// MultiQC custom content sections keyed by section name, the run is the sample
+    ///
+    /// The general statistics section is always present, the others only with their metrics
+    pub fn multiqc_sections(&self) -> Vec<(&'static str, Value)> {
+        let sample = &self.run;
+        let mut general = Map::new();
+        let mut sections = Vec::new();
+        if let Some(qc) = &self.touchbarcode {
+            general.insert("barcodes".into(), json!(qc.rows));
+            let data: Map<String, Value> = qc.tiles.iter().map(|tile| (
+                format!("{} {}", sample, tile.tile_id),
+                json!({"tile_id": tile.tile_id, "barcodes": tile.rows, "mean_quality": tile.mean_quality}),
+            )).collect();
+            sections.push(("touchbarcode_tiles", json!({
+                "section_name": "touchbarcode tiles",
+                "description": "Barcodes and their mean quality per tile of the barcode file",
+                "plot_type": "table",
+                "data": data,
+            })));
+            if !qc.quality_histogram.is_empty() {
+                let curve: Map<String, Value> = qc.quality_histogram.iter()
+                    .map(|(quality, rows)| (quality.to_string(), json!(rows)))
+                    .collect();
+                sections.push(("touchbarcode_quality", json!({
+                    "section_name": "Barcode mean quality",
+                    "plot_type": "linegraph",
+                    "pconfig": {"id": "opentools_touchbarcode_quality_plot", "xlab": "Mean quality", "ylab": "Barcodes"},
+                    "data": {sample.as_str(): curve},
+                })));
+            }
+        }
+        if let Some(files) = &self.tilesmatch {
+            let reports = files.iter().flat_map(|file| &file.reports);
+            let selected: Vec<&TileMatchQc> = reports.clone().filter(|report| report.selected).collect();
+            general.insert("selected_tiles".into(), json!(selected.len()));
+            let data: Map<String, Value> = reports.map(|report| (
+                format!("{} {}", sample, report.tile_id),
+                json!({
+                    "tile_id": report.tile_id, "total": report.total_num, "matched": report.passed_num,
+                    "percent": report.percent, "selected": report.selected,
+                }),
+            )).collect();
+            sections.push(("tilesmatch", json!({
+                "section_name": "tilesmatch",
+                "description": "Query barcodes matched on every tile",
+                "plot_type": "table",
+                "data": data,
+            })));
+        }
+        if let Some(qc) = &self.dedupbarcode {
+            general.insert("unique_barcodes".into(), json!(qc.unique_barcodes));
+            general.insert("kept_barcodes".into(), json!(qc.kept));
+            sections.push(("dedupbarcode", json!({
+                "section_name": "dedupbarcode",
+                "description": "Barcode rows kept and lost to duplicates",
+                "plot_type": "bargraph",
+                "pconfig": {"id": "opentools_dedupbarcode_plot", "ylab": "Rows"},
+                "data": {sample.as_str(): {
+                    "kept": qc.kept,
+                    "within_tile_duplicated": qc.within_tile_duplicated,
+                    "across_tile_duplicated": qc.across_tile_duplicated,
+                }},
+            })));
+        }
+        if let Some(qc) = &self.barcoderank {
+            general.insert("ranked_barcodes".into(), json!(qc.barcodes));
+            if let Some((rank, _)) = qc.knee {
+                general.insert("knee_rank".into(), json!(rank));
+            }
+            let curve: Map<String, Value> = qc.curve.iter()
+                .map(|(rank, count)| (rank.to_string(), json!(count)))
+                .collect();
+            sections.push(("barcoderank", json!({
+                "section_name": "Barcode rank",
+                "plot_type": "linegraph",
+                "pconfig": {"id": "opentools_barcoderank_plot", "xlab": "Rank", "ylab": "Reads", "xlog": true, "ylog": true},
+                "data": {sample.as_str(): curve},
+            })));
+        }
+        sections.insert(0, ("general_stats", json!({
+            "plot_type": "generalstats",
+            "data": {sample.as_str(): general},
+        })));
+        sections
+    }
+
+    /// Self-contained HTML page, plots are inlined as SVG
+    fn to_html(&self) -> String {
+        let mut html = String::new();
+        let _ = write!(
+            html,
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{0} QC</title>\n<style>\
+             body{{font-family:sans-serif;margin:2em}}table{{border-collapse:collapse;margin:1em 0}}\
+             th,td{{border:1px solid #ccc;padding:2px 8px;text-align:right}}</style>\n</head>\n<body>\n\
+             <h1>{0} QC</h1>\n<p>opentools {1}</p>\n",
+            escape(&self.run), self.version,
+        );
+        if let Some(qc) = &self.touchbarcode {
+            let _ = writeln!(html, "<h2>touchbarcode</h2>\n<p>Barcodes: {}</p>", qc.rows);
+            html_table(&mut html, &["Tile id", "Barcodes", "Mean quality"], qc.tiles.iter().map(|tile| vec![
+                tile.tile_id.to_string(),
+                tile.rows.to_string(),
+                tile.mean_quality.map_or("-".to_string(), |quality| format!("{quality:.2}")),
+            ]));
+            if !qc.quality_histogram.is_empty() {
+                let mut plot = LinePlot::new("Barcode mean quality", "Mean quality", "Barcodes");
+                plot.add_series(
+                    qc.quality_histogram.iter().map(|&(quality, rows)| (quality as f64, rows as f64)).collect(),
+                    "steelblue",
+                );
+                html.push_str(&plot.to_svg());
+            }
+        }
+        if let Some(files) = &self.tilesmatch {
+            html.push_str("<h2>tilesmatch</h2>\n");
+            for file in files {
+                let _ = writeln!(html, "<p>{}</p>", escape(&file.barcode_file.display().to_string()));
+                html_table(
+                    &mut html,
+                    &["Tile id", "Total", "Matched", "Percent", "Selected"],
+                    file.reports.iter().map(|report| vec![
+                        report.tile_id.to_string(),
+                        report.total_num.to_string(),
+                        report.passed_num.to_string(),
+                        format!("{:.5}", report.percent),
+                        if report.selected { "yes" } else { "no" }.to_string(),
+                    ]),
+                );
+                let mut plot = LinePlot::new("Matched barcodes per tile", "Tile (file order)", "Percent");
+                plot.add_series(
+                    file.reports.iter().enumerate().map(|(index, report)| (index as f64 + 1.0, report.percent)).collect(),
+                    "steelblue",
+                );
+                html.push_str(&plot.to_svg());
+            }
+        }
+        if let Some(qc) = &self.dedupbarcode {
+            let _ = writeln!(
+                html,
+                "<h2>dedupbarcode</h2>\n<p>Total: {}, Unique: {}, Duplicated within tile: {}, across tiles: {}, Kept: {}</p>",
+                qc.total_rows, qc.unique_barcodes, qc.within_tile_duplicated, qc.across_tile_duplicated, qc.kept,
+            );
+            html_table(
+                &mut html,
+                &["Tile id", "Rows", "Kept", "Within tile lost", "Cross tile lost", "Retention"],
+                qc.tiles.iter().map(|tile| vec![
+                    tile.tile_id.to_string(),
+                    tile.rows.to_string(),
+                    tile.kept.to_string(),
+                    tile.within_tile_lost.to_string(),
+                    tile.cross_tile_lost.to_string(),
+                    format!("{:.5}", tile.retention),
+                ]),
+            );
+        }
+        if let Some(qc) = &self.barcoderank {
+            let _ = writeln!(html, "<h2>barcoderank</h2>\n<p>Barcodes: {}, Reads: {}</p>", qc.barcodes, qc.reads);
+            let mut plot = LinePlot::new("Barcode rank", "Rank", "Reads").log_scale(true, true);
+            plot.add_series(qc.curve.clone(), "steelblue");
+            if let Some((rank, count)) = qc.knee {
+                plot.add_marker(&format!("knee {rank}"), rank as f64, count as f64);
+            }
+            html.push_str(&plot.to_svg());
+        }
+        html.push_str("</body>\n</html>\n");
+        html
+    }
+}
+
+impl std::fmt::Display for QcReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let sections: Vec<&str> = [
+            ("touchbarcode", self.touchbarcode.is_some()),
+            ("tilesmatch", self.tilesmatch.is_some()),
+            ("dedupbarcode", self.dedupbarcode.is_some()),
+            ("barcoderank", self.barcoderank.is_some()),
+        ].into_iter().filter(|(_, present)| *present).map(|(name, _)| name).collect();
+        write!(f, "Run={}, Sections={}", self.run, sections.join(","))
+    }
+}
@@ -0,0 +1,163 @@
+use crate::utils::{
+    hts,
+    barcode_file::{build_tabix_index, create_bgzf, list_tiles, BarcodeRecord, BARCODE_FILE_HEADER},
+    barcode_iter::validate_absolute_filepath,
+    coordinate::{tile_grid, PuckTransform, TileSize},
+    atomic_file::{persist_indexed, temp_path},
+    error::AppError,
+};
+use crate::argparse::tilesmatch::is_valid_tile_id;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use clap::{Parser, ValueEnum};
+use rust_htslib::tbx::Read;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum RegionUnit {
+    /// pixels of each tile, the box is applied to every tile
+    Pixel,
+    /// µm of the lane surface with tiles placed by swath and tile number, same as puck coordinates
+    Um,
+}
+
+/// Inclusive `start-end` range of numbers
+pub fn parse_range(value: &str) -> Result<(f64, f64), String> {
+    let (start, end) = value.split_once('-')
+        .ok_or(format!("`{}` is not a range, expected 'start-end'", value))?;
+    let start: f64 = start.parse().map_err(|_| format!("`{}` is not valid number", start))?;
+    let end: f64 = end.parse().map_err(|_| format!("`{}` is not valid number", end))?;
+    if end < start {
+        return Err(format!("range end {} must be >= start {}", end, start));
+    }
+    Ok((start, end))
+}
+
+#[derive(Parser, Debug)]
+#[command(name = "region")]
+#[command(about = "Extract the barcodes inside a bounding box of tiles or of a lane surface", long_about = None)]
+#[command(next_line_help = true)]
+pub struct RegionArgs {
+    /// The path to the barcode file
+    #[arg(short = 'I', long, value_parser = validate_absolute_filepath)]
+    barcode_file: PathBuf,
+
+    /// the tile id list to query, all tiles in the barcode file by default
+    #[arg(
+        long,
+        value_delimiter = ' ',
+        num_args = 1..,
+        value_parser = is_valid_tile_id,
+    )]
+    tile_list: Vec<u64>,
+
+    /// inclusive x range of the box (e.g. "1000-5000")
+    #[arg(short, long, value_parser = parse_range, value_name = "START-END")]
+    x_range: (f64, f64),
+
+    /// inclusive y range of the box (e.g. "1000-5000")
+    #[arg(short, long, value_parser = parse_range, value_name = "START-END")]
+    y_range: (f64, f64),
+
+    /// unit of the box
+    #[arg(long, value_enum, default_value_t = RegionUnit::Pixel)]
+    unit: RegionUnit,
+
+    /// µm per pixel (only effective with --unit um)
+    #[arg(long, default_value_t = 0.6, value_name = "UM")]
+    um_per_pixel: f64,
+
+    /// tile size in pixels used to offset tiles onto the lane surface (only effective with --unit um)
+    #[arg(long, default_value_t = TileSize { width: 33000.0, height: 37100.0 }, value_name = "WIDTH,HEIGHT")]
+    tile_size: TileSize,
+
+    /// lane * 10 + surface the box lies on (only effective with --unit um)
+    #[arg(long, default_value_t = 11, value_name = "N")]
+    lane_surface: u64,
+
+    /// output barcode file, bgzf compressed and tabix indexed like the input (e.g. region.txt.gz)
+    #[arg(short, long, help_heading = super::OUTPUTS)]
+    output: PathBuf,
+}
+
+impl RegionArgs {
+    /// Pixel box of the tile covered by the region, `None` when the tile lies outside of it
+    fn tile_box(&self, tile_id: u64) -> Option<((f64, f64), (f64, f64))> {
+        match self.unit {
+            RegionUnit::Pixel => Some((self.x_range, self.y_range)),
+            RegionUnit::Um => {
+                if tile_grid(tile_id).0 != self.lane_surface {
+                    return None;
+                }
+                let transform = PuckTransform::new(self.um_per_pixel, self.tile_size);
+                let (x_offset, y_offset) = transform.apply(tile_id, 0.0, 0.0);
+                let to_pixel = |(start, end): (f64, f64), offset: f64, size: f64| {
+                    let range = ((start - offset) / self.um_per_pixel, (end - offset) / self.um_per_pixel);
+                    (range.1 >= 0.0 && range.0 < size).then_some(range)
+                };
+                Some((
+                    to_pixel(self.x_range, x_offset, self.tile_size.width)?,
+                    to_pixel(self.y_range, y_offset, self.tile_size.height)?,
+                ))
+            }
+        }
+    }
+
+    pub fn extract(self) -> Result<RegionReport, AppError> {
+        let tiles = if self.tile_list.is_empty() {
+            list_tiles(&self.barcode_file)?
+        } else {
+            self.tile_list.clone()
+        };
+        let invalid = |value: &str| AppError::IoError(io::Error::new(
+            io::ErrorKind::InvalidData, format!("Invalid position `{value}` in {}", self.barcode_file.display())
+        ));
+
+        let mut reader = hts::open_tabix(&self.barcode_file)?;
+        let mut writer = create_bgzf(&temp_path(&self.output))?;
+        writeln!(writer, "{}", BARCODE_FILE_HEADER)?;
+        let mut report = RegionReport::default();
+        for tile_id in tiles {
+            let Some(((x_start, x_end), (y_start, y_end))) = self.tile_box(tile_id) else {
+                continue;
+            };
+            report.tiles += 1;
+            // tabix positions are 0-based, y is the indexed column
+            let tid = reader.tid(&tile_id.to_string())?;
+            reader.fetch(tid, y_start.max(0.0).floor() as u64, y_end.max(0.0).ceil() as u64 + 1)?;
+            let mut found = false;
+            for record in reader.records() {
+                let record = record?;
+                let line = String::from_utf8_lossy(&record);
+                let BarcodeRecord { x_pos, y_pos, .. } = BarcodeRecord::parse(&line)?;
+                let x: f64 = x_pos.parse().map_err(|_| invalid(x_pos))?;
+                let y: f64 = y_pos.parse().map_err(|_| invalid(y_pos))?;
+                if (x_start..=x_end).contains(&x) && (y_start..=y_end).contains(&y) {
+                    writer.write_all(&record)?;
+                    writeln!(writer)?;
+                    report.barcodes += 1;
+                    found = true;
+                }
+            }
+            report.hit_tiles += found as u64;
+        }
+        writer.flush()?;
+        drop(writer);
+        build_tabix_index(&temp_path(&self.output))?;
+        persist_indexed(&self.output)?;
+        Ok(report)
+    }
+}
+
+/// Tiles overlapping the box and barcodes inside it
+#[derive(Default)]
+pub struct RegionReport {
+    tiles: u64,
+    hit_tiles: u64,
+    barcodes: u64,
+}
+
+impl std::fmt::Display for RegionReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Tiles scanned={}, Tiles with barcodes={}, Barcodes={}", self.tiles, self.hit_tiles, self.barcodes)
+    }
+}
@@ -0,0 +1,326 @@
+use crate::utils::{
+    barcode_iter::{validate_absolute_filepath, validate_output_filepath},
+    fastqfile::open_text,
+    error::AppError,
+};
+use crate::argparse::convert::write_text;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+use clap::{ArgGroup, Parser, ValueEnum};
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum TransformModel {
+    /// rotation, uniform scale and translation, from at least 2 landmarks
+    Similarity,
+    /// any linear map and translation (adds shear and per axis scale), from at least 3 landmarks
+    Affine,
+}
+
+#[derive(Parser, Debug)]
+#[command(name = "register")]
+#[command(about = "Transform barcode coordinates into microscope image space by landmarks or a given matrix", long_about = None)]
+#[command(next_line_help = true)]
+#[command(group(ArgGroup::new("transform").required(true).args(["landmarks", "matrix"])))]
+pub struct RegisterArgs {
+    /// coordinates with a header line naming the columns, e.g. stitch output or a barcode file
+    ///
+    /// x and y are taken from the first of the `x_um`/`x_pos`/`x` and `y_um`/`y_pos`/`y` columns
+    #[arg(short, long, value_parser = validate_absolute_filepath)]
+    input: PathBuf,
+
+    /// landmark pairs to fit the transform on, rows of `x\ty\timage_x\timage_y`
+    #[arg(long, value_parser = validate_absolute_filepath)]
+    landmarks: Option<PathBuf>,
+
+    /// transform fitted on the landmarks
+    #[arg(long, value_enum, default_value_t = TransformModel::Affine)]
+    model: TransformModel,
+
+    /// apply this matrix instead of fitting one, 2 rows of 3 numbers (`a b c` and `d e f`) mapping
+    /// (x, y) to (a*x + b*y + c, d*x + e*y + f), e.g. the matrix written by an earlier run
+    #[arg(long, value_parser = validate_absolute_filepath)]
+    matrix: Option<PathBuf>,
+
+    /// output coordinates with `image_x\timage_y` appended, gzipped when the path ends with `.gz`
+    ///
+    /// the matrix used is written next to it into `{output}.matrix.tsv`
+    #[arg(short, long, value_parser = validate_output_filepath, help_heading = super::OUTPUTS)]
+    output: PathBuf,
+}
+
+fn invalid_data(message: String) -> AppError {
+    AppError::IoError(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+/// Numbers of the data rows of a whitespace separated table, comment lines and a text header skipped
+fn read_rows(path: &Path, columns: usize) -> Result<Vec<Vec<f64>>, AppError> {
+    let mut rows = Vec::new();
+    for (index, line) in open_text(path)?.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let row: Result<Vec<f64>, _> = line.split_whitespace().map(str::parse).collect();
+        match row {
+            Ok(row) if row.len() == columns => rows.push(row),
+            // text header line
+            Err(_) if index == 0 => continue,
+            _ => return Err(invalid_data(format!(
+                "Invalid line {} in {}, expected {} numbers", index + 1, path.display(), columns
+            ))),
+        }
+    }
+    Ok(rows)
+}
+
+/// Row major 2x3 matrix of an affine transform
+#[derive(Debug, Clone, Copy)]
+pub struct AffineMatrix([f64; 6]);
+
+impl AffineMatrix {
+    fn load(path: &Path) -> Result<Self, AppError> {
+        let rows = read_rows(path, 3)?;
+        let [first, second] = rows.as_slice() else {
+            return Err(invalid_data(format!("{} does not hold 2 rows of 3 numbers", path.display())));
+        };
+        Ok(Self([first[0], first[1], first[2], second[0], second[1], second[2]]))
+    }
+
+    #[inline]
+    fn apply(&self, x: f64, y: f64) -> (f64, f64) {
+        let [a, b, c, d, e, f] = self.0;
+        (a * x + b * y + c, d * x + e * y + f)
+    }
+
+    /// Least squares fit of the landmark pairs `(x, y, image_x, image_y)`, on centered coordinates
+    fn fit(landmarks: &[Vec<f64>], model: TransformModel) -> Result<Self, AppError> {
+        let required = match model {
+            TransformModel::Similarity => 2,
+            TransformModel::Affine => 3,
+        };
+        if landmarks.len() < required {
+            return Err(invalid_data(format!(
+                "{:?} transform needs at least {} landmarks, got {}", model, required, landmarks.len()
+            )));
+        }
+        let n = landmarks.len() as f64;
+        let mean = |column: usize| landmarks.iter().map(|row| row[column]).sum::<f64>() / n;
+        let (mx, my, mu, mv) = (mean(0), mean(1), mean(2), mean(3));
+        let (mut sxx, mut sxy, mut syy) = (0.0, 0.0, 0.0);
+        let (mut sxu, mut syu, mut sxv, mut syv) = (0.0, 0.0, 0.0, 0.0);
+        for row in landmarks {
+            let (x, y, u, v) = (row[0] - mx, row[1] - my, row[2] - mu, row[3] - mv);
+            sxx += x * x;
+            sxy += x * y;
+            syy += y * y;
+            sxu += x * u;
+            syu += y * u;
+            sxv += x * v;
+            syv += y * v;
+        }
+        let degenerate = || invalid_data("Landmarks are collinear or coincide, the transform is undetermined".to_string());
+        let (a, b, d, e) = match model {
+            TransformModel::Similarity => {
+                let norm = sxx + syy;
+                if norm <= f64::EPSILON {
+                    return Err(degenerate());
+                }
+                let (p, q) = ((sxu + syv) / norm, (sxv - syu) / norm);
+                (p, -q, q, p)
+            }
+            TransformModel::Affine => {
+                let det = sxx * syy - sxy * sxy;
+                if det.abs() <= f64::EPSILON * sxx.max(syy).max(1.0).powi(2) {
+                    return Err(degenerate());
+                }
+                let solve = |sx: f64, sy: f64| ((sx * syy - sy * sxy) / det, (sy * sxx - sx * sxy) / det);
+                let (a, b) = solve(sxu, syu);
+                let (d, e) = solve(sxv, syv);
+                (a, b, d, e)
+            }
+        };
+        Ok(Self([a, b, mu - a * mx - b * my, d, e, mv - d * mx - e * my]))
+    }
+
+    /// Root mean square and largest distance of the mapped landmarks to their image positions
+    fn residuals(&self, landmarks: &[Vec<f64>]) -> (f64, f64) {
+        let distances: Vec<f64> = landmarks.iter().map(|row| {
+            let (u, v) = self.apply(row[0], row[1]);
+            (u - row[2]).hypot(v - row[3])
+        }).collect();
+        let rms = (distances.iter().map(|d| d * d).sum::<f64>() / distances.len() as f64).sqrt();
+        (rms, distances.iter().copied().fold(0.0, f64::max))
+    }
+}
+
+impl std::fmt::Display for AffineMatrix {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{}\t{}\t{}\n{}\t{}\t{}", a, b, c, d, e, g)
+    }
+}
+
+/// Column of the first name found in the header
+pub fn find_column(header: &[&str], names: &[&str]) -> Option<usize> {
+    names.iter().find_map(|name| header.iter().position(|column| column.trim_start_matches('#') == *name))
+}
+
+impl RegisterArgs {
+    pub fn register(self) -> Result<RegisterReport, AppError> {
+        let (matrix, residuals) = match (&self.matrix, &self.landmarks) {
+            (Some(path), _) => (AffineMatrix::load(path)?, None),
+            (None, Some(path)) => {
+                let landmarks = read_rows(path, 4)?;
+                let matrix = AffineMatrix::fit(&landmarks, self.model)?;
+                (matrix, Some((landmarks.len(), matrix.residuals(&landmarks))))
+            }
+            (None, None) => unreachable!("clap requires --landmarks or --matrix"),
+        };
+
+        let mut lines = open_text(&self.input)?.lines();
+        let header = lines.next().transpose()?.unwrap_or_default();
+        let columns: Vec<&str> = header.split('\t').collect();
+        let (Some(x_column), Some(y_column)) = (
+            find_column(&columns, &["x_um", "x_pos", "x"]), find_column(&columns, &["y_um", "y_pos", "y"]),
+        ) else {
+            return Err(invalid_data(format!("No x and y columns in the header of {}", self.input.display())));
+        };
+        let invalid = |line: usize| io::Error::new(
+            io::ErrorKind::InvalidData, format!("Invalid line {line} in {}", self.input.display())
+        );
+        let mut points = 0;
+        write_text(&self.output, |writer| {
+            writeln!(writer, "{}\timage_x\timage_y", header)?;
+            for (index, line) in lines.enumerate() {
+                let line = line?;
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let fields: Vec<&str> = line.split('\t').collect();
+                let position = |column: usize| fields.get(column).and_then(|value| value.parse::<f64>().ok());
+                let (Some(x), Some(y)) = (position(x_column), position(y_column)) else {
+                    return Err(invalid(index + 2));
+                };
+                let (image_x, image_y) = matrix.apply(x, y);
+                writeln!(writer, "{}\t{:.2}\t{:.2}", line, image_x, image_y)?;
+                points += 1;
+            }
+            Ok(())
+        })?;
+        let mut matrix_file = self.output.as_os_str().to_owned();
+        matrix_file.push(".matrix.tsv");
+        write_text(Path::new(&matrix_file), |writer| writeln!(writer, "{}", matrix))?;
+        Ok(RegisterReport { points, matrix, residuals })
+    }
+}
+
+/// Transformed points, the matrix used and the fit of the landmarks
+pub struct RegisterReport {
+    points: u64,
+    matrix: AffineMatrix,
+    /// landmarks and their root mean square and largest residual
+    residuals: Option<(usize, (f64, f64))>,
+}
+
+impl std::fmt::Display for RegisterReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Points={}", self.points)?;
+        if let Some((landmarks, (rms, max))) = self.residuals {
+            write!(f, ", Landmarks={}, RMS residual={:.3}, Max residual={:.3}", landmarks, rms, max)?;
+        }
+        write!(f, "\nMatrix\n{}", self.matrix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Landmarks of the points mapped by `matrix`
+    fn landmarks(matrix: &AffineMatrix, points: &[(f64, f64)]) -> Vec<Vec<f64>> {
+        points.iter().map(|&(x, y)| {
+            let (u, v) = matrix.apply(x, y);
+            vec![x, y, u, v]
+        }).collect()
+    }
+
+    fn assert_recovered(fitted: &AffineMatrix, expected: &AffineMatrix) {
+        for (fitted, expected) in fitted.0.iter().zip(expected.0) {
+            assert!((fitted - expected).abs() < 1e-9, "{fitted:?} != {expected:?}");
+        }
+    }
+
+    const POINTS: [(f64, f64); 4] = [(1200.0, 350.0), (4800.0, 420.0), (4700.0, 3900.0), (1350.0, 4100.0)];
+
+    #[test]
+    fn test_fit_similarity() {
+        // scale 0.5 turned by 30 degrees
+        let (p, q) = (0.5 * 30f64.to_radians().cos(), 0.5 * 30f64.to_radians().sin());
+        let expected = AffineMatrix([p, -q, 812.5, q, p, -40.25]);
+        for model in [TransformModel::Similarity, TransformModel::Affine] {
+            let fitted = AffineMatrix::fit(&landmarks(&expected, &POINTS), model).unwrap();
+            assert_recovered(&fitted, &expected);
+        }
+        // 2 landmarks determine a similarity
+        let fitted = AffineMatrix::fit(&landmarks(&expected, &POINTS[..2]), TransformModel::Similarity).unwrap();
+        assert_recovered(&fitted, &expected);
+        assert!(AffineMatrix::fit(&landmarks(&expected, &POINTS[..2]), TransformModel::Affine).is_err());
+    }
+
+    #[test]
+    fn test_fit_affine() {
+        // shear and a different scale per axis
+        let expected = AffineMatrix([1.5, 0.3, -120.0, -0.2, 0.8, 64.0]);
+        let landmarks = landmarks(&expected, &POINTS);
+        let fitted = AffineMatrix::fit(&landmarks, TransformModel::Affine).unwrap();
+        assert_recovered(&fitted, &expected);
+        let (rms, max) = fitted.residuals(&landmarks);
+        assert!(rms < 1e-6 && max < 1e-6);
+        // a similarity cannot shear, it leaves residuals
+        let similarity = AffineMatrix::fit(&landmarks, TransformModel::Similarity).unwrap();
+        assert!(similarity.residuals(&landmarks).1 > 1.0);
+    }
+
+    #[test]
+    fn test_fit_degenerate() {
+        let matrix = AffineMatrix([1.5, 0.3, -120.0, -0.2, 0.8, 64.0]);
+        let collinear: Vec<(f64, f64)> = (0..5).map(|k| (1000.0 + 0.7 * k as f64, 2000.0 + 0.3 * k as f64)).collect();
+        assert!(AffineMatrix::fit(&landmarks(&matrix, &collinear), TransformModel::Affine).is_err());
+        // a line still fixes a similarity
+        assert!(AffineMatrix::fit(&landmarks(&matrix, &collinear), TransformModel::Similarity).is_ok());
+        let coincident = [(250.0, 250.0); 3];
+        for model in [TransformModel::Similarity, TransformModel::Affine] {
+            assert!(AffineMatrix::fit(&landmarks(&matrix, &coincident), model).is_err());
+        }
+    }
+
+    #[test]
+    fn test_register() {
+        let dir = std::env::temp_dir().join(format!("opentools-test-register-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let expected = AffineMatrix([1.5, 0.3, -120.0, -0.2, 0.8, 64.0]);
+        let input = dir.join("coordinates.tsv");
+        fs::write(&input, "barcode\tx_um\ty_um\nAAAA\t100\t200\n").unwrap();
+        let landmarks_path = dir.join("landmarks.tsv");
+        let rows: Vec<String> = landmarks(&expected, &POINTS).iter()
+            .map(|row| row.iter().map(f64::to_string).collect::<Vec<_>>().join("\t"))
+            .collect();
+        fs::write(&landmarks_path, format!("x\ty\timage_x\timage_y\n{}\n", rows.join("\n"))).unwrap();
+        let output = dir.join("registered.tsv");
+        let args = |output: &Path| RegisterArgs::try_parse_from([
+            "register", "-i", input.to_str().unwrap(), "--landmarks", landmarks_path.to_str().unwrap(), "-o", output.to_str().unwrap(),
+        ]);
+
+        let report = args(&output).unwrap().register().unwrap();
+        assert_eq!(report.points, 1);
+        assert_eq!(fs::read_to_string(&output).unwrap(), "barcode\tx_um\ty_um\timage_x\timage_y\nAAAA\t100\t200\t90.00\t204.00\n");
+        let matrix = AffineMatrix::load(&dir.join("registered.tsv.matrix.tsv")).unwrap();
+        assert_recovered(&matrix, &expected);
+
+        // outputs in a missing directory or onto a directory are refused up front
+        assert!(args(&dir.join("missing").join("registered.tsv")).is_err());
+        assert!(args(&dir).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,131 @@
+use crate::utils::{
+    barcode_iter::{validate_absolute_dirpath, validate_absolute_filepath},
+    fastqfile::open_text,
+    atomic_file::{persist, temp_path},
+    error::AppError,
+};
+use crate::argparse::qc::QcReport;
+use std::fs;
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use clap::{ArgGroup, Parser};
+use serde_json::{json, Map, Value};
+
+#[derive(Parser, Debug)]
+#[command(name = "report")]
+#[command(about = "Write the metrics of a run as MultiQC custom content", long_about = None)]
+#[command(next_line_help = true)]
+#[command(after_help = "Run it once per sample into the MultiQC search path, sections of several samples are merged by MultiQC")]
+#[command(group(
+    ArgGroup::new("metrics").required(true).multiple(true)
+        .args(["barcode_file", "tilesmatch_json", "dedup_summary", "barcode_rank", "saturation"])
+))]
+pub struct ReportArgs {
+    /// sample name the metrics are reported under
+    #[arg(short, long)]
+    sample: String,
+
+    /// barcode file written by touchbarcode
+    #[arg(short = 'I', long, value_parser = validate_absolute_filepath)]
+    barcode_file: Option<PathBuf>,
+
+    /// report written by `tilesmatch --json`
+    #[arg(long, value_parser = validate_absolute_filepath)]
+    tilesmatch_json: Option<PathBuf>,
+
+    /// run_summary.json written by `dedupbarcode --metrics-dir`
+    #[arg(long, value_parser = validate_absolute_filepath)]
+    dedup_summary: Option<PathBuf>,
+
+    /// barcode_rank.tsv written by barcoderank
+    #[arg(long, value_parser = validate_absolute_filepath)]
+    barcode_rank: Option<PathBuf>,
+
+    /// saturation.tsv written by saturation
+    #[arg(long, value_parser = validate_absolute_filepath)]
+    saturation: Option<PathBuf>,
+
+    /// write one `opentools_{section}_mqc.json` per section into this directory
+    #[arg(short, long, value_parser = validate_absolute_dirpath, help_heading = super::OUTPUTS)]
+    output_dir: PathBuf,
+}
+
+fn invalid_data(message: String) -> AppError {
+    AppError::IoError(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+/// (reads, umis, saturation) of every row of saturation.tsv
+fn read_saturation(path: &Path) -> Result<Vec<(f64, f64, f64)>, AppError> {
+    let mut rows = Vec::new();
+    for line in open_text(path)?.lines().skip(1) {
+        let line = line?;
+        let fields: Vec<f64> = line.split('\t').skip(1).take(3).map(str::parse).collect::<Result<_, _>>()
+            .map_err(|_| invalid_data(format!("Invalid saturation line: {line}")))?;
+        let [reads, umis, saturation] = fields[..] else {
+            return Err(invalid_data(format!("Invalid saturation line: {line}")));
+        };
+        rows.push((reads, umis, saturation));
+    }
+    Ok(rows)
+}
+
+impl ReportArgs {
+    pub fn write(self) -> Result<ReportSummary, AppError> {
+        let qc = QcReport::collect(
+            self.sample.clone(),
+            self.barcode_file.as_deref(),
+            self.tilesmatch_json.as_deref(),
+            self.dedup_summary.as_deref(),
+            self.barcode_rank.as_deref(),
+        )?;
+        let mut sections = qc.multiqc_sections();
+        if let Some(path) = &self.saturation {
+            let rows = read_saturation(path)?;
+            if let (Some((_, general)), Some(&(_, _, saturation))) = (sections.first_mut(), rows.last()) {
+                general["data"][self.sample.as_str()]["saturation"] = json!(saturation * 100.0);
+            }
+            let curve: Map<String, Value> = rows.iter()
+                .map(|(reads, umis, _)| (format!("{reads:.0}"), json!(umis)))
+                .collect();
+            sections.push(("saturation", json!({
+                "section_name": "Sequencing saturation",
+                "description": "Expected unique UMIs of subsampled reads",
+                "plot_type": "linegraph",
+                "pconfig": {"id": "opentools_saturation_plot", "xlab": "Reads", "ylab": "Unique UMIs"},
+                "data": {self.sample.as_str(): curve},
+            })));
+        }
+
+        let mut summary = ReportSummary { sample: self.sample, files: Vec::new() };
+        for (name, mut section) in sections {
+            let id = format!("opentools_{name}");
+            section["id"] = json!(id);
+            section["parent_id"] = json!("opentools");
+            section["parent_name"] = json!("opentools");
+            let path = self.output_dir.join(format!("{id}_mqc.json"));
+            let mut writer = BufWriter::new(fs::File::create(temp_path(&path))?);
+            serde_json::to_writer_pretty(&mut writer, &section).map_err(io::Error::from)?;
+            writer.flush()?;
+            drop(writer);
+            persist(&path)?;
+            summary.files.push(path);
+        }
+        Ok(summary)
+    }
+}
+
+/// Custom content files written for the sample
+pub struct ReportSummary {
+    sample: String,
+    files: Vec<PathBuf>,
+}
+
+impl std::fmt::Display for ReportSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Sample={}, Files={}", self.sample, self.files.len())?;
+        for path in &self.files {
+            write!(f, "\n{}", path.display())?;
+        }
+        Ok(())
+    }
+}
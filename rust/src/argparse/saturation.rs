@@ -0,0 +1,288 @@
+use crate::utils::{
+    hts,
+    barcode_iter::{validate_absolute_dirpath, validate_filepath_or_stdin},
+    fastqfile::open_text,
+    plot::LinePlot,
+    error::AppError,
+};
+use crate::argparse::{
+    barcoderank::parse_bam_tag,
+    convert::write_text,
+};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+use clap::{Parser, ValueEnum};
+use rust_htslib::bam::{self, Read, record::Aux};
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum SaturationInput {
+    /// `.bam`, `.sam` and `.cram` are read as alignments, anything else as molecule table
+    Auto,
+    /// alignments with the barcode and UMI in tags
+    Bam,
+    /// `barcode\tumi\treads` per line, optionally gzipped, a non-numeric first line is taken as header
+    Table,
+}
+
+fn parse_fraction(value: &str) -> Result<f64, String> {
+    let fraction: f64 = value.parse().map_err(|_| format!("`{}` is not valid number", value))?;
+    if !(fraction > 0.0 && fraction <= 1.0) {
+        return Err(format!("fraction {} must be in (0, 1]", fraction));
+    }
+    Ok(fraction)
+}
+
+#[derive(Parser, Debug)]
+#[command(name = "saturation")]
+#[command(about = "Sequencing saturation curve of unique UMIs against subsampled reads", long_about = None)]
+#[command(next_line_help = true)]
+pub struct SaturationArgs {
+    /// tagged BAM or molecule table (`-` for a table from stdin)
+    #[arg(short, long, value_parser = validate_filepath_or_stdin)]
+    input: PathBuf,
+
+    /// how to read the input
+    #[arg(long, value_enum, default_value_t = SaturationInput::Auto)]
+    input_format: SaturationInput,
+
+    /// SAM tag holding the barcode (only effective for BAM input)
+    #[arg(long, default_value = "CB", value_parser = parse_bam_tag)]
+    barcode_tag: String,
+
+    /// SAM tag holding the UMI (only effective for BAM input)
+    #[arg(long, default_value = "UB", value_parser = parse_bam_tag)]
+    umi_tag: String,
+
+    /// read fractions the curve is estimated at
+    #[arg(
+        long,
+        value_delimiter = ',',
+        num_args = 1..,
+        value_parser = parse_fraction,
+        default_value = "0.05,0.1,0.2,0.3,0.4,0.5,0.6,0.7,0.8,0.9,1",
+    )]
+    fractions: Vec<f64>,
+
+    /// further sequencing is reported worthwhile below this saturation
+    #[arg(long, default_value_t = 0.8, value_parser = parse_fraction)]
+    target: f64,
+
+    /// write `saturation.tsv` and `saturation.svg` into this directory
+    #[arg(short, long, value_parser = validate_absolute_dirpath, help_heading = super::OUTPUTS)]
+    output_dir: PathBuf,
+}
+
+/// Molecules by their reads, with the barcodes holding them
+#[derive(Default)]
+struct Molecules {
+    /// reads per molecule to molecules
+    histogram: BTreeMap<u64, u64>,
+    barcodes: u64,
+}
+
+impl Molecules {
+    fn from_counts(counts: HashMap<(String, String), u64>) -> Self {
+        let mut molecules = Self {
+            barcodes: counts.keys().map(|(barcode, _)| barcode).collect::<HashSet<_>>().len() as u64,
+            ..Self::default()
+        };
+        for reads in counts.into_values() {
+            *molecules.histogram.entry(reads).or_default() += 1;
+        }
+        molecules
+    }
+
+    fn reads(&self) -> u64 {
+        self.histogram.iter().map(|(reads, molecules)| reads * molecules).sum()
+    }
+
+    /// Expected unique molecules when every read is kept with probability `fraction`
+    fn expected_unique(&self, fraction: f64) -> f64 {
+        self.histogram.iter()
+            .map(|(&reads, &molecules)| molecules as f64 * (1.0 - (1.0 - fraction).powf(reads as f64)))
+            .sum()
+    }
+}
+
+/// Reads of every (barcode, UMI) pair of a BAM file, secondary and supplementary alignments skipped
+fn count_bam_molecules(path: &Path, barcode_tag: &str, umi_tag: &str) -> Result<HashMap<(String, String), u64>, AppError> {
+    let mut reader = hts::open_bam(path)?;
+    let mut counts: HashMap<(String, String), u64> = HashMap::new();
+    let mut record = bam::Record::new();
+    while let Some(result) = reader.read(&mut record) {
+        result?;
+        if record.is_secondary() || record.is_supplementary() {
+            continue;
+        }
+        if let (Ok(Aux::String(barcode)), Ok(Aux::String(umi))) =
+            (record.aux(barcode_tag.as_bytes()), record.aux(umi_tag.as_bytes()))
+        {
+            *counts.entry((barcode.to_string(), umi.to_string())).or_default() += 1;
+        }
+    }
+    Ok(counts)
+}
+
+/// Reads of every (barcode, UMI) pair from `barcode\tumi\treads` lines
+fn read_molecule_table(path: &Path) -> Result<HashMap<(String, String), u64>, AppError> {
+    let mut counts: HashMap<(String, String), u64> = HashMap::new();
+    for (index, line) in open_text(path)?.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = || AppError::IoError(io::Error::new(
+            io::ErrorKind::InvalidData, format!("Invalid molecule table line {}: {}", index + 1, line)
+        ));
+        let mut fields = line.split_whitespace();
+        let (Some(barcode), Some(umi), Some(reads)) = (fields.next(), fields.next(), fields.next()) else {
+            return Err(invalid());
+        };
+        let reads: u64 = match reads.parse() {
+            Ok(reads) => reads,
+            Err(_) if index == 0 => continue,
+            Err(_) => return Err(invalid()),
+        };
+        *counts.entry((barcode.to_string(), umi.to_string())).or_default() += reads;
+    }
+    Ok(counts)
+}
+
+impl SaturationArgs {
+    fn is_bam(&self) -> bool {
+        match self.input_format {
+            SaturationInput::Bam => true,
+            SaturationInput::Table => false,
+            SaturationInput::Auto => self.input.extension()
+                .is_some_and(|ext| ext == "bam" || ext == "sam" || ext == "cram"),
+        }
+    }
+
+    pub fn estimate(self) -> Result<SaturationReport, AppError> {
+        let counts = if self.is_bam() {
+            count_bam_molecules(&self.input, &self.barcode_tag, &self.umi_tag)?
+        } else {
+            read_molecule_table(&self.input)?
+        };
+        let molecules = Molecules::from_counts(counts);
+        let reads = molecules.reads();
+
+        let mut fractions = self.fractions.clone();
+        fractions.sort_by(f64::total_cmp);
+        fractions.dedup();
+        let curve: Vec<SaturationPoint> = fractions.iter().map(|&fraction| {
+            let umis = molecules.expected_unique(fraction);
+            let subsampled = reads as f64 * fraction;
+            SaturationPoint {
+                fraction,
+                reads: subsampled,
+                umis,
+                saturation: if subsampled > 0.0 { 1.0 - umis / subsampled } else { 0.0 },
+            }
+        }).collect();
+
+        write_text(&self.output_dir.join("saturation.tsv"), |writer| {
+            writeln!(writer, "fraction\treads\tumis\tsaturation\tumis_per_barcode")?;
+            for point in &curve {
+                let per_barcode = if molecules.barcodes == 0 { 0.0 } else { point.umis / molecules.barcodes as f64 };
+                writeln!(
+                    writer, "{}\t{:.0}\t{:.1}\t{:.4}\t{:.2}",
+                    point.fraction, point.reads, point.umis, point.saturation, per_barcode,
+                )?;
+            }
+            Ok(())
+        })?;
+
+        let mut plot = LinePlot::new("Sequencing saturation", "Reads", "Unique UMIs");
+        plot.add_series(curve.iter().map(|point| (point.reads, point.umis)).collect(), "steelblue");
+        write_text(&self.output_dir.join("saturation.svg"), |writer| writer.write_all(plot.to_svg().as_bytes()))?;
+
+        // new UMIs per extra read at full depth are the molecules seen once per read (Good-Turing)
+        let singletons = molecules.histogram.get(&1).copied().unwrap_or(0);
+        let umis = molecules.histogram.values().sum::<u64>();
+        Ok(SaturationReport {
+            reads,
+            umis,
+            barcodes: molecules.barcodes,
+            saturation: if reads == 0 { 0.0 } else { 1.0 - umis as f64 / reads as f64 },
+            new_per_read: if reads == 0 { 0.0 } else { singletons as f64 / reads as f64 },
+            target: self.target,
+        })
+    }
+}
+
+/// Expected unique UMIs of the reads kept at one fraction
+struct SaturationPoint {
+    fraction: f64,
+    reads: f64,
+    umis: f64,
+    saturation: f64,
+}
+
+/// Saturation at full depth and the return of further reads
+pub struct SaturationReport {
+    reads: u64,
+    umis: u64,
+    barcodes: u64,
+    saturation: f64,
+    /// expected new UMIs per additional read
+    new_per_read: f64,
+    target: f64,
+}
+
+impl std::fmt::Display for SaturationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Reads={}, UMIs={}, Barcodes={}, Saturation={:.2}%, New UMIs per 100 extra reads={:.1}",
+            self.reads, self.umis, self.barcodes, self.saturation * 100.0, self.new_per_read * 100.0,
+        )?;
+        if self.saturation < self.target {
+            write!(f, "Further sequencing is worthwhile, saturation is below the {:.0}% target", self.target * 100.0)
+        } else {
+            write!(f, "Further sequencing adds few UMIs, saturation reached the {:.0}% target", self.target * 100.0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// 4 molecules of 1 read, 2 of 2 reads and 1 of 4 reads (on two lines) over 2 barcodes
+    const TABLE: &str = "barcode\tumi\treads\nB1\tU1\t1\nB1\tU2\t1\nB1\tU3\t2\nB1\tU4\t3\nB2\tU1\t1\nB2\tU2\t1\nB2\tU3\t2\nB1\tU4\t1\n";
+
+    #[test]
+    fn test_expected_unique() {
+        let molecules = Molecules { histogram: BTreeMap::from([(1, 4), (2, 2), (4, 1)]), barcodes: 2 };
+        assert_eq!(molecules.reads(), 12);
+        assert_eq!(molecules.expected_unique(1.0), 7.0);
+        // every molecule is seen with probability 1 - (1 - fraction)^reads
+        assert_eq!(molecules.expected_unique(0.5), 4.0 * 0.5 + 2.0 * 0.75 + 0.9375);
+        assert!((molecules.expected_unique(0.1) - (0.4 + 2.0 * 0.19 + 0.3439)).abs() < 1e-12);
+        assert_eq!(Molecules::default().expected_unique(0.5), 0.0);
+    }
+
+    #[test]
+    fn test_estimate() {
+        let dir = std::env::temp_dir().join(format!("opentools-test-saturation-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("molecules.tsv");
+        fs::write(&input, TABLE).unwrap();
+        let report = SaturationArgs::try_parse_from([
+            "saturation", "-i", input.to_str().unwrap(), "--fractions", "1,0.5,0.5", "-o", dir.to_str().unwrap(),
+        ]).unwrap().estimate().unwrap();
+        assert_eq!((report.reads, report.umis, report.barcodes), (12, 7, 2));
+        assert_eq!(report.saturation, 1.0 - 7.0 / 12.0);
+        // the 4 single read molecules of 12 reads
+        assert_eq!(report.new_per_read, 4.0 / 12.0);
+        assert_eq!(
+            fs::read_to_string(dir.join("saturation.tsv")).unwrap(),
+            "fraction\treads\tumis\tsaturation\tumis_per_barcode\n0.5\t6\t4.4\t0.2604\t2.22\n1\t12\t7.0\t0.4167\t3.50\n"
+        );
+        assert!(fs::read_to_string(dir.join("saturation.svg")).unwrap().starts_with("<svg"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,142 @@
+use crate::utils::{
+    barcode_iter::validate_absolute_filepath,
+    fastqfile::open_text,
+    label_image::LabelImage,
+    error::AppError,
+};
+use crate::argparse::convert::write_text;
+use std::collections::HashMap;
+use std::io::{self, BufRead};
+use std::path::PathBuf;
+use clap::Parser;
+
+#[derive(Parser, Debug)]
+#[command(name = "segment")]
+#[command(about = "Assign spatial barcodes to the segments of a label image", long_about = None)]
+#[command(next_line_help = true)]
+pub struct SegmentArgs {
+    /// label image of segment ids (PNG or TIFF, e.g. cellpose or stardist masks), 0 is background
+    #[arg(short, long, value_parser = validate_absolute_filepath)]
+    labels: PathBuf,
+
+    /// registered barcode coordinates, `barcode\tx\ty` per line, extra columns and a header line are skipped
+    #[arg(short, long, value_parser = validate_absolute_filepath)]
+    input: PathBuf,
+
+    /// write `barcode\tsegment_id\tx\ty` rows into this file, image pixel coordinates, gzipped when ending with `.gz`
+    #[arg(short, long, help_heading = super::OUTPUTS)]
+    output: PathBuf,
+
+    /// image pixels per unit of the barcode coordinates (e.g. 1/0.6 for µm coordinates on a 0.6 µm image)
+    #[arg(long, default_value_t = 1.0)]
+    scale: f64,
+
+    /// image pixel of coordinate zero along x, after scaling
+    #[arg(long, default_value_t = 0.0, allow_hyphen_values = true)]
+    x_offset: f64,
+
+    /// image pixel of coordinate zero along y, after scaling
+    #[arg(long, default_value_t = 0.0, allow_hyphen_values = true)]
+    y_offset: f64,
+
+    /// also write barcodes on background with segment id 0
+    #[arg(long)]
+    keep_background: bool,
+}
+
+impl SegmentArgs {
+    pub fn segment(self) -> Result<SegmentReport, AppError> {
+        let image = LabelImage::open(&self.labels)?;
+        let invalid = |line: usize| AppError::IoError(io::Error::new(
+            io::ErrorKind::InvalidData, format!("Invalid coordinate line {line} in {}", self.input.display())
+        ));
+
+        let mut report = SegmentReport::default();
+        let mut rows = Vec::new();
+        let mut segments: HashMap<u32, u64> = HashMap::new();
+        for (index, line) in open_text(&self.input)?.lines().enumerate() {
+            let line = line?;
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split('\t');
+            let (Some(barcode), Some(x), Some(y)) = (fields.next(), fields.next(), fields.next()) else {
+                return Err(invalid(index + 1));
+            };
+            let (x, y) = match (x.parse::<f64>(), y.parse::<f64>()) {
+                (Ok(x), Ok(y)) => (x * self.scale + self.x_offset, y * self.scale + self.y_offset),
+                // a non-numeric first line is the header
+                _ if index == 0 => continue,
+                _ => return Err(invalid(index + 1)),
+            };
+            report.barcodes += 1;
+            let (column, row) = (x.floor() as i64, y.floor() as i64);
+            let segment = match image.get(column, row) {
+                Some(0) => {
+                    report.background += 1;
+                    if !self.keep_background {
+                        continue;
+                    }
+                    0
+                }
+                Some(segment) => {
+                    report.assigned += 1;
+                    *segments.entry(segment).or_default() += 1;
+                    segment
+                }
+                None => {
+                    report.outside += 1;
+                    continue;
+                }
+            };
+            rows.push(format!("{}\t{}\t{:.2}\t{:.2}", barcode, segment, x, y));
+        }
+        report.segments = segments.len() as u64;
+        report.median_per_segment = median(segments.into_values().collect());
+
+        write_text(&self.output, |writer| {
+            writeln!(writer, "barcode\tsegment_id\tx\ty")?;
+            for row in &rows {
+                writeln!(writer, "{row}")?;
+            }
+            Ok(())
+        })?;
+        Ok(report)
+    }
+}
+
+fn median(mut values: Vec<u64>) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    values.sort_unstable();
+    let middle = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        (values[middle - 1] + values[middle]) as f64 / 2.0
+    } else {
+        values[middle] as f64
+    }
+}
+
+/// Barcodes assigned to segments and why the others are not
+#[derive(Default)]
+pub struct SegmentReport {
+    barcodes: u64,
+    assigned: u64,
+    background: u64,
+    /// barcodes outside the label image
+    outside: u64,
+    /// segments with at least one barcode
+    segments: u64,
+    median_per_segment: f64,
+}
+
+impl std::fmt::Display for SegmentReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Barcodes={}, Assigned={}, Background={}, Outside image={}\nSegments={}, Median barcodes per segment={}",
+            self.barcodes, self.assigned, self.background, self.outside, self.segments, self.median_per_segment,
+        )
+    }
+}
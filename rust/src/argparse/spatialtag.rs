@@ -0,0 +1,173 @@
+use crate::utils::{
+    threads,
+    hts,
+    barcode_file::BarcodeRecord,
+    barcode_iter::validate_absolute_filepath,
+    fastqfile::open_text,
+    error::AppError,
+};
+use crate::argparse::barcoderank::parse_bam_tag;
+use std::collections::HashMap;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+use clap::Parser;
+use rust_htslib::bam::{self, Read, record::Aux, header::HeaderRecord};
+
+/// Barcode to its (tile, x, y) pixel position, `None` when the barcode map holds it more than once
+pub type BarcodeMap = HashMap<Vec<u8>, Option<(i32, i32, i32)>>;
+
+#[derive(Parser, Debug)]
+#[command(name = "spatialtag")]
+#[command(about = "Tag aligned reads with the spatial position of their barcode", long_about = None)]
+#[command(next_line_help = true)]
+pub struct SpatialTagArgs {
+    /// aligned BAM with the barcode in a tag
+    #[arg(short, long, value_parser = validate_absolute_filepath)]
+    input: PathBuf,
+
+    /// barcode map with `tile_id\tx_pos\ty_pos\tbarcode` rows, e.g. barcode_mapping.txt.gz from dedupbarcode
+    #[arg(short, long, value_parser = validate_absolute_filepath)]
+    barcode_map: PathBuf,
+
+    /// output BAM
+    #[arg(short, long, help_heading = super::OUTPUTS)]
+    output: PathBuf,
+
+    /// SAM tag holding the barcode, e.g. CB for corrected or CR for raw barcodes
+    #[arg(long, default_value = "CB", value_parser = parse_bam_tag)]
+    tag: String,
+
+    /// integer tag of the x position
+    #[arg(long, default_value = "XC", value_parser = parse_bam_tag)]
+    x_tag: String,
+
+    /// integer tag of the y position
+    #[arg(long, default_value = "YC", value_parser = parse_bam_tag)]
+    y_tag: String,
+
+    /// integer tag of the tile id
+    #[arg(long, default_value = "TL", value_parser = parse_bam_tag)]
+    tile_tag: String,
+
+    /// drop reads whose barcode is not found in the barcode map
+    #[arg(long)]
+    drop_unmatched: bool,
+
+    /// BAM compression threads, the global `--threads` or 4 by default
+    #[arg(short = '@', long)]
+    threads: Option<usize>,
+}
+
+/// Load `barcode -> (tile, x, y)` from a barcode file, header and comment lines skipped
+pub fn load_barcode_map(path: &Path) -> Result<BarcodeMap, AppError> {
+    let invalid = |value: &str| AppError::IoError(io::Error::new(
+        io::ErrorKind::InvalidData, format!("Invalid barcode map value: {value}")
+    ));
+    let mut map = BarcodeMap::new();
+    for line in open_text(path)?.lines() {
+        let line = line?;
+        if line.is_empty() || line.starts_with('#') || line.starts_with("tile_id") {
+            continue;
+        }
+        let record = BarcodeRecord::parse(&line)?;
+        let position = (
+            record.tile_id.parse().map_err(|_| invalid(record.tile_id))?,
+            record.x_pos.parse().map_err(|_| invalid(record.x_pos))?,
+            record.y_pos.parse().map_err(|_| invalid(record.y_pos))?,
+        );
+        map.entry(record.barcode.as_bytes().to_vec())
+            .and_modify(|kept| *kept = None)
+            .or_insert(Some(position));
+    }
+    Ok(map)
+}
+
+/// Look up the barcode, retrying without a `-1` like suffix added by cellranger or STARsolo
+pub fn lookup<'a>(map: &'a BarcodeMap, barcode: &[u8]) -> Option<&'a Option<(i32, i32, i32)>> {
+    map.get(barcode).or_else(|| {
+        let dash = barcode.iter().rposition(|&b| b == b'-')?;
+        barcode[dash + 1..].iter().all(u8::is_ascii_digit).then(|| map.get(&barcode[..dash]))?
+    })
+}
+
+impl SpatialTagArgs {
+    pub fn tag(self) -> Result<SpatialTagReport, AppError> {
+        let map = load_barcode_map(&self.barcode_map)?;
+
+        let threads = threads::resolve(self.threads, 4);
+        let mut reader = hts::open_bam(&self.input)?;
+        reader.set_threads(threads)?;
+        let mut header = bam::Header::from_template(reader.header());
+        header.push_record(
+            HeaderRecord::new(b"PG")
+                .push_tag(b"ID", "opentools-spatialtag")
+                .push_tag(b"PN", "opentools")
+                .push_tag(b"VN", env!("CARGO_PKG_VERSION")),
+        );
+        let mut writer = bam::Writer::from_path(&self.output, &header, bam::Format::Bam)?;
+        writer.set_threads(threads)?;
+
+        let tags = [self.tile_tag.as_bytes(), self.x_tag.as_bytes(), self.y_tag.as_bytes()];
+        let mut report = SpatialTagReport::default();
+        let mut record = bam::Record::new();
+        while let Some(result) = reader.read(&mut record) {
+            result?;
+            report.total += 1;
+            let position = match record.aux(self.tag.as_bytes()) {
+                Ok(Aux::String(barcode)) => match lookup(&map, barcode.as_bytes()) {
+                    Some(Some(position)) => Some(*position),
+                    Some(None) => {
+                        report.ambiguous += 1;
+                        None
+                    }
+                    None => {
+                        report.unmatched += 1;
+                        None
+                    }
+                },
+                _ => {
+                    report.no_barcode += 1;
+                    None
+                }
+            };
+            match position {
+                Some((tile, x, y)) => {
+                    for (tag, value) in tags.iter().zip([tile, x, y]) {
+                        // replace the tags of an earlier run
+                        if record.aux(tag).is_ok() {
+                            record.remove_aux(tag)?;
+                        }
+                        record.push_aux(tag, Aux::I32(value))?;
+                    }
+                    report.tagged += 1;
+                }
+                None if self.drop_unmatched => continue,
+                None => {}
+            }
+            writer.write(&record)?;
+        }
+        Ok(report)
+    }
+}
+
+/// Reads tagged and why the others are not
+#[derive(Default)]
+pub struct SpatialTagReport {
+    total: u64,
+    tagged: u64,
+    no_barcode: u64,
+    unmatched: u64,
+    /// barcode observed at several positions of the barcode map
+    ambiguous: u64,
+}
+
+impl std::fmt::Display for SpatialTagReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ratio = if self.total == 0 { 0.0 } else { self.tagged as f64 / self.total as f64 };
+        write!(
+            f,
+            "Total={}, Tagged={} ({:.5}), No barcode={}, Unmatched={}, Ambiguous={}",
+            self.total, self.tagged, ratio, self.no_barcode, self.unmatched, self.ambiguous,
+        )
+    }
+}
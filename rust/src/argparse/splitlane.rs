@@ -0,0 +1,180 @@
+use crate::utils::{
+    threads,
+    hts,
+    barcode_iter::{validate_absolute_dirpath, validate_absolute_filepath},
+    fastqfile,
+    atomic_file::{persist, temp_path},
+    error::AppError,
+};
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use clap::Parser;
+use rust_htslib::{bam::{self, Read as _}, bgzf, tpool::ThreadPool};
+use seq_io::fastq::{self, Record};
+
+#[derive(Parser, Debug)]
+#[command(name = "splitlane")]
+#[command(about = "Split FASTQ or BAM files into per-lane files by the lane field of the Illumina read names", long_about = None)]
+#[command(next_line_help = true)]
+pub struct SplitLaneArgs {
+    /// FASTQ (plain, gzip or bgzf) or BAM file, repeat it for several files (e.g. R1 and R2)
+    ///
+    /// BAM is told by the `.bam` extension
+    #[arg(short, long, required = true, value_parser = validate_absolute_filepath)]
+    input: Vec<PathBuf>,
+
+    /// write `L{lane:03}/{input file name}` into this directory, FASTQ bgzf compressed
+    #[arg(short, long, value_parser = validate_absolute_dirpath, help_heading = super::OUTPUTS)]
+    output_dir: PathBuf,
+
+    /// compression and decompression threads, the global `--threads` or 4 by default
+    #[arg(short = '@', long)]
+    threads: Option<usize>,
+}
+
+#[inline]
+fn is_bam(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "bam")
+}
+
+/// Lane of an Illumina read name `instrument:run:flowcell:lane:tile:x:y`
+fn lane_of(name: &[u8]) -> Result<u32, AppError> {
+    name.split(|&b| b == b':')
+        .nth(3)
+        .and_then(|lane| std::str::from_utf8(lane).ok()?.parse().ok())
+        .ok_or_else(|| AppError::IoError(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("read `{}` has no Illumina lane field", String::from_utf8_lossy(name)),
+        )))
+}
+
+impl SplitLaneArgs {
+    /// Output path of a lane, created on first use
+    fn lane_path(&self, input: &Path, lane: u32) -> Result<PathBuf, AppError> {
+        let dir = self.output_dir.join(format!("L{lane:03}"));
+        fs::create_dir_all(&dir)?;
+        Ok(dir.join(Self::output_name(input)))
+    }
+
+    /// Output file name of an input, FASTQ always gets the `.gz` extension
+    fn output_name(input: &Path) -> OsString {
+        let mut name = input.file_name().unwrap_or_default().to_os_string();
+        if !is_bam(input) && input.extension().is_none_or(|ext| ext != "gz") {
+            name.push(".gz");
+        }
+        name
+    }
+
+    fn split_fastq(&self, input: &Path, pool: &ThreadPool) -> Result<BTreeMap<u32, u64>, AppError> {
+        // bgzf blocks are decompressed by the thread pool, plain gzip on the current thread
+        if bgzf::is_bgzip(input)? {
+            let mut reader = bgzf::Reader::from_path(input)?;
+            reader.set_thread_pool(pool)?;
+            self.split_fastq_records(input, fastq::Reader::new(reader), pool)
+        } else {
+            self.split_fastq_records(input, fastqfile::open(input)?, pool)
+        }
+    }
+
+    fn split_fastq_records<R: Read>(
+        &self,
+        input: &Path,
+        mut reader: fastq::Reader<R>,
+        pool: &ThreadPool,
+    ) -> Result<BTreeMap<u32, u64>, AppError> {
+        let mut writers: BTreeMap<u32, (PathBuf, bgzf::Writer)> = BTreeMap::new();
+        let mut reads: BTreeMap<u32, u64> = BTreeMap::new();
+        while let Some(record) = reader.next() {
+            let record = record?;
+            let lane = lane_of(record.id_bytes())?;
+            let (_, writer) = match writers.get_mut(&lane) {
+                Some(writer) => writer,
+                None => {
+                    let path = self.lane_path(input, lane)?;
+                    let mut writer = bgzf::Writer::from_path(temp_path(&path))?;
+                    writer.set_thread_pool(pool)?;
+                    writers.entry(lane).or_insert((path, writer))
+                }
+            };
+            record.write_unchanged(&mut *writer)?;
+            *reads.entry(lane).or_default() += 1;
+        }
+        for (_, (path, mut writer)) in writers {
+            writer.flush()?;
+            drop(writer);
+            persist(&path)?;
+        }
+        Ok(reads)
+    }
+
+    fn split_bam(&self, input: &Path, pool: &ThreadPool) -> Result<BTreeMap<u32, u64>, AppError> {
+        let mut reader = hts::open_bam(input)?;
+        reader.set_thread_pool(pool)?;
+        let header = bam::Header::from_template(reader.header());
+        let mut writers: BTreeMap<u32, (PathBuf, bam::Writer)> = BTreeMap::new();
+        let mut reads: BTreeMap<u32, u64> = BTreeMap::new();
+        let mut record = bam::Record::new();
+        while let Some(result) = reader.read(&mut record) {
+            result?;
+            let lane = lane_of(record.qname())?;
+            let (_, writer) = match writers.get_mut(&lane) {
+                Some(writer) => writer,
+                None => {
+                    let path = self.lane_path(input, lane)?;
+                    let mut writer = bam::Writer::from_path(temp_path(&path), &header, bam::Format::Bam)?;
+                    writer.set_thread_pool(pool)?;
+                    writers.entry(lane).or_insert((path, writer))
+                }
+            };
+            writer.write(&record)?;
+            *reads.entry(lane).or_default() += 1;
+        }
+        for (_, (path, writer)) in writers {
+            drop(writer);
+            persist(&path)?;
+        }
+        Ok(reads)
+    }
+
+    pub fn split(self) -> Result<SplitLaneReport, AppError> {
+        let mut names = HashSet::new();
+        if let Some(input) = self.input.iter().find(|input| !names.insert(Self::output_name(input))) {
+            return Err(AppError::IoError(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} has the output name of an earlier input", input.display()),
+            )));
+        }
+        let pool = ThreadPool::new(threads::resolve(self.threads, 4) as u32)?;
+        let mut report = SplitLaneReport::default();
+        for input in &self.input {
+            let reads = if is_bam(input) {
+                self.split_bam(input, &pool)?
+            } else {
+                self.split_fastq(input, &pool)?
+            };
+            report.files.push((input.clone(), reads));
+        }
+        Ok(report)
+    }
+}
+
+/// Reads written per lane of every input
+#[derive(Default)]
+pub struct SplitLaneReport {
+    files: Vec<(PathBuf, BTreeMap<u32, u64>)>,
+}
+
+impl std::fmt::Display for SplitLaneReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "File\tLane\tReads")?;
+        for (input, lanes) in &self.files {
+            for (lane, reads) in lanes {
+                write!(f, "\n{}\t{}\t{}", input.display(), lane, reads)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,151 @@
+use crate::utils::{
+    threads,
+    barcode_iter::{validate_absolute_dirpath, validate_absolute_filepath},
+    fastqfile,
+    atomic_file::{persist, temp_path},
+    error::AppError,
+};
+use crate::argparse::convert::write_text;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use clap::{ArgGroup, Parser};
+use rust_htslib::{bgzf, tpool::ThreadPool};
+
+#[derive(Parser, Debug)]
+#[command(name = "splitpool")]
+#[command(about = "Split paired FASTQ into balanced shards for array jobs, with a manifest of the shards", long_about = None)]
+#[command(next_line_help = true)]
+#[command(group(ArgGroup::new("size").required(true).args(["shards", "reads_per_shard"])))]
+pub struct SplitPoolArgs {
+    /// FASTQ read 1 (optionally gzipped)
+    #[arg(short = '1', long, value_parser = validate_absolute_filepath)]
+    read1: PathBuf,
+
+    /// FASTQ read 2, the mates stay in the shard of their read 1
+    #[arg(short = '2', long, value_parser = validate_absolute_filepath)]
+    read2: Option<PathBuf>,
+
+    /// number of shards, reads are dealt round-robin so shards differ by at most one read
+    #[arg(short = 'n', long, value_parser = clap::value_parser!(u32).range(1..=10000))]
+    shards: Option<u32>,
+
+    /// reads per shard, consecutive reads fill one shard after another
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), value_name = "N")]
+    reads_per_shard: Option<u64>,
+
+    /// write `{prefix}_{shard:04}_R1.fastq.gz`/`_R2.fastq.gz` and `{prefix}_manifest.tsv` into this directory
+    #[arg(short, long, value_parser = validate_absolute_dirpath, help_heading = super::OUTPUTS)]
+    output_dir: PathBuf,
+
+    /// file name prefix of the shards and the manifest
+    #[arg(long, default_value = "shard")]
+    prefix: String,
+
+    /// compression threads, the global `--threads` or 4 by default
+    #[arg(short = '@', long)]
+    threads: Option<usize>,
+}
+
+/// bgzf writers of the reads of one shard
+struct Shard {
+    paths: Vec<PathBuf>,
+    writers: Vec<bgzf::Writer>,
+    reads: u64,
+}
+
+impl SplitPoolArgs {
+    fn create_shard(&self, index: usize, mates: usize, pool: &ThreadPool) -> Result<Shard, AppError> {
+        let mut shard = Shard { paths: Vec::new(), writers: Vec::new(), reads: 0 };
+        for mate in 1..=mates {
+            let path = self.output_dir.join(format!("{}_{:04}_R{}.fastq.gz", self.prefix, index, mate));
+            let mut writer = bgzf::Writer::from_path(temp_path(&path))?;
+            writer.set_thread_pool(pool)?;
+            shard.paths.push(path);
+            shard.writers.push(writer);
+        }
+        Ok(shard)
+    }
+
+    pub fn split(self) -> Result<SplitPoolReport, AppError> {
+        let pool = ThreadPool::new(threads::resolve(self.threads, 4) as u32)?;
+        let mut reader1 = fastqfile::open(&self.read1)?;
+        let mut reader2 = self.read2.as_ref().map(fastqfile::open).transpose()?;
+        let mates = if reader2.is_some() { 2 } else { 1 };
+        let mut shards: Vec<Shard> = Vec::new();
+        if let Some(count) = self.shards {
+            for index in 0..count as usize {
+                shards.push(self.create_shard(index, mates, &pool)?);
+            }
+        }
+
+        let mut reads: u64 = 0;
+        loop {
+            let (record1, record2) = match (reader1.next(), reader2.as_mut().map(|reader| reader.next())) {
+                (Some(record1), None) => (record1?, None),
+                (Some(record1), Some(Some(record2))) => (record1?, Some(record2?)),
+                (None, None | Some(None)) => break,
+                _ => return Err(AppError::IoError(io::Error::new(
+                    io::ErrorKind::UnexpectedEof, "FASTQ pair has different numbers of reads"
+                ))),
+            };
+            let index = match self.reads_per_shard {
+                Some(size) => (reads / size) as usize,
+                None => (reads % shards.len() as u64) as usize,
+            };
+            if index == shards.len() {
+                shards.push(self.create_shard(index, mates, &pool)?);
+            }
+            let shard = &mut shards[index];
+            record1.write_unchanged(&mut shard.writers[0])?;
+            if let Some(record2) = record2 {
+                record2.write_unchanged(&mut shard.writers[1])?;
+            }
+            shard.reads += 1;
+            reads += 1;
+        }
+
+        let mut report = SplitPoolReport { reads, shards: Vec::new() };
+        for shard in shards {
+            for mut writer in shard.writers {
+                writer.flush()?;
+            }
+            for path in &shard.paths {
+                persist(path)?;
+            }
+            report.shards.push((shard.paths, shard.reads));
+        }
+        let manifest = self.output_dir.join(format!("{}_manifest.tsv", self.prefix));
+        write_text(&manifest, |writer| {
+            write!(writer, "shard\treads\tread1")?;
+            if mates == 2 {
+                write!(writer, "\tread2")?;
+            }
+            writeln!(writer)?;
+            for (index, (paths, reads)) in report.shards.iter().enumerate() {
+                write!(writer, "{}\t{}", index, reads)?;
+                for path in paths {
+                    write!(writer, "\t{}", path.display())?;
+                }
+                writeln!(writer)?;
+            }
+            Ok(())
+        })?;
+        Ok(report)
+    }
+}
+
+/// Reads written into every shard
+pub struct SplitPoolReport {
+    reads: u64,
+    shards: Vec<(Vec<PathBuf>, u64)>,
+}
+
+impl std::fmt::Display for SplitPoolReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (min, max) = self.shards.iter().fold((u64::MAX, 0), |(min, max), (_, reads)| (min.min(*reads), max.max(*reads)));
+        write!(
+            f, "Reads={}, Shards={}, Min Reads={}, Max Reads={}",
+            self.reads, self.shards.len(), if self.shards.is_empty() { 0 } else { min }, max,
+        )
+    }
+}
@@ -0,0 +1,208 @@
+use crate::utils::{
+    barcode_file::BarcodeRecord,
+    barcode_iter::validate_absolute_filepath,
+    coordinate::tile_grid,
+    fastqfile::open_text,
+    error::AppError,
+};
+use crate::argparse::convert::write_text;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+use clap::Parser;
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Axis {
+    X,
+    Y,
+}
+
+/// Placement of tiles and lane surfaces in the stitched coordinates, read from a TOML file
+///
+/// The default places swaths along x and tiles along y with no gaps, same as `puck_collection.tsv.gz`
+/// of dedupbarcode, each lane surface in coordinates of its own
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct FlowcellLayout {
+    /// tile width and height in pixels
+    tile_size: [f64; 2],
+    /// pixels between neighbouring tiles along x and y
+    tile_gap: [f64; 2],
+    um_per_pixel: f64,
+    /// axis the tile numbers run along, swaths run along the other one
+    tile_axis: Axis,
+    /// tile numbers count down along the tile axis
+    reverse_tiles: bool,
+    /// swath numbers count down along the swath axis
+    reverse_swaths: bool,
+    /// swaths and tiles of a lane surface, the largest numbers of the input by default
+    swaths: Option<u64>,
+    tiles: Option<u64>,
+    /// place lane surfaces one after another along the swath axis instead of each on its own
+    stack_surfaces: bool,
+    /// pixels between stacked lane surfaces
+    surface_gap: f64,
+}
+
+impl Default for FlowcellLayout {
+    fn default() -> Self {
+        Self {
+            tile_size: [33000.0, 37100.0],
+            tile_gap: [0.0, 0.0],
+            um_per_pixel: 0.6,
+            tile_axis: Axis::Y,
+            reverse_tiles: false,
+            reverse_swaths: false,
+            swaths: None,
+            tiles: None,
+            stack_surfaces: false,
+            surface_gap: 0.0,
+        }
+    }
+}
+
+impl FlowcellLayout {
+    pub fn load(path: &Path) -> Result<Self, AppError> {
+        toml::from_str(&fs::read_to_string(path)?).map_err(|err| AppError::IoError(io::Error::new(
+            io::ErrorKind::InvalidData, format!("Invalid layout {}: {}", path.display(), err.message())
+        )))
+    }
+
+    /// Size of a tile step along the swath and the tile axis, gap included
+    fn steps(&self) -> (f64, f64) {
+        let step_x = self.tile_size[0] + self.tile_gap[0];
+        let step_y = self.tile_size[1] + self.tile_gap[1];
+        match self.tile_axis {
+            Axis::Y => (step_x, step_y),
+            Axis::X => (step_y, step_x),
+        }
+    }
+
+    /// Stitched µm position of a pixel of a tile, `rank` orders the stacked lane surfaces
+    fn place(&self, tile_id: u64, rank: u64, swaths: u64, tiles: u64, x_pos: f64, y_pos: f64) -> (f64, f64) {
+        let (_, swath, tile) = tile_grid(tile_id);
+        let swath = if self.reverse_swaths { swaths.saturating_sub(swath) } else { swath.saturating_sub(1) };
+        let tile = if self.reverse_tiles { tiles.saturating_sub(tile) } else { tile.saturating_sub(1) };
+        let (swath_step, tile_step) = self.steps();
+        let surface_offset = rank as f64 * (swaths as f64 * swath_step + self.surface_gap);
+        let along_swaths = surface_offset + swath as f64 * swath_step;
+        let along_tiles = tile as f64 * tile_step;
+        let (x, y) = match self.tile_axis {
+            Axis::Y => (along_swaths + x_pos, along_tiles + y_pos),
+            Axis::X => (along_tiles + x_pos, along_swaths + y_pos),
+        };
+        (x * self.um_per_pixel, y * self.um_per_pixel)
+    }
+}
+
+#[derive(Parser, Debug)]
+#[command(name = "stitch")]
+#[command(about = "Stitch per tile barcode positions into global µm coordinates of the flowcell", long_about = None)]
+#[command(next_line_help = true)]
+pub struct StitchArgs {
+    /// The path to the barcode file (optionally gzipped)
+    #[arg(short = 'I', long, value_parser = validate_absolute_filepath)]
+    barcode_file: PathBuf,
+
+    /// TOML file of the flowcell layout, swaths along x and tiles along y at 0.6 µm per pixel by default
+    ///
+    /// keys: tile_size = [width, height], tile_gap = [x, y], um_per_pixel, tile_axis = "x"/"y",
+    /// reverse_tiles, reverse_swaths, swaths, tiles, stack_surfaces, surface_gap
+    #[arg(long, value_parser = validate_absolute_filepath)]
+    layout: Option<PathBuf>,
+
+    /// output `barcode\tx_um\ty_um\ttile` coordinates, gzipped when the path ends with `.gz`
+    #[arg(short, long, help_heading = super::OUTPUTS)]
+    output: PathBuf,
+}
+
+impl StitchArgs {
+    pub fn stitch(self) -> Result<StitchReport, AppError> {
+        let layout = match &self.layout {
+            Some(path) => FlowcellLayout::load(path)?,
+            None => FlowcellLayout::default(),
+        };
+        let invalid = |line: usize| io::Error::new(
+            io::ErrorKind::InvalidData, format!("Invalid barcode file line {line} in {}", self.barcode_file.display())
+        );
+        let skipped = |line: &str| line.is_empty() || line.starts_with('#') || line.starts_with("tile_id");
+
+        // first pass for the extent of the lane surfaces
+        let mut report = StitchReport::default();
+        for (index, line) in open_text(&self.barcode_file)?.lines().enumerate() {
+            let line = line?;
+            if skipped(&line) {
+                continue;
+            }
+            let tile_id: u64 = BarcodeRecord::parse(&line)?.tile_id.parse().map_err(|_| invalid(index + 1))?;
+            let (lane_surface, swath, tile) = tile_grid(tile_id);
+            let extent = report.surfaces.entry(lane_surface).or_default();
+            extent.0 = extent.0.max(swath);
+            extent.1 = extent.1.max(tile);
+        }
+        let swaths = layout.swaths.unwrap_or_else(|| report.surfaces.values().map(|extent| extent.0).max().unwrap_or(1));
+        let tiles = layout.tiles.unwrap_or_else(|| report.surfaces.values().map(|extent| extent.1).max().unwrap_or(1));
+        let ranks: BTreeMap<u64, u64> = report.surfaces.keys().enumerate()
+            .map(|(rank, &lane_surface)| (lane_surface, if layout.stack_surfaces { rank as u64 } else { 0 }))
+            .collect();
+
+        let mut barcodes = 0;
+        let mut bounds = [f64::INFINITY, f64::INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY];
+        let reader = open_text(&self.barcode_file)?;
+        write_text(&self.output, |writer| {
+            writeln!(writer, "barcode\tx_um\ty_um\ttile")?;
+            for (index, line) in reader.lines().enumerate() {
+                let line = line?;
+                if skipped(&line) {
+                    continue;
+                }
+                let record = BarcodeRecord::parse(&line).map_err(|_| invalid(index + 1))?;
+                let (Ok(tile_id), Ok(x_pos), Ok(y_pos)) = (
+                    record.tile_id.parse::<u64>(), record.x_pos.parse::<f64>(), record.y_pos.parse::<f64>(),
+                ) else {
+                    return Err(invalid(index + 1));
+                };
+                let rank = ranks[&tile_grid(tile_id).0];
+                let (x_um, y_um) = layout.place(tile_id, rank, swaths, tiles, x_pos, y_pos);
+                writeln!(writer, "{}\t{:.2}\t{:.2}\t{}", record.barcode, x_um, y_um, tile_id)?;
+                bounds = [bounds[0].min(x_um), bounds[1].min(y_um), bounds[2].max(x_um), bounds[3].max(y_um)];
+                barcodes += 1;
+            }
+            Ok(())
+        })?;
+        report.barcodes = barcodes;
+        report.bounds = (barcodes > 0).then_some(bounds);
+        report.stacked = layout.stack_surfaces;
+        Ok(report)
+    }
+}
+
+/// Stitched barcodes and the extent of the coordinates
+#[derive(Default)]
+pub struct StitchReport {
+    barcodes: u64,
+    /// largest swath and tile number of every lane surface
+    surfaces: BTreeMap<u64, (u64, u64)>,
+    /// x min, y min, x max, y max in µm
+    bounds: Option<[f64; 4]>,
+    stacked: bool,
+}
+
+impl std::fmt::Display for StitchReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Barcodes={}, Lane surfaces={}", self.barcodes, self.surfaces.len())?;
+        if self.stacked {
+            write!(f, " (stacked)")?;
+        }
+        for (lane_surface, (swaths, tiles)) in &self.surfaces {
+            write!(f, "\nLane {} surface {}: swaths={}, tiles={}", lane_surface / 10, lane_surface % 10, swaths, tiles)?;
+        }
+        if let Some([x_min, y_min, x_max, y_max]) = self.bounds {
+            write!(f, "\nx_um={:.2}-{:.2}, y_um={:.2}-{:.2}", x_min, x_max, y_min, y_max)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,228 @@
+use crate::utils::{
+    barcode_file::BarcodeRecord,
+    barcode_iter::{validate_absolute_dirpath, validate_absolute_filepath},
+    coordinate::{tile_grid, TileSize},
+    fastqfile::open_text,
+    atomic_file::{persist, temp_path},
+    error::AppError,
+};
+use crate::argparse::tilesmatch::is_valid_tile_id;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, BufRead, BufWriter};
+use std::path::{Path, PathBuf};
+use clap::Parser;
+
+#[derive(Parser, Debug)]
+#[command(name = "tileimage")]
+#[command(about = "Render barcode density of tiles or whole lane surfaces into PNG images", long_about = None)]
+#[command(next_line_help = true)]
+pub struct TileImageArgs {
+    /// The path to the barcode file
+    #[arg(short = 'I', long, value_parser = validate_absolute_filepath)]
+    barcode_file: PathBuf,
+
+    /// the tile id list to render, all tiles in the barcode file by default
+    #[arg(
+        long,
+        value_delimiter = ' ',
+        num_args = 1..,
+        value_parser = is_valid_tile_id,
+    )]
+    tile_list: Vec<u64>,
+
+    /// barcodes colored red by the share of them found in this file, one barcode in the first column per line
+    ///
+    /// (e.g. query barcodes of tilesmatch, or the whitelist of dedupbarcode)
+    #[arg(long, value_parser = validate_absolute_filepath, value_name = "FILE")]
+    matched: Option<PathBuf>,
+
+    /// render one `lane{lane}_surface{surface}.png` per lane surface with tiles placed by swath and tile number,
+    /// instead of one `{tile_id}.png` per tile
+    #[arg(long)]
+    flowcell: bool,
+
+    /// barcode pixels per image pixel, 50 for tiles and 500 for lane surfaces by default
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    bin: Option<u32>,
+
+    /// tile size in pixels
+    #[arg(long, default_value_t = TileSize { width: 33000.0, height: 37100.0 }, value_name = "WIDTH,HEIGHT")]
+    tile_size: TileSize,
+
+    /// write the PNG images into this directory
+    #[arg(short, long, value_parser = validate_absolute_dirpath, help_heading = super::OUTPUTS)]
+    output_dir: PathBuf,
+}
+
+/// Barcodes and matched barcodes of the image pixels holding any, keyed by (x, y)
+type SparseCounts = HashMap<(u32, u32), (u32, u32)>;
+
+/// Barcodes and matched barcodes per image pixel
+struct Density {
+    width: u32,
+    height: u32,
+    counts: Vec<(u32, u32)>,
+}
+
+impl Density {
+    fn new(width: u32, height: u32) -> Self {
+        Self { width, height, counts: vec![(0, 0); width as usize * height as usize] }
+    }
+
+    #[inline]
+    fn add(&mut self, x: u32, y: u32, matched: bool) {
+        if x < self.width && y < self.height {
+            let count = &mut self.counts[y as usize * self.width as usize + x as usize];
+            count.0 += 1;
+            count.1 += matched as u32;
+        }
+    }
+
+    /// Log scaled brightness of the density on black, white shifted to red by the matched share
+    fn render(&self) -> Vec<u8> {
+        let max = self.counts.iter().map(|count| count.0).max().unwrap_or(0);
+        let scale = (max as f64).ln_1p().max(f64::MIN_POSITIVE);
+        let mut pixels = Vec::with_capacity(self.counts.len() * 3);
+        for &(total, matched) in &self.counts {
+            let brightness = (total as f64).ln_1p() / scale * 255.0;
+            let unmatched = if total == 0 { 1.0 } else { 1.0 - matched as f64 / total as f64 };
+            pixels.extend([brightness as u8, (brightness * unmatched) as u8, (brightness * unmatched) as u8]);
+        }
+        pixels
+    }
+
+    fn save(&self, path: &Path) -> Result<(), AppError> {
+        let image_error = |err: png::EncodingError| AppError::ImageError(format!("{}: {}", path.display(), err));
+        let file = BufWriter::new(fs::File::create(temp_path(path))?);
+        let mut encoder = png::Encoder::new(file, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().map_err(image_error)?;
+        writer.write_image_data(&self.render()).map_err(image_error)?;
+        writer.finish().map_err(image_error)?;
+        persist(path)?;
+        Ok(())
+    }
+}
+
+/// Barcodes of the first column, header and comment lines skipped
+pub fn load_barcodes(path: &Path) -> Result<HashSet<String>, AppError> {
+    let mut barcodes = HashSet::new();
+    for line in open_text(path)?.lines() {
+        let line = line?;
+        if let Some(barcode) = line.split('\t').next().filter(|b| !b.is_empty() && !b.starts_with('#')) {
+            barcodes.insert(barcode.to_string());
+        }
+    }
+    Ok(barcodes)
+}
+
+impl TileImageArgs {
+    pub fn render(self) -> Result<TileImageReport, AppError> {
+        let matched = match &self.matched {
+            Some(path) => Some(load_barcodes(path)?),
+            None => None,
+        };
+        let bin = self.bin.unwrap_or(if self.flowcell { 500 } else { 50 }) as f64;
+        let invalid = |line: usize| AppError::IoError(io::Error::new(
+            io::ErrorKind::InvalidData, format!("Invalid barcode file line {line} in {}", self.barcode_file.display())
+        ));
+        let (tile_width, tile_height) = (
+            (self.tile_size.width / bin).ceil() as u32,
+            (self.tile_size.height / bin).ceil() as u32,
+        );
+
+        let mut report = TileImageReport::default();
+        // lane surface images are sized once all of their tiles are seen
+        let mut surfaces: HashMap<u64, SparseCounts> = HashMap::new();
+        let mut current: Option<(u64, Density)> = None;
+        let mut rendered = HashSet::new();
+        for (index, line) in open_text(&self.barcode_file)?.lines().enumerate() {
+            let line = line?;
+            if line.is_empty() || line.starts_with('#') || line.starts_with("tile_id") {
+                continue;
+            }
+            let record = BarcodeRecord::parse(&line)?;
+            let (Ok(tile_id), Ok(x_pos), Ok(y_pos)) = (
+                record.tile_id.parse::<u64>(), record.x_pos.parse::<f64>(), record.y_pos.parse::<f64>(),
+            ) else {
+                return Err(invalid(index + 1));
+            };
+            if !self.tile_list.is_empty() && !self.tile_list.contains(&tile_id) {
+                continue;
+            }
+            report.barcodes += 1;
+            let is_matched = matched.as_ref().is_some_and(|matched| matched.contains(record.barcode));
+            report.matched += is_matched as u64;
+            let (x, y) = ((x_pos / bin) as u32, (y_pos / bin) as u32);
+
+            if self.flowcell {
+                let (lane_surface, swath, tile) = tile_grid(tile_id);
+                let x = swath.saturating_sub(1) as u32 * tile_width + x.min(tile_width - 1);
+                let y = tile.saturating_sub(1) as u32 * tile_height + y.min(tile_height - 1);
+                let count = surfaces.entry(lane_surface).or_default().entry((x, y)).or_default();
+                count.0 += 1;
+                count.1 += is_matched as u32;
+                rendered.insert(tile_id);
+                continue;
+            }
+            // tiles are contiguous in a sorted barcode file, each one is written once complete
+            if current.as_ref().is_none_or(|(current_tile, _)| *current_tile != tile_id) {
+                if let Some((tile, density)) = current.take() {
+                    density.save(&self.output_dir.join(format!("{tile}.png")))?;
+                    report.images += 1;
+                }
+                if !rendered.insert(tile_id) {
+                    return Err(AppError::IoError(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{} is not sorted by tile, rows of tile {} are split", self.barcode_file.display(), tile_id),
+                    )));
+                }
+                current = Some((tile_id, Density::new(tile_width, tile_height)));
+            }
+            if let Some((_, density)) = current.as_mut() {
+                density.add(x, y, is_matched);
+            }
+        }
+        if let Some((tile, density)) = current {
+            density.save(&self.output_dir.join(format!("{tile}.png")))?;
+            report.images += 1;
+        }
+
+        for (lane_surface, counts) in surfaces {
+            let swaths = counts.keys().map(|&(x, _)| x / tile_width + 1).max().unwrap_or(1);
+            let tiles = counts.keys().map(|&(_, y)| y / tile_height + 1).max().unwrap_or(1);
+            let mut density = Density::new(swaths * tile_width, tiles * tile_height);
+            for ((x, y), count) in counts {
+                density.counts[y as usize * density.width as usize + x as usize] = count;
+            }
+            let name = format!("lane{}_surface{}.png", lane_surface / 10, lane_surface % 10);
+            density.save(&self.output_dir.join(name))?;
+            report.images += 1;
+        }
+        report.tiles = rendered.len() as u64;
+        report.colored = matched.is_some();
+        Ok(report)
+    }
+}
+
+/// Barcodes drawn and images written
+#[derive(Default)]
+pub struct TileImageReport {
+    barcodes: u64,
+    matched: u64,
+    tiles: u64,
+    images: u64,
+    colored: bool,
+}
+
+impl std::fmt::Display for TileImageReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Barcodes={}, Tiles={}, Images={}", self.barcodes, self.tiles, self.images)?;
+        if self.colored {
+            write!(f, ", Matched={}", self.matched)?;
+        }
+        Ok(())
+    }
+}
@@ -1,17 +1,34 @@
 
 use crate::argparse::touchbarcode::{validate_barcode_pattern};
 use crate::utils::{
-    fastqfile::{open, FastqReader},
+    fastqfile::{open, open_text, pattern_diversity, FastqReader},
+    spill::{bucket_of, parse_memory_size, scratch_dir, SortedRuns, SpillBuckets},
     position::Position,
-    barcode_iter::{validate_absolute_filepath, BarcodesIter},
+    chemistry::{self, BarcodeConfig, Chemistry, OpenSt},
+    barcode_file::{barcode_field, TabixPool},
+    barcode_index::BarcodeIndex,
+    progress::Tracker,
+    seed,
+    buffers,
+    coordinate::tile_distance,
+    barcode_iter::{validate_absolute_dirpath, validate_absolute_filepath, validate_filepath_or_stdin, BarcodesIter},
     error::AppError,
+    packed::PackedBarcode,
 };
-use std::io;
-use std::path::PathBuf;
-use std::collections::HashSet;
-use clap::{Parser, ValueEnum};
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::fs;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+use clap::Parser;
 use rayon::prelude::*;
-use rust_htslib::tbx::{self, Read};
+use serde::Serialize;
+use rust_htslib::tbx::Read;
+use tracing::debug;
 
 pub fn is_valid_tile_id(value: &str) -> Result<u64, String> {
     let tile_id: u64 = value.parse()
@@ -24,7 +41,48 @@ pub fn is_valid_tile_id(value: &str) -> Result<u64, String> {
     }
 }
 
-const VALID_TILE_IDS: [u64; 3744] = {
+/// A tile id given on the command line, or a file listing tile ids
+#[derive(Clone, Debug)]
+pub enum TileSource {
+    Id(u64),
+    File(PathBuf),
+}
+
+pub fn is_valid_tile_or_file(value: &str) -> Result<TileSource, String> {
+    let path = Path::new(value);
+    if path.is_file() {
+        Ok(TileSource::File(path.to_path_buf()))
+    } else {
+        is_valid_tile_id(value).map(TileSource::Id)
+    }
+}
+
+/// Resolve tile ids and tile list files (whitespace separated, `#` starts a comment line) into tile ids
+pub fn resolve_tile_sources(sources: &[TileSource]) -> Result<Vec<u64>, AppError> {
+    let mut tile_ids = Vec::new();
+    for source in sources {
+        match source {
+            TileSource::Id(tile_id) => tile_ids.push(*tile_id),
+            TileSource::File(path) => {
+                for line in open_text(path)?.lines() {
+                    let line = line?;
+                    if line.starts_with('#') {
+                        continue;
+                    }
+                    for value in line.split_whitespace() {
+                        let tile_id = is_valid_tile_id(value).map_err(
+                            |e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {e}", path.display()))
+                        )?;
+                        tile_ids.push(tile_id);
+                    }
+                }
+            }
+        }
+    }
+    Ok(tile_ids)
+}
+
+static VALID_TILE_IDS: [u64; 3744] = {
     // Array size: 4 × 2 × 6 × 78 = 3744
     let mut result = [0u64; 3744];
     let mut index = 0;
@@ -59,22 +117,35 @@ const VALID_TILE_IDS: [u64; 3744] = {
 )]
 #[command(next_line_help = true)]
 pub struct TilesMatchArgs {
-    /// Generally Read1 fastq file
+    /// Generally Read1 fastq file, `-` for stdin
     #[arg(
         short = 'R', 
         long, 
-        required = true,
+        required_unless_present = "query_barcodes",
+        conflicts_with = "query_barcodes",
     )]
-    read: PathBuf,
+    read: Option<PathBuf>,
 
-    /// The path to the barcode file
+    /// Query barcodes from a text file instead of fastq, one barcode per line (optionally gzipped)
+    /// 
+    /// (e.g. barcode_whitelist.txt from dedupbarcode, `-` for stdin)
+    #[arg(
+        long, 
+        value_parser = validate_filepath_or_stdin,
+        value_name = "FILE",
+    )]
+    query_barcodes: Option<PathBuf>,
+
+    /// The path to the barcode file, repeat it for chips from several flowcells
+    /// 
+    /// (e.g. "-I chip_a/barcodes.txt.gz -I chip_b/barcodes.txt.gz")
     #[arg(
         short = 'I', 
         long, 
         required = true, 
         value_parser = validate_absolute_filepath,
     )]
-    barcode_file: PathBuf,
+    barcode_file: Vec<PathBuf>,
 
     /// the tile id list to query
     #[arg(
@@ -85,21 +156,76 @@ pub struct TilesMatchArgs {
     )]
     tile_list: Option<Vec<u64>>,
 
+    /// the tile ids to skip, or files listing them (e.g. edge tiles or bubbles flagged by InterOp)
+    #[arg(
+        long, 
+        value_delimiter = ' ',
+        num_args = 1..,
+        value_parser = is_valid_tile_or_file,
+        value_name = "TILE_ID|FILE",
+    )]
+    exclude_tiles: Vec<TileSource>,
+
     /// the number of barcodes used to query
     #[arg(short, long, default_value_t = 100_000_000)]
     num_barcode: usize,
 
+    /// bound the memory of the query barcodes by spilling them into temporary files (e.g. 4G)
+    /// 
+    /// past half this size the query barcodes are partitioned by hash on disk, then the tiles are searched
+    /// once per partition, only holding its share of the tile barcodes; nothing is spilled when they fit
+    #[arg(long, value_parser = parse_memory_size, value_name = "SIZE")]
+    max_memory: Option<u64>,
+
     /// the threshold to filter tile
     #[arg(long, default_value_t = 0.1)]
     threshold: f32,
 
+    /// the minimum number of matched barcodes, must be satisfied in addition to threshold
+    #[arg(long, default_value_t = 0, value_name = "N")]
+    min_matched: usize,
+
+    /// write the reports of all tiles into this file as JSON
+    #[arg(long, value_name = "FILE", help_heading = super::OUTPUTS)]
+    json: Option<PathBuf>,
+
+    /// record per-tile barcode load time, intersection time and peak set size in the JSON output
+    #[arg(long, requires = "json")]
+    metrics: bool,
+
+    /// report the expected random matches from the pattern diversity, the enrichment over it and -log10(p-value)
+    #[arg(long)]
+    background: bool,
+
+    /// split query barcodes into N subsamples and report mean match ratio with its 95% confidence interval
+    #[arg(long, value_parser = clap::value_parser!(u64).range(2..), value_name = "N")]
+    replicates: Option<u64>,
+
+    /// also select tiles within K grid steps (same lane and surface) of any passed tile
+    #[arg(long, default_value_t = 0, value_name = "K")]
+    expand: u64,
+
     /// turn on it to output tile id that passed threshold.
     #[arg(short, long)]
     quiet: bool,
 
+    /// write tile ids that passed threshold (or selected by `--expand`) into this file, one per line.
+    /// 
+    /// (e.g. `--tile-list $(cat tiles.txt)` in dedupbarcode)
+    #[arg(long, value_name = "FILE", help_heading = super::OUTPUTS)]
+    passed_out: Option<PathBuf>,
+
+    /// write the rows (tile_id, x, y, barcode) matched by query barcodes of each passed tile into this directory
+    /// 
+    /// one `{tile_id}.matched.txt` per tile, prefixed with `file{N}_` when several barcode files are given
+    #[arg(long, value_parser = validate_absolute_dirpath, value_name = "DIR", help_heading = super::OUTPUTS)]
+    emit_matched: Option<PathBuf>,
+
     /// barcode/UMI parsing mode
-    #[arg(short, long, value_enum, default_value_t = BarcodeMode::Openst)]
-    mode: BarcodeMode,
+    ///
+    /// `openst`, a chemistry of a `[chemistry.NAME]` table in the `--config` TOML, or `custom`
+    #[arg(short, long, default_value = "openst")]
+    mode: String,
 
     /// Custom barcode position (only effective when mode=custom)
     /// 
@@ -133,59 +259,265 @@ impl TilesMatchArgs {
     pub fn init(self) -> Result<InitTilesMatchArgs, AppError> {
         let (pos, pattern) = match (self.barcode_pos, self.barcode_pattern) {
             (Some(pos), Some(pattern)) => (pos, pattern),
-            (None, None) => BarcodeMode::openst(),
+            (None, None) => chemistry::lookup(&self.mode)?.library_barcode(),
             _ => unreachable!("clap parse the error is impossible.")
         };
-        let tile_list = if let Some(list) = self.tile_list {
+        let query = match (self.read, self.query_barcodes) {
+            (Some(read), None) => QueryInput::Fastq(read),
+            (None, Some(file)) => QueryInput::Barcodes(file),
+            _ => unreachable!("clap parse the error is impossible.")
+        };
+        let mut tile_list = if let Some(list) = self.tile_list {
             list
         } else {
             // 直接返回预生成的常量数组
             VALID_TILE_IDS.to_vec()
         };
+        let exclude_tiles: HashSet<u64> = resolve_tile_sources(&self.exclude_tiles)?.into_iter().collect();
+        tile_list.retain(|tile_id| !exclude_tiles.contains(tile_id));
         
         Ok(InitTilesMatchArgs::new(
-            self.read, 
+            query, 
             self.barcode_file, 
             tile_list, 
             self.num_barcode, 
+            self.max_memory,
             self.threshold,
+            self.min_matched,
+            self.expand,
+            self.json,
+            self.metrics,
+            self.background,
+            self.replicates,
             self.quiet,
+            self.passed_out,
+            self.emit_matched,
             pos,
             pattern,
         ))
     }
 }
 
+/// Tile search for library use, set up by its builder methods instead of command line arguments
+///
+/// ```no_run
+/// use opentools::TileMatcher;
+///
+/// let reports = TileMatcher::builder()
+///     .read("/data/R1.fastq.gz")
+///     .barcode_file("/data/barcodes.txt.gz")
+///     .threshold(0.2)
+///     .run()?;
+/// # Ok::<(), opentools::AppError>(())
+/// ```
+pub struct TileMatcher {
+    query: Option<QueryInput>,
+    barcode_file: Vec<PathBuf>,
+    tile_list: Option<Vec<u64>>,
+    exclude_tiles: Vec<u64>,
+    num_barcode: usize,
+    max_memory: Option<u64>,
+    threshold: f32,
+    min_matched: usize,
+    expand: u64,
+    background: bool,
+    replicates: Option<u64>,
+    barcode: Option<BarcodeConfig>,
+}
+
+impl TileMatcher {
+    /// Search every valid tile with the OpenST barcode and the defaults of `tilesmatch`
+    pub fn builder() -> Self {
+        Self {
+            query: None,
+            barcode_file: Vec::new(),
+            tile_list: None,
+            exclude_tiles: Vec::new(),
+            num_barcode: 100_000_000,
+            max_memory: None,
+            threshold: 0.1,
+            min_matched: 0,
+            expand: 0,
+            background: false,
+            replicates: None,
+            barcode: None,
+        }
+    }
+
+    /// FASTQ the query barcodes are extracted from
+    pub fn read(mut self, path: impl Into<PathBuf>) -> Self {
+        self.query = Some(QueryInput::Fastq(path.into()));
+        self
+    }
+
+    /// Text file of query barcodes, one per line
+    pub fn query_barcodes(mut self, path: impl Into<PathBuf>) -> Self {
+        self.query = Some(QueryInput::Barcodes(path.into()));
+        self
+    }
+
+    /// Barcode file searched, call it again for chips from several flowcells
+    pub fn barcode_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.barcode_file.push(path.into());
+        self
+    }
+
+    pub fn tile_list(mut self, tile_ids: Vec<u64>) -> Self {
+        self.tile_list = Some(tile_ids);
+        self
+    }
+
+    pub fn exclude_tiles(mut self, tile_ids: Vec<u64>) -> Self {
+        self.exclude_tiles = tile_ids;
+        self
+    }
+
+    pub fn num_barcode(mut self, num_barcode: usize) -> Self {
+        self.num_barcode = num_barcode;
+        self
+    }
+
+    /// Partition the query barcodes on disk past half of `bytes`, see `--max-memory`
+    pub fn max_memory(mut self, bytes: u64) -> Self {
+        self.max_memory = Some(bytes);
+        self
+    }
+
+    pub fn threshold(mut self, threshold: f32) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    pub fn min_matched(mut self, min_matched: usize) -> Self {
+        self.min_matched = min_matched;
+        self
+    }
+
+    pub fn expand(mut self, k: u64) -> Self {
+        self.expand = k;
+        self
+    }
+
+    pub fn background(mut self, background: bool) -> Self {
+        self.background = background;
+        self
+    }
+
+    pub fn replicates(mut self, n: u64) -> Self {
+        self.replicates = Some(n);
+        self
+    }
+
+    /// Custom barcode position and pattern instead of the OpenST ones
+    pub fn barcode(mut self, pos: Position, pattern: &str) -> Self {
+        self.barcode = Some((pos, pattern.to_string()));
+        self
+    }
+
+    /// Search the tiles, one report per barcode file
+    pub fn run(self) -> Result<Vec<BarcodeFileReport>, AppError> {
+        let invalid = |message: &str| AppError::IoError(io::Error::new(io::ErrorKind::InvalidInput, message.to_string()));
+        let query = self.query.ok_or_else(|| invalid("a read or query barcodes are required"))?;
+        if self.barcode_file.is_empty() {
+            return Err(invalid("at least one barcode file is required"));
+        }
+        if self.replicates.is_some_and(|n| n < 2) {
+            return Err(invalid("replicates must be at least 2"));
+        }
+        let (pos, pattern) = match self.barcode {
+            Some((pos, pattern)) => (pos, validate_barcode_pattern(&pattern).map_err(AppError::InvalidBarcodePattern)?),
+            None => OpenSt.library_barcode(),
+        };
+        let mut tile_list = self.tile_list.unwrap_or_else(|| VALID_TILE_IDS.to_vec());
+        tile_list.retain(|tile_id| !self.exclude_tiles.contains(tile_id));
+        InitTilesMatchArgs::new(
+            query,
+            self.barcode_file,
+            tile_list,
+            self.num_barcode,
+            self.max_memory,
+            self.threshold,
+            self.min_matched,
+            self.expand,
+            None,
+            false,
+            self.background,
+            self.replicates,
+            true,
+            None,
+            None,
+            pos,
+            pattern,
+        ).search_tile()
+    }
+}
+
+/// Where the query barcodes come from
+pub enum QueryInput {
+    /// Extract barcodes from fastq with position and pattern
+    Fastq(PathBuf),
+    /// Read barcodes directly, one per line
+    Barcodes(PathBuf),
+}
+
 pub struct InitTilesMatchArgs {
-    read: PathBuf,
-    barcode_file: PathBuf,
+    query: QueryInput,
+    barcode_file: Vec<PathBuf>,
     tile_list: Vec<u64>,
     num_barcode: usize,
+    max_memory: Option<u64>,
     threshold: f32,
+    min_matched: usize,
+    expand: u64,
+    json: Option<PathBuf>,
+    metrics: bool,
+    background: bool,
+    replicates: Option<u64>,
     quiet: bool,
+    passed_out: Option<PathBuf>,
+    emit_matched: Option<PathBuf>,
     pos: Position,
     pattern: String,
 }
 
 impl InitTilesMatchArgs {
     #[inline]
+    #[allow(clippy::too_many_arguments)]
     fn new(
-        read: PathBuf,
-        barcode_file: PathBuf,
+        query: QueryInput,
+        barcode_file: Vec<PathBuf>,
         tile_list: Vec<u64>,
         num_barcode: usize,
+        max_memory: Option<u64>,
         threshold: f32,
+        min_matched: usize,
+        expand: u64,
+        json: Option<PathBuf>,
+        metrics: bool,
+        background: bool,
+        replicates: Option<u64>,
         quiet: bool,
+        passed_out: Option<PathBuf>,
+        emit_matched: Option<PathBuf>,
         pos: Position,
         pattern: String,
     ) -> Self {
         Self { 
-            read, 
+            query, 
             barcode_file, 
             tile_list, 
             num_barcode, 
+            max_memory,
             threshold, 
+            min_matched,
+            expand,
+            json,
+            metrics,
+            background,
+            replicates,
             quiet,
+            passed_out,
+            emit_matched,
             pos, 
             pattern 
         }
@@ -194,8 +526,46 @@ impl InitTilesMatchArgs {
     #[inline]
     pub fn quiet(&self) -> bool { self.quiet }
 
-    pub fn create_barcode_iter(&self) -> Result<BarcodesIter<HashSet<String>>, AppError> {
-        let inner: FastqReader = open(&self.read)?;
+    #[inline]
+    pub fn background(&self) -> bool { self.background }
+
+    #[inline]
+    pub fn replicates(&self) -> Option<u64> { self.replicates }
+
+    /// Whether more than one barcode file is searched, results are grouped by file then
+    #[inline]
+    pub fn multi_file(&self) -> bool { self.barcode_file.len() > 1 }
+
+    pub fn write_json(&self, file_reports: &[BarcodeFileReport]) -> Result<(), AppError> {
+        let Some(path) = &self.json else {
+            return Ok(());
+        };
+        let mut writer = BufWriter::new(fs::File::create(path)?);
+        serde_json::to_writer_pretty(&mut writer, file_reports).map_err(io::Error::from)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Write passed tile ids, preceded by a `#barcode_file` line per group when several files are searched
+    pub fn write_passed_tiles(&self, file_reports: &[BarcodeFileReport]) -> Result<(), AppError> {
+        let Some(path) = &self.passed_out else {
+            return Ok(());
+        };
+        let mut writer = BufWriter::new(fs::File::create(path)?);
+        for file_report in file_reports {
+            if self.multi_file() {
+                writeln!(writer, "#{}", file_report.barcode_file().display())?;
+            }
+            for report in file_report.reports().iter().filter(|report| report.selected()) {
+                writeln!(writer, "{}", report.tile_id())?;
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn create_barcode_iter(&self, read: &Path) -> Result<BarcodesIter<'_, HashSet<PackedBarcode>>, AppError> {
+        let inner: FastqReader = open(read)?;
         Ok(BarcodesIter::into_set(
             inner, 
             &self.pos, 
@@ -204,63 +574,715 @@ impl InitTilesMatchArgs {
         ))
     }
 
-    pub fn search_tile(&self) -> Result<Vec<TileMatchReport>, AppError> {
-        let barcode_list = self.create_barcode_iter()?.extract_sample_barcodes(self.num_barcode)?;
-        self.tile_list.par_iter().map(
-            |&tile_id| {
-                let mut chip_reader = tbx::Reader::from_path(&self.barcode_file)?;
-                let tid = chip_reader.tid(&tile_id.to_string())?;
-                chip_reader.fetch(tid, 1000, 37100)?;
+    /// Collect at most `num_barcode` unique query barcodes from the query input, partitioned on
+    /// disk when they outgrow `--max-memory`
+    pub fn query_barcodes(&self) -> Result<QuerySet, AppError> {
+        let mut collector = QueryCollector::new(self.num_barcode, self.max_memory, self.spill_dir());
+        match &self.query {
+            QueryInput::Fastq(read) => {
+                self.create_barcode_iter(read)?.for_each_barcode(|barcode| collector.add(barcode))?;
+            }
+            QueryInput::Barcodes(file) => {
+                for line in open_text(file)?.lines() {
+                    let line = line?;
+                    let barcode = line.trim();
+                    if barcode.is_empty() || barcode.starts_with('#') {
+                        continue;
+                    }
+                    if !collector.add(barcode.as_bytes())? {
+                        break;
+                    }
+                }
+            }
+        }
+        collector.finish()
+    }
+
+    /// Scratch directory of the query partitions, next to the JSON or passed tiles output by default
+    fn spill_dir(&self) -> PathBuf {
+        let output = self.json.as_deref().or(self.passed_out.as_deref());
+        let default = output.and_then(Path::parent).unwrap_or(Path::new("")).join("tilesmatch_spill");
+        scratch_dir(default, "tilesmatch")
+    }
 
-                let tile_list = chip_reader.records().map(
-                    |record| {
-                        let record = record?;
-                        let record = unsafe { String::from_utf8_unchecked(record) };
-                        let barcode = record.splitn(4, '\t').nth(3).ok_or(AppError::IoError(
-                            io::Error::new(io::ErrorKind::InvalidData, "Invalid tile's barcode file format")
-                        ))?;
+    pub fn search_tile(&self) -> Result<Vec<BarcodeFileReport>, AppError> {
+        let query = self.query_barcodes()?;
+        let file_reports = self.barcode_file.iter().enumerate().map(
+            |(index, barcode_file)| {
+                // tile tasks of the searches and the matched rows share the readers of the file
+                let readers = TabixPool::new(barcode_file);
+                let mut reports = self.search_file(&readers, &query)?;
+                expand_selection(&mut reports, self.expand);
+                if let Some(dir) = &self.emit_matched {
+                    let prefix = if self.multi_file() { format!("file{index}_") } else { String::new() };
+                    let selected: Vec<u64> = reports.iter()
+                        .filter(|report| report.selected())
+                        .map(|report| report.tile_id())
+                        .collect();
+                    let path_of = |tile_id: u64| dir.join(format!("{prefix}{tile_id}.matched.txt"));
+                    match &query {
+                        QuerySet::Memory(barcode_list) => selected.par_iter().try_for_each(
+                            |&tile_id| write_matched_rows(&readers, tile_id, barcode_list, &path_of(tile_id))
+                        )?,
+                        QuerySet::Partitioned(partitions) => {
+                            partitions.write_matched_rows(&readers, &selected, path_of, self.max_memory.unwrap_or(0))?
+                        }
+                    }
+                }
+                Ok(BarcodeFileReport::new(barcode_file.clone(), reports))
+            }
+        ).collect::<Result<Vec<BarcodeFileReport>, AppError>>();
+        if let QuerySet::Partitioned(partitions) = query {
+            partitions.remove()?;
+        }
+        file_reports
+    }
 
-                        Ok(barcode.to_string())
+    /// Intersect the tiles with the query, in one pass per query partition on disk
+    fn search_file(
+        &self, 
+        readers: &TabixPool, 
+        query: &QuerySet
+    ) -> Result<Vec<TileMatchReport>, AppError> {
+        // chance for a random tile barcode to hit the query set
+        let random_rate = query.len() as f64 / pattern_diversity(&self.pattern);
+        let passes = query.passes();
+        let tracker = Tracker::new("tilesmatch", self.tile_list.len() * passes);
+        let index = BarcodeIndex::open(readers.barcode_file());
+        let mut tallies: Vec<TileTally> = self.tile_list.iter()
+            .map(|_| TileTally::new(self.replicates))
+            .collect();
+        for pass in 0..passes {
+            let query_pass = query.pass(pass)?;
+            let barcode_list = &query_pass.barcode_list;
+            let pass_tallies = self.tile_list.par_iter().map(
+                |&tile_id| {
+                    let load_start = Instant::now();
+                    let tile_entries = index.as_ref().and_then(|index| index.tile(tile_id));
+                    let tile_list = match tile_entries {
+                        Some(_) => HashSet::new(),
+                        None => {
+                            let mut chip_reader = readers.fetch_tile(tile_id)?;
+                            let mut tile_list = HashSet::new();
+                            for record in chip_reader.records() {
+                                let record = record?;
+                                let barcode = PackedBarcode::new(barcode_field(&record)?);
+                                if query_pass.covers(&barcode) {
+                                    tile_list.insert(barcode);
+                                }
+                            }
+                            tile_list
+                        }
+                    };
+                    // the mapped index holds the whole tile, counted in the first pass only
+                    let tile_size = match tile_entries {
+                        Some(entries) if pass == 0 => entries.unique_barcodes(),
+                        Some(_) => 0,
+                        None => tile_list.len(),
+                    };
+                    let load_time = load_start.elapsed();
+                    let intersect_start = Instant::now();
+                    // query barcodes found in the tile, each distinct barcode of the mapped index is looked up in the query set
+                    let matched: Vec<&PackedBarcode> = match tile_entries {
+                        Some(entries) => entries.distinct_barcodes().filter_map(|barcode| barcode_list.get(&barcode)).collect(),
+                        None => tile_list.iter().filter_map(|barcode| barcode_list.get(barcode)).collect(),
+                    };
+                    let intersect_time = intersect_start.elapsed();
+                    tracker.finish(Some(&tile_id.to_string()), Some(tile_size as u64));
+                    let mut tally = TileTally::new(self.replicates);
+                    tally.tile_size = tile_size;
+                    tally.passed_num = matched.len();
+                    tally.load_time = load_time;
+                    tally.intersect_time = intersect_time;
+                    // the mapped index is paged in by the OS, only a tile read from the tabix text is held in a set
+                    tally.peak_set_size = tile_list.len();
+                    if let Some(n) = self.replicates {
+                        matched.iter().for_each(
+                            |barcode| tally.replicates[replicate_of(barcode, n)] += 1
+                        );
                     }
-                ).collect::<Result<HashSet<String>, AppError>>()?;
-                let passed_num = tile_list.intersection(&barcode_list).count();
-                let percent = passed_num as f32 / tile_list.len() as f32;
-                let pass_threshold = percent >= self.threshold;
-                Ok(TileMatchReport::new(
+                    Ok(tally)
+                }
+            ).collect::<Result<Vec<TileTally>, AppError>>()?;
+            tallies.iter_mut().zip(pass_tallies).for_each(|(tally, pass_tally)| tally.add(pass_tally));
+        }
+
+        Ok(self.tile_list.iter().zip(tallies).map(
+            |(&tile_id, tally)| {
+                let TileTally { tile_size, passed_num, .. } = tally;
+                let percent = passed_num as f32 / tile_size as f32;
+                let pass_threshold = percent >= self.threshold && passed_num >= self.min_matched;
+                let mut report = TileMatchReport::new(
                     tile_id, 
                     passed_num, 
-                    tile_list.len(), 
+                    tile_size, 
                     percent, 
                     pass_threshold
-                ))
+                );
+                if self.metrics {
+                    report.metrics = Some(TileMetrics {
+                        load_ms: tally.load_time.as_secs_f64() * 1000.0,
+                        intersect_ms: tally.intersect_time.as_secs_f64() * 1000.0,
+                        peak_set_size: tally.peak_set_size,
+                    });
+                }
+                if self.replicates.is_some() {
+                    report.replicates = Some(Replicates::new(&tally.replicates, tile_size));
+                }
+                if self.background {
+                    report.background = Some(Background::new(
+                        passed_num, 
+                        tile_size as f64 * random_rate
+                    ));
+                }
+                report
             }
-        ).collect::<Result<Vec<TileMatchReport>, AppError>>()
-    }  
+        ).collect())
+    }
 }
 
-#[derive(ValueEnum, Clone, Copy, Debug)]
-pub enum BarcodeMode {
-    Openst,
-    Custom,
+/// Counts of one tile summed over the passes of a search
+#[derive(Default)]
+struct TileTally {
+    tile_size: usize,
+    passed_num: usize,
+    load_time: Duration,
+    intersect_time: Duration,
+    peak_set_size: usize,
+    /// matched barcodes of every `--replicates` subsample
+    replicates: Vec<usize>,
 }
 
-pub type BarcodeConfig = (Position, String);
-impl BarcodeMode {
-    pub fn openst() -> BarcodeConfig {
-        let pos = Position::new(false, false, 2, 30);
-        // HDMI32-DraI: NNVNBVNNVNNVNNVNNVNNVNNVNNVNNNNN
-        // revcomp:     NNNNNBNNBNNBNNBNNBNNBNNBNNBVNBNN
-        let pattern: String = String::from("VNBVNNVNNVNNVNNVNNVNNVNNVNNN");
-        (pos, pattern)
+impl TileTally {
+    fn new(replicates: Option<u64>) -> Self {
+        Self { replicates: vec![0; replicates.unwrap_or(0) as usize], ..Self::default() }
     }
+
+    fn add(&mut self, other: TileTally) {
+        self.tile_size += other.tile_size;
+        self.passed_num += other.passed_num;
+        self.load_time += other.load_time;
+        self.intersect_time += other.intersect_time;
+        self.peak_set_size = self.peak_set_size.max(other.peak_set_size);
+        self.replicates.iter_mut().zip(other.replicates).for_each(|(count, other)| *count += other);
+    }
+}
+
+/// Estimated bytes of one barcode in a `HashSet<PackedBarcode>`, the key and the table overhead
+const SET_ENTRY_BYTES: u64 = 40;
+/// Hash buckets the query barcodes are spilled into, partitions are runs of whole buckets
+const QUERY_BUCKETS: usize = 256;
+
+/// Query barcodes, in memory or partitioned by hash on disk under `--max-memory`
+pub enum QuerySet {
+    Memory(HashSet<PackedBarcode>),
+    Partitioned(QueryPartitions),
 }
 
+impl QuerySet {
+    /// Number of query barcodes
+    pub fn len(&self) -> usize {
+        match self {
+            QuerySet::Memory(barcode_list) => barcode_list.len(),
+            QuerySet::Partitioned(partitions) => partitions.len,
+        }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Passes over the tiles needed to intersect them with every partition
+    fn passes(&self) -> usize {
+        match self {
+            QuerySet::Memory(_) => 1,
+            QuerySet::Partitioned(partitions) => partitions.ranges.len(),
+        }
+    }
+
+    /// Query barcodes of a pass, the whole set in memory or one partition loaded from disk
+    fn pass(&self, pass: usize) -> Result<QueryPass<'_>, AppError> {
+        match self {
+            QuerySet::Memory(barcode_list) => Ok(QueryPass { barcode_list: Cow::Borrowed(barcode_list), buckets: None }),
+            QuerySet::Partitioned(partitions) => {
+                let range = partitions.ranges[pass].clone();
+                Ok(QueryPass { barcode_list: Cow::Owned(partitions.load(range.clone())?), buckets: Some(range) })
+            }
+        }
+    }
+}
+
+/// Query barcodes searched in one pass over the tiles
+struct QueryPass<'a> {
+    barcode_list: Cow<'a, HashSet<PackedBarcode>>,
+    /// hash buckets of the partition, `None` for all of them
+    buckets: Option<Range<usize>>,
+}
+
+impl QueryPass<'_> {
+    /// Whether a tile barcode falls in the buckets of the pass, others are counted in their own pass
+    #[inline]
+    fn covers(&self, barcode: &PackedBarcode) -> bool {
+        self.buckets.as_ref().is_none_or(|range| range.contains(&bucket_of(barcode, QUERY_BUCKETS)))
+    }
+}
+
+/// Collects distinct query barcodes in memory, spilling them into hash buckets past half of `--max-memory`
+struct QueryCollector {
+    barcode_list: HashSet<PackedBarcode>,
+    capacity: usize,
+    max_memory: Option<u64>,
+    dir: PathBuf,
+    spill: Option<SpillBuckets>,
+    /// barcodes spilled so far, their order decides which are kept under the cap
+    ordinal: u64,
+    /// lines spilled since the buckets were last deduplicated
+    pending: u64,
+    /// distinct barcodes of the buckets when they were last deduplicated
+    distinct: u64,
+}
+
+impl QueryCollector {
+    fn new(capacity: usize, max_memory: Option<u64>, dir: PathBuf) -> Self {
+        Self { barcode_list: HashSet::new(), capacity, max_memory, dir, spill: None, ordinal: 0, pending: 0, distinct: 0 }
+    }
+
+    /// Add a barcode, `false` once `capacity` distinct barcodes are collected
+    ///
+    /// Spilled barcodes are only told apart from earlier ones when the buckets are deduplicated,
+    /// which happens once the lines spilled since the last time outnumber the distinct barcodes
+    /// (and the barcodes of half of `--max-memory`), so every spilled line is rewritten about twice.
+    fn add(&mut self, barcode: &[u8]) -> Result<bool, AppError> {
+        let barcode = PackedBarcode::new(barcode);
+        if let Some(spill) = self.spill.as_mut() {
+            self.ordinal += 1;
+            spill.write_line(bucket_of(&barcode, QUERY_BUCKETS), format_args!("{barcode}\t{}", self.ordinal))?;
+            self.pending += 1;
+            let per_dedup = self.max_memory.unwrap_or(0) / 2 / SET_ENTRY_BYTES;
+            if self.pending >= self.distinct.max(per_dedup) {
+                self.distinct = dedup_buckets(spill)?;
+                self.pending = 0;
+                return Ok(self.distinct < self.capacity as u64);
+            }
+            return Ok(true);
+        }
+        if self.barcode_list.insert(barcode) && self.barcode_list.len() >= self.capacity {
+            return Ok(false);
+        }
+        if self.max_memory.is_some_and(|max_memory| self.barcode_list.len() as u64 * SET_ENTRY_BYTES > max_memory / 2) {
+            let mut spill = SpillBuckets::create(&self.dir, QUERY_BUCKETS)?;
+            // all barcodes so far are within the cap, ordinal 0 puts them ahead of any later one
+            self.distinct = self.barcode_list.len() as u64;
+            for barcode in self.barcode_list.drain() {
+                spill.write_line(bucket_of(&barcode, QUERY_BUCKETS), format_args!("{barcode}\t0"))?;
+            }
+            self.barcode_list.shrink_to_fit();
+            self.spill = Some(spill);
+        }
+        Ok(true)
+    }
+
+    fn finish(self) -> Result<QuerySet, AppError> {
+        match self.spill {
+            None => Ok(QuerySet::Memory(self.barcode_list)),
+            Some(mut spill) => {
+                if self.pending > 0 {
+                    dedup_buckets(&mut spill)?;
+                }
+                let buckets = spill.finish()?;
+                let partitions = QueryPartitions::build(self.dir, buckets, self.capacity, self.max_memory.unwrap_or(0))?;
+                debug!(
+                    "{} query barcodes partitioned into {} passes under {}",
+                    partitions.len, partitions.ranges.len(), partitions.dir.display()
+                );
+                Ok(QuerySet::Partitioned(partitions))
+            }
+        }
+    }
+}
+
+/// Query barcodes spilled into hash buckets, deduplicated and loaded one partition of buckets at a time
+pub struct QueryPartitions {
+    dir: PathBuf,
+    buckets: Vec<PathBuf>,
+    /// consecutive buckets loaded together, each within half of `--max-memory` unless one bucket is larger
+    ranges: Vec<Range<usize>>,
+    /// last ordinal kept, the first `--num-barcode` distinct barcodes of the input
+    last_ordinal: u64,
+    len: usize,
+}
+
+impl QueryPartitions {
+    /// Apply the cap across the deduplicated buckets and group the buckets into partitions
+    fn build(dir: PathBuf, buckets: Vec<PathBuf>, capacity: usize, max_memory: u64) -> Result<Self, AppError> {
+        // merge the buckets in ordinal order up to the cap
+        let mut readers = buckets.iter()
+            .map(|path| Ok(BufReader::new(fs::File::open(path)?).lines()))
+            .collect::<io::Result<Vec<_>>>()?;
+        let next = |reader: &mut io::Lines<BufReader<fs::File>>| -> Result<Option<u64>, AppError> {
+            match reader.next() {
+                Some(line) => Ok(Some(parse_spill_line(&line?)?.1)),
+                None => Ok(None),
+            }
+        };
+        let mut heap = BinaryHeap::new();
+        for (index, reader) in readers.iter_mut().enumerate() {
+            if let Some(ordinal) = next(reader)? {
+                heap.push(Reverse((ordinal, index)));
+            }
+        }
+        let mut counts = vec![0u64; buckets.len()];
+        let mut len = 0;
+        let mut last_ordinal = u64::MAX;
+        while let Some(Reverse((ordinal, index))) = heap.pop() {
+            counts[index] += 1;
+            len += 1;
+            if len >= capacity {
+                last_ordinal = ordinal;
+                break;
+            }
+            if let Some(ordinal) = next(&mut readers[index])? {
+                heap.push(Reverse((ordinal, index)));
+            }
+        }
+
+        let per_partition = (max_memory / 2 / SET_ENTRY_BYTES).max(1);
+        let mut ranges: Vec<Range<usize>> = Vec::new();
+        let mut held = 0;
+        for (index, &count) in counts.iter().enumerate() {
+            match ranges.last_mut() {
+                Some(range) if held + count <= per_partition => {
+                    range.end = index + 1;
+                    held += count;
+                }
+                _ => {
+                    ranges.push(index..index + 1);
+                    held = count;
+                }
+            }
+        }
+        Ok(Self { dir, buckets, ranges, last_ordinal, len })
+    }
+
+    /// Query barcodes of the buckets in `range`
+    fn load(&self, range: Range<usize>) -> Result<HashSet<PackedBarcode>, AppError> {
+        let mut barcode_list = HashSet::new();
+        for path in &self.buckets[range] {
+            for line in BufReader::new(fs::File::open(path)?).lines() {
+                let line = line?;
+                let (barcode, ordinal) = parse_spill_line(&line)?;
+                // rows are in ordinal order
+                if ordinal > self.last_ordinal {
+                    break;
+                }
+                barcode_list.insert(PackedBarcode::from(barcode));
+            }
+        }
+        Ok(barcode_list)
+    }
+
+    /// Write the matched rows of the `selected` tiles, collected over all partitions
+    ///
+    /// Matched rows are sorted in runs spilled past `max_memory`, then every tile is fetched once
+    /// more to write its matched rows in file order.
+    fn write_matched_rows<F>(&self, readers: &TabixPool, selected: &[u64], path_of: F, max_memory: u64) -> Result<(), AppError>
+    where
+        F: Fn(u64) -> PathBuf,
+    {
+        let runs = SortedRuns::new(&self.dir.join("matched"), max_memory);
+        for range in &self.ranges {
+            let barcode_list = self.load(range.clone())?;
+            selected.par_iter().enumerate().try_for_each(|(tile_index, &tile_id)| {
+                let mut reader = readers.fetch_tile(tile_id)?;
+                let mut lines = Vec::new();
+                for (row, record) in reader.records().enumerate() {
+                    let record = record?;
+                    let barcode = PackedBarcode::new(barcode_field(&record)?);
+                    if range.contains(&bucket_of(&barcode, QUERY_BUCKETS)) && barcode_list.contains(&barcode) {
+                        // zero padded so the text order of the runs is the numeric order
+                        lines.push(format!("{tile_index:010}\t{row:020}"));
+                    }
+                }
+                runs.push(lines)?;
+                Ok::<(), AppError>(())
+            })?;
+        }
+
+        let mut merged = runs.finish()?;
+        // first matched row of the next tile, read ahead while collecting the current one
+        let mut pending: Option<(usize, u64)> = None;
+        for (tile_index, &tile_id) in selected.iter().enumerate() {
+            let mut rows = Vec::new();
+            loop {
+                let next = match pending.take() {
+                    Some(next) => Some(next),
+                    None => merged.next().transpose()?.map(|line| parse_matched_line(&line)).transpose()?,
+                };
+                match next {
+                    Some((index, row)) if index == tile_index => rows.push(row),
+                    next => {
+                        pending = next;
+                        break;
+                    }
+                }
+            }
+            let mut reader = readers.fetch_tile(tile_id)?;
+            let mut writer = buffers::writer(fs::File::create(path_of(tile_id))?);
+            writeln!(writer, "tile_id\tx_pos\ty_pos\tbarcode")?;
+            let mut rows = rows.into_iter().peekable();
+            for (row, record) in reader.records().enumerate() {
+                if rows.peek().is_none() {
+                    break;
+                }
+                let record = record?;
+                if rows.next_if_eq(&(row as u64)).is_some() {
+                    writer.write_all(&record)?;
+                    writer.write_all(b"\n")?;
+                }
+            }
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Remove the spilled buckets
+    fn remove(self) -> Result<(), AppError> {
+        if self.dir.exists() {
+            fs::remove_dir_all(&self.dir)?;
+        }
+        Ok(())
+    }
+}
+
+/// Keep the first ordinal of every barcode of the buckets, rewritten in ordinal order for the merge of
+/// `QueryPartitions::build`, returning the distinct barcodes
+fn dedup_buckets(spill: &mut SpillBuckets) -> Result<u64, AppError> {
+    let mut distinct = 0;
+    spill.rewrite(|lines, writer| {
+        let mut first: HashMap<PackedBarcode, u64> = HashMap::new();
+        for line in lines {
+            let line = line?;
+            let (barcode, ordinal) = parse_spill_line(&line)?;
+            first.entry(PackedBarcode::from(barcode))
+                .and_modify(|first| *first = (*first).min(ordinal))
+                .or_insert(ordinal);
+        }
+        distinct += first.len() as u64;
+        let mut rows: Vec<(u64, PackedBarcode)> = first.into_iter().map(|(barcode, ordinal)| (ordinal, barcode)).collect();
+        rows.sort_unstable_by_key(|(ordinal, _)| *ordinal);
+        for (ordinal, barcode) in rows {
+            writeln!(writer, "{barcode}\t{ordinal}")?;
+        }
+        Ok::<_, AppError>(())
+    })?;
+    Ok(distinct)
+}
+
+/// `barcode\tordinal` line of a query bucket
+fn parse_spill_line(line: &str) -> Result<(&str, u64), AppError> {
+    line.rsplit_once('\t')
+        .and_then(|(barcode, ordinal)| Some((barcode, ordinal.parse().ok()?)))
+        .ok_or_else(|| AppError::IoError(io::Error::new(io::ErrorKind::InvalidData, "Invalid query spill row")))
+}
+
+/// `tile_index\trow` line of the matched rows
+fn parse_matched_line(line: &str) -> Result<(usize, u64), AppError> {
+    line.split_once('\t')
+        .and_then(|(tile_index, row)| Some((tile_index.parse().ok()?, row.parse().ok()?)))
+        .ok_or_else(|| AppError::IoError(io::Error::new(io::ErrorKind::InvalidData, "Invalid matched spill row")))
+}
+
+/// Select the tiles within `k` grid steps of any passed tile on the same lane and surface
+fn expand_selection(reports: &mut [TileMatchReport], k: u64) {
+    if k == 0 {
+        return;
+    }
+    let passed: Vec<u64> = reports.iter()
+        .filter(|report| report.pass_threshold())
+        .map(|report| report.tile_id())
+        .collect();
+    for report in reports.iter_mut().filter(|report| !report.selected) {
+        let tile_id = report.tile_id();
+        report.selected = passed.iter()
+            .any(|&passed_tile| tile_distance(passed_tile, tile_id).is_some_and(|distance| distance <= k));
+    }
+}
+
+/// Deterministically assign a query barcode to one of `n` subsamples by its packed key, the split
+/// changes with `--seed`
+#[inline]
+fn replicate_of(barcode: &PackedBarcode, n: u64) -> usize {
+    let mut hasher = seed::hasher();
+    barcode.hash(&mut hasher);
+    (hasher.finish() % n) as usize
+}
+
+/// Two-sided 95% quantile of student's t distribution with `df` degrees of freedom
+fn t_quantile_95(df: usize) -> f64 {
+    const TABLE: [f64; 30] = [
+        12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228,
+        2.201, 2.179, 2.160, 2.145, 2.131, 2.120, 2.110, 2.101, 2.093, 2.086,
+        2.080, 2.074, 2.069, 2.064, 2.060, 2.056, 2.052, 2.048, 2.045, 2.042,
+    ];
+    TABLE.get(df.wrapping_sub(1)).copied().unwrap_or(1.960)
+}
+
+/// Natural logarithm of k!
+fn ln_factorial(k: usize) -> f64 {
+    if k < 256 {
+        (2..=k).map(|i| (i as f64).ln()).sum()
+    } else {
+        // Stirling series
+        let k = k as f64;
+        k * k.ln() - k + 0.5 * (2.0 * std::f64::consts::PI * k).ln() + 1.0 / (12.0 * k)
+    }
+}
+
+/// log10 of P(X >= k) for X ~ Poisson(lambda)
+fn poisson_log10_sf(k: usize, lambda: f64) -> f64 {
+    if k == 0 {
+        return 0.0;
+    }
+    if lambda <= 0.0 {
+        return f64::NEG_INFINITY;
+    }
+    let ln_lambda = lambda.ln();
+    let mut ln_term = -lambda + k as f64 * ln_lambda - ln_factorial(k);
+    let mut ln_sum = ln_term;
+    let max_iter = k + 10 * lambda.ceil() as usize + 100;
+    for i in k + 1..max_iter {
+        ln_term += ln_lambda - (i as f64).ln();
+        let next = ln_sum + (ln_term - ln_sum).exp().ln_1p();
+        if next - ln_sum < 1e-12 && (i as f64) > lambda {
+            break;
+        }
+        ln_sum = next;
+    }
+    ln_sum.min(0.0) / std::f64::consts::LN_10
+}
+
+/// Write the records of the tile whose barcode is in the query set
+fn write_matched_rows(
+    readers: &TabixPool, 
+    tile_id: u64, 
+    barcode_list: &HashSet<PackedBarcode>, 
+    path: &Path
+) -> Result<(), AppError> {
+    let mut reader = readers.fetch_tile(tile_id)?;
+    let mut writer = buffers::writer(fs::File::create(path)?);
+    writeln!(writer, "tile_id\tx_pos\ty_pos\tbarcode")?;
+    for record in reader.records() {
+        let record = record?;
+        if barcode_list.contains(&PackedBarcode::new(barcode_field(&record)?)) {
+            writer.write_all(&record)?;
+            writer.write_all(b"\n")?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Tile reports of one barcode file
+#[derive(Serialize)]
+pub struct BarcodeFileReport {
+    barcode_file: PathBuf,
+    reports: Vec<TileMatchReport>,
+}
+
+impl BarcodeFileReport {
+    #[inline]
+    fn new(barcode_file: PathBuf, reports: Vec<TileMatchReport>) -> Self {
+        Self { barcode_file, reports }
+    }
+
+    #[inline]
+    pub fn barcode_file(&self) -> &Path { &self.barcode_file }
+
+    #[inline]
+    pub fn reports(&self) -> &[TileMatchReport] { &self.reports }
+}
+
+/// Matched number compared with the random background expected from the pattern diversity
+#[derive(Serialize)]
+pub struct Background {
+    expected: f64,
+    enrichment: f64,
+    neg_log10_p: f64,
+}
+
+impl Background {
+    fn new(passed_num: usize, expected: f64) -> Self {
+        let enrichment = if expected > 0.0 { passed_num as f64 / expected } else { f64::INFINITY };
+        let neg_log10_p = -poisson_log10_sf(passed_num, expected);
+        Self { expected, enrichment, neg_log10_p }
+    }
+}
+
+impl std::fmt::Display for Background {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:<10.3e}\t{:<10.3e}\t{:.3}",
+            self.expected,
+            self.enrichment,
+            self.neg_log10_p,
+        )
+    }
+}
+
+/// Match ratio estimated from query subsamples, each ratio is scaled up by the number of subsamples
+#[derive(Serialize)]
+pub struct Replicates {
+    ratios: Vec<f64>,
+    mean: f64,
+    ci_low: f64,
+    ci_high: f64,
+}
+
+impl Replicates {
+    fn new(matched: &[usize], total_num: usize) -> Self {
+        let n = matched.len() as f64;
+        let ratios: Vec<f64> = matched.iter()
+            .map(|&m| if total_num == 0 { 0.0 } else { m as f64 * n / total_num as f64 })
+            .collect();
+        let mean = ratios.iter().sum::<f64>() / n;
+        let var = ratios.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / (n - 1.0);
+        let half_width = t_quantile_95(matched.len() - 1) * (var / n).sqrt();
+        Self { ratios, mean, ci_low: mean - half_width, ci_high: mean + half_width }
+    }
+}
+
+impl std::fmt::Display for Replicates {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:<11.5}\t[{:.5}, {:.5}]", self.mean, self.ci_low, self.ci_high)
+    }
+}
+
+/// Resource usage of one tile, for profiling
+#[derive(Serialize)]
+pub struct TileMetrics {
+    load_ms: f64,
+    intersect_ms: f64,
+    /// barcodes held in memory for the tile, 0 when it is looked up in the binary index
+    peak_set_size: usize,
+}
+
+#[derive(Serialize)]
 pub struct TileMatchReport {
     tile_id: u64,
     passed_num: usize,
     total_num: usize,
     percent: f32,
     pass_threshold: bool,
+    /// passed threshold, or neighbor of a passed tile when `--expand` is given
+    selected: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    background: Option<Background>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    replicates: Option<Replicates>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metrics: Option<TileMetrics>,
 }
 
 impl TileMatchReport {
@@ -278,26 +1300,113 @@ impl TileMatchReport {
             total_num,
             percent,
             pass_threshold,
+            selected: pass_threshold,
+            background: None,
+            replicates: None,
+            metrics: None,
         }
     }
 
     #[inline]
     pub fn tile_id(&self) -> u64 { self.tile_id }
 
+    #[inline]
+    pub fn passed_num(&self) -> usize { self.passed_num }
+
+    #[inline]
+    pub fn total_num(&self) -> usize { self.total_num }
+
+    #[inline]
+    pub fn percent(&self) -> f32 { self.percent }
+
     #[inline]
     pub fn pass_threshold(&self) -> bool { self.pass_threshold }
+
+    #[inline]
+    pub fn selected(&self) -> bool { self.selected }
 }
 
 impl std::fmt::Display for TileMatchReport {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "{:<7}\t{:<12}\t{:<14}\t{:<11.5}\t{}",
+            "{:<7}\t{:<12}\t{:<14}\t{:<11.5}\t{:<14}\t{}",
             self.tile_id,
             self.total_num,
             self.passed_num,
             self.percent,
             if self.pass_threshold { 1 } else { 0 },
-        )
+            if self.selected { 1 } else { 0 },
+        )?;
+        if let Some(background) = &self.background {
+            write!(f, "\t{background}")?;
+        }
+        if let Some(replicates) = &self.replicates {
+            write!(f, "\t{replicates}")?;
+        }
+        Ok(())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_poisson_log10_sf() {
+        let p = 10f64.powf(poisson_log10_sf(1, 1.0));
+        assert!((p - (1.0 - (-1.0f64).exp())).abs() < 1e-9);
+        let p = 10f64.powf(poisson_log10_sf(3, 0.5));
+        assert!((p - (1.0 - (-0.5f64).exp() * 1.625)).abs() < 1e-9);
+        assert_eq!(poisson_log10_sf(0, 2.0), 0.0);
+    }
+
+    /// Barcodes of `query` until `add` asks to stop, with the number of barcodes read
+    fn collect(query: &[String], capacity: usize, max_memory: Option<u64>, dir: &Path) -> (HashSet<String>, usize) {
+        let mut collector = QueryCollector::new(capacity, max_memory, dir.to_path_buf());
+        let mut read = 0;
+        for barcode in query {
+            read += 1;
+            if !collector.add(barcode.as_bytes()).unwrap() {
+                break;
+            }
+        }
+        let barcodes = match collector.finish().unwrap() {
+            QuerySet::Memory(barcode_list) => barcode_list,
+            QuerySet::Partitioned(partitions) => {
+                let barcode_list = partitions.ranges.iter()
+                    .flat_map(|range| partitions.load(range.clone()).unwrap())
+                    .collect();
+                partitions.remove().unwrap();
+                barcode_list
+            }
+        };
+        (barcodes.iter().map(ToString::to_string).collect(), read)
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_query_collector_cap() {
+        let dir = std::env::temp_dir().join(format!("opentools-test-query-collector-{}", std::process::id()));
+        let barcode = |i: usize| format!("{i:012b}").replace('0', "A").replace('1', "C");
+        // 200 distinct barcodes, each repeated right away and again later, then a tail of new ones
+        let query: Vec<String> = (0..200).flat_map(|i| [barcode(i), barcode(i), barcode(i / 2)])
+            .chain((200..4000).map(barcode))
+            .collect();
+        let first = |n: usize| (0..n).map(barcode).collect::<HashSet<_>>();
+
+        let (barcodes, read) = collect(&query, 150, None, &dir);
+        assert_eq!(barcodes, first(150));
+        assert_eq!(read, 149 * 3 + 1);
+
+        // spilled from the second barcode on, the cap holds across the buckets and the read stops
+        // once a deduplication finds enough distinct barcodes, long before the tail ends
+        for capacity in [3, 150, 1000] {
+            let (barcodes, read) = collect(&query, capacity, Some(2 * SET_ENTRY_BYTES), &dir);
+            assert_eq!(barcodes, first(capacity), "capacity {capacity}");
+            assert!(read < query.len(), "capacity {capacity} read the whole query");
+        }
+        let (barcodes, read) = collect(&query, 5000, Some(2 * SET_ENTRY_BYTES), &dir);
+        assert_eq!(barcodes, first(4000));
+        assert_eq!(read, query.len());
+        assert!(!dir.exists());
+    }
+}
@@ -2,6 +2,10 @@
 use crate::utils::{
     fastqfile::{open, FastqReader},
     position::Position,
+    chemistry,
+    spill::scratch_dir,
+    buffers,
+    atomic_file::{persist, temp_path},
     barcode_iter::{validate_absolute_dirpath, BarcodesIter},
     error::AppError,
 };
@@ -9,7 +13,10 @@ use crate::utils::{
 use std::{fs, io::{self, BufWriter, Write}, process::Command};
 use std::path::{PathBuf, Path};
 use regex::Regex;
-use clap::{Parser, ValueEnum};
+use clap::Parser;
+
+/// Barcode file written into the output directory, bgzf compressed and tabix indexed
+pub const BARCODE_FILE: &str = "barcodes.txt.gz";
 
 pub fn validate_barcode_pattern(s: &str) -> Result<String, String> {
     let re = Regex::new(r"^[ATGCURYMKSWHBVDN]+$").unwrap();
@@ -38,12 +45,14 @@ pub struct TouchBarcodeArgs {
     bcl_dir: PathBuf,
 
     /// Path to output directory
-    #[arg(short, long, required = true, value_parser = validate_absolute_dirpath)]
+    #[arg(short, long, required = true, value_parser = validate_absolute_dirpath, help_heading = super::OUTPUTS)]
     output: PathBuf,
 
     /// barcode parsing mode
-    #[arg(short, long, value_enum, default_value_t = BarcodeMode::Openst)]
-    mode: BarcodeMode,
+    ///
+    /// `openst`, a chemistry of a `[chemistry.NAME]` table in the `--config` TOML, or `custom`
+    #[arg(short, long, default_value = "openst")]
+    mode: String,
 
     /// turn on to run fastqc on each tile's fastq file
     #[arg(long)]
@@ -75,16 +84,26 @@ pub struct TouchBarcodeArgs {
         value_name = "BARCODE_PATTERN",
     )]
     barcode_pattern: Option<String>,
+
+    /// bcl-convert image run by docker on macOS
+    #[arg(long, env = "OPENTOOLS_CONTAINER_IMAGE", default_value = "zymoresearch/bcl-convert", value_name = "IMAGE")]
+    container_image: String,
+
+    /// also write the binary index `barcodes.txt.gz.bix` of packed barcodes, memory-mapped by tilesmatch and dedupbarcode
+    #[arg(long)]
+    binary_index: bool,
 }
 
 impl TouchBarcodeArgs {
-    pub fn init(self) -> InitTouchBarcodeArgs {
+    pub fn init(self) -> Result<InitTouchBarcodeArgs, AppError> {
         let (pos, pattern) = match (self.barcode_pos, self.barcode_pattern) {
             (Some(pos), Some(pattern)) => (pos, pattern),
-            (None, None) => BarcodeMode::openst(),
+            (None, None) => chemistry::lookup(&self.mode)?.chip_barcode(),
             _ => unreachable!("clap parse the error is impossible.")
         };
-        InitTouchBarcodeArgs::new(self.bcl_dir, self.output, self.fastqc, pos, pattern)
+        Ok(InitTouchBarcodeArgs::new(
+            self.bcl_dir, self.output, self.fastqc, pos, pattern, self.container_image, self.binary_index
+        ))
     }
 }
 
@@ -94,6 +113,8 @@ pub struct InitTouchBarcodeArgs {
     fastqc: bool,
     pos: Position,
     pattern: String,
+    container_image: String,
+    binary_index: bool,
 }
 
 impl InitTouchBarcodeArgs {
@@ -103,14 +124,18 @@ impl InitTouchBarcodeArgs {
         output: PathBuf, 
         fastqc: bool, 
         pos: Position, 
-        pattern: String
+        pattern: String,
+        container_image: String,
+        binary_index: bool,
     ) -> Self {
         Self {
             bcl_dir,
             output,
             fastqc,
             pos,
-            pattern
+            pattern,
+            container_image,
+            binary_index,
         }
     }
 
@@ -118,7 +143,7 @@ impl InitTouchBarcodeArgs {
     fn bcl_dir(&self) -> &Path { self.bcl_dir.as_path() }
 
     #[inline]
-    pub fn output(&self) -> &Path { &self.output.as_path() }
+    pub fn output(&self) -> &Path { self.output.as_path() }
 
     #[inline]
     fn pos(&self) -> &Position { &self.pos }
@@ -126,6 +151,9 @@ impl InitTouchBarcodeArgs {
     #[inline]
     fn pattern(&self) -> &str { &self.pattern }
 
+    #[inline]
+    pub fn binary_index(&self) -> bool { self.binary_index }
+
     #[inline]
     pub fn fastq_path(&self, tile_id: &str) -> PathBuf { 
         self.output.join(format!("fastq/{tile_id}"))
@@ -136,9 +164,15 @@ impl InitTouchBarcodeArgs {
         self.output.join(format!("fastq/{tile_id}/Undetermined_S0_R1_001.fastq.gz"))
     }
 
+    /// Barcodes of the tiles before they are merged, under `--tmpdir` when given
+    #[inline]
+    pub fn tmp_dir(&self) -> PathBuf {
+        scratch_dir(self.output.join("tmp"), "touchbarcode")
+    }
+
     #[inline]
     pub fn tmp_file(&self, tile_id: &str) -> PathBuf {
-        self.output.join(format!("tmp/{}.txt", tile_id))
+        self.tmp_dir().join(format!("{}.txt", tile_id))
     }
 
     fn command_nonexists(&self, command: &str) -> io::Result<()> {
@@ -180,7 +214,7 @@ impl InitTouchBarcodeArgs {
         #[cfg(target_os = "macos")]
         {
             self.command_nonexists("docker")?;
-            self.docker_image_nonexists("zymoresearch/bcl-convert")?;
+            self.docker_image_nonexists(&self.container_image)?;
         }
         self.command_nonexists("bgzip")?;
         self.command_nonexists("tabix")
@@ -195,7 +229,7 @@ impl InitTouchBarcodeArgs {
             |id| id.as_str().to_string()
         )).collect();
         if tile_ids.is_empty() { 
-            return Err(AppError::EmptyTileIDsList(path)) 
+            Err(AppError::EmptyTileIDsList(path))
         } else {
             Ok(tile_ids)
         }
@@ -263,7 +297,7 @@ impl InitTouchBarcodeArgs {
         self.run_command(
             "bcl-convert",
             &args,
-            &fastq_dir,
+            fastq_dir,
             tile_id,
             "bcl-convert run failed"
         )
@@ -274,7 +308,7 @@ impl InitTouchBarcodeArgs {
             "run", "--rm",
             "-v", &format!("{}:/mnt/run", self.bcl_dir.display()),
             "-v", &format!("{}:/mnt/output", fastq_dir.display()),
-            "zymoresearch/bcl-convert",
+            &self.container_image,
             "--bcl-input-directory", "/mnt/run",
             "--output-directory", "/mnt/output",
             "--tiles", &format!("s_{}", tile_id),
@@ -286,65 +320,58 @@ impl InitTouchBarcodeArgs {
         self.run_command(
             "docker",
             &args,
-            &fastq_dir,
+            fastq_dir,
             tile_id,
             "Docker run failed"
         )
     }
 
-    fn fastqc_run(&self, tile_id: &str) -> Result<(), AppError> {
-        let fastq_file = self.fastq_file(tile_id);
+    fn fastqc_run(&self, tile_id: &str, fastq_dir: &Path) -> Result<(), AppError> {
+        let fastq_file = fastq_dir.join("Undetermined_S0_R1_001.fastq.gz");
         
         self.run_command(
             "fastqc",
             &[fastq_file.as_os_str().to_str().unwrap()],
-            &self.fastq_path(tile_id),
+            fastq_dir,
             tile_id,
             "FastQC failed"
         )
     }
 
+    /// Convert the tile into `{fastq_dir}.tmp` and rename it into place once complete, so an
+    /// interrupted conversion never counts as a converted tile on a rerun
     pub fn convert_bcl_into_tile(&self, tile_id: &str) -> Result<(), AppError> {
         let fastq_dir = self.fastq_path(tile_id);
+        let temp_dir = temp_path(&fastq_dir);
+        if temp_dir.exists() {
+            fs::remove_dir_all(&temp_dir)?;
+        }
         if cfg!(target_os = "linux") {
-            self.bcl_convert(tile_id, &fastq_dir)?;
+            self.bcl_convert(tile_id, &temp_dir)?;
         } else if cfg!(target_os = "macos") {
-            self.docker_image_run(tile_id, &fastq_dir)?;
+            self.docker_image_run(tile_id, &temp_dir)?;
         } else {
             return Err(AppError::UnsupportedOS);
         }
     
         if self.fastqc {
-            self.fastqc_run(tile_id)?;
+            self.fastqc_run(tile_id, &temp_dir)?;
         }
+        // left by a conversion of an older version, without the FASTQ that marks it complete
+        if fastq_dir.exists() {
+            fs::remove_dir_all(&fastq_dir)?;
+        }
+        persist(&fastq_dir)?;
         Ok(())
     }
 
-    pub fn create_barcode_iter(&self, tile_id: &str) -> io::Result<BarcodesIter<BufWriter<fs::File>>> {
+    pub fn create_barcode_iter(&self, tile_id: &str) -> io::Result<BarcodesIter<'_, BufWriter<fs::File>>> {
         let inner: FastqReader = open(
             self.fastq_path(tile_id).join("Undetermined_S0_R1_001.fastq.gz")
         )?;
         let tmp_path = self.tmp_file(tile_id);
         let writer = fs::OpenOptions::new().write(true)
-            .create(true).open(tmp_path).map(BufWriter::new)?;
+            .create(true).truncate(true).open(tmp_path).map(buffers::writer)?;
         Ok(BarcodesIter::into_file(inner, self.pos(), self.pattern(), writer))
     }
 }
-
-
-#[derive(ValueEnum, Clone, Copy, Debug)]
-enum BarcodeMode {
-    Openst,
-    Custom,
-}
-
-pub type BarcodeConfig = (Position, String);
-impl BarcodeMode {
-    pub fn openst() -> BarcodeConfig {
-        let pos = Position::new(false, true, 2, 30);
-        // HDMI32-DraI: NNVNBVNNVNNVNNVNNVNNVNNVNNVNNNNN
-        // revcomp:     NNNNNBNNBNNBNNBNNBNNBNNBNNBVNBNN
-        let pattern: String = String::from("NNNBNNBNNBNNBNNBNNBNNBNNBVNB");
-        (pos, pattern)
-    }
-}
\ No newline at end of file
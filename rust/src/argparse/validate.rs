@@ -0,0 +1,209 @@
+use crate::utils::{
+    barcode_file::{list_tiles, BarcodeRecord, TabixPool, TILE_FETCH_END, TILE_FETCH_START},
+    barcode_iter::validate_absolute_filepath,
+    fastqfile::open_text,
+    error::AppError,
+};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use clap::Parser;
+use rust_htslib::tbx::Read;
+
+#[derive(Parser, Debug)]
+#[command(name = "validate")]
+#[command(about = "Check a barcode file and its tabix index for schema problems", long_about = None)]
+#[command(next_line_help = true)]
+pub struct ValidateArgs {
+    /// barcode file with `tile_id\tx_pos\ty_pos\tbarcode[\tquality]` rows
+    #[arg(short = 'I', long, value_parser = validate_absolute_filepath)]
+    barcode_file: PathBuf,
+
+    /// skip the checks of the `.tbi` index
+    #[arg(long)]
+    no_index: bool,
+
+    /// number of problem lines printed as examples
+    #[arg(long, default_value_t = 10, value_name = "N")]
+    examples: usize,
+}
+
+/// Problems found in the rows of one tile
+#[derive(Default, Clone, Copy)]
+struct TileIssues {
+    /// rows without 4 or 5 columns
+    columns: u64,
+    /// barcodes with bases other than ACGT
+    barcode: u64,
+    /// invalid or negative positions and y positions outside the fetched range
+    coordinate: u64,
+    /// rows breaking the tabix sort order
+    unsorted: u64,
+    /// rows repeating a position of the tile
+    duplicate: u64,
+    /// index and file disagree on the tile
+    index: u64,
+}
+
+impl TileIssues {
+    fn total(&self) -> u64 {
+        self.columns + self.barcode + self.coordinate + self.unsorted + self.duplicate + self.index
+    }
+}
+
+impl ValidateArgs {
+    pub fn validate(self) -> Result<ValidateReport, AppError> {
+        let mut report = ValidateReport { file: self.barcode_file.clone(), ..Default::default() };
+        let mut examples = Vec::new();
+        let mut note = |line: usize, message: String| {
+            if examples.len() < self.examples {
+                examples.push(format!("line {line}: {message}"));
+            }
+        };
+
+        // rows within the fetched range per tile, compared with the index
+        let mut fetched: HashMap<String, u64> = HashMap::new();
+        let mut positions: HashSet<(i64, i64)> = HashSet::new();
+        let mut seen_tiles: HashSet<String> = HashSet::new();
+        let mut last: Option<(String, i64)> = None;
+        for (index, line) in open_text(&self.barcode_file)?.lines().enumerate() {
+            let line = line?;
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            report.rows += 1;
+            let columns = line.split('\t').count();
+            let tile = line.split('\t').next().unwrap_or_default().to_string();
+            let issues = report.tiles.entry(tile.clone()).or_default();
+            let record = match BarcodeRecord::parse(&line) {
+                Ok(record) if columns <= 5 => record,
+                _ => {
+                    issues.columns += 1;
+                    note(index + 1, format!("{columns} columns"));
+                    continue;
+                }
+            };
+            if record.barcode.is_empty() || !record.barcode.bytes().all(|base| matches!(base, b'A' | b'C' | b'G' | b'T')) {
+                issues.barcode += 1;
+                note(index + 1, format!("barcode `{}`", record.barcode));
+            }
+            let (Ok(x_pos), Ok(y_pos), Ok(_)) = (
+                record.x_pos.parse::<i64>(), record.y_pos.parse::<i64>(), record.tile_id.parse::<u64>(),
+            ) else {
+                issues.coordinate += 1;
+                note(index + 1, "invalid tile id or position".to_string());
+                continue;
+            };
+            if x_pos < 0 || !(TILE_FETCH_START as i64..TILE_FETCH_END as i64).contains(&y_pos) {
+                issues.coordinate += 1;
+                note(index + 1, format!("position ({x_pos}, {y_pos}) out of range"));
+            } else {
+                *fetched.entry(tile.clone()).or_default() += 1;
+            }
+
+            let sorted = match &last {
+                Some((last_tile, last_y)) if *last_tile == tile => y_pos >= *last_y,
+                _ => {
+                    positions.clear();
+                    seen_tiles.insert(tile.clone())
+                }
+            };
+            if !sorted {
+                issues.unsorted += 1;
+                note(index + 1, format!("tile {tile} y position {y_pos} out of order"));
+            }
+            if !positions.insert((x_pos, y_pos)) {
+                issues.duplicate += 1;
+                note(index + 1, format!("duplicate position ({x_pos}, {y_pos})"));
+            }
+            last = Some((tile, y_pos));
+        }
+        report.examples = examples;
+
+        if !self.no_index {
+            self.check_index(&mut report, &fetched)?;
+        }
+        Ok(report)
+    }
+
+    /// Compare the tiles and rows fetched through the index with the file
+    fn check_index(&self, report: &mut ValidateReport, fetched: &HashMap<String, u64>) -> Result<(), AppError> {
+        let mut index_path = self.barcode_file.as_os_str().to_owned();
+        index_path.push(".tbi");
+        let index_path = PathBuf::from(index_path);
+        if !index_path.exists() {
+            report.index_problem = Some(format!("{} is missing", index_path.display()));
+            return Ok(());
+        }
+        if modified(&index_path)? < modified(&self.barcode_file)? {
+            report.index_problem = Some(format!("{} is older than the barcode file", index_path.display()));
+        }
+        let indexed = match list_tiles(&self.barcode_file) {
+            Ok(tiles) => tiles,
+            Err(err) => {
+                report.index_problem = Some(format!("{} is unreadable: {}", index_path.display(), err));
+                return Ok(());
+            }
+        };
+        let mut indexed_tiles = HashSet::new();
+        let readers = TabixPool::new(&self.barcode_file);
+        for tile_id in indexed {
+            let tile = tile_id.to_string();
+            let rows = readers.fetch_tile(tile_id)?.records().count() as u64;
+            if rows != fetched.get(&tile).copied().unwrap_or(0) {
+                report.tiles.entry(tile.clone()).or_default().index += 1;
+            }
+            indexed_tiles.insert(tile);
+        }
+        for tile in fetched.keys().filter(|tile| !indexed_tiles.contains(*tile)) {
+            report.tiles.entry(tile.clone()).or_default().index += 1;
+        }
+        Ok(())
+    }
+}
+
+fn modified(path: &Path) -> Result<std::time::SystemTime, AppError> {
+    Ok(fs::metadata(path)?.modified()?)
+}
+
+/// Issue counts per tile, the file fails validation with any issue
+#[derive(Default)]
+pub struct ValidateReport {
+    file: PathBuf,
+    rows: u64,
+    tiles: BTreeMap<String, TileIssues>,
+    /// missing, stale or unreadable index
+    index_problem: Option<String>,
+    examples: Vec<String>,
+}
+
+impl ValidateReport {
+    pub fn issues(&self) -> u64 {
+        self.tiles.values().map(TileIssues::total).sum::<u64>() + self.index_problem.is_some() as u64
+    }
+}
+
+impl std::fmt::Display for ValidateReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Rows={}, Tiles={}, Issues={}", self.rows, self.tiles.len(), self.issues())?;
+        if let Some(problem) = &self.index_problem {
+            write!(f, "\nIndex: {problem}")?;
+        }
+        let failed: Vec<_> = self.tiles.iter().filter(|(_, issues)| issues.total() > 0).collect();
+        if !failed.is_empty() {
+            write!(f, "\ntile_id\tcolumns\tbarcode\tcoordinate\tunsorted\tduplicate\tindex")?;
+            for (tile, issues) in failed {
+                write!(
+                    f,
+                    "\n{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    tile, issues.columns, issues.barcode, issues.coordinate, issues.unsorted, issues.duplicate, issues.index,
+                )?;
+            }
+        }
+        for example in &self.examples {
+            write!(f, "\n{}: {}", self.file.display(), example)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,125 @@
+use crate::utils::{
+    hts,
+    barcode_file::{BarcodeRecord, BARCODE_FILE_HEADER},
+    barcode_iter::validate_absolute_filepath,
+    error::AppError,
+};
+use crate::argparse::tilesmatch::is_valid_tile_id;
+use std::fs;
+use std::io::{self, Write, BufWriter};
+use std::path::PathBuf;
+use clap::{Parser, ValueEnum};
+use rust_htslib::tbx::Read;
+
+/// Largest position addressable by a tabix index
+const TBX_MAX_POS: u64 = 1 << 29;
+
+pub fn parse_coord_range(value: &str) -> Result<(u64, u64), String> {
+    let (start, end) = value.split_once('-')
+        .ok_or(format!("`{}` is not a range, expected 'start-end'", value))?;
+    let start: u64 = start.parse().map_err(|_| format!("`{}` is not valid integer", start))?;
+    let end: u64 = end.parse().map_err(|_| format!("`{}` is not valid integer", end))?;
+    if end < start {
+        return Err(format!("range end {} must be >= start {}", end, start));
+    }
+    Ok((start, end))
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum ViewFormat {
+    /// tab separated, same as the barcode file
+    Tsv,
+    /// aligned columns for reading in terminal
+    Pretty,
+}
+
+#[derive(Parser, Debug)]
+#[command(name = "viewbarcode")]
+#[command(about = "Query and print records of a barcode file", long_about = None)]
+#[command(next_line_help = true)]
+pub struct ViewBarcodeArgs {
+    /// The path to the barcode file
+    #[arg(
+        short = 'I', 
+        long, 
+        required = true, 
+        value_parser = validate_absolute_filepath,
+    )]
+    barcode_file: PathBuf,
+
+    /// the tile id list to query, all tiles in the barcode file by default
+    #[arg(
+        long, 
+        value_delimiter = ' ',
+        num_args = 1..,
+        value_parser = is_valid_tile_id,
+    )]
+    tile_list: Vec<u64>,
+
+    /// only print records with x position in this inclusive range (e.g. "1000-5000")
+    #[arg(short, long, value_parser = parse_coord_range, value_name = "START-END")]
+    x_range: Option<(u64, u64)>,
+
+    /// only print records with y position in this inclusive range (e.g. "1000-5000")
+    #[arg(short, long, value_parser = parse_coord_range, value_name = "START-END")]
+    y_range: Option<(u64, u64)>,
+
+    /// output format
+    #[arg(short, long, value_enum, default_value_t = ViewFormat::Tsv)]
+    format: ViewFormat,
+
+    /// Path to output file, stdout by default
+    #[arg(short, long, help_heading = super::OUTPUTS)]
+    output: Option<PathBuf>,
+}
+
+impl ViewBarcodeArgs {
+    pub fn view(self) -> Result<(), AppError> {
+        let inner: Box<dyn Write> = match &self.output {
+            Some(path) => Box::new(fs::File::create(path)?),
+            None => Box::new(io::stdout().lock()),
+        };
+        let mut writer = BufWriter::new(inner);
+
+        let mut reader = hts::open_tabix(&self.barcode_file)?;
+        let tile_list: Vec<String> = if self.tile_list.is_empty() {
+            reader.seqnames()
+        } else {
+            self.tile_list.iter().map(|tile_id| tile_id.to_string()).collect()
+        };
+
+        // tabix positions are 0-based, y is the indexed column
+        let (y_start, y_end) = self.y_range.unwrap_or((0, TBX_MAX_POS));
+        match self.format {
+            ViewFormat::Tsv => writeln!(writer, "{BARCODE_FILE_HEADER}")?,
+            ViewFormat::Pretty => writeln!(
+                writer, "{:<8} {:>8} {:>8}  barcode", "tile_id", "x_pos", "y_pos"
+            )?,
+        }
+        for tile_id in tile_list {
+            let tid = reader.tid(&tile_id)?;
+            reader.fetch(tid, y_start, y_end + 1)?;
+            for record in reader.records() {
+                let record = record?;
+                let record = String::from_utf8_lossy(&record);
+                let BarcodeRecord { tile_id: tile, x_pos, y_pos, barcode, .. } = BarcodeRecord::parse(&record)?;
+                if let Some((x_start, x_end)) = self.x_range {
+                    let x: u64 = x_pos.parse().map_err(|_| AppError::IoError(io::Error::new(
+                        io::ErrorKind::InvalidData, format!("Invalid x position: {x_pos}")
+                    )))?;
+                    if x < x_start || x > x_end {
+                        continue;
+                    }
+                }
+                match self.format {
+                    ViewFormat::Tsv => writeln!(writer, "{record}")?,
+                    ViewFormat::Pretty => writeln!(
+                        writer, "{:<8} {:>8} {:>8}  {}", tile, x_pos, y_pos, barcode
+                    )?,
+                }
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,191 @@
+use crate::utils::{
+    barcode_file::BarcodeRecord,
+    barcode_iter::{validate_absolute_dirpath, validate_absolute_filepath},
+    coordinate::{load_regions, Region},
+    fastqfile::{check_base_match, open_text},
+    atomic_file::{persist, temp_path},
+    error::AppError,
+};
+use crate::argparse::{
+    barcoderank::read_count_table,
+    dedupbarcode::WhitelistFormat,
+    tilesmatch::is_valid_tile_id,
+    touchbarcode::validate_barcode_pattern,
+};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use clap::Parser;
+
+#[derive(Parser, Debug)]
+#[command(name = "whitelist")]
+#[command(about = "Build a filtered barcode whitelist for STARsolo or alevin-fry", long_about = None)]
+#[command(next_line_help = true)]
+pub struct WhitelistArgs {
+    /// The path to the barcode file
+    #[arg(short = 'I', long, value_parser = validate_absolute_filepath)]
+    barcode_file: PathBuf,
+
+    /// the tile id list to keep, all tiles in the barcode file by default
+    #[arg(
+        long,
+        value_delimiter = ' ',
+        num_args = 1..,
+        value_parser = is_valid_tile_id,
+    )]
+    tile_list: Vec<u64>,
+
+    /// barcodes not matching this pattern (e.g. NNNNNNNNNNNNNNNNNNNNNNNNNNV) are dropped
+    #[arg(long, value_parser = validate_barcode_pattern)]
+    pattern: Option<String>,
+
+    /// `barcode\tcount` table of read support, e.g. barcode_rank.tsv columns 2-3 or a STARsolo count
+    #[arg(long, value_parser = validate_absolute_filepath)]
+    counts: Option<PathBuf>,
+
+    /// barcodes with fewer reads in --counts are dropped
+    #[arg(long, default_value_t = 1, requires = "counts")]
+    min_count: u64,
+
+    /// keep only barcodes inside one of these regions
+    ///
+    /// rows of `name\ttile_id\tx_start\tx_end\ty_start\ty_end` with inclusive pixel ranges,
+    /// `*` as tile id for every tile
+    #[arg(long, value_parser = validate_absolute_filepath)]
+    include_regions: Option<PathBuf>,
+
+    /// drop barcodes inside any of these regions, same format as --include-regions
+    #[arg(long, value_parser = validate_absolute_filepath)]
+    exclude_regions: Option<PathBuf>,
+
+    /// drop barcodes observed at more than one position
+    #[arg(long)]
+    drop_collided: bool,
+
+    /// whitelist format, also sets the file name inside the output directory
+    #[arg(long, value_enum, default_value_t = WhitelistFormat::Starsolo)]
+    whitelist_format: WhitelistFormat,
+
+    /// The output directory
+    #[arg(short, long, value_parser = validate_absolute_dirpath, help_heading = super::OUTPUTS)]
+    output_dir: PathBuf,
+}
+
+/// Barcode seen in the barcode file
+struct Entry {
+    /// first-seen order, the whitelist keeps it
+    order: usize,
+    positions: u32,
+    /// at least one position passes the region masks
+    unmasked: bool,
+}
+
+impl WhitelistArgs {
+    #[inline]
+    fn matches_pattern(&self, barcode: &str) -> bool {
+        self.pattern.as_ref().is_none_or(|pattern| {
+            pattern.len() == barcode.len()
+                && !barcode.bytes().zip(pattern.bytes()).any(|(base, p)| check_base_match(base, p))
+        })
+    }
+
+    fn is_masked(position: (i32, i32, i32), include: &Option<Vec<Region>>, exclude: &[Region]) -> bool {
+        include.as_ref().is_some_and(|regions| !regions.iter().any(|region| region.contains(position)))
+            || exclude.iter().any(|region| region.contains(position))
+    }
+
+    pub fn build(self) -> Result<WhitelistReport, AppError> {
+        let invalid = |value: &str| AppError::IoError(io::Error::new(
+            io::ErrorKind::InvalidData, format!("Invalid barcode file value: {value}")
+        ));
+        let include = self.include_regions.as_deref().map(load_regions).transpose()?;
+        let exclude = match &self.exclude_regions {
+            Some(path) => load_regions(path)?,
+            None => Vec::new(),
+        };
+        let counts = self.counts.as_deref().map(read_count_table).transpose()?;
+
+        let mut report = WhitelistReport::default();
+        let mut entries: HashMap<String, Entry> = HashMap::new();
+        for line in open_text(&self.barcode_file)?.lines() {
+            let line = line?;
+            if line.is_empty() || line.starts_with('#') || line.starts_with("tile_id") {
+                continue;
+            }
+            let record = BarcodeRecord::parse(&line)?;
+            let tile_id: i32 = record.tile_id.parse().map_err(|_| invalid(record.tile_id))?;
+            if !self.tile_list.is_empty() && !self.tile_list.contains(&(tile_id as u64)) {
+                continue;
+            }
+            report.rows += 1;
+            let position = (
+                tile_id,
+                record.x_pos.parse().map_err(|_| invalid(record.x_pos))?,
+                record.y_pos.parse().map_err(|_| invalid(record.y_pos))?,
+            );
+            let unmasked = !Self::is_masked(position, &include, &exclude);
+            let order = entries.len();
+            match entries.get_mut(record.barcode) {
+                Some(entry) => {
+                    entry.positions += 1;
+                    entry.unmasked |= unmasked;
+                }
+                None => {
+                    entries.insert(record.barcode.to_string(), Entry { order, positions: 1, unmasked });
+                }
+            }
+        }
+        report.unique = entries.len() as u64;
+
+        let mut kept: Vec<(usize, String)> = Vec::new();
+        for (barcode, entry) in entries {
+            if !self.matches_pattern(&barcode) {
+                report.failed_pattern += 1;
+            } else if !entry.unmasked {
+                report.masked += 1;
+            } else if self.drop_collided && entry.positions > 1 {
+                report.collided += 1;
+            } else if counts.as_ref()
+                .is_some_and(|counts| counts.get(&barcode).copied().unwrap_or(0) < self.min_count)
+            {
+                report.low_support += 1;
+            } else {
+                kept.push((entry.order, barcode));
+            }
+        }
+        kept.sort_unstable();
+        report.written = kept.len() as u64;
+
+        let path = self.output_dir.join(self.whitelist_format.file_name());
+        let mut writer = self.whitelist_format.create(&temp_path(&path))?;
+        for (_, barcode) in kept {
+            writeln!(writer, "{}{}", barcode, self.whitelist_format.suffix())?;
+        }
+        writer.flush()?;
+        drop(writer);
+        persist(&path)?;
+        Ok(report)
+    }
+}
+
+/// Barcodes written and why the others are not, each barcode counted under its first failed filter
+#[derive(Default)]
+pub struct WhitelistReport {
+    rows: u64,
+    unique: u64,
+    failed_pattern: u64,
+    masked: u64,
+    collided: u64,
+    low_support: u64,
+    written: u64,
+}
+
+impl std::fmt::Display for WhitelistReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Rows={}, Unique={}, Failed pattern={}, Masked={}, Collided={}, Low support={}, Written={}",
+            self.rows, self.unique, self.failed_pattern, self.masked, self.collided, self.low_support, self.written,
+        )
+    }
+}
@@ -0,0 +1,290 @@
+//! C interface of the barcode extraction core, declared in `include/opentools.h`
+//!
+//! All functions work on caller owned buffers and never panic across the boundary:
+//! invalid input is reported by the negative status codes below.
+
+use crate::utils::{
+    chemistry::{Chemistry, OpenSt},
+    fastqfile::{check_base_match, cut, too_short},
+    position::Position,
+};
+use std::ffi::{c_char, CStr, CString};
+use std::slice;
+use std::sync::OnceLock;
+
+pub const OPENTOOLS_OK: i32 = 0;
+pub const OPENTOOLS_ERR_NULL: i32 = -1;
+pub const OPENTOOLS_ERR_POSITION: i32 = -2;
+pub const OPENTOOLS_ERR_PATTERN: i32 = -3;
+pub const OPENTOOLS_ERR_BASE: i32 = -4;
+pub const OPENTOOLS_ERR_TOO_SHORT: i32 = -5;
+pub const OPENTOOLS_ERR_MISMATCH: i32 = -6;
+pub const OPENTOOLS_ERR_BUFFER: i32 = -7;
+
+/// Position of a sequence in a read, same fields as "read{1/2}:{+/-}:start-end",
+/// `start`/`end` count back from the 3' end of the read when their `_from_end` flag is set
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct OpentoolsPosition {
+    pub read2: bool,
+    pub revcomp: bool,
+    pub start: usize,
+    pub end: usize,
+    pub start_from_end: bool,
+    pub end_from_end: bool,
+}
+
+impl From<Position> for OpentoolsPosition {
+    fn from(pos: Position) -> Self {
+        Self {
+            read2: pos.is_read2(), revcomp: pos.is_revcomp(), start: pos.start(), end: pos.end(),
+            start_from_end: pos.is_start_from_end(), end_from_end: pos.is_end_from_end(),
+        }
+    }
+}
+
+impl OpentoolsPosition {
+    fn to_position(self) -> Option<Position> {
+        let ordered = match (self.start_from_end, self.end_from_end) {
+            (false, false) => self.start <= self.end,
+            (true, true) => self.start >= self.end,
+            _ => true,
+        };
+        ordered.then(|| Position::from_end(
+            self.read2, self.revcomp, (self.start, self.start_from_end), (self.end, self.end_from_end)
+        ))
+    }
+}
+
+/// Borrow `len` bytes, an empty slice for a null pointer with zero length
+unsafe fn bytes<'a>(ptr: *const u8, len: usize) -> Option<&'a [u8]> {
+    match (ptr.is_null(), len) {
+        (true, 0) => Some(&[]),
+        (true, _) => None,
+        (false, _) => Some(unsafe { slice::from_raw_parts(ptr, len) }),
+    }
+}
+
+#[inline]
+fn valid_pattern(pattern: &[u8]) -> bool {
+    !pattern.is_empty() && pattern.iter().all(|b| b"ATGCURYMKSWHBVDN".contains(b))
+}
+
+/// Parse "read{1/2}:{+/-}:start-end" into `out`
+///
+/// # Safety
+/// `text` is a NUL terminated string and `out` points to a writable position
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn opentools_position_parse(text: *const c_char, out: *mut OpentoolsPosition) -> i32 {
+    if text.is_null() || out.is_null() {
+        return OPENTOOLS_ERR_NULL;
+    }
+    let Ok(text) = unsafe { CStr::from_ptr(text) }.to_str() else {
+        return OPENTOOLS_ERR_POSITION;
+    };
+    match text.parse::<Position>() {
+        Ok(pos) => {
+            unsafe { *out = pos.into() };
+            OPENTOOLS_OK
+        }
+        Err(_) => OPENTOOLS_ERR_POSITION,
+    }
+}
+
+/// Position and pattern of the OpenST chemistry, the pattern is a static NUL terminated string
+///
+/// # Safety
+/// `pos` and `pattern` are null or writable
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn opentools_openst(pos: *mut OpentoolsPosition, pattern: *mut *const c_char) {
+    static PATTERN: OnceLock<CString> = OnceLock::new();
+    let (openst, openst_pattern) = OpenSt.library_barcode();
+    if !pos.is_null() {
+        unsafe { *pos = openst.into() };
+    }
+    if !pattern.is_null() {
+        let openst_pattern = PATTERN.get_or_init(|| CString::new(openst_pattern).expect("pattern has no NUL"));
+        unsafe { *pattern = openst_pattern.as_ptr() };
+    }
+}
+
+/// Whether `seq` matches the IUPAC `pattern` base by base, an `N` in the sequence mismatches every
+/// pattern base, `N` included
+///
+/// Returns 1 on a match, 0 on a mismatch and a negative status for an invalid pattern
+///
+/// # Safety
+/// `seq` and `pattern` point to `seq_len` and `pattern_len` readable bytes
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn opentools_pattern_match(
+    seq: *const u8, seq_len: usize, pattern: *const u8, pattern_len: usize,
+) -> i32 {
+    let (Some(seq), Some(pattern)) = (unsafe { bytes(seq, seq_len) }, unsafe { bytes(pattern, pattern_len) }) else {
+        return OPENTOOLS_ERR_NULL;
+    };
+    if !valid_pattern(pattern) {
+        return OPENTOOLS_ERR_PATTERN;
+    }
+    !seq.iter().zip(pattern).any(|(&b, &p)| check_base_match(b, p)) as i32
+}
+
+/// Cut the bases and qualities at `pos` from one read, reverse complemented on the minus strand,
+/// the same way `opentools extract` does
+///
+/// `pattern` may be null to skip the pattern check. `out_seq` and `out_qual` take `out_cap` bytes,
+/// the barcode length is returned, or a negative status when the read is too short, fails the
+/// pattern or has a base other than A, T, G, C or N. An `N` in the barcode fails any pattern
+///
+/// # Safety
+/// `seq` and `qual` point to `len` readable bytes, `pattern` to `pattern_len` readable bytes or is null,
+/// `out_seq` and `out_qual` to `out_cap` writable bytes
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn opentools_extract(
+    pos: *const OpentoolsPosition,
+    seq: *const u8, qual: *const u8, len: usize,
+    pattern: *const u8, pattern_len: usize,
+    out_seq: *mut u8, out_qual: *mut u8, out_cap: usize,
+) -> isize {
+    if pos.is_null() || out_seq.is_null() || out_qual.is_null() {
+        return OPENTOOLS_ERR_NULL as isize;
+    }
+    let Some(pos) = unsafe { *pos }.to_position() else {
+        return OPENTOOLS_ERR_POSITION as isize;
+    };
+    let (Some(seq), Some(qual)) = (unsafe { bytes(seq, len) }, unsafe { bytes(qual, len) }) else {
+        return OPENTOOLS_ERR_NULL as isize;
+    };
+    if too_short(&pos, seq.len()) {
+        return OPENTOOLS_ERR_TOO_SHORT as isize;
+    }
+    let barcode = pos.safe_slice(seq);
+    if !barcode.iter().all(|b| b"ATGCN".contains(b)) {
+        return OPENTOOLS_ERR_BASE as isize;
+    }
+    if !pattern.is_null() {
+        let pattern = unsafe { slice::from_raw_parts(pattern, pattern_len) };
+        if !valid_pattern(pattern) {
+            return OPENTOOLS_ERR_PATTERN as isize;
+        }
+        if barcode.iter().zip(pattern).any(|(&b, &p)| check_base_match(b, p)) {
+            return OPENTOOLS_ERR_MISMATCH as isize;
+        }
+    }
+    if barcode.len() > out_cap {
+        return OPENTOOLS_ERR_BUFFER as isize;
+    }
+    let (barcode, barcode_qual) = cut(&pos, seq, qual);
+    unsafe {
+        std::ptr::copy_nonoverlapping(barcode.as_ptr(), out_seq, barcode.len());
+        std::ptr::copy_nonoverlapping(barcode_qual.as_ptr(), out_qual, barcode_qual.len());
+    }
+    barcode.len() as isize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ptr;
+
+    fn position(text: &str) -> OpentoolsPosition {
+        let text = CString::new(text).unwrap();
+        let mut pos = OpentoolsPosition { read2: false, revcomp: false, start: 0, end: 0, start_from_end: false, end_from_end: false };
+        assert_eq!(unsafe { opentools_position_parse(text.as_ptr(), &mut pos) }, OPENTOOLS_OK);
+        pos
+    }
+
+    fn pattern_match(seq: &[u8], pattern: &[u8]) -> i32 {
+        unsafe { opentools_pattern_match(seq.as_ptr(), seq.len(), pattern.as_ptr(), pattern.len()) }
+    }
+
+    /// Barcode and qualities cut by `opentools_extract`, or its status
+    fn extract(pos: &OpentoolsPosition, seq: &[u8], qual: &[u8], pattern: Option<&[u8]>, cap: usize) -> Result<(Vec<u8>, Vec<u8>), isize> {
+        let (mut out_seq, mut out_qual) = (vec![0; cap], vec![0; cap]);
+        let (pattern, pattern_len) = pattern.map_or((ptr::null(), 0), |pattern| (pattern.as_ptr(), pattern.len()));
+        let len = unsafe {
+            opentools_extract(pos, seq.as_ptr(), qual.as_ptr(), seq.len(), pattern, pattern_len,
+                out_seq.as_mut_ptr(), out_qual.as_mut_ptr(), cap)
+        };
+        if len < 0 {
+            return Err(len);
+        }
+        out_seq.truncate(len as usize);
+        out_qual.truncate(len as usize);
+        Ok((out_seq, out_qual))
+    }
+
+    #[test]
+    fn test_position_parse() {
+        let pos = position("read2:-:5-end");
+        assert!(pos.read2 && pos.revcomp && pos.start == 5 && pos.end == 150);
+        let pos = position("read1:+:-8-end");
+        assert!(!pos.read2 && pos.start_from_end && pos.end_from_end && pos.start == 8 && pos.end == 0);
+
+        let mut out = position("read1:+:0-4");
+        for text in [c"read3:+:0-4", c"read1:*:0-4", c"read1:+:4-0", c"garbage"] {
+            assert_eq!(unsafe { opentools_position_parse(text.as_ptr(), &mut out) }, OPENTOOLS_ERR_POSITION, "{text:?}");
+        }
+        let not_utf8 = [0xff, 0xfe, 0];
+        assert_eq!(unsafe { opentools_position_parse(not_utf8.as_ptr().cast(), &mut out) }, OPENTOOLS_ERR_POSITION);
+        assert_eq!(unsafe { opentools_position_parse(ptr::null(), &mut out) }, OPENTOOLS_ERR_NULL);
+        assert_eq!(unsafe { opentools_position_parse(c"read1:+:0-4".as_ptr(), ptr::null_mut()) }, OPENTOOLS_ERR_NULL);
+    }
+
+    #[test]
+    fn test_openst() {
+        let (openst, openst_pattern) = OpenSt.library_barcode();
+        let mut pos = position("read2:+:0-1");
+        let mut pattern = ptr::null();
+        unsafe { opentools_openst(&mut pos, &mut pattern) };
+        assert_eq!(pos.to_position().unwrap().to_string(), openst.to_string());
+        assert_eq!(unsafe { CStr::from_ptr(pattern) }.to_str().unwrap(), openst_pattern);
+        // either output may be left out
+        unsafe { opentools_openst(ptr::null_mut(), ptr::null_mut()) };
+    }
+
+    #[test]
+    fn test_pattern_match() {
+        assert_eq!(pattern_match(b"ACGT", b"ACGT"), 1);
+        assert_eq!(pattern_match(b"ACGT", b"ACGA"), 0);
+        assert_eq!(pattern_match(b"ACGT", b"NVNB"), 1);
+        // an N read base mismatches, whatever the pattern says
+        assert_eq!(pattern_match(b"ACNT", b"ACGT"), 0);
+        assert_eq!(pattern_match(b"ACNT", b"ACNT"), 0);
+        assert_eq!(pattern_match(b"ACGT", b"ACXT"), OPENTOOLS_ERR_PATTERN);
+        assert_eq!(pattern_match(b"ACGT", b""), OPENTOOLS_ERR_PATTERN);
+        assert_eq!(unsafe { opentools_pattern_match(ptr::null(), 4, b"ACGT".as_ptr(), 4) }, OPENTOOLS_ERR_NULL);
+        assert_eq!(unsafe { opentools_pattern_match(b"ACGT".as_ptr(), 4, ptr::null(), 4) }, OPENTOOLS_ERR_NULL);
+        // a null sequence of no bases is empty and matches
+        assert_eq!(unsafe { opentools_pattern_match(ptr::null(), 0, b"ACGT".as_ptr(), 4) }, 1);
+    }
+
+    #[test]
+    fn test_extract() {
+        let forward = position("read1:+:1-5");
+        assert_eq!(extract(&forward, b"TAACCG", b"ABCDEF", None, 8), Ok((b"AACC".to_vec(), b"BCDE".to_vec())));
+        let minus = position("read1:-:1-5");
+        assert_eq!(extract(&minus, b"TAACCG", b"ABCDEF", None, 8), Ok((b"GGTT".to_vec(), b"EDCB".to_vec())));
+        assert_eq!(extract(&forward, b"TAACCG", b"ABCDEF", Some(b"NNBB"), 8), Ok((b"AACC".to_vec(), b"BCDE".to_vec())));
+
+        assert_eq!(extract(&forward, b"TAAC", b"ABCD", None, 8), Err(OPENTOOLS_ERR_TOO_SHORT as isize));
+        assert_eq!(extract(&forward, b"TAXCCG", b"ABCDEF", None, 8), Err(OPENTOOLS_ERR_BASE as isize));
+        assert_eq!(extract(&forward, b"TAACCG", b"ABCDEF", Some(b"NNGG"), 8), Err(OPENTOOLS_ERR_MISMATCH as isize));
+        // N is a valid base but fails the pattern, even an N of the pattern
+        assert_eq!(extract(&forward, b"TANCCG", b"ABCDEF", None, 8), Ok((b"ANCC".to_vec(), b"BCDE".to_vec())));
+        assert_eq!(extract(&forward, b"TANCCG", b"ABCDEF", Some(b"NNNN"), 8), Err(OPENTOOLS_ERR_MISMATCH as isize));
+        assert_eq!(extract(&forward, b"TAACCG", b"ABCDEF", Some(b"NNZN"), 8), Err(OPENTOOLS_ERR_PATTERN as isize));
+        assert_eq!(extract(&forward, b"TAACCG", b"ABCDEF", None, 3), Err(OPENTOOLS_ERR_BUFFER as isize));
+
+        let reversed = OpentoolsPosition { start: 5, end: 1, ..forward };
+        assert_eq!(extract(&reversed, b"TAACCG", b"ABCDEF", None, 8), Err(OPENTOOLS_ERR_POSITION as isize));
+
+        let (mut out_seq, mut out_qual) = ([0u8; 4], [0u8; 4]);
+        let out_qual = out_qual.as_mut_ptr();
+        let status = |pos: *const OpentoolsPosition, seq: *const u8, out_seq: *mut u8| unsafe {
+            opentools_extract(pos, seq, b"ABCDEF".as_ptr(), 6, ptr::null(), 0, out_seq, out_qual, 4)
+        };
+        assert_eq!(status(ptr::null(), b"TAACCG".as_ptr(), out_seq.as_mut_ptr()), OPENTOOLS_ERR_NULL as isize);
+        assert_eq!(status(&forward, ptr::null(), out_seq.as_mut_ptr()), OPENTOOLS_ERR_NULL as isize);
+        assert_eq!(status(&forward, b"TAACCG".as_ptr(), ptr::null_mut()), OPENTOOLS_ERR_NULL as isize);
+    }
+}
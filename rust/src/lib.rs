@@ -1,7 +1,31 @@
 //! # Opentools
 //!
 //! `opentools` is a rust toolbox that replaces spacemake
+//!
+//! Besides the `opentools` command line, the entry points below run the same processing
+//! from other Rust tools without building command line arguments, and [`ffi`] exposes the
+//! barcode extraction core to C through `include/opentools.h`.
+//!
+//! Without the default `bam` feature only [`utils`] and [`ffi`] build: the FASTQ, barcode pattern,
+//! chemistry and position core, free of htslib and other C libraries, e.g. for wasm32.
 
 pub mod utils;
+#[cfg(feature = "bam")]
 pub mod argparse;
-pub mod run;
\ No newline at end of file
+#[cfg(feature = "bam")]
+pub mod run;
+pub mod ffi;
+
+#[cfg(feature = "bam")]
+pub use argparse::{
+    extract::{BarcodeExtractor, ExtractReport, NameFormat},
+    tilesmatch::{BarcodeFileReport, TileMatchReport, TileMatcher},
+};
+pub use utils::{
+    chemistry::{register as register_chemistry, BarcodeConfig, Chemistry, OpenSt},
+    error::AppError,
+    observer::{BarcodeRead, Filter, RecordObserver},
+    position::Position,
+};
+#[cfg(feature = "tokio")]
+pub use utils::async_io::{AsyncBarcodeReader, AsyncFastqReader, AsyncFastqWriter, FastqRecord};
@@ -1,16 +1,94 @@
 
-use clap::Parser;
-use opentools::argparse::{Cli, Commands};
+use clap::{ArgMatches, CommandFactory, FromArgMatches};
+use opentools::argparse::{config, existing_outputs, Cli, Commands, ErrorFormat, ProgressFormat};
 use opentools::run;
-use opentools::utils::error::AppError;
+use opentools::utils::{error::AppError, buffers, interrupt, pgzip, progress, seed, spill, term, threads};
 
 fn main() -> Result<(), AppError> {
-    let cli = Cli::parse();
-    
+    let matches = Cli::command().get_matches_from(config::with_config(std::env::args_os().collect())?);
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    term::init(cli.no_color);
+    // log lines go to stderr, stdout only carries the output of the subcommands
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_max_level(cli.log_level())
+        .with_target(false)
+        .with_ansi(term::stderr())
+        .init();
+    interrupt::install()?;
+    let error_format = cli.error_format;
+    let result = run(cli, &matches);
+    match (&result, error_format) {
+        (Err(err), ErrorFormat::Json) => eprintln!("{}", err.to_json()),
+        (Err(err), ErrorFormat::Text) if term::stderr() => eprintln!("{} {err:?}", term::paint("Error:", term::ERROR, true)),
+        _ => return result,
+    }
+    std::process::exit(1)
+}
+
+fn run(cli: Cli, matches: &ArgMatches) -> Result<(), AppError> {
+    if !cli.force {
+        let existing = existing_outputs(matches);
+        if !existing.is_empty() {
+            return Err(AppError::OutputExists(existing));
+        }
+    }
+    if let Some(count) = cli.threads {
+        threads::init(count)?;
+    }
+    if let Some(count) = cli.decompress_threads {
+        pgzip::init(count);
+    }
+    buffers::init_buffers(cli.read_buffer_size, cli.write_buffer_size);
+    if let Some(record_sets) = cli.queue_size {
+        buffers::init_queue(record_sets);
+    }
+    if let Some(tmpdir) = cli.tmpdir() {
+        spill::set_tmpdir(tmpdir);
+    }
+    if let Some(seed) = cli.seed {
+        seed::init(seed);
+    }
+    if let Some(ProgressFormat::Jsonl) = cli.progress {
+        progress::init(cli.progress_file.as_deref())?;
+    }
+
     match cli.command {
         Commands::TouchBarcode(args) => run::touchbarcode(args)?,
-        Commands::ViewBarcode(args) => run::dedupbarcode(args)?,
+        Commands::DedupBarcode(args) => run::dedupbarcode(args)?,
+        Commands::ViewBarcode(args) => run::viewbarcode(args)?,
         Commands::TilesMatch(args) => run::tilesmatch(args)?,
+        Commands::BarcodeRank(args) => run::barcoderank(args)?,
+        Commands::SpatialTag(args) => run::spatialtag(args)?,
+        Commands::Count(args) => run::count(args)?,
+        Commands::Demux(args) => run::demux(args)?,
+        Commands::Whitelist(args) => run::whitelist(args)?,
+        Commands::Qc(args) => run::qc(args)?,
+        Commands::Convert(args) => run::convert(args)?,
+        Commands::Segment(args) => run::segment(args)?,
+        Commands::MergeBam(args) => run::mergebam(args)?,
+        Commands::IndexBarcode(args) => run::indexbarcode(args)?,
+        Commands::FqStat(args) => run::fqstat(args)?,
+        Commands::Validate(args) => run::validate(args)?,
+        Commands::Extract(args) => run::extract(args)?,
+        Commands::Pipeline(args) => run::pipeline(args)?,
+        Commands::Completions(args) => run::completions(args)?,
+        Commands::TileImage(args) => run::tileimage(args)?,
+        Commands::Collide(args) => run::collide(args)?,
+        Commands::AdapterScan(args) => run::adapterscan(args)?,
+        Commands::SplitLane(args) => run::splitlane(args)?,
+        Commands::Region(args) => run::region(args)?,
+        Commands::Compare(args) => run::compare(args)?,
+        Commands::Stitch(args) => run::stitch(args)?,
+        Commands::Register(args) => run::register(args)?,
+        Commands::Bin(args) => run::bin(args)?,
+        Commands::Saturation(args) => run::saturation(args)?,
+        Commands::Mask(args) => run::mask(args)?,
+        Commands::Report(args) => run::report(args)?,
+        Commands::Interop(args) => run::interop(args)?,
+        Commands::Phix(args) => run::phix(args)?,
+        Commands::ErrProfile(args) => run::errprofile(args)?,
+        Commands::SplitPool(args) => run::splitpool(args)?,
     }
     
     Ok(())
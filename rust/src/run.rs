@@ -1,12 +1,49 @@
 use crate::argparse::{
+    barcoderank::BarcodeRankArgs,
+    spatialtag::SpatialTagArgs,
+    count::CountArgs,
+    demux::DemuxArgs,
+    whitelist::WhitelistArgs,
+    qc::QcArgs,
+    convert::ConvertArgs,
+    segment::SegmentArgs,
+    mergebam::MergeBamArgs,
+    indexbarcode::IndexBarcodeArgs,
+    fqstat::FqStatArgs,
+    validate::ValidateArgs,
+    extract::ExtractArgs,
+    pipeline::PipelineArgs,
+    completions::CompletionsArgs,
+    tileimage::TileImageArgs,
+    collide::CollideArgs,
+    adapterscan::AdapterScanArgs,
+    splitlane::SplitLaneArgs,
+    region::RegionArgs,
+    compare::CompareArgs,
+    stitch::StitchArgs,
+    register::RegisterArgs,
+    bin::BinArgs,
+    saturation::SaturationArgs,
+    mask::MaskArgs,
+    report::ReportArgs,
+    interop::InteropArgs,
+    phix::PhixArgs,
+    errprofile::ErrProfileArgs,
+    splitpool::SplitPoolArgs,
     dedupbarcode::DedupBarcodeArgs, 
     tilesmatch::TilesMatchArgs,
-    touchbarcode::TouchBarcodeArgs,
+    touchbarcode::{TouchBarcodeArgs, BARCODE_FILE},
+    viewbarcode::ViewBarcodeArgs,
+};
+use crate::utils::{
+    atomic_file::{persist_indexed, temp_path}, barcode_file::BARCODE_FILE_HEADER, barcode_index, error::AppError, observer::RecordObserver, progress::Tracker, threads,
+    term::{self, Table},
 };
-use crate::utils::error::AppError;
 
+use anstyle::Style;
 use rayon::{ThreadPoolBuilder, prelude::*};
 use std::{fs, process::Command};
+use tracing::{debug, info};
 
 /// Default thread count configuration
 /// 
@@ -14,7 +51,7 @@ use std::{fs, process::Command};
 pub const DEFAULT_LINUX_THREADS: usize = 12;
 pub const DEFAULT_MAC_THREADS: usize = 3;
 
-/// Handles barcode viewing and deduplication
+/// Handles barcode deduplication
 ///
 /// # Arguments
 /// - `args`: DedupBarcodeArgs struct containing input files and deduplication configuration
@@ -22,7 +59,435 @@ pub const DEFAULT_MAC_THREADS: usize = 3;
 /// # Errors
 /// Returns AppError for possible I/O errors or data processing errors
 pub fn dedupbarcode(args: DedupBarcodeArgs) -> Result<(), AppError> {
-    args.dedup()?;
+    let stats = args.dedup()?;
+    let report = stats.to_string();
+    match report.split_once('\n') {
+        // the per tile table is aligned on a terminal
+        Some((summary, tiles)) if term::stderr() => {
+            let mut lines = tiles.lines();
+            let mut table = Table::new(lines.next().unwrap_or_default());
+            lines.for_each(|line| table.push(line, Style::new()));
+            info!("{summary}\n{}", table.render(true));
+        }
+        _ => info!("{report}"),
+    }
+    Ok(())
+}
+
+/// Handles barcode file query and printing
+///
+/// # Arguments
+/// - `args`: ViewBarcodeArgs struct containing barcode file and record filters
+///
+/// # Errors
+/// Returns AppError for possible I/O errors or tabix query errors
+pub fn viewbarcode(args: ViewBarcodeArgs) -> Result<(), AppError> {
+    args.view()?;
+    Ok(())
+}
+
+/// Handles barcode rank curve
+///
+/// # Arguments
+/// - `args`: BarcodeRankArgs struct containing the tagged BAM or count table and output directory
+///
+/// # Errors
+/// Returns AppError for possible I/O errors or BAM parsing errors
+pub fn barcoderank(args: BarcodeRankArgs) -> Result<(), AppError> {
+    let report = args.rank()?;
+    info!("{report}");
+    Ok(())
+}
+
+/// Handles spatial tagging of aligned reads
+///
+/// # Arguments
+/// - `args`: SpatialTagArgs struct containing the tagged BAM, barcode map and output tags
+///
+/// # Errors
+/// Returns AppError for possible I/O errors or BAM read/write errors
+pub fn spatialtag(args: SpatialTagArgs) -> Result<(), AppError> {
+    let report = args.tag()?;
+    info!("{report}");
+    Ok(())
+}
+
+/// Handles gene x spatial barcode UMI counting
+///
+/// # Arguments
+/// - `args`: CountArgs struct containing the tagged BAM, GTF and output directory
+///
+/// # Errors
+/// Returns AppError for possible I/O errors, GTF format errors or BAM read errors
+pub fn count(args: CountArgs) -> Result<(), AppError> {
+    let report = args.count()?;
+    info!("{report}");
+    Ok(())
+}
+
+/// Handles splitting reads by tile or region
+///
+/// # Arguments
+/// - `args`: DemuxArgs struct containing the reads, barcode map and regions
+///
+/// # Errors
+/// Returns AppError for possible I/O errors, FASTQ parsing errors or BAM read/write errors
+pub fn demux(args: DemuxArgs) -> Result<(), AppError> {
+    let report = args.demux()?;
+    info!("{report}");
+    Ok(())
+}
+
+/// Handles whitelist building
+///
+/// # Arguments
+/// - `args`: WhitelistArgs struct containing the barcode file, read support and filters
+///
+/// # Errors
+/// Returns AppError for possible I/O errors or invalid barcode, count or region files
+pub fn whitelist(args: WhitelistArgs) -> Result<(), AppError> {
+    let report = args.build()?;
+    info!("{report}");
+    Ok(())
+}
+
+/// Handles run QC reporting
+///
+/// # Arguments
+/// - `args`: QcArgs struct containing the metric files of a run and the output directory
+///
+/// # Errors
+/// Returns AppError for possible I/O errors or invalid metric files
+pub fn qc(args: QcArgs) -> Result<(), AppError> {
+    let report = args.qc()?;
+    info!("{report}");
+    Ok(())
+}
+
+/// Handles barcode map format conversion
+///
+/// # Arguments
+/// - `args`: ConvertArgs struct containing the input and output barcode maps with their formats
+///
+/// # Errors
+/// Returns AppError for possible I/O errors, invalid barcode maps or parquet errors
+pub fn convert(args: ConvertArgs) -> Result<(), AppError> {
+    let report = args.convert()?;
+    info!("{report}");
+    Ok(())
+}
+
+/// Handles barcode to segment assignment
+///
+/// # Arguments
+/// - `args`: SegmentArgs struct containing the label image, barcode coordinates and their registration
+///
+/// # Errors
+/// Returns AppError for possible I/O errors, unreadable label images or invalid coordinates
+pub fn segment(args: SegmentArgs) -> Result<(), AppError> {
+    let report = args.segment()?;
+    info!("{report}");
+    Ok(())
+}
+
+/// Handles BAM merging
+///
+/// # Arguments
+/// - `args`: MergeBamArgs struct containing the input BAMs, output and sort order
+///
+/// # Errors
+/// Returns AppError for possible I/O errors, incompatible headers or BAM read/write errors
+pub fn mergebam(args: MergeBamArgs) -> Result<(), AppError> {
+    let report = args.merge()?;
+    info!("{report}");
+    Ok(())
+}
+
+/// Handles barcode file indexing
+///
+/// # Arguments
+/// - `args`: IndexBarcodeArgs struct containing the barcode file, output path and sort switch
+///
+/// # Errors
+/// Returns AppError for possible I/O errors, invalid or unsorted barcode files or tabix index errors
+pub fn indexbarcode(args: IndexBarcodeArgs) -> Result<(), AppError> {
+    let report = args.index()?;
+    info!("{report}");
+    Ok(())
+}
+
+/// Handles FASTQ statistics
+///
+/// # Arguments
+/// - `args`: FqStatArgs struct containing the FASTQ files and output options
+///
+/// # Errors
+/// Returns AppError for possible I/O errors or FASTQ parsing errors
+pub fn fqstat(args: FqStatArgs) -> Result<(), AppError> {
+    let report = args.stat()?;
+    info!("{report}");
+    Ok(())
+}
+
+/// Handles barcode file validation
+///
+/// # Arguments
+/// - `args`: ValidateArgs struct containing the barcode file and index checks
+///
+/// # Errors
+/// Returns AppError for possible I/O errors, tabix errors, or a failed validation
+pub fn validate(args: ValidateArgs) -> Result<(), AppError> {
+    let report = args.validate()?;
+    info!("{report}");
+    match report.issues() {
+        0 => Ok(()),
+        issues => Err(AppError::ValidationFailed(issues)),
+    }
+}
+
+/// Handles FASTQ barcode and UMI extraction
+///
+/// # Arguments
+/// - `args`: ExtractArgs struct containing the FASTQ files, barcode and UMI positions and name format
+///
+/// # Errors
+/// Returns AppError for possible I/O errors or FASTQ parsing errors
+pub fn extract(args: ExtractArgs) -> Result<(), AppError> {
+    let report = args.extract()?;
+    info!("{report}");
+    Ok(())
+}
+
+/// Handles the end-to-end pipeline of one chip
+///
+/// # Arguments
+/// - `args`: PipelineArgs struct containing the TOML config and checkpoint options
+///
+/// # Errors
+/// Returns AppError for possible I/O errors, invalid configs or failed steps
+pub fn pipeline(args: PipelineArgs) -> Result<(), AppError> {
+    let report = args.run()?;
+    info!("{report}");
+    Ok(())
+}
+
+/// Handles shell completion scripts
+///
+/// # Arguments
+/// - `args`: CompletionsArgs struct containing the target shell
+///
+/// # Errors
+/// Returns AppError for possible I/O errors
+pub fn completions(args: CompletionsArgs) -> Result<(), AppError> {
+    args.print()?;
+    Ok(())
+}
+
+/// Handles barcode density images
+///
+/// # Arguments
+/// - `args`: TileImageArgs struct containing the barcode file, layout and image options
+///
+/// # Errors
+/// Returns AppError for possible I/O errors, unsorted barcode files or PNG encoding errors
+pub fn tileimage(args: TileImageArgs) -> Result<(), AppError> {
+    let report = args.render()?;
+    info!("{report}");
+    Ok(())
+}
+
+/// Handles inter-tile barcode collision analysis
+///
+/// # Arguments
+/// - `args`: CollideArgs struct containing the barcode file, tile list and output directory
+///
+/// # Errors
+/// Returns AppError for possible I/O errors or invalid barcode files
+pub fn collide(args: CollideArgs) -> Result<(), AppError> {
+    let report = args.collide()?;
+    info!("{report}");
+    Ok(())
+}
+
+/// Handles adapter content scanning
+///
+/// # Arguments
+/// - `args`: AdapterScanArgs struct containing the FASTQ file, adapters and output table
+///
+/// # Errors
+/// Returns AppError for possible I/O errors or FASTQ parsing errors
+pub fn adapterscan(args: AdapterScanArgs) -> Result<(), AppError> {
+    let report = args.scan()?;
+    info!("{report}");
+    Ok(())
+}
+
+/// Handles per-lane splitting of FASTQ and BAM files
+///
+/// # Arguments
+/// - `args`: SplitLaneArgs struct containing the input files, output directory and threads
+///
+/// # Errors
+/// Returns AppError for possible I/O errors, FASTQ or BAM parsing errors, or reads without a lane field
+pub fn splitlane(args: SplitLaneArgs) -> Result<(), AppError> {
+    let report = args.split()?;
+    info!("{report}");
+    Ok(())
+}
+
+/// Handles bounding box extraction of barcodes
+///
+/// # Arguments
+/// - `args`: RegionArgs struct containing the barcode file, bounding box and output path
+///
+/// # Errors
+/// Returns AppError for index, parse or write failures
+pub fn region(args: RegionArgs) -> Result<(), AppError> {
+    let report = args.extract()?;
+    info!("{report}");
+    Ok(())
+}
+
+/// Handles barcode set comparison
+///
+/// # Arguments
+/// - `args`: CompareArgs struct containing the two barcode files and the mismatch tolerance
+///
+/// # Errors
+/// Returns AppError for read or parse failures
+pub fn compare(args: CompareArgs) -> Result<(), AppError> {
+    let report = args.compare()?;
+    info!("{report}");
+    Ok(())
+}
+
+/// Handles global coordinate stitching
+///
+/// # Arguments
+/// - `args`: StitchArgs struct containing the barcode file, flowcell layout and output path
+///
+/// # Errors
+/// Returns AppError for read, layout or write failures
+pub fn stitch(args: StitchArgs) -> Result<(), AppError> {
+    let report = args.stitch()?;
+    info!("{report}");
+    Ok(())
+}
+
+/// Handles registration of barcode coordinates to image space
+///
+/// # Arguments
+/// - `args`: RegisterArgs struct containing the coordinates, landmarks or matrix and output path
+///
+/// # Errors
+/// Returns AppError for read, fit or write failures
+pub fn register(args: RegisterArgs) -> Result<(), AppError> {
+    let report = args.register()?;
+    info!("{report}");
+    Ok(())
+}
+
+/// Handles binning of barcodes into pseudo-spots
+///
+/// # Arguments
+/// - `args`: BinArgs struct containing the coordinates, bin size and shape, counts and output directory
+///
+/// # Errors
+/// Returns AppError for read, parse or write failures
+pub fn bin(args: BinArgs) -> Result<(), AppError> {
+    let report = args.bin()?;
+    info!("{report}");
+    Ok(())
+}
+
+/// Handles sequencing saturation estimation
+///
+/// # Arguments
+/// - `args`: SaturationArgs struct containing the tagged BAM or molecule table and output directory
+///
+/// # Errors
+/// Returns AppError for read, parse or write failures
+pub fn saturation(args: SaturationArgs) -> Result<(), AppError> {
+    let report = args.estimate()?;
+    info!("{report}");
+    Ok(())
+}
+
+/// Handles region and blacklist masking of barcodes
+///
+/// # Arguments
+/// - `args`: MaskArgs struct containing the barcode file, masks and output path
+///
+/// # Errors
+/// Returns AppError for read, parse or write failures
+pub fn mask(args: MaskArgs) -> Result<(), AppError> {
+    let report = args.mask()?;
+    info!("{report}");
+    Ok(())
+}
+
+/// Handles MultiQC custom content output
+///
+/// # Arguments
+/// - `args`: ReportArgs struct containing the sample name, metric files and output directory
+///
+/// # Errors
+/// Returns AppError for read, parse or write failures
+pub fn report(args: ReportArgs) -> Result<(), AppError> {
+    let report = args.write()?;
+    info!("{report}");
+    Ok(())
+}
+
+/// Handles InterOp metrics dump
+///
+/// # Arguments
+/// - `args`: InteropArgs struct containing the run folder and output directory
+///
+/// # Errors
+/// Returns AppError for read or parse failures
+pub fn interop(args: InteropArgs) -> Result<(), AppError> {
+    let report = args.dump()?;
+    info!("{report}");
+    Ok(())
+}
+
+/// Handles PhiX detection and removal
+///
+/// # Arguments
+/// - `args`: PhixArgs struct containing the inputs, PhiX reference and output directory
+///
+/// # Errors
+/// Returns AppError for read, index or write failures
+pub fn phix(args: PhixArgs) -> Result<(), AppError> {
+    let report = args.filter()?;
+    info!("{report}");
+    Ok(())
+}
+
+/// Handles barcode error profile estimation
+///
+/// # Arguments
+/// - `args`: ErrProfileArgs struct containing the whitelist, barcode reads and output directory
+///
+/// # Errors
+/// Returns AppError for read or write failures
+pub fn errprofile(args: ErrProfileArgs) -> Result<(), AppError> {
+    let report = args.estimate()?;
+    info!("{report}");
+    Ok(())
+}
+
+/// Handles FASTQ sharding for array jobs
+///
+/// # Arguments
+/// - `args`: SplitPoolArgs struct containing the FASTQ pair, shard size and output directory
+///
+/// # Errors
+/// Returns AppError for read or write failures
+pub fn splitpool(args: SplitPoolArgs) -> Result<(), AppError> {
+    let report = args.split()?;
+    info!("{report}");
     Ok(())
 }
 
@@ -34,22 +499,34 @@ pub fn dedupbarcode(args: DedupBarcodeArgs) -> Result<(), AppError> {
 /// # Errors
 /// Returns AppError for possible I/O errors, system command not found, or execution failure
 pub fn touchbarcode(args: TouchBarcodeArgs) -> Result<(), AppError> {
-    let args = args.init();
+    run_touchbarcode(args, None)
+}
+
+/// Same as `touchbarcode`, calling the hooks of `observer` for every read of every tile
+///
+/// # Errors
+/// Returns AppError for possible I/O errors, system command not found, or execution failure
+pub fn touchbarcode_with_observer(args: TouchBarcodeArgs, observer: &dyn RecordObserver) -> Result<(), AppError> {
+    run_touchbarcode(args, Some(observer))
+}
+
+fn run_touchbarcode(args: TouchBarcodeArgs, observer: Option<&dyn RecordObserver>) -> Result<(), AppError> {
+    let args = args.init()?;
     args.validate_command()?;
 
     // Create output directories
     let fastq_dir = args.output().join("fastq");
-    let tmp_dir = args.output().join("tmp");
+    let tmp_dir = args.tmp_dir();
     if !fastq_dir.exists() {
         fs::create_dir(&fastq_dir)?;
     }
     if !tmp_dir.exists() {
-        fs::create_dir(&tmp_dir)?;
+        fs::create_dir_all(&tmp_dir)?;
     }
 
     // Extract tile IDs
     let tile_ids = args.extract_tile_ids()?;
-    println!("Extracted tile IDs from bcl directory RunInfo.xml file");
+    info!("Extracted tile IDs from bcl directory RunInfo.xml file");
     let num_threads: usize = if cfg!(target_os = "linux") {
         DEFAULT_LINUX_THREADS
     } else if cfg!(target_os = "macos") {
@@ -59,9 +536,10 @@ pub fn touchbarcode(args: TouchBarcodeArgs) -> Result<(), AppError> {
     };
 
     let pool = ThreadPoolBuilder::new()
-        .num_threads(num_threads)
+        .num_threads(threads::resolve(None, num_threads))
         .build()
         .expect("Build thread pool failed");
+    let convert_tracker = Tracker::new("bcl-convert", tile_ids.len());
     let tile_ids: Vec<String> = pool.install(|| {
         tile_ids
             .par_iter()
@@ -70,24 +548,32 @@ pub fn touchbarcode(args: TouchBarcodeArgs) -> Result<(), AppError> {
                     .fastq_path(tile_id)
                     .join("Undetermined_S0_R1_001.fastq.gz");
                 if !fastq_file.exists() {
-                    println!("Converted tile {tile_id} into fastq");
-                    args.convert_bcl_into_tile(&tile_id)?;
+                    info!("Converted tile {tile_id} into fastq");
+                    args.convert_bcl_into_tile(tile_id)?;
                 } else {
-                    println!("Have already converted tile {tile_id}");
+                    debug!("Have already converted tile {tile_id}");
                 };
+                convert_tracker.finish(Some(tile_id), None);
                 let tile_id = tile_id.replace("_", "");
                 Ok(tile_id)
             })
             .collect::<Result<Vec<String>, AppError>>()
     })?;
 
+    let extract_tracker = Tracker::new("touchbarcode", tile_ids.len());
+    // tiles run side by side on the global pool, the spare threads check the reads within a tile
+    let tile_threads = (threads::resolve(None, threads::available()) / tile_ids.len().max(1)).max(1);
     let mut tile_ids: Vec<String> = tile_ids
         .into_par_iter()
         .map(|tile_id| {
-            let barcode_iter = args.create_barcode_iter(&tile_id)?;
+            let mut barcode_iter = args.create_barcode_iter(&tile_id)?.with_threads(tile_threads);
+            if let Some(observer) = observer {
+                barcode_iter = barcode_iter.with_observer(observer);
+            }
             let report = barcode_iter.extract_chip_barcodes()?;
-            println!("Tile {tile_id}: {report}");
-            println!("Extracted Barcode of tile_id {tile_id} into tmp file.");
+            info!("Tile {tile_id}: {report}");
+            extract_tracker.finish(Some(&tile_id), Some(report.total_count()));
+            debug!("Extracted Barcode of tile_id {tile_id} into tmp file.");
             Ok(tile_id)
         })
         .collect::<Result<Vec<String>, AppError>>()?;
@@ -95,21 +581,17 @@ pub fn touchbarcode(args: TouchBarcodeArgs) -> Result<(), AppError> {
 
     let files: Vec<String> = tile_ids
         .into_iter()
-        .map(|tile_id| {
-            args.output()
-                .join(format!("tmp/{}.txt", tile_id))
-                .display()
-                .to_string()
-        })
+        .map(|tile_id| args.tmp_file(&tile_id).display().to_string())
         .collect();
-    let output_path = args.output().join("barcodes.txt.gz");
+    let output_path = args.output().join(BARCODE_FILE);
 
     let output = Command::new("bash")
         .arg("-c")
-        .arg(&format!(
-            "{{ echo '#tile_id\tx_pos\ty_pos\tbarcode'; cat {}; }} | bgzip -@ $(nproc) > {}",
+        .arg(format!(
+            "{{ echo '{}'; cat {}; }} | bgzip -@ $(nproc) > {}",
+            BARCODE_FILE_HEADER,
             files.join(" "),
-            output_path.display()
+            temp_path(&output_path).display()
         ))
         .output()?;
     if !output.status.success() {
@@ -123,12 +605,16 @@ pub fn touchbarcode(args: TouchBarcodeArgs) -> Result<(), AppError> {
     }
 
     let tabix_status = Command::new("tabix")
-        .args(&["-0", "-s", "1", "-b", "3", "-e", "3"])
-        .arg(output_path)
+        .args(["-0", "-s", "1", "-b", "3", "-e", "3"])
+        .arg(temp_path(&output_path))
         .status()?;
     if !tabix_status.success() {
         return Err(AppError::CommandError("tabix run failed".to_string()));
     }
+    persist_indexed(&output_path)?;
+    if args.binary_index() && barcode_index::build(&output_path)? {
+        info!("Wrote binary index {}", barcode_index::path_of(&output_path).display());
+    }
     Ok(())
 }
 
@@ -141,19 +627,49 @@ pub fn touchbarcode(args: TouchBarcodeArgs) -> Result<(), AppError> {
 /// Returns AppError for possible I/O errors or data processing errors
 pub fn tilesmatch(args: TilesMatchArgs) -> Result<(), AppError> {
     let args = args.init()?;
-    let reports = args.search_tile()?;
-    if !args.quiet() {
-        println!("Tile id\tTotal number\tMatched number\tMatch ratio\tPass threshold")
+    let file_reports = args.search_tile()?;
+    args.write_passed_tiles(&file_reports)?;
+    args.write_json(&file_reports)?;
+    if args.quiet() {
+        for file_report in file_reports {
+            if args.multi_file() {
+                print!("{}\t", file_report.barcode_file().display());
+            }
+            file_report.reports().iter()
+                .filter(|report| report.selected())
+                .for_each(|report| print!("{} ", report.tile_id()));
+            if args.multi_file() {
+                println!();
+            }
+        }
+        return Ok(());
+    }
+    let mut header = String::from("Tile id\tTotal number\tMatched number\tMatch ratio\tPass threshold\tSelected");
+    if args.background() {
+        header.push_str("\tExpected\tEnrichment\t-log10(p)");
+    }
+    if args.replicates().is_some() {
+        header.push_str("\tReplicate mean\t95% CI");
     }
-    reports.into_iter().for_each(|report| {
-        if args.quiet() {
-            if report.pass_threshold() {
-                print!("{} ", report.tile_id());
+    // a terminal gets aligned columns with the selected tiles highlighted, pipes keep the tab separated lines
+    let color = term::stdout();
+    if !color {
+        println!("{header}");
+    }
+    for file_report in file_reports {
+        if args.multi_file() {
+            println!("#{}", file_report.barcode_file().display());
+        }
+        if color {
+            let mut table = Table::new(&header);
+            for report in file_report.reports() {
+                table.push(&report.to_string(), if report.selected() { term::PASS } else { Style::new() });
             }
+            println!("{}", table.render(true));
         } else {
-            println!("{report}")
+            file_report.reports().iter().for_each(|report| println!("{report}"));
         }
-    });
+    }
     Ok(())
 }
 
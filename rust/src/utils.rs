@@ -1,5 +1,31 @@
 
 pub mod fastqfile;
 pub mod position;
+pub mod chemistry;
 pub mod barcode_iter;
+pub mod packed;
+pub mod observer;
+pub mod threads;
+pub mod barcode_file;
+#[cfg(feature = "bam")]
+pub mod barcode_index;
+pub mod coordinate;
+pub mod spill;
+pub mod pgzip;
+pub mod buffers;
+pub mod atomic_file;
+pub mod plot;
+pub mod gtf;
+pub mod label_image;
+pub mod interop;
+#[cfg(feature = "bam")]
+pub mod hts;
+pub mod interrupt;
+pub mod progress;
+pub mod seed;
+pub mod term;
+#[cfg(feature = "tokio")]
+pub mod async_io;
+#[cfg(feature = "remote")]
+pub mod remote;
 pub mod error;
\ No newline at end of file
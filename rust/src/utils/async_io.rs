@@ -0,0 +1,144 @@
+use super::{
+    atomic_file::temp_path,
+    barcode_file::BarcodeRecord,
+    error::AppError,
+};
+use std::io;
+use std::path::{Path, PathBuf};
+use async_compression::tokio::{bufread::GzipDecoder, write::GzipEncoder};
+use tokio::fs::{self, File};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+
+type AsyncReader = Box<dyn AsyncBufRead + Send + Unpin>;
+
+/// Open a file, decompressing it when it starts with the gzip magic bytes
+async fn open_raw(path: &Path) -> io::Result<AsyncReader> {
+    let mut reader = BufReader::with_capacity(64 * 1024, File::open(path).await?);
+    if reader.fill_buf().await?.starts_with(&[0x1f, 0x8b]) {
+        let mut decoder = GzipDecoder::new(reader);
+        decoder.multiple_members(true);
+        Ok(Box::new(BufReader::new(decoder)))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+/// Line without its line break, `false` at the end of the file
+async fn read_line(reader: &mut AsyncReader, line: &mut Vec<u8>) -> io::Result<bool> {
+    line.clear();
+    if reader.read_until(b'\n', line).await? == 0 {
+        return Ok(false);
+    }
+    while line.last().is_some_and(|&b| b == b'\n' || b == b'\r') {
+        line.pop();
+    }
+    Ok(true)
+}
+
+/// Owned FASTQ record, `head` is the line after `@`
+#[derive(Debug, Clone, Default)]
+pub struct FastqRecord {
+    pub head: Vec<u8>,
+    pub seq: Vec<u8>,
+    pub qual: Vec<u8>,
+}
+
+/// FASTQ reader on tokio, plain or gzipped
+pub struct AsyncFastqReader {
+    reader: AsyncReader,
+    line: Vec<u8>,
+}
+
+impl AsyncFastqReader {
+    pub async fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self { reader: open_raw(path.as_ref()).await?, line: Vec::new() })
+    }
+
+    /// Read the next record into `record`, `false` at the end of the file
+    pub async fn read(&mut self, record: &mut FastqRecord) -> Result<bool, AppError> {
+        let invalid = |message: &str| AppError::IoError(io::Error::new(io::ErrorKind::InvalidData, message.to_string()));
+        if !read_line(&mut self.reader, &mut self.line).await? {
+            return Ok(false);
+        }
+        let Some(head) = self.line.strip_prefix(b"@") else {
+            return Err(invalid("FASTQ record does not start with '@'"));
+        };
+        record.head.clear();
+        record.head.extend_from_slice(head);
+        if !read_line(&mut self.reader, &mut record.seq).await?
+            || !read_line(&mut self.reader, &mut self.line).await?
+            || !self.line.starts_with(b"+")
+            || !read_line(&mut self.reader, &mut record.qual).await?
+        {
+            return Err(invalid("truncated FASTQ record"));
+        }
+        if record.seq.len() != record.qual.len() {
+            return Err(invalid("FASTQ sequence and quality lengths differ"));
+        }
+        Ok(true)
+    }
+
+    pub async fn next(&mut self) -> Result<Option<FastqRecord>, AppError> {
+        let mut record = FastqRecord::default();
+        Ok(self.read(&mut record).await?.then_some(record))
+    }
+}
+
+/// Barcode file reader on tokio, header and comment lines skipped
+pub struct AsyncBarcodeReader {
+    reader: AsyncReader,
+    line: String,
+}
+
+impl AsyncBarcodeReader {
+    pub async fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self { reader: open_raw(path.as_ref()).await?, line: String::new() })
+    }
+
+    pub async fn next(&mut self) -> Result<Option<BarcodeRecord<'_>>, AppError> {
+        loop {
+            self.line.clear();
+            if self.reader.read_line(&mut self.line).await? == 0 {
+                return Ok(None);
+            }
+            let line = self.line.trim_end_matches(['\n', '\r']);
+            if !(line.is_empty() || line.starts_with('#') || line.starts_with("tile_id")) {
+                break;
+            }
+        }
+        BarcodeRecord::parse(self.line.trim_end_matches(['\n', '\r'])).map(Some)
+    }
+}
+
+/// FASTQ writer on tokio, gzipped when the path ends with `.gz`
+///
+/// Records go to the temporary path of the output, which `finish` moves into place
+pub struct AsyncFastqWriter {
+    path: PathBuf,
+    writer: Box<dyn AsyncWrite + Send + Unpin>,
+}
+
+impl AsyncFastqWriter {
+    pub async fn create(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let file = BufWriter::new(File::create(temp_path(&path)).await?);
+        let writer: Box<dyn AsyncWrite + Send + Unpin> = if path.extension().is_some_and(|ext| ext == "gz") {
+            Box::new(GzipEncoder::new(file))
+        } else {
+            Box::new(file)
+        };
+        Ok(Self { path, writer })
+    }
+
+    pub async fn write(&mut self, record: &FastqRecord) -> io::Result<()> {
+        for part in [b"@".as_slice(), &record.head, b"\n", &record.seq, b"\n+\n", &record.qual, b"\n"] {
+            self.writer.write_all(part).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn finish(mut self) -> io::Result<()> {
+        self.writer.shutdown().await?;
+        fs::rename(temp_path(&self.path), &self.path).await
+    }
+}
@@ -0,0 +1,36 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use super::interrupt::remove_on_interrupt;
+
+/// Temporary path `{path}.tmp` an output is written to before it is complete
+/// 
+/// Kept next to `path` so the final rename stays on the same filesystem, it is removed together
+/// with its `.tbi` index when the run is interrupted
+pub fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".tmp");
+    let temp = PathBuf::from(name);
+    remove_on_interrupt(&temp);
+    remove_on_interrupt(&index_of(&temp));
+    temp
+}
+
+#[inline]
+fn index_of(path: &Path) -> PathBuf {
+    let mut index = path.as_os_str().to_owned();
+    index.push(".tbi");
+    PathBuf::from(index)
+}
+
+/// Move the complete temporary file of `path` into place
+#[inline]
+pub fn persist(path: &Path) -> io::Result<()> {
+    fs::rename(temp_path(path), path)
+}
+
+/// Same as `persist`, also moving the `.tbi` index built on the temporary file
+pub fn persist_indexed(path: &Path) -> io::Result<()> {
+    fs::rename(index_of(&temp_path(path)), index_of(path))?;
+    persist(path)
+}
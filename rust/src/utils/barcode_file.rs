@@ -0,0 +1,169 @@
+use super::error::AppError;
+use memchr::memchr_iter;
+use std::io;
+#[cfg(feature = "bam")]
+use {
+    super::hts::open_tabix,
+    rust_htslib::{bgzf, htslib, tbx},
+    std::ffi::CString,
+    std::ops::{Deref, DerefMut},
+    std::path::{Path, PathBuf},
+    std::sync::Mutex,
+};
+
+/// Start and end of tile positions fetched from the tabix index
+pub const TILE_FETCH_START: u64 = 1000;
+pub const TILE_FETCH_END: u64 = 37100;
+
+/// One row of the barcode file: `tile_id\tx_pos\ty_pos\tbarcode[\tquality]`
+#[derive(Debug, Clone, Copy)]
+pub struct BarcodeRecord<'a> {
+    pub tile_id: &'a str,
+    pub x_pos: &'a str,
+    pub y_pos: &'a str,
+    pub barcode: &'a str,
+    /// mean Phred quality of the barcode bases, missing in files of older touchbarcode
+    pub quality: Option<&'a str>,
+}
+
+impl<'a> BarcodeRecord<'a> {
+    pub fn parse(line: &'a str) -> Result<Self, AppError> {
+        let mut fields = line.split('\t');
+        match (fields.next(), fields.next(), fields.next(), fields.next()) {
+            (Some(tile_id), Some(x_pos), Some(y_pos), Some(barcode)) => Ok(Self {
+                tile_id,
+                x_pos,
+                y_pos,
+                barcode,
+                quality: fields.next(),
+            }),
+            _ => Err(AppError::IoError(io::Error::new(
+                io::ErrorKind::InvalidData, 
+                "Invalid tile's barcode file format"
+            ))),
+        }
+    }
+}
+
+/// Barcode column of a raw barcode file record, found with memchr without decoding the line
+#[inline]
+pub fn barcode_field(record: &[u8]) -> Result<&[u8], AppError> {
+    let mut tabs = memchr_iter(b'\t', record);
+    match tabs.nth(2) {
+        Some(start) => Ok(&record[start + 1..tabs.next().unwrap_or(record.len())]),
+        None => Err(AppError::IoError(io::Error::new(
+            io::ErrorKind::InvalidData, 
+            "Invalid tile's barcode file format"
+        ))),
+    }
+}
+
+/// Tabix readers of one barcode file shared by parallel tile tasks
+///
+/// Opening a reader loads the whole `.tbi`, which is the slow part on network file systems. A
+/// task borrows an idle reader and fetches its tile with it, so the index is loaded once per
+/// concurrent task instead of once per tile.
+#[cfg(feature = "bam")]
+pub struct TabixPool {
+    barcode_file: PathBuf,
+    idle: Mutex<Vec<tbx::Reader>>,
+}
+
+#[cfg(feature = "bam")]
+impl TabixPool {
+    pub fn new(barcode_file: &Path) -> Self {
+        Self { barcode_file: barcode_file.to_path_buf(), idle: Mutex::new(Vec::new()) }
+    }
+
+    #[inline]
+    pub fn barcode_file(&self) -> &Path {
+        &self.barcode_file
+    }
+
+    /// Reader fetching all records of the tile, back in the pool when dropped
+    pub fn fetch_tile(&self, tile_id: u64) -> Result<PooledReader<'_>, AppError> {
+        let idle = self.idle.lock().ok().and_then(|mut idle| idle.pop());
+        let mut reader = match idle {
+            Some(reader) => reader,
+            None => open_tabix(&self.barcode_file)?,
+        };
+        // a new fetch drops whatever the previous borrower left of its own
+        let tid = reader.tid(&tile_id.to_string())?;
+        reader.fetch(tid, TILE_FETCH_START, TILE_FETCH_END)?;
+        Ok(PooledReader { pool: self, reader: Some(reader) })
+    }
+}
+
+/// Reader borrowed from a `TabixPool`
+#[cfg(feature = "bam")]
+pub struct PooledReader<'a> {
+    pool: &'a TabixPool,
+    reader: Option<tbx::Reader>,
+}
+
+#[cfg(feature = "bam")]
+impl Deref for PooledReader<'_> {
+    type Target = tbx::Reader;
+
+    fn deref(&self) -> &tbx::Reader {
+        self.reader.as_ref().expect("reader taken back by the pool")
+    }
+}
+
+#[cfg(feature = "bam")]
+impl DerefMut for PooledReader<'_> {
+    fn deref_mut(&mut self) -> &mut tbx::Reader {
+        self.reader.as_mut().expect("reader taken back by the pool")
+    }
+}
+
+#[cfg(feature = "bam")]
+impl Drop for PooledReader<'_> {
+    fn drop(&mut self) {
+        if let (Some(reader), Ok(mut idle)) = (self.reader.take(), self.pool.idle.lock()) {
+            idle.push(reader);
+        }
+    }
+}
+
+/// Tile ids of all sequences in the tabix index, in file order
+#[cfg(feature = "bam")]
+pub fn list_tiles(barcode_file: &Path) -> Result<Vec<u64>, AppError> {
+    let reader = open_tabix(barcode_file)?;
+    reader.seqnames().iter().map(|name| {
+        name.parse().map_err(|_| AppError::IoError(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid tile id `{}` in {}", name, barcode_file.display())
+        )))
+    }).collect()
+}
+
+/// Header line of the barcode file
+pub const BARCODE_FILE_HEADER: &str = "#tile_id\tx_pos\ty_pos\tbarcode\tquality";
+
+/// Create a bgzf writer for a barcode file, index it with `build_tabix_index` after dropping
+#[cfg(feature = "bam")]
+pub fn create_bgzf(path: &Path) -> Result<bgzf::Writer, AppError> {
+    Ok(bgzf::Writer::from_path(path)?)
+}
+
+/// Build the `.tbi` index of a bgzf barcode file, same as `tabix -0 -s 1 -b 3 -e 3`
+#[cfg(feature = "bam")]
+pub fn build_tabix_index(path: &Path) -> Result<(), AppError> {
+    let c_path = CString::new(path.as_os_str().as_encoded_bytes())
+        .map_err(|_| AppError::TabixIndexError(path.to_path_buf()))?;
+    let conf = htslib::tbx_conf_t {
+        preset: htslib::TBX_UCSC as i32,
+        sc: 1,
+        bc: 3,
+        ec: 3,
+        meta_char: b'#' as i32,
+        line_skip: 0,
+    };
+    // SAFETY: the path is a valid nul-terminated string and conf outlives the call
+    let ret = unsafe { htslib::tbx_index_build(c_path.as_ptr(), 0, &conf) };
+    if ret != 0 {
+        return Err(AppError::TabixIndexError(path.to_path_buf()));
+    }
+    Ok(())
+}
@@ -0,0 +1,418 @@
+use super::{
+    atomic_file::{persist, temp_path},
+    barcode_file::{list_tiles, BarcodeRecord, TabixPool},
+    error::AppError,
+    packed::PackedBarcode,
+};
+use memmap2::Mmap;
+use rust_htslib::tbx::Read;
+use std::fs;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tracing::{debug, warn};
+
+/// First bytes of a binary index, the last two digits are the format version
+const MAGIC: &[u8; 8] = b"OTBIX001";
+/// Magic, size and modification time of the barcode file, number of tiles
+const HEADER_BYTES: usize = 32;
+/// Tile id, first entry and number of entries
+const TILE_BYTES: usize = 24;
+/// Packed barcode, row, x, y and quality of one row
+const ENTRY_BYTES: usize = 32;
+
+/// Sidecar binary index `{barcode_file}.bix`, next to the tabix `.tbi`
+pub fn path_of(barcode_file: &Path) -> PathBuf {
+    let mut path = barcode_file.as_os_str().to_owned();
+    path.push(".bix");
+    PathBuf::from(path)
+}
+
+/// Size and modification time recorded in the index, a rewritten barcode file makes the index stale
+fn stamp(barcode_file: &Path) -> io::Result<(u64, u64)> {
+    let metadata = fs::metadata(barcode_file)?;
+    let modified = metadata.modified()?.duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_nanos() as u64);
+    Ok((metadata.len(), modified))
+}
+
+/// One row of a tile: its packed barcode and where it is in the tabix fetch of the tile
+#[derive(Debug, Clone, Copy)]
+pub struct IndexEntry {
+    bits: u64,
+    n_mask: u32,
+    len: u8,
+    /// record index inside the tile, the same as enumerating `TabixPool::fetch_tile`
+    pub row: u32,
+    pub x_pos: u32,
+    pub y_pos: u32,
+    /// mean barcode base quality, 0 without a quality column
+    pub score: f32,
+}
+
+impl IndexEntry {
+    #[inline]
+    pub fn barcode(&self) -> PackedBarcode {
+        PackedBarcode::Packed { bits: self.bits, n_mask: self.n_mask, len: self.len }
+    }
+
+    #[inline]
+    fn key(&self) -> (u64, u32, u8) {
+        (self.bits, self.n_mask, self.len)
+    }
+
+    fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(&self.bits.to_le_bytes())?;
+        writer.write_all(&self.n_mask.to_le_bytes())?;
+        writer.write_all(&[self.len, 0, 0, 0])?;
+        writer.write_all(&self.row.to_le_bytes())?;
+        writer.write_all(&self.x_pos.to_le_bytes())?;
+        writer.write_all(&self.y_pos.to_le_bytes())?;
+        writer.write_all(&self.score.to_le_bytes())
+    }
+
+    fn read_from(bytes: &[u8]) -> Self {
+        let u32_at = |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        Self {
+            bits: u64::from_le_bytes(bytes[..8].try_into().unwrap()),
+            n_mask: u32_at(8),
+            len: bytes[12],
+            row: u32_at(16),
+            x_pos: u32_at(20),
+            y_pos: u32_at(24),
+            score: f32::from_bits(u32_at(28)),
+        }
+    }
+}
+
+/// Build `{barcode_file}.bix` from the tabix fetch of every tile, returns whether it was written
+///
+/// Rows are sorted by packed barcode inside each tile. Files with barcodes that do not pack
+/// (longer than 32 bases or letters besides A/C/G/T/N) or non integer positions get no index,
+/// their readers keep parsing the tabix text.
+///
+/// Each tile is sorted and written on its own, the tile table is filled in once all entries are
+/// written, so memory holds one tile at a time.
+pub fn build(barcode_file: &Path) -> Result<bool, AppError> {
+    let tile_ids = list_tiles(barcode_file)?;
+    let path = path_of(barcode_file);
+    let (size, modified) = stamp(barcode_file)?;
+    let mut writer = BufWriter::new(fs::File::create(temp_path(&path))?);
+    writer.write_all(MAGIC)?;
+    for value in [size, modified, tile_ids.len() as u64] {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    // room for the tile table, written over at the end
+    writer.write_all(&vec![0; tile_ids.len() * TILE_BYTES])?;
+
+    let mut tiles = Vec::with_capacity(tile_ids.len());
+    let mut start = 0;
+    let readers = TabixPool::new(barcode_file);
+    for tile_id in tile_ids {
+        let mut reader = readers.fetch_tile(tile_id)?;
+        let mut entries = Vec::new();
+        for (row, record) in reader.records().enumerate() {
+            let record = record?;
+            let record = String::from_utf8_lossy(&record);
+            let record = BarcodeRecord::parse(&record)?;
+            let (PackedBarcode::Packed { bits, n_mask, len }, Ok(x_pos), Ok(y_pos)) =
+                (PackedBarcode::from(record.barcode), record.x_pos.parse(), record.y_pos.parse()) else {
+                warn!("{}: row {} of tile {} does not fit the binary index, no index written", barcode_file.display(), row, tile_id);
+                drop(writer);
+                fs::remove_file(temp_path(&path))?;
+                return Ok(false);
+            };
+            let score = record.quality.and_then(|q| q.parse().ok()).unwrap_or(0.0);
+            entries.push(IndexEntry { bits, n_mask, len, row: row as u32, x_pos, y_pos, score });
+        }
+        entries.sort_unstable_by_key(|entry| (entry.key(), entry.row));
+        for entry in &entries {
+            entry.write_to(&mut writer)?;
+        }
+        tiles.push((tile_id, start, entries.len() as u64));
+        start += entries.len() as u64;
+    }
+
+    let mut file = writer.into_inner().map_err(|err| err.into_error())?;
+    file.seek(SeekFrom::Start(HEADER_BYTES as u64))?;
+    let mut table = Vec::with_capacity(tiles.len() * TILE_BYTES);
+    for (tile_id, start, count) in tiles {
+        for value in [tile_id, start, count] {
+            table.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+    file.write_all(&table)?;
+    drop(file);
+    persist(&path)?;
+    Ok(true)
+}
+
+/// Memory-mapped `{barcode_file}.bix`
+pub struct BarcodeIndex {
+    map: Mmap,
+    /// tile id, first entry and number of entries
+    tiles: Vec<(u64, usize, usize)>,
+}
+
+impl BarcodeIndex {
+    /// Map the index of `barcode_file`, `None` when it is missing, stale or unreadable
+    pub fn open(barcode_file: &Path) -> Option<Self> {
+        let path = path_of(barcode_file);
+        if !path.is_file() {
+            return None;
+        }
+        match Self::map(barcode_file, &path) {
+            Ok(index) => index,
+            Err(err) => {
+                warn!("{}: {}, parsing the barcode file instead", path.display(), err);
+                None
+            }
+        }
+    }
+
+    fn map(barcode_file: &Path, path: &Path) -> io::Result<Option<Self>> {
+        let file = fs::File::open(path)?;
+        // SAFETY: the index is only replaced by renaming a new file over it, never written in place
+        let map = unsafe { Mmap::map(&file)? };
+        let u64_at = |offset: usize| map.get(offset..offset + 8).map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()));
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid binary index");
+        if map.get(..MAGIC.len()) != Some(MAGIC) {
+            return Err(invalid());
+        }
+        let (size, modified) = stamp(barcode_file)?;
+        if (u64_at(8), u64_at(16)) != (Some(size), Some(modified)) {
+            debug!("{} does not match its barcode file, parsing the barcode file instead", path.display());
+            return Ok(None);
+        }
+        let n_tiles = u64_at(24).ok_or_else(invalid)? as usize;
+        if n_tiles > map.len() / TILE_BYTES {
+            return Err(invalid());
+        }
+        let mut entries = 0;
+        let tiles = (0..n_tiles).map(|index| {
+            let offset = HEADER_BYTES + index * TILE_BYTES;
+            match (u64_at(offset), u64_at(offset + 8), u64_at(offset + 16)) {
+                // tiles follow each other without gaps
+                (Some(tile_id), Some(start), Some(count)) if start as usize == entries => {
+                    entries = entries.saturating_add(count as usize);
+                    Ok((tile_id, start as usize, count as usize))
+                }
+                _ => Err(invalid()),
+            }
+        }).collect::<io::Result<Vec<_>>>()?;
+        if entries.checked_mul(ENTRY_BYTES).map(|bytes| HEADER_BYTES + n_tiles * TILE_BYTES + bytes) != Some(map.len()) {
+            return Err(invalid());
+        }
+        Ok(Some(Self { map, tiles }))
+    }
+
+    /// Rows of `tile_id` sorted by barcode, `None` for a tile missing from the index
+    pub fn tile(&self, tile_id: u64) -> Option<TileEntries<'_>> {
+        let &(_, start, count) = self.tiles.iter().find(|(id, ..)| *id == tile_id)?;
+        let offset = HEADER_BYTES + self.tiles.len() * TILE_BYTES + start * ENTRY_BYTES;
+        Some(TileEntries { bytes: &self.map[offset..offset + count * ENTRY_BYTES] })
+    }
+}
+
+/// Rows of one tile in the mapped index, sorted by packed barcode
+#[derive(Clone, Copy)]
+pub struct TileEntries<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> TileEntries<'a> {
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.bytes.len() / ENTRY_BYTES
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    #[inline]
+    pub fn get(&self, index: usize) -> IndexEntry {
+        IndexEntry::read_from(&self.bytes[index * ENTRY_BYTES..(index + 1) * ENTRY_BYTES])
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = IndexEntry> + 'a {
+        self.bytes.chunks_exact(ENTRY_BYTES).map(IndexEntry::read_from)
+    }
+
+    /// Distinct barcodes of the tile in sorted order
+    pub fn distinct_barcodes(&self) -> impl Iterator<Item = PackedBarcode> + 'a {
+        let mut last = None;
+        self.iter().filter(move |entry| last.replace(entry.key()) != Some(entry.key())).map(|entry| entry.barcode())
+    }
+
+    /// Number of distinct barcodes of the tile
+    pub fn unique_barcodes(&self) -> usize {
+        self.distinct_barcodes().count()
+    }
+
+    /// Whether the tile holds `barcode`, a binary search over its sorted rows
+    pub fn contains(&self, barcode: &PackedBarcode) -> bool {
+        let &PackedBarcode::Packed { bits, n_mask, len } = barcode else {
+            return false;
+        };
+        let key = (bits, n_mask, len);
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let middle = (low + high) / 2;
+            if self.get(middle).key() < key {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+        low < self.len() && self.get(low).key() == key
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::barcode_file::{build_tabix_index, create_bgzf, BARCODE_FILE_HEADER};
+
+    /// bgzipped and tabix indexed barcode file of `rows`, already sorted by tile and y inside the fetched range
+    fn write_barcode_file(path: &Path, rows: &[&str]) {
+        let mut writer = create_bgzf(path).unwrap();
+        writeln!(writer, "{BARCODE_FILE_HEADER}").unwrap();
+        for row in rows {
+            writeln!(writer, "{row}").unwrap();
+        }
+        writer.flush().unwrap();
+        drop(writer);
+        build_tabix_index(path).unwrap();
+    }
+
+    #[test]
+    fn test_build_and_lookup() {
+        let dir = std::env::temp_dir().join(format!("opentools-test-barcode-index-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let barcodes = dir.join("barcodes.txt.gz");
+        write_barcode_file(&barcodes, &[
+            "11\t5\t1001\tTTTT\t30.0",
+            "11\t7\t1002\tAAAA\t20.5",
+            "11\t9\t1003\tTTTT\t10.0",
+            "11\t2\t1004\tACNT\t15.0",
+            "12\t3\t1008\tGGGG\t40.0",
+        ]);
+        assert!(build(&barcodes).unwrap());
+        let index = BarcodeIndex::open(&barcodes).unwrap();
+
+        let tile = index.tile(11).unwrap();
+        assert_eq!(tile.len(), 4);
+        let keys: Vec<_> = tile.iter().map(|entry| (entry.key(), entry.row)).collect();
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+        // rows keep their place in the tabix fetch, with their position and quality
+        let mut by_row: Vec<IndexEntry> = tile.iter().collect();
+        by_row.sort_by_key(|entry| entry.row);
+        let rows: Vec<_> = by_row.iter().map(|entry| (entry.barcode(), entry.x_pos, entry.y_pos, entry.score)).collect();
+        assert_eq!(rows, vec![
+            (PackedBarcode::from("TTTT"), 5, 1001, 30.0),
+            (PackedBarcode::from("AAAA"), 7, 1002, 20.5),
+            (PackedBarcode::from("TTTT"), 9, 1003, 10.0),
+            (PackedBarcode::from("ACNT"), 2, 1004, 15.0),
+        ]);
+        assert_eq!(tile.unique_barcodes(), 3);
+        let mut distinct: Vec<String> = tile.distinct_barcodes().map(|barcode| barcode.to_string()).collect();
+        distinct.sort();
+        assert_eq!(distinct, ["AAAA", "ACNT", "TTTT"]);
+        for barcode in ["TTTT", "AAAA", "ACNT"] {
+            assert!(tile.contains(&PackedBarcode::from(barcode)), "{barcode}");
+        }
+        for barcode in ["GGGG", "ACGT", "TTT", "acgt"] {
+            assert!(!tile.contains(&PackedBarcode::from(barcode)), "{barcode}");
+        }
+
+        let tile = index.tile(12).unwrap();
+        assert_eq!(tile.len(), 1);
+        assert!(tile.contains(&PackedBarcode::from("GGGG")));
+        assert!(index.tile(13).is_none());
+
+        // a barcode that does not pack leaves the file without an index
+        let unpacked = dir.join("unpacked.txt.gz");
+        write_barcode_file(&unpacked, &["11\t5\t1001\tTTTT\t30.0", "11\t7\t1002\tacgt\t20.5"]);
+        assert!(!build(&unpacked).unwrap());
+        assert!(!path_of(&unpacked).exists());
+        assert!(BarcodeIndex::open(&unpacked).is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_stale_index() {
+        let dir = std::env::temp_dir().join(format!("opentools-test-barcode-index-stale-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let barcodes = dir.join("barcodes.txt.gz");
+        write_barcode_file(&barcodes, &["11\t5\t1001\tTTTT\t30.0", "11\t7\t1002\tAAAA\t20.5"]);
+        assert!(build(&barcodes).unwrap());
+        assert!(BarcodeIndex::open(&barcodes).is_some());
+
+        // touched without a change of size
+        let modified = fs::metadata(&barcodes).unwrap().modified().unwrap();
+        let file = fs::File::options().write(true).open(&barcodes).unwrap();
+        file.set_modified(modified - std::time::Duration::from_secs(60)).unwrap();
+        assert!(BarcodeIndex::open(&barcodes).is_none());
+        file.set_modified(modified).unwrap();
+        assert!(BarcodeIndex::open(&barcodes).is_some());
+
+        // rewritten with other rows, the old index is left in place
+        write_barcode_file(&barcodes, &["11\t5\t1001\tTTTT\t30.0", "11\t7\t1002\tCCCC\t20.5", "12\t3\t1008\tGGGG\t40.0"]);
+        assert!(path_of(&barcodes).exists());
+        assert!(BarcodeIndex::open(&barcodes).is_none());
+        assert!(build(&barcodes).unwrap());
+        let index = BarcodeIndex::open(&barcodes).unwrap();
+        assert!(index.tile(11).unwrap().contains(&PackedBarcode::from("CCCC")));
+        assert_eq!(index.tile(12).unwrap().len(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_index() {
+        let dir = std::env::temp_dir().join(format!("opentools-test-barcode-index-invalid-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let barcodes = dir.join("barcodes.txt.gz");
+        write_barcode_file(&barcodes, &["11\t5\t1001\tTTTT\t30.0", "11\t7\t1002\tAAAA\t20.5", "12\t3\t1008\tGGGG\t40.0"]);
+        assert!(build(&barcodes).unwrap());
+        let path = path_of(&barcodes);
+        let bytes = fs::read(&path).unwrap();
+        assert_eq!(bytes.len(), HEADER_BYTES + 2 * TILE_BYTES + 3 * ENTRY_BYTES);
+
+        let mut foreign = b"OTBIX002".to_vec();
+        foreign.extend_from_slice(&bytes[8..]);
+        // tile count beyond the file
+        let mut too_many_tiles = bytes.clone();
+        too_many_tiles[24..32].copy_from_slice(&u64::MAX.to_le_bytes());
+        // the second tile does not start after the first
+        let mut gap = bytes.clone();
+        gap[HEADER_BYTES + TILE_BYTES + 8..HEADER_BYTES + TILE_BYTES + 16].copy_from_slice(&3u64.to_le_bytes());
+        let mut trailing = bytes.clone();
+        trailing.extend_from_slice(&[0; ENTRY_BYTES]);
+        let gzip = fs::read(&barcodes).unwrap();
+        let cases: [(&str, &[u8]); 11] = [
+            ("empty", &[]),
+            ("magic only", &bytes[..MAGIC.len()]),
+            ("header only", &bytes[..HEADER_BYTES]),
+            ("half a tile table", &bytes[..HEADER_BYTES + TILE_BYTES]),
+            ("one entry short", &bytes[..bytes.len() - ENTRY_BYTES]),
+            ("one byte short", &bytes[..bytes.len() - 1]),
+            ("trailing entry", &trailing),
+            ("foreign", &foreign),
+            ("barcode file", &gzip),
+            ("tile count", &too_many_tiles),
+            ("gap", &gap),
+        ];
+        for (case, data) in cases {
+            fs::write(&path, data).unwrap();
+            assert!(BarcodeIndex::open(&barcodes).is_none(), "{case}");
+        }
+        fs::write(&path, &bytes).unwrap();
+        assert!(BarcodeIndex::open(&barcodes).is_some());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,10 +1,14 @@
 use super::{
+    buffers,
     error::AppError,
-    fastqfile::{FastqReader, check_base_match, complement},
+    fastqfile::{FastqReader, check_base_match, complement, is_stdin},
+    observer::{BarcodeRead, Filter, RecordObserver},
+    packed::PackedBarcode,
     position::Position,
 };
-use seq_io::fastq::Record;
-use std::collections::HashSet;
+use seq_io::fastq::{self, Record, RecordSet};
+use seq_io::parallel::{self, read_parallel};
+use std::collections::{BTreeMap, HashSet};
 use std::io::{self, Write};
 use std::path::{Path, PathBuf};
 
@@ -22,7 +26,12 @@ pub fn validate_absolute_dirpath(s: &str) -> io::Result<PathBuf> {
     Ok(path)
 }
 
+/// Existing file, or an `s3://`, `gs://` or `http(s)://` URL with the `remote` feature
 pub fn validate_absolute_filepath(s: &str) -> io::Result<PathBuf> {
+    #[cfg(feature = "remote")]
+    if super::remote::is_remote(s) {
+        return Ok(PathBuf::from(s));
+    }
     let path = Path::new(s).to_path_buf();
     if !path.is_file() {
         return Err(io::Error::new(
@@ -33,11 +42,39 @@ pub fn validate_absolute_filepath(s: &str) -> io::Result<PathBuf> {
     Ok(path)
 }
 
+/// File to be written, in an existing directory and not a directory itself
+pub fn validate_output_filepath(s: &str) -> io::Result<PathBuf> {
+    let path = Path::new(s).to_path_buf();
+    if path.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::IsADirectory,
+            format!("{} is a directory", s),
+        ));
+    }
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() && !parent.is_dir() => Err(io::Error::new(
+            io::ErrorKind::NotADirectory,
+            format!("{} is not a directory", parent.display()),
+        )),
+        _ => Ok(path),
+    }
+}
+
+/// Same as `validate_absolute_filepath`, but also accepts `-` for stdin
+pub fn validate_filepath_or_stdin(s: &str) -> io::Result<PathBuf> {
+    if is_stdin(s) {
+        return Ok(PathBuf::from(s));
+    }
+    validate_absolute_filepath(s)
+}
+
 pub struct BarcodesIter<'a, W> {
     inner: FastqReader,
     pos: &'a Position,
     pattern: &'a str,
     writer: W,
+    observer: Option<&'a dyn RecordObserver>,
+    threads: usize,
 }
 
 impl<'a, W> BarcodesIter<'a, W> {
@@ -48,9 +85,23 @@ impl<'a, W> BarcodesIter<'a, W> {
             pos,
             pattern,
             writer,
+            observer: None,
+            threads: 1,
         }
     }
 
+    /// Call the hooks of `observer` for every kept and dropped read
+    pub fn with_observer(mut self, observer: &'a dyn RecordObserver) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Check the reads with `threads` workers besides the reader thread, at least one
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
     // Associated method
     fn fail_quality_filter(qual: &[u8]) -> bool {
         let mut low_qual_count: u64 = 0;
@@ -71,24 +122,98 @@ impl<'a, W> BarcodesIter<'a, W> {
             .any(|(&b, p)| check_base_match(b, p))
     }
 
-    fn process_barcode(seq: &[u8], is_revcomp: bool) -> String {
-        let barcode: Vec<u8> = if is_revcomp {
-            seq.iter().rev().map(complement).collect()
+    /// Mean Phred quality of the barcode bases
+    fn mean_quality(qual: &[u8]) -> f64 {
+        if qual.is_empty() {
+            return 0.0;
+        }
+        qual.iter().map(|&q| q.saturating_sub(33) as u64).sum::<u64>() as f64 / qual.len() as f64
+    }
+
+    /// Append the barcode, reverse complemented for `is_revcomp`, to `out`
+    fn push_barcode(out: &mut Vec<u8>, seq: &[u8], is_revcomp: bool) {
+        if is_revcomp {
+            out.extend(seq.iter().rev().map(complement));
         } else {
-            seq.to_vec()
-        };
-        unsafe { String::from_utf8_unchecked(barcode) }
+            out.extend_from_slice(seq);
+        }
+    }
+
+    /// Call `f` with the barcode of every read until it returns `false`, the bytes are only valid
+    /// during the call
+    pub fn for_each_barcode<F>(mut self, mut f: F) -> Result<(), AppError>
+    where
+        F: FnMut(&[u8]) -> Result<bool, AppError>,
+    {
+        // barcode bytes of the current read, packed without ever becoming a string
+        let mut barcode = Vec::new();
+        while let Some(rec) = self.inner.next() {
+            let rec = rec?;
+            barcode.clear();
+            Self::push_barcode(&mut barcode, self.pos.safe_slice(rec.seq()), self.pos.is_revcomp());
+            if !f(&barcode)? {
+                break;
+            }
+        }
+        Ok(())
     }
 
-    fn parse_id(id: &str) -> (&str, &str, &str, &str) {
-        let mut parts = id.splitn(7, ':');
-        match (parts.nth(3), parts.next(), parts.next(), parts.next()) {
-            (Some(l), Some(t), Some(x), Some(y)) => (l, t, x, y),
-            _ => unreachable!("Invalid fastq id occurs!"),
+    /// Split the read name in place, the coordinates are parsed straight from its bytes
+    fn parse_id(id: &[u8]) -> Result<ReadName<'_>, AppError> {
+        let invalid = || AppError::InvalidReadName(String::from_utf8_lossy(id).into_owned());
+        let mut parts = id.splitn(7, |&b| b == b':');
+        let (Some(lane), Some(tile), Some(x_pos), Some(y_pos)) = (parts.nth(3), parts.next(), parts.next(), parts.next()) else {
+            return Err(invalid());
+        };
+        // the last field keeps anything after y, such as a UMI, as before
+        let y_digits = y_pos.split(|&b| b == b':').next().unwrap_or_default();
+        match (parse_coordinate(x_pos), parse_coordinate(y_digits)) {
+            (Some(x), Some(y)) => Ok(ReadName { lane, tile, x_pos, y_pos, position: (x as u64) << 32 | y as u64 }),
+            _ => Err(invalid()),
         }
     }
 }
 
+/// Fields of an Illumina read name `{instrument}:{run}:{flowcell}:{lane}:{tile}:{x}:{y}`
+struct ReadName<'a> {
+    lane: &'a [u8],
+    tile: &'a [u8],
+    x_pos: &'a [u8],
+    y_pos: &'a [u8],
+    /// x and y packed into one key of the duplicate check
+    position: u64,
+}
+
+/// Decimal coordinate of a read name, `None` for anything but digits or an overflow
+#[inline]
+fn parse_coordinate(digits: &[u8]) -> Option<u32> {
+    if digits.is_empty() {
+        return None;
+    }
+    digits.iter().try_fold(0u32, |value, &b| {
+        let digit = b.wrapping_sub(b'0');
+        if digit > 9 {
+            return None;
+        }
+        value.checked_mul(10)?.checked_add(digit as u32)
+    })
+}
+
+/// Kept read of a record set: where its line ends in the lines of the set and its position
+struct ReadLine {
+    end: usize,
+    position: u64,
+}
+
+/// Output lines of a record set and the verdict of each of its reads, or the read name that
+/// stopped the set
+#[derive(Default)]
+struct Verdicts {
+    lines: Vec<u8>,
+    reads: Vec<Result<ReadLine, Filter>>,
+    error: Option<AppError>,
+}
+
 impl<'a, W> BarcodesIter<'a, W>
 where
     W: Write,
@@ -99,88 +224,166 @@ where
     }
 
     // Public method
-    pub fn extract_chip_barcodes(mut self) -> Result<Report, AppError> {
+    /// Filter and format the reads of a tile, record sets are checked by `threads` workers
+    ///
+    /// Record sets are put back into file order before the duplicate check, so the first read at a
+    /// position still wins and the output matches a single threaded run line for line.
+    pub fn extract_chip_barcodes(self) -> Result<Report, AppError> {
+        let Self { inner, pos, pattern, mut writer, observer, threads } = self;
         let mut seen_positions = HashSet::new();
-        let mut buffer = Vec::with_capacity(1000);
+        let mut buffer: Vec<u8> = Vec::with_capacity(1 << 16);
 
         let mut total_count: u64 = 0;
         let mut filter_seq_count: u64 = 0;
         let mut filter_qual_count: u64 = 0;
         let mut filter_dup_count: u64 = 0;
-        for rec in self.inner.records() {
-            let rec = rec?;
-            total_count += 1;
-            let (seq, qual) = (
-                self.pos.safe_slice(&rec.seq),
-                self.pos.safe_slice(&rec.qual),
-            );
-            let id = rec.id().expect("Invalid record id");
-            let (lane, tile, x_pos, y_pos) = Self::parse_id(id);
-            let pos_key = (x_pos.to_string(), y_pos.to_string());
-
-            if Self::fail_quality_filter(qual) {
-                filter_qual_count += 1;
-                continue;
-            }
-            if Self::fail_sequence_filter(seq, self.pattern) {
-                filter_seq_count += 1;
-                continue;
+        let mut filter_observer_count: u64 = 0;
+        // the quality and sequence filters and the formatting need no shared state, the lines of a
+        // set are written into one buffer so a read costs no allocation
+        let work = |(_, rset): &mut (u64, RecordSet)| -> Verdicts {
+            let mut lines = Vec::new();
+            let reads = (&*rset).into_iter().map(|rec| {
+                let (seq, qual) = (pos.safe_slice(rec.seq()), pos.safe_slice(rec.qual()));
+                if Self::fail_quality_filter(qual) {
+                    return Ok(Err(Filter::Quality));
+                }
+                if Self::fail_sequence_filter(seq, pattern) {
+                    return Ok(Err(Filter::Sequence));
+                }
+                let name = Self::parse_id(rec.id_bytes())?;
+                for field in [name.lane, name.tile, b"\t", name.x_pos, b"\t", name.y_pos, b"\t"] {
+                    lines.extend_from_slice(field);
+                }
+                Self::push_barcode(&mut lines, seq, pos.is_revcomp());
+                // writing into a Vec cannot fail
+                let _ = writeln!(lines, "\t{:.1}", Self::mean_quality(qual));
+                Ok(Ok(ReadLine { end: lines.len(), position: name.position }))
+            }).collect::<Result<_, AppError>>();
+            match reads {
+                Ok(reads) => Verdicts { lines, reads, error: None },
+                Err(error) => Verdicts { error: Some(error), ..Verdicts::default() },
             }
-            if !seen_positions.insert(pos_key) {
-                filter_dup_count += 1;
-                continue;
+        };
+        // a set with a bad read name fails when its turn comes, so the reads before it are written
+        let mut take = |rset: &RecordSet, Verdicts { lines, reads, error }: Verdicts| -> Result<(), AppError> {
+            if let Some(error) = error {
+                return Err(error);
             }
+            let mut start = 0;
+            for (rec, verdict) in rset.into_iter().zip(reads) {
+                total_count += 1;
+                let line = verdict.as_ref().map(|line| {
+                    let range = start..line.end;
+                    start = line.end;
+                    (range, line.position)
+                });
 
-            let barcode = Self::process_barcode(seq, self.pos.is_revcomp());
-            buffer.push(format!(
-                "{}{}\t{}\t{}\t{}\n",
-                lane, tile, x_pos, y_pos, barcode
-            ));
-            if buffer.len() >= 1000 {
-                self.writer.write_all(buffer.concat().as_bytes())?;
-                buffer.clear();
+                let filter = match verdict {
+                    Err(Filter::Quality) => {
+                        filter_qual_count += 1;
+                        Some(Filter::Quality)
+                    }
+                    Err(filter) => {
+                        filter_seq_count += 1;
+                        Some(filter)
+                    }
+                    Ok(ReadLine { position, .. }) if !seen_positions.insert(position) => {
+                        filter_dup_count += 1;
+                        Some(Filter::Duplicate)
+                    }
+                    Ok(_) => None,
+                };
+                if let Some(observer) = observer {
+                    let id = rec.id().map_err(|_| AppError::InvalidReadName(String::from_utf8_lossy(rec.id_bytes()).into_owned()))?;
+                    let read = BarcodeRead { id, seq: pos.safe_slice(rec.seq()), qual: pos.safe_slice(rec.qual()) };
+                    match filter {
+                        Some(filter) => observer.reject(&read, filter),
+                        None if !observer.accept(&read) => {
+                            filter_observer_count += 1;
+                            observer.reject(&read, Filter::Observer);
+                            // a rejected read does not hold its position against the reads after it
+                            if let Ok((_, position)) = line {
+                                seen_positions.remove(&position);
+                            }
+                            continue;
+                        }
+                        None => {}
+                    }
+                }
+                if let Ok((line, _)) = line && filter.is_none() {
+                    buffer.extend_from_slice(&lines[line]);
+                }
             }
-        }
-        if !buffer.is_empty() {
-            self.writer.write_all(buffer.concat().as_bytes())?;
-        }
-        self.writer.flush()?;
+            writer.write_all(&buffer)?;
+            buffer.clear();
+            Ok(())
+        };
+        let reader = NumberedReader { inner, next: 0 };
+        read_parallel(reader, threads as u32, buffers::queue(threads), work, |rsets| -> Result<(), AppError> {
+            // sets finished ahead of their turn wait here, at most the queue length of them
+            let mut waiting = BTreeMap::new();
+            let mut turn = 0;
+            while let Some(result) = rsets.next() {
+                let ((index, rset), verdicts) = result?;
+                if *index != turn {
+                    waiting.insert(*index, (rset.clone(), verdicts));
+                    continue;
+                }
+                take(rset, verdicts)?;
+                turn += 1;
+                while let Some((rset, verdicts)) = waiting.remove(&turn) {
+                    take(&rset, verdicts)?;
+                    turn += 1;
+                }
+            }
+            Ok(())
+        })?;
+        writer.flush()?;
 
         Ok(Report::new(
             total_count,
             filter_qual_count,
             filter_seq_count,
             filter_dup_count,
+            filter_observer_count,
         ))
     }
 }
 
-impl<'a> BarcodesIter<'a, HashSet<String>> {
+/// Reader numbering the record sets in file order, seq_io hands them back as the workers finish
+struct NumberedReader {
+    inner: FastqReader,
+    next: u64,
+}
+
+impl parallel::Reader for NumberedReader {
+    type DataSet = (u64, RecordSet);
+    type Err = fastq::Error;
+
+    fn fill_data(&mut self, (index, rset): &mut Self::DataSet) -> Option<Result<(), fastq::Error>> {
+        let filled = self.inner.read_record_set(rset)?;
+        *index = self.next;
+        self.next += 1;
+        Some(filled)
+    }
+}
+
+impl<'a> BarcodesIter<'a, HashSet<PackedBarcode>> {
     pub fn into_set(
         // tile_id: &'a str,
         inner: FastqReader,
         pos: &'a Position,
         pattern: &'a str,
-        writer: HashSet<String>,
+        writer: HashSet<PackedBarcode>,
     ) -> Self {
         Self::new(inner, pos, pattern, writer)
     }
 
-    pub fn extract_sample_barcodes(mut self, capacity: usize) -> Result<HashSet<String>, AppError> {
+    pub fn extract_sample_barcodes(self, capacity: usize) -> Result<HashSet<PackedBarcode>, AppError> {
         let mut barcode_set = HashSet::new();
-        let mut unique_barcode_num = 0;
-
-        for rec in self.inner.records() {
-            let rec = rec?;
-            let seq = &rec.seq[self.pos.range()];
-            let barcode = Self::process_barcode(seq, self.pos.is_revcomp());
-            if barcode_set.insert(barcode) {
-                unique_barcode_num += 1;
-                if unique_barcode_num >= capacity {
-                    break;
-                }
-            }
-        }
+        self.for_each_barcode(|barcode| {
+            Ok(!(barcode_set.insert(PackedBarcode::new(barcode)) && barcode_set.len() >= capacity))
+        })?;
         Ok(barcode_set)
     }
 }
@@ -190,6 +393,7 @@ pub struct Report {
     filter_qual_count: u64,
     filter_seq_count: u64,
     filter_dup_count: u64,
+    filter_observer_count: u64,
 }
 
 impl Report {
@@ -199,18 +403,23 @@ impl Report {
         filter_qual_count: u64,
         filter_seq_count: u64,
         filter_dup_count: u64,
+        filter_observer_count: u64,
     ) -> Self {
         Self {
             total_count,
             filter_qual_count,
             filter_seq_count,
             filter_dup_count,
+            filter_observer_count,
         }
     }
 
+    #[inline]
+    pub fn total_count(&self) -> u64 { self.total_count }
+
     #[inline]
     fn filtered_count(&self) -> u64 {
-        self.filter_qual_count + self.filter_seq_count + self.filter_dup_count
+        self.filter_qual_count + self.filter_seq_count + self.filter_dup_count + self.filter_observer_count
     }
 
     #[inline]
@@ -223,13 +432,16 @@ impl std::fmt::Display for Report {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "Total={}, Filtered={} (Qual={}, Seq={}, Dup={}), Passed={}",
+            "Total={}, Filtered={} (Qual={}, Seq={}, Dup={}",
             self.total_count,
             self.filtered_count(),
             self.filter_qual_count,
             self.filter_seq_count,
             self.filter_dup_count,
-            self.passed_count()
-        )
+        )?;
+        if self.filter_observer_count > 0 {
+            write!(f, ", Observer={}", self.filter_observer_count)?;
+        }
+        write!(f, "), Passed={}", self.passed_count())
     }
 }
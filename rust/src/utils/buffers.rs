@@ -0,0 +1,52 @@
+use std::io::{BufWriter, Write};
+use std::num::NonZeroUsize;
+use std::sync::OnceLock;
+
+/// Bytes of the global `--read-buffer-size`
+static READ_BUFFER: OnceLock<usize> = OnceLock::new();
+/// Bytes of the global `--write-buffer-size`
+static WRITE_BUFFER: OnceLock<usize> = OnceLock::new();
+/// Record sets of the global `--queue-size`
+static QUEUE: OnceLock<usize> = OnceLock::new();
+
+/// Read buffer of the FASTQ and text inputs without `--read-buffer-size`
+pub const DEFAULT_READ_BUFFER: usize = 64 << 10;
+/// Write buffer of the barcode and matched row outputs without `--write-buffer-size`
+pub const DEFAULT_WRITE_BUFFER: usize = 64 << 10;
+
+/// Set the buffer sizes in bytes, once before any input is opened
+pub fn init_buffers(read: Option<u64>, write: Option<u64>) {
+    if let Some(bytes) = read {
+        let _ = READ_BUFFER.set((bytes as usize).max(1));
+    }
+    if let Some(bytes) = write {
+        let _ = WRITE_BUFFER.set((bytes as usize).max(1));
+    }
+}
+
+/// Set the record sets queued between a reader and its workers, once before any input is opened
+pub fn init_queue(record_sets: NonZeroUsize) {
+    let _ = QUEUE.set(record_sets.get());
+}
+
+#[inline]
+pub fn read_buffer() -> usize {
+    READ_BUFFER.get().copied().unwrap_or(DEFAULT_READ_BUFFER)
+}
+
+#[inline]
+pub fn write_buffer() -> usize {
+    WRITE_BUFFER.get().copied().unwrap_or(DEFAULT_WRITE_BUFFER)
+}
+
+/// Record sets read ahead of `threads` workers: `--queue-size`, else two per worker
+#[inline]
+pub fn queue(threads: usize) -> usize {
+    QUEUE.get().copied().unwrap_or(threads * 2)
+}
+
+/// `inner` buffered by `--write-buffer-size`
+#[inline]
+pub fn writer<W: Write>(inner: W) -> BufWriter<W> {
+    BufWriter::with_capacity(write_buffer(), inner)
+}
@@ -0,0 +1,125 @@
+use super::{error::AppError, position::Position};
+use std::io;
+use std::sync::{Arc, OnceLock, RwLock};
+use serde::Deserialize;
+
+/// Barcode position and IUPAC pattern, the pattern is checked before reverse complement
+pub type BarcodeConfig = (Position, String);
+
+/// Barcode layout of a spatial chemistry
+///
+/// The chip barcode is read from the sequencing of the chip itself (touchbarcode), the library
+/// barcode from read 1 of the libraries captured on it (tilesmatch, extract, demux, errprofile),
+/// both give the barcode in the orientation stored in the barcode file.
+pub trait Chemistry: Send + Sync {
+    /// Name selected by `--mode`
+    fn name(&self) -> &str;
+
+    /// Barcode in the chip sequencing reads
+    fn chip_barcode(&self) -> BarcodeConfig;
+
+    /// Barcode in read 1 of the libraries
+    fn library_barcode(&self) -> BarcodeConfig;
+}
+
+/// OpenST with HDMI32-DraI chips
+pub struct OpenSt;
+
+impl Chemistry for OpenSt {
+    fn name(&self) -> &str { "openst" }
+
+    fn chip_barcode(&self) -> BarcodeConfig {
+        // HDMI32-DraI: NNVNBVNNVNNVNNVNNVNNVNNVNNVNNNNN
+        // revcomp:     NNNNNBNNBNNBNNBNNBNNBNNBNNBVNBNN
+        (Position::new(false, true, 2, 30), String::from("NNNBNNBNNBNNBNNBNNBNNBNNBVNB"))
+    }
+
+    fn library_barcode(&self) -> BarcodeConfig {
+        (Position::new(false, false, 2, 30), String::from("VNBVNNVNNVNNVNNVNNVNNVNNVNNN"))
+    }
+}
+
+/// Chemistry of a `[chemistry.NAME]` table in the `--config` TOML
+///
+/// keys `chip_barcode_pos`, `chip_barcode_pattern`, `library_barcode_pos` and `library_barcode_pattern`
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct ConfigChemistry {
+    chip_barcode_pos: String,
+    chip_barcode_pattern: String,
+    library_barcode_pos: String,
+    library_barcode_pattern: String,
+}
+
+impl ConfigChemistry {
+    /// Parse the positions and patterns once, so the getters cannot fail
+    fn parse(self, name: &str) -> Result<ParsedChemistry, AppError> {
+        let invalid = |key: &str, err: &dyn std::fmt::Display| AppError::IoError(io::Error::new(
+            io::ErrorKind::InvalidData, format!("[chemistry.{}] {}: {}", name, key, err)
+        ));
+        let pattern = |key: &str, pattern: String| {
+            if !pattern.is_empty() && pattern.bytes().all(|b| b"ATGCURYMKSWHBVDN".contains(&b)) {
+                Ok(pattern)
+            } else {
+                Err(invalid(key, &"allowed characters: A, T, G, C, U, R, Y, M, K, S, W, H, B, V, D, N"))
+            }
+        };
+        Ok(ParsedChemistry {
+            name: name.to_string(),
+            chip: (
+                self.chip_barcode_pos.parse().map_err(|err| invalid("chip_barcode_pos", &err))?,
+                pattern("chip_barcode_pattern", self.chip_barcode_pattern)?,
+            ),
+            library: (
+                self.library_barcode_pos.parse().map_err(|err| invalid("library_barcode_pos", &err))?,
+                pattern("library_barcode_pattern", self.library_barcode_pattern)?,
+            ),
+        })
+    }
+}
+
+struct ParsedChemistry {
+    name: String,
+    chip: BarcodeConfig,
+    library: BarcodeConfig,
+}
+
+impl Chemistry for ParsedChemistry {
+    fn name(&self) -> &str { &self.name }
+
+    fn chip_barcode(&self) -> BarcodeConfig { self.chip.clone() }
+
+    fn library_barcode(&self) -> BarcodeConfig { self.library.clone() }
+}
+
+fn registry() -> &'static RwLock<Vec<Arc<dyn Chemistry>>> {
+    static REGISTRY: OnceLock<RwLock<Vec<Arc<dyn Chemistry>>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| RwLock::new(vec![Arc::new(OpenSt)]))
+}
+
+/// Add a chemistry, replacing a registered one of the same name
+pub fn register(chemistry: impl Chemistry + 'static) {
+    let mut chemistries = registry().write().expect("chemistry registry poisoned");
+    chemistries.retain(|registered| registered.name() != chemistry.name());
+    chemistries.push(Arc::new(chemistry));
+}
+
+/// Register the `[chemistry.NAME]` tables of a config
+pub fn register_table(table: &toml::Table) -> Result<(), AppError> {
+    for (name, value) in table {
+        let chemistry: ConfigChemistry = value.clone().try_into().map_err(|err| AppError::IoError(io::Error::new(
+            io::ErrorKind::InvalidData, format!("[chemistry.{}] {}", name, err)
+        )))?;
+        register(chemistry.parse(name)?);
+    }
+    Ok(())
+}
+
+/// Registered chemistry of this name
+pub fn lookup(name: &str) -> Result<Arc<dyn Chemistry>, AppError> {
+    let chemistries = registry().read().expect("chemistry registry poisoned");
+    chemistries.iter().find(|chemistry| chemistry.name() == name).cloned().ok_or_else(|| {
+        let names: Vec<&str> = chemistries.iter().map(|chemistry| chemistry.name()).collect();
+        AppError::InvalidBarcodePattern(format!("unknown chemistry `{}`, registered: {}, or custom", name, names.join(", ")))
+    })
+}
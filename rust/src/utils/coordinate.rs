@@ -0,0 +1,186 @@
+use super::{error::AppError, fastqfile::open_text};
+use std::io::{self, BufRead};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Split tile id into (lane * 10 + surface, swath, tile number)
+#[inline]
+pub fn tile_grid(tile_id: u64) -> (u64, u64, u64) {
+    (tile_id / 1000, tile_id / 100 % 10, tile_id % 100)
+}
+
+/// Grid steps between two tiles of the same lane surface, the larger of swath and tile number distance
+/// 
+/// `None` for tiles of different lane surfaces
+#[inline]
+pub fn tile_distance(a: u64, b: u64) -> Option<u64> {
+    let ((a_surface, a_swath, a_tile), (b_surface, b_swath, b_tile)) = (tile_grid(a), tile_grid(b));
+    (a_surface == b_surface).then(|| a_swath.abs_diff(b_swath).max(a_tile.abs_diff(b_tile)))
+}
+
+/// Tile size in pixels as `width,height`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TileSize {
+    pub width: f64,
+    pub height: f64,
+}
+
+impl FromStr for TileSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (width, height) = s.split_once(',')
+            .ok_or(format!("`{}` is not a tile size, expected 'width,height'", s))?;
+        let width: f64 = width.parse().map_err(|_| format!("`{}` is not valid number", width))?;
+        let height: f64 = height.parse().map_err(|_| format!("`{}` is not valid number", height))?;
+        Ok(Self { width, height })
+    }
+}
+
+impl std::fmt::Display for TileSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{},{}", self.width, self.height)
+    }
+}
+
+/// Place tiles of one lane surface side by side, swaths along x and tiles along y, then scale into µm
+#[derive(Debug, Clone, Copy)]
+pub struct PuckTransform {
+    um_per_pixel: f64,
+    tile_size: TileSize,
+}
+
+impl PuckTransform {
+    pub fn new(um_per_pixel: f64, tile_size: TileSize) -> Self {
+        Self { um_per_pixel, tile_size }
+    }
+
+    /// Transform pixel position of a tile into µm of the lane surface
+    pub fn apply(&self, tile_id: u64, x_pos: f64, y_pos: f64) -> (f64, f64) {
+        let (_, swath, tile) = tile_grid(tile_id);
+        let x_offset = swath.saturating_sub(1) as f64 * self.tile_size.width;
+        let y_offset = tile.saturating_sub(1) as f64 * self.tile_size.height;
+        ((x_offset + x_pos) * self.um_per_pixel, (y_offset + y_pos) * self.um_per_pixel)
+    }
+
+    /// Tile id and pixel position of a µm position on the lane surface, inverse of `apply`
+    pub fn locate(&self, lane_surface: u64, x_um: f64, y_um: f64) -> (u64, f64, f64) {
+        let (x, y) = (x_um / self.um_per_pixel, y_um / self.um_per_pixel);
+        let swath = (x / self.tile_size.width).floor().max(0.0);
+        let tile = (y / self.tile_size.height).floor().max(0.0);
+        let tile_id = lane_surface * 1000 + (swath as u64 + 1) * 100 + tile as u64 + 1;
+        (tile_id, x - swath * self.tile_size.width, y - tile * self.tile_size.height)
+    }
+}
+
+/// A named rectangle of pixels on one tile, or on every tile
+#[derive(Debug, Clone)]
+pub struct Region {
+    pub name: String,
+    tile_id: Option<i32>,
+    x: (i32, i32),
+    y: (i32, i32),
+}
+
+impl Region {
+    /// Whether the (tile, x, y) pixel position lies inside the region, bounds included
+    #[inline]
+    pub fn contains(&self, (tile_id, x, y): (i32, i32, i32)) -> bool {
+        self.tile_id.is_none_or(|tile| tile == tile_id)
+            && (self.x.0..=self.x.1).contains(&x)
+            && (self.y.0..=self.y.1).contains(&y)
+    }
+}
+
+/// Read `name\ttile_id\tx_start\tx_end\ty_start\ty_end` rows, `*` as tile id for every tile
+pub fn load_regions(path: &Path) -> Result<Vec<Region>, AppError> {
+    let invalid = |line: usize| AppError::IoError(io::Error::new(
+        io::ErrorKind::InvalidData, format!("Invalid region line {line} in {}", path.display())
+    ));
+    let mut regions = Vec::new();
+    for (index, line) in open_text(path)?.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        // names end up in file names
+        let [name, tile_id, x_start, x_end, y_start, y_end] = fields[..] else {
+            return Err(invalid(index + 1));
+        };
+        if name.is_empty() || name.contains('/') {
+            return Err(AppError::IoError(io::Error::new(io::ErrorKind::InvalidData, format!(
+                "Region name `{name}` on line {} of {} is empty or holds a `/`", index + 1, path.display()
+            ))));
+        }
+        let parse = |value: &str| value.parse::<i32>().map_err(|_| invalid(index + 1));
+        regions.push(Region {
+            name: name.to_string(),
+            tile_id: if tile_id == "*" { None } else { Some(parse(tile_id)?) },
+            x: (parse(x_start)?, parse(x_end)?),
+            y: (parse(y_start)?, parse(y_end)?),
+        });
+    }
+    Ok(regions)
+}
+
+/// A named polygon of pixels on one tile, or on every tile
+#[derive(Debug, Clone)]
+pub struct Polygon {
+    pub name: String,
+    tile_id: Option<i32>,
+    vertices: Vec<(f64, f64)>,
+}
+
+impl Polygon {
+    /// Whether the (tile, x, y) pixel position lies inside the polygon, by the even-odd rule
+    pub fn contains(&self, (tile_id, x, y): (i32, f64, f64)) -> bool {
+        if self.tile_id.is_some_and(|tile| tile != tile_id) {
+            return false;
+        }
+        let mut inside = false;
+        let mut previous = self.vertices[self.vertices.len() - 1];
+        for &(x1, y1) in &self.vertices {
+            let (x0, y0) = previous;
+            if (y1 > y) != (y0 > y) && x < x0 + (y - y0) * (x1 - x0) / (y1 - y0) {
+                inside = !inside;
+            }
+            previous = (x1, y1);
+        }
+        inside
+    }
+}
+
+/// Read `name\ttile_id\tx1,y1 x2,y2 x3,y3 ...` rows of at least 3 vertices, `*` as tile id for every tile
+pub fn load_polygons(path: &Path) -> Result<Vec<Polygon>, AppError> {
+    let invalid = |line: usize| AppError::IoError(io::Error::new(
+        io::ErrorKind::InvalidData, format!("Invalid polygon line {line} in {}", path.display())
+    ));
+    let mut polygons = Vec::new();
+    for (index, line) in open_text(path)?.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        let [name, tile_id, vertices] = fields[..] else {
+            return Err(invalid(index + 1));
+        };
+        let vertices: Vec<(f64, f64)> = vertices.split_whitespace()
+            .map(|vertex| {
+                let (x, y) = vertex.split_once(',')?;
+                Some((x.parse().ok()?, y.parse().ok()?))
+            })
+            .collect::<Option<_>>()
+            .ok_or_else(|| invalid(index + 1))?;
+        if name.is_empty() || vertices.len() < 3 {
+            return Err(invalid(index + 1));
+        }
+        polygons.push(Polygon {
+            name: name.to_string(),
+            tile_id: if tile_id == "*" { None } else { Some(tile_id.parse().map_err(|_| invalid(index + 1))?) },
+            vertices,
+        });
+    }
+    Ok(polygons)
+}
@@ -1,6 +1,7 @@
 use std::path::PathBuf;
 use thiserror::Error;
 use seq_io::fastq::Error as SeqIoError;
+#[cfg(feature = "bam")]
 use rust_htslib::errors::Error as BamError;
 
 /// Unified error handling type for the application
@@ -17,9 +18,23 @@ pub enum AppError {
     FastqParseError(#[source] SeqIoError),
     
     /// BAM record operation error: {0}
+    #[cfg(feature = "bam")]
     #[error("BAM record operation error: {0}")]
     BamRecordError(#[from] BamError),
     
+    /// Database operation error: {0}
+    #[cfg(feature = "bam")]
+    #[error("Database operation error: {0}")]
+    DatabaseError(#[from] rusqlite::Error),
+    
+    /// Parquet operation error: {0}
+    #[error("Parquet operation error: {0}")]
+    ParquetError(#[from] parquet::errors::ParquetError),
+    
+    /// Image decoding error: {0}
+    #[error("Image decoding error: {0}")]
+    ImageError(String),
+    
     /// Empty tile IDs list: {0:?}
     #[error("Empty tile IDs list: {0:?}")]
     EmptyTileIDsList(PathBuf),
@@ -28,6 +43,10 @@ pub enum AppError {
     #[error("Invalid barcode pattern: {0}")]
     InvalidBarcodePattern(String),
     
+    /// Read name that is not UTF-8 or has no integer tile coordinates: {0}
+    #[error("Invalid read name: {0}")]
+    InvalidReadName(String),
+    
     /// Thread channel communication failed
     #[error("Thread channel communication failed")]
     ChannelError,
@@ -44,9 +63,21 @@ pub enum AppError {
     #[error("System command not found: {0}")]
     CommandNotFound(String),
     
+    /// Tabix index build failed: {0:?}
+    #[error("Tabix index build failed: {0:?}")]
+    TabixIndexError(PathBuf),
+    
     /// Command execution failed: {0}
     #[error("Command execution failed: {0}")]
     CommandError(String),
+    
+    /// Barcode file validation failed: {0} issues
+    #[error("Barcode file validation failed: {0} issues")]
+    ValidationFailed(u64),
+    
+    /// Outputs already exist, pass --force to overwrite them: {0:?}
+    #[error("Outputs already exist, pass --force to overwrite them: {}", .0.iter().map(|path| path.display().to_string()).collect::<Vec<_>>().join(", "))]
+    OutputExists(Vec<PathBuf>),
 }
 
 impl From<SeqIoError> for AppError {
@@ -56,4 +87,65 @@ impl From<SeqIoError> for AppError {
             _ => AppError::FastqParseError(err),
         }
     }
-}
\ No newline at end of file
+}
+impl AppError {
+    /// Name of the variant, stable for machine readers
+    pub fn variant(&self) -> &'static str {
+        match self {
+            AppError::IoError(_) => "IoError",
+            AppError::FastqParseError(_) => "FastqParseError",
+            #[cfg(feature = "bam")]
+            AppError::BamRecordError(_) => "BamRecordError",
+            #[cfg(feature = "bam")]
+            AppError::DatabaseError(_) => "DatabaseError",
+            AppError::ParquetError(_) => "ParquetError",
+            AppError::ImageError(_) => "ImageError",
+            AppError::EmptyTileIDsList(_) => "EmptyTileIDsList",
+            AppError::InvalidBarcodePattern(_) => "InvalidBarcodePattern",
+            AppError::InvalidReadName(_) => "InvalidReadName",
+            AppError::ChannelError => "ChannelError",
+            AppError::UnsupportedOS => "UnsupportedOS",
+            AppError::DockerImageNotFound(_) => "DockerImageNotFound",
+            AppError::CommandNotFound(_) => "CommandNotFound",
+            AppError::TabixIndexError(_) => "TabixIndexError",
+            AppError::CommandError(_) => "CommandError",
+            AppError::ValidationFailed(_) => "ValidationFailed",
+            AppError::OutputExists(_) => "OutputExists",
+        }
+    }
+
+    /// File the error is about, when the variant records it
+    pub fn path(&self) -> Option<String> {
+        match self {
+            AppError::EmptyTileIDsList(path) | AppError::TabixIndexError(path) => Some(path.display().to_string()),
+            #[cfg(feature = "bam")]
+            AppError::BamRecordError(BamError::FileNotFound { path }) => Some(path.display().to_string()),
+            #[cfg(feature = "bam")]
+            AppError::BamRecordError(BamError::FileOpen { path }) => Some(path.clone()),
+            _ => None,
+        }
+    }
+
+    /// Line of the FASTQ record the error is about
+    pub fn line(&self) -> Option<u64> {
+        match self {
+            AppError::FastqParseError(
+                SeqIoError::UnequalLengths { pos, .. }
+                | SeqIoError::InvalidStart { pos, .. }
+                | SeqIoError::InvalidSep { pos, .. }
+                | SeqIoError::UnexpectedEnd { pos },
+            ) => Some(pos.line),
+            _ => None,
+        }
+    }
+
+    /// `{"error": variant, "message": ..., "path": ..., "line": ...}` for `--error-format json`
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "error": self.variant(),
+            "message": self.to_string(),
+            "path": self.path(),
+            "line": self.line(),
+        })
+    }
+}
@@ -1,40 +1,151 @@
-
 use std::fs::File;
-use std::io::{self, BufReader};
+use std::io::{self, BufRead, BufReader, Read};
 use std::path::Path;
-use flate2::bufread::MultiGzDecoder;
 use seq_io::fastq;
+use super::position::Position;
 
-pub type FastqReader = fastq::Reader<MultiGzDecoder<BufReader<File>>>;
+pub type FastqReader = fastq::Reader<Box<dyn Read + Send>>;
 pub fn open<P>(path: P) -> io::Result<FastqReader> 
 where 
     P: AsRef<Path>
 {
-    let f = File::open(path)?;
-    Ok(fastq::Reader::new(
-        MultiGzDecoder::new(BufReader::with_capacity(64*1024, f))
-    ))
+    Ok(fastq::Reader::new(open_raw(path)?))
+}
+
+/// Open a plain text file for line reading, transparently decompressing gzip input
+pub fn open_text<P>(path: P) -> io::Result<Box<dyn BufRead + Send>>
+where 
+    P: AsRef<Path>
+{
+    Ok(Box::new(BufReader::new(open_raw(path)?)))
+}
+
+/// Whether the path stands for standard input (`-`)
+#[inline]
+pub fn is_stdin<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref() == Path::new("-")
+}
+
+/// Open a file (or stdin for `-`), decompressing it when it starts with the gzip magic bytes
+fn open_raw<P>(path: P) -> io::Result<Box<dyn Read + Send>>
+where 
+    P: AsRef<Path>
+{
+    let inner: Box<dyn Read + Send> = if is_stdin(&path) {
+        Box::new(io::stdin())
+    } else {
+        open_file(path.as_ref())?
+    };
+    let mut reader = BufReader::with_capacity(super::buffers::read_buffer(), inner);
+    if reader.fill_buf()?.starts_with(&[0x1f, 0x8b]) {
+        super::pgzip::decoder(reader)
+    } else {
+        Ok(Box::new(reader))
+    }
 }
 
+/// Open a local file, or stream a remote URL by range requests with the `remote` feature
+fn open_file(path: &Path) -> io::Result<Box<dyn Read + Send>> {
+    #[cfg(feature = "remote")]
+    if super::remote::is_remote(path) {
+        return Ok(Box::new(super::remote::RemoteReader::open(&path.to_string_lossy())?));
+    }
+    Ok(Box::new(File::open(path)?))
+}
+
+/// Complement of a base, any byte besides A/C/G/T becomes `N`
 pub fn complement(b: &u8) -> u8 {
     match b {
         b'A' => b'T',
         b'T' => b'A',
         b'G' => b'C',
         b'C' => b'G',
-        b'N' => b'N',
-        _ => unreachable!("Invalid base: {b}"),
+        _ => b'N',
+    }
+}
+
+/// Bases and qualities cut from a read, reverse complemented when the position is on the minus strand
+pub fn cut(pos: &Position, seq: &[u8], qual: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let (seq, qual) = (pos.safe_slice(seq), pos.safe_slice(qual));
+    if pos.is_revcomp() {
+        (seq.iter().rev().map(complement).collect(), qual.iter().rev().copied().collect())
+    } else {
+        (seq.to_vec(), qual.to_vec())
     }
 }
 
-pub fn check_base_match(base: u8, pattern_char: u8) -> bool {    
-    // 碱基匹配
-    match (base, pattern_char) {
-        (b'N', _) => true,
-        (b'A', b'A' | b'R' | b'M' | b'W' | b'H' | b'V' | b'D' | b'N') => false,
-        (b'T', b'T' | b'Y' | b'K' | b'W' | b'H' | b'B' | b'D' | b'N') => false,
-        (b'G', b'G' | b'R' | b'K' | b'S' | b'B' | b'V' | b'D' | b'N') => false,
-        (b'C', b'C' | b'Y' | b'M' | b'S' | b'H' | b'B' | b'V' | b'N') => false,
-        _ => true,
+/// Whether a read of `len` bases ends before the position, `end` positions only need the start
+#[inline]
+pub fn too_short(pos: &Position, len: usize) -> bool {
+    if pos.is_relative() {
+        return !pos.fits(len);
     }
+    len < pos.end() && !(pos.end() == 150 && len > pos.start())
+}
+
+/// Number of distinct sequences a IUPAC pattern can match
+pub fn pattern_diversity(pattern: &str) -> f64 {
+    pattern.bytes().map(|p| match p {
+        b'A' | b'T' | b'G' | b'C' | b'U' => 1.0,
+        b'R' | b'Y' | b'M' | b'K' | b'S' | b'W' => 2.0,
+        b'H' | b'B' | b'V' | b'D' => 3.0,
+        _ => 4.0,
+    }).product()
+}
+
+/// Bases a IUPAC code stands for, one bit each for A, C, G and T
+const fn iupac_bits(code: u8) -> u8 {
+    const A: u8 = 1;
+    const C: u8 = 2;
+    const G: u8 = 4;
+    const T: u8 = 8;
+    match code {
+        b'A' => A,
+        b'C' => C,
+        b'G' => G,
+        b'T' => T,
+        b'R' => A | G,
+        b'Y' => C | T,
+        b'M' => A | C,
+        b'K' => G | T,
+        b'S' => C | G,
+        b'W' => A | T,
+        b'H' => A | C | T,
+        b'B' => C | G | T,
+        b'V' => A | C | G,
+        b'D' => A | G | T,
+        b'N' => A | C | G | T,
+        _ => 0,
+    }
+}
+
+/// `iupac_bits` of every byte as a pattern letter, built at compile time
+pub const PATTERN_BITS: [u8; 256] = {
+    let mut table = [0; 256];
+    let mut code = 0;
+    while code < 256 {
+        table[code] = iupac_bits(code as u8);
+        code += 1;
+    }
+    table
+};
+
+/// Bit of every byte as a read base, only A, C, G and T have one, so an `N` read base matches nothing
+pub const BASE_BITS: [u8; 256] = {
+    let mut table = [0; 256];
+    let mut code = 0;
+    while code < 256 {
+        let bits = iupac_bits(code as u8);
+        if bits.count_ones() == 1 {
+            table[code] = bits;
+        }
+        code += 1;
+    }
+    table
+};
+
+/// Whether `base` does NOT match the IUPAC `pattern_char`, two table lookups instead of a match
+#[inline]
+pub fn check_base_match(base: u8, pattern_char: u8) -> bool {
+    BASE_BITS[base as usize] & PATTERN_BITS[pattern_char as usize] == 0
 }
\ No newline at end of file
@@ -0,0 +1,207 @@
+use super::{error::AppError, fastqfile::open_text};
+use std::collections::HashMap;
+use std::io::{self, BufRead};
+use std::path::Path;
+
+/// Gene of the annotation, rows of `features.tsv.gz` follow their order in the GTF
+#[derive(Debug, Clone)]
+pub struct Gene {
+    pub id: String,
+    pub name: String,
+}
+
+/// Exon in 0-based half-open coordinates
+#[derive(Debug, Clone, Copy)]
+struct Exon {
+    start: u64,
+    end: u64,
+    gene: u32,
+    reverse: bool,
+}
+
+/// Exons of every chromosome sorted by start, searchable by overlap
+pub struct GeneIndex {
+    genes: Vec<Gene>,
+    /// exons and the running maximum of their ends, so a search can stop early
+    chroms: HashMap<String, (Vec<Exon>, Vec<u64>)>,
+}
+
+/// Value of `key "value";` in the GTF attribute column
+fn attribute<'a>(attributes: &'a str, key: &str) -> Option<&'a str> {
+    attributes.split(';')
+        .map(str::trim)
+        .find_map(|field| field.strip_prefix(key)?.strip_prefix(' '))
+        .map(|value| value.trim_matches('"'))
+}
+
+impl GeneIndex {
+    /// Load exons of a GTF (optionally gzipped), genes named by `gene_name` or their id
+    pub fn from_gtf(path: &Path) -> Result<Self, AppError> {
+        let invalid = |line: usize, message: &str| AppError::IoError(io::Error::new(
+            io::ErrorKind::InvalidData, format!("Invalid GTF line {line}: {message}")
+        ));
+        let mut genes = Vec::new();
+        let mut gene_index: HashMap<String, u32> = HashMap::new();
+        let mut chroms: HashMap<String, Vec<Exon>> = HashMap::new();
+        for (index, line) in open_text(path)?.lines().enumerate() {
+            let line = line?;
+            if line.starts_with('#') || line.is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() < 9 {
+                return Err(invalid(index + 1, "expected 9 columns"));
+            }
+            if fields[2] != "exon" {
+                continue;
+            }
+            let start: u64 = fields[3].parse().map_err(|_| invalid(index + 1, "start"))?;
+            let end: u64 = fields[4].parse().map_err(|_| invalid(index + 1, "end"))?;
+            let gene_id = attribute(fields[8], "gene_id").ok_or_else(|| invalid(index + 1, "no gene_id"))?;
+            let gene = *gene_index.entry(gene_id.to_string()).or_insert_with(|| {
+                let name = attribute(fields[8], "gene_name").unwrap_or(gene_id);
+                genes.push(Gene { id: gene_id.to_string(), name: name.to_string() });
+                (genes.len() - 1) as u32
+            });
+            chroms.entry(fields[0].to_string()).or_default().push(Exon {
+                start: start.saturating_sub(1),
+                end,
+                gene,
+                reverse: fields[6] == "-",
+            });
+        }
+        let chroms = chroms.into_iter()
+            .map(|(chrom, mut exons)| {
+                exons.sort_unstable_by_key(|exon| exon.start);
+                let max_ends = exons.iter()
+                    .scan(0, |max_end, exon| {
+                        *max_end = exon.end.max(*max_end);
+                        Some(*max_end)
+                    })
+                    .collect();
+                (chrom, (exons, max_ends))
+            })
+            .collect();
+        Ok(Self { genes, chroms })
+    }
+
+    #[inline]
+    pub fn genes(&self) -> &[Gene] {
+        &self.genes
+    }
+
+    /// Push genes with an exon overlapping `[start, end)` on `chrom` into `hits`
+    ///
+    /// `reverse` keeps only genes of that strand, `None` keeps both
+    pub fn overlapping(&self, chrom: &str, start: u64, end: u64, reverse: Option<bool>, hits: &mut Vec<u32>) {
+        let Some((exons, max_ends)) = self.chroms.get(chrom) else {
+            return;
+        };
+        let last = exons.partition_point(|exon| exon.start < end);
+        for index in (0..last).rev() {
+            if max_ends[index] <= start {
+                break;
+            }
+            let exon = exons[index];
+            if exon.end > start && reverse.is_none_or(|reverse| reverse == exon.reverse) {
+                hits.push(exon.gene);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Index of a GTF written from `lines` (chrom, feature, start, end, strand, attributes)
+    fn index(name: &str, lines: &[(&str, &str, u64, u64, char, &str)]) -> GeneIndex {
+        let path = std::env::temp_dir().join(format!("opentools-test-gtf-{name}-{}.gtf", std::process::id()));
+        let mut text = String::from("#!genome-build test\n");
+        for (chrom, feature, start, end, strand, attributes) in lines {
+            text.push_str(&format!("{chrom}\ttest\t{feature}\t{start}\t{end}\t.\t{strand}\t.\t{attributes}\n"));
+        }
+        fs::write(&path, text).unwrap();
+        let index = GeneIndex::from_gtf(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        index
+    }
+
+    fn overlapping<'a>(index: &'a GeneIndex, chrom: &str, start: u64, end: u64, reverse: Option<bool>) -> Vec<&'a str> {
+        let mut hits = Vec::new();
+        index.overlapping(chrom, start, end, reverse, &mut hits);
+        hits.sort_unstable();
+        hits.dedup();
+        hits.into_iter().map(|gene| index.genes()[gene as usize].id.as_str()).collect()
+    }
+
+    #[test]
+    fn test_attribute() {
+        let attributes = r#"gene_id "ENSG01"; transcript_id "ENST01"; gene_name "Alpha"; tag basic;"#;
+        assert_eq!(attribute(attributes, "gene_id"), Some("ENSG01"));
+        assert_eq!(attribute(attributes, "gene_name"), Some("Alpha"));
+        assert_eq!(attribute(attributes, "tag"), Some("basic"));
+        // a key is not matched by its prefix
+        assert_eq!(attribute(attributes, "gene"), None);
+        assert_eq!(attribute(attributes, "exon_number"), None);
+        assert_eq!(attribute(r#"gene_id "G";"#, "gene_id"), Some("G"));
+        assert_eq!(attribute(r#"gene_id "G""#, "gene_id"), Some("G"));
+    }
+
+    #[test]
+    fn test_from_gtf() {
+        let index = index("genes", &[
+            ("chr1", "gene", 1, 1000, '+', r#"gene_id "G1"; gene_name "Alpha";"#),
+            ("chr1", "exon", 101, 200, '+', r#"gene_id "G1"; gene_name "Alpha";"#),
+            ("chr2", "exon", 1, 10, '-', r#"gene_id "G2";"#),
+            ("chr1", "exon", 301, 400, '+', r#"gene_id "G1"; gene_name "Alpha";"#),
+        ]);
+        let genes: Vec<(&str, &str)> = index.genes().iter().map(|gene| (gene.id.as_str(), gene.name.as_str())).collect();
+        // named by the id without gene_name, in the order of the GTF
+        assert_eq!(genes, [("G1", "Alpha"), ("G2", "G2")]);
+
+        // the GTF exon 101-200 is 1-based inclusive, [100, 200) half-open
+        assert!(overlapping(&index, "chr1", 99, 100, None).is_empty());
+        assert_eq!(overlapping(&index, "chr1", 99, 101, None), ["G1"]);
+        assert_eq!(overlapping(&index, "chr1", 199, 200, None), ["G1"]);
+        assert!(overlapping(&index, "chr1", 200, 300, None).is_empty());
+        assert_eq!(overlapping(&index, "chr2", 0, 1, None), ["G2"]);
+        assert!(overlapping(&index, "chrM", 0, 1000, None).is_empty());
+
+        let path = std::env::temp_dir().join(format!("opentools-test-gtf-invalid-{}.gtf", std::process::id()));
+        fs::write(&path, "chr1\ttest\texon\t1\t10\t.\t+\t.\tgene_name \"A\";\n").unwrap();
+        assert!(GeneIndex::from_gtf(&path).is_err());
+        fs::write(&path, "chr1\ttest\texon\t1\n").unwrap();
+        assert!(GeneIndex::from_gtf(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_overlapping() {
+        let index = index("overlapping", &[
+            // a long exon started before many short ones, found only through the running maximum of ends
+            ("chr1", "exon", 1, 10000, '+', r#"gene_id "Long";"#),
+            ("chr1", "exon", 2001, 2100, '+', r#"gene_id "Short";"#),
+            ("chr1", "exon", 3001, 3100, '+', r#"gene_id "Short";"#),
+            ("chr1", "exon", 4001, 4500, '-', r#"gene_id "Nested";"#),
+            ("chr1", "exon", 4101, 4200, '+', r#"gene_id "Inner";"#),
+            ("chr1", "exon", 6001, 6100, '+', r#"gene_id "Short";"#),
+            ("chr1", "exon", 12001, 12100, '-', r#"gene_id "Far";"#),
+        ]);
+        assert_eq!(overlapping(&index, "chr1", 9000, 9001, None), ["Long"]);
+        assert_eq!(overlapping(&index, "chr1", 9999, 12000, None), ["Long"]);
+        assert_eq!(overlapping(&index, "chr1", 2050, 2060, None), ["Long", "Short"]);
+        assert_eq!(overlapping(&index, "chr1", 4150, 4160, None), ["Long", "Nested", "Inner"]);
+        // the nested exon still reaches past the inner one
+        assert_eq!(overlapping(&index, "chr1", 4300, 4301, None), ["Long", "Nested"]);
+        assert!(overlapping(&index, "chr1", 10000, 12000, None).is_empty());
+        assert_eq!(overlapping(&index, "chr1", 10000, 12001, None), ["Far"]);
+        assert!(overlapping(&index, "chr1", 12100, 20000, None).is_empty());
+
+        // strands
+        assert_eq!(overlapping(&index, "chr1", 4150, 4160, Some(false)), ["Long", "Inner"]);
+        assert_eq!(overlapping(&index, "chr1", 4150, 4160, Some(true)), ["Nested"]);
+        assert!(overlapping(&index, "chr1", 9000, 9001, Some(true)).is_empty());
+    }
+}
@@ -0,0 +1,30 @@
+use std::path::Path;
+use rust_htslib::{bam, tbx, errors::Error as BamError};
+
+/// URL of a remote path, htslib streams these itself instead of checking the local file
+#[cfg(feature = "remote")]
+fn remote_url(path: &Path) -> Result<Option<url::Url>, BamError> {
+    if !super::remote::is_remote(path) {
+        return Ok(None);
+    }
+    let path = path.to_str().ok_or(BamError::NonUnicodePath)?;
+    url::Url::parse(path).map(Some).map_err(|_| BamError::FileOpen { path: path.to_string() })
+}
+
+/// Open a BAM/SAM/CRAM file, or an `s3://`, `gs://` or `http(s)://` URL with the `remote` feature
+pub fn open_bam<P: AsRef<Path>>(path: P) -> Result<bam::Reader, BamError> {
+    #[cfg(feature = "remote")]
+    if let Some(url) = remote_url(path.as_ref())? {
+        return bam::Reader::from_url(&url);
+    }
+    bam::Reader::from_path(path)
+}
+
+/// Open a tabix indexed file, or a URL with the `remote` feature (the index is fetched next to it)
+pub fn open_tabix<P: AsRef<Path>>(path: P) -> Result<tbx::Reader, BamError> {
+    #[cfg(feature = "remote")]
+    if let Some(url) = remote_url(path.as_ref())? {
+        return tbx::Reader::from_url(&url);
+    }
+    tbx::Reader::from_path(path)
+}
@@ -0,0 +1,404 @@
+use super::error::AppError;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Tile metrics by (lane, tile)
+pub type TileMetricsMap = BTreeMap<(u16, u32), TileMetrics>;
+
+/// Metrics by (lane, tile, cycle)
+pub type CycleMap<T> = BTreeMap<(u16, u32, u16), T>;
+
+fn interop_error(path: &Path, message: impl std::fmt::Display) -> AppError {
+    AppError::IoError(io::Error::new(io::ErrorKind::InvalidData, format!("InterOp {}: {}", path.display(), message)))
+}
+
+/// Tile id of the barcode file, `lane * 10000` on top of the Illumina tile number (e.g. 11101)
+#[inline]
+pub fn tile_id(lane: u16, tile: u32) -> u64 {
+    lane as u64 * 10000 + tile as u64
+}
+
+/// Little endian reader over the bytes of an InterOp file
+struct Bytes<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Bytes<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.offset..self.offset + len)?;
+        self.offset += len;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take(4).map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn f32(&mut self) -> Option<f32> {
+        self.u32().map(f32::from_bits)
+    }
+
+    /// Tile number stored in `width` bytes
+    fn tile(&mut self, width: usize) -> Option<u32> {
+        if width == 2 { self.u16().map(u32::from) } else { self.u32() }
+    }
+}
+
+/// Version and record size of an InterOp file
+fn open(path: &Path, data: &[u8]) -> Result<(u8, usize), AppError> {
+    match data {
+        [version, record_size, ..] => Ok((*version, *record_size as usize)),
+        _ => Err(interop_error(path, "file too short")),
+    }
+}
+
+/// Records of `record_size` bytes after the header, a trailing partial record is ignored
+fn records<'a>(
+    path: &Path,
+    data: &'a [u8],
+    header: usize,
+    record_size: usize,
+    min_size: usize,
+) -> Result<impl Iterator<Item = Bytes<'a>>, AppError> {
+    if record_size < min_size {
+        return Err(interop_error(path, format!("unexpected record size {record_size}")));
+    }
+    let body = data.get(header..).unwrap_or_default();
+    Ok(body.chunks_exact(record_size).map(|record| Bytes { data: record, offset: 0 }))
+}
+
+/// Cluster density and count of a tile, densities in clusters per mm²
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TileMetrics {
+    pub density: Option<f64>,
+    pub pf_density: Option<f64>,
+    pub clusters: Option<f64>,
+    pub pf_clusters: Option<f64>,
+}
+
+impl TileMetrics {
+    /// Share of clusters passing filter
+    pub fn percent_pf(&self) -> Option<f64> {
+        match (self.clusters, self.pf_clusters) {
+            (Some(clusters), Some(pf)) if clusters > 0.0 => Some(pf * 100.0 / clusters),
+            _ => None,
+        }
+    }
+}
+
+/// Read `TileMetricsOut.bin` of version 2 (metric code records) or 3 (cluster count records)
+pub fn read_tile_metrics(path: &Path) -> Result<TileMetricsMap, AppError> {
+    let data = fs::read(path)?;
+    let (version, record_size) = open(path, &data)?;
+    let mut tiles = TileMetricsMap::new();
+    match version {
+        2 => {
+            for mut record in records(path, &data, 2, record_size, 10)? {
+                let (Some(lane), Some(tile), Some(code), Some(value)) =
+                    (record.u16(), record.u16(), record.u16(), record.f32()) else { continue };
+                let metrics = tiles.entry((lane, tile as u32)).or_default();
+                let value = Some(value as f64);
+                match code {
+                    100 => metrics.density = value,
+                    101 => metrics.pf_density = value,
+                    102 => metrics.clusters = value,
+                    103 => metrics.pf_clusters = value,
+                    // phasing, prephasing and aligned rates per read
+                    _ => {}
+                }
+            }
+        }
+        3 => {
+            let area = Bytes { data: &data, offset: 2 }.f32().ok_or_else(|| interop_error(path, "file too short"))? as f64;
+            for mut record in records(path, &data, 6, record_size, 15)? {
+                let (Some(lane), Some(tile), Some(code), Some(clusters), Some(pf_clusters)) =
+                    (record.u16(), record.u32(), record.u8(), record.f32(), record.f32()) else { continue };
+                // 'r' records hold the aligned rate of a read
+                if code == b't' {
+                    let metrics = tiles.entry((lane, tile)).or_default();
+                    metrics.clusters = Some(clusters as f64);
+                    metrics.pf_clusters = Some(pf_clusters as f64);
+                    if area > 0.0 {
+                        metrics.density = Some(clusters as f64 / area);
+                        metrics.pf_density = Some(pf_clusters as f64 / area);
+                    }
+                }
+            }
+        }
+        _ => return Err(interop_error(path, format!("unsupported version {version}"))),
+    }
+    Ok(tiles)
+}
+
+/// Clusters of a tile and cycle by quality score
+#[derive(Debug, Default, Clone)]
+pub struct CycleQuality {
+    /// (quality score, clusters)
+    pub histogram: Vec<(u8, u64)>,
+}
+
+impl CycleQuality {
+    pub fn clusters(&self) -> u64 {
+        self.histogram.iter().map(|(_, count)| count).sum()
+    }
+
+    pub fn mean(&self) -> Option<f64> {
+        let clusters = self.clusters();
+        let sum: u64 = self.histogram.iter().map(|&(quality, count)| quality as u64 * count).sum();
+        (clusters > 0).then(|| sum as f64 / clusters as f64)
+    }
+
+    /// Percent of clusters at or above the quality score
+    pub fn percent_at_least(&self, quality: u8) -> Option<f64> {
+        let clusters = self.clusters();
+        let passed: u64 = self.histogram.iter().filter(|(q, _)| *q >= quality).map(|(_, count)| count).sum();
+        (clusters > 0).then(|| passed as f64 * 100.0 / clusters as f64)
+    }
+}
+
+/// Read `QMetricsOut.bin` of version 4 to 7, unbinned or binned
+pub fn read_q_metrics(path: &Path) -> Result<CycleMap<CycleQuality>, AppError> {
+    let data = fs::read(path)?;
+    let (version, record_size) = open(path, &data)?;
+    if !(4..=7).contains(&version) {
+        return Err(interop_error(path, format!("unsupported version {version}")));
+    }
+    let mut bytes = Bytes { data: &data, offset: 2 };
+    let truncated = || interop_error(path, "truncated header");
+    // quality score of every histogram entry
+    let mut scores: Vec<u8> = (1..=50).collect();
+    if version >= 5 && bytes.u8().ok_or_else(truncated)? == 1 {
+        let bins = bytes.u8().ok_or_else(truncated)? as usize;
+        bytes.take(2 * bins).ok_or_else(truncated)?;
+        let remapped = bytes.take(bins).ok_or_else(truncated)?.to_vec();
+        // version 5 keeps 50 entries with the counts at the remapped scores
+        if version >= 6 {
+            scores = remapped;
+        }
+    }
+    // lane, tile, cycle and one count per score
+    let tile_width = if record_size == 8 + 4 * scores.len() { 4 } else { 2 };
+
+    let mut cycles = CycleMap::new();
+    for mut record in records(path, &data, bytes.offset, record_size, 4 + tile_width + 4 * scores.len())? {
+        let (Some(lane), Some(tile), Some(cycle)) = (record.u16(), record.tile(tile_width), record.u16()) else {
+            continue;
+        };
+        let quality: &mut CycleQuality = cycles.entry((lane, tile, cycle)).or_default();
+        for &score in &scores {
+            match record.u32() {
+                Some(count) if count > 0 => quality.histogram.push((score, count as u64)),
+                _ => {}
+            }
+        }
+    }
+    Ok(cycles)
+}
+
+/// Read the error rates (percent) of `ErrorMetricsOut.bin` of version 3 or 4
+pub fn read_error_metrics(path: &Path) -> Result<CycleMap<f64>, AppError> {
+    let data = fs::read(path)?;
+    let (version, record_size) = open(path, &data)?;
+    let tile_width = match version {
+        3 => 2,
+        4 => 4,
+        _ => return Err(interop_error(path, format!("unsupported version {version}"))),
+    };
+    let mut errors = CycleMap::new();
+    for mut record in records(path, &data, 2, record_size, 8 + tile_width)? {
+        if let (Some(lane), Some(tile), Some(cycle), Some(rate)) =
+            (record.u16(), record.tile(tile_width), record.u16(), record.f32())
+        {
+            errors.insert((lane, tile, cycle), rate as f64);
+        }
+    }
+    Ok(errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Little endian bytes of an InterOp fixture
+    #[derive(Default)]
+    struct Fixture(Vec<u8>);
+
+    impl Fixture {
+        fn u8(mut self, value: u8) -> Self {
+            self.0.push(value);
+            self
+        }
+
+        fn u16(mut self, value: u16) -> Self {
+            self.0.extend(value.to_le_bytes());
+            self
+        }
+
+        fn u32(mut self, value: u32) -> Self {
+            self.0.extend(value.to_le_bytes());
+            self
+        }
+
+        fn f32(self, value: f32) -> Self {
+            self.u32(value.to_bits())
+        }
+
+        fn counts(self, counts: &[u32]) -> Self {
+            counts.iter().fold(self, |fixture, &count| fixture.u32(count))
+        }
+
+        /// Write the bytes to a temporary file and read it back with `read`
+        fn read<T>(self, name: &str, read: fn(&Path) -> Result<T, AppError>) -> Result<T, AppError> {
+            let path = std::env::temp_dir().join(format!("opentools-test-interop-{name}-{}.bin", std::process::id()));
+            fs::write(&path, &self.0).unwrap();
+            let result = read(&path);
+            fs::remove_file(&path).unwrap();
+            result
+        }
+    }
+
+    /// 50 unbinned counts with `count` at the quality score `score`
+    fn unbinned(entries: &[(u8, u32)]) -> Vec<u32> {
+        let mut counts = vec![0; 50];
+        for &(score, count) in entries {
+            counts[score as usize - 1] = count;
+        }
+        counts
+    }
+
+    #[test]
+    fn test_tile_metrics_v2() {
+        let mut fixture = Fixture::default().u8(2).u8(10);
+        for (tile, code, value) in [
+            (1101, 100, 250_000.0), (1101, 101, 200_000.0), (1101, 102, 1_000_000.0), (1101, 103, 800_000.0),
+            (1101, 200, 0.1), (2205, 102, 500.0),
+        ] {
+            fixture = fixture.u16(1).u16(tile).u16(code).f32(value);
+        }
+        // a partial record at the end is left out
+        let tiles = fixture.u16(2).u16(1101).read("tile-v2", read_tile_metrics).unwrap();
+        assert_eq!(tiles.keys().copied().collect::<Vec<_>>(), [(1, 1101), (1, 2205)]);
+        let metrics = tiles[&(1, 1101)];
+        assert_eq!(
+            (metrics.density, metrics.pf_density, metrics.clusters, metrics.pf_clusters),
+            (Some(250_000.0), Some(200_000.0), Some(1_000_000.0), Some(800_000.0))
+        );
+        assert_eq!(metrics.percent_pf(), Some(80.0));
+        let metrics = tiles[&(1, 2205)];
+        assert_eq!((metrics.clusters, metrics.pf_clusters, metrics.percent_pf()), (Some(500.0), None, None));
+    }
+
+    #[test]
+    fn test_tile_metrics_v3() {
+        let tiles = Fixture::default().u8(3).u8(15).f32(2.0)
+            .u16(1).u32(11101).u8(b't').f32(1000.0).f32(750.0)
+            // the aligned rate of read 1
+            .u16(1).u32(11101).u8(b'r').u32(1).f32(0.5)
+            .u16(2).u32(21102).u8(b't').f32(10.0).f32(10.0)
+            .read("tile-v3", read_tile_metrics)
+            .unwrap();
+        assert_eq!(tiles.keys().copied().collect::<Vec<_>>(), [(1, 11101), (2, 21102)]);
+        let metrics = tiles[&(1, 11101)];
+        assert_eq!(
+            (metrics.density, metrics.pf_density, metrics.clusters, metrics.pf_clusters),
+            (Some(500.0), Some(375.0), Some(1000.0), Some(750.0))
+        );
+        assert_eq!(tiles[&(2, 21102)].percent_pf(), Some(100.0));
+
+        assert!(Fixture::default().u8(3).u8(15).u16(0).read("tile-v3-short", read_tile_metrics).is_err());
+        assert!(Fixture::default().u8(3).u8(12).f32(1.0).read("tile-v3-size", read_tile_metrics).is_err());
+        assert!(Fixture::default().u8(1).u8(10).read("tile-v1", read_tile_metrics).is_err());
+        assert!(Fixture::default().u8(2).read("tile-empty", read_tile_metrics).is_err());
+    }
+
+    #[test]
+    fn test_q_metrics_unbinned() {
+        // version 4 has no binning flag, version 6 says unbinned
+        for (version, header) in [(4, Fixture::default().u8(4).u8(206)), (6, Fixture::default().u8(6).u8(206).u8(0))] {
+            let cycles = header
+                .u16(1).u16(1101).u16(1).counts(&unbinned(&[(30, 6), (40, 2)]))
+                .u16(1).u16(1101).u16(2).counts(&unbinned(&[(10, 1), (35, 3)]))
+                .read("q-unbinned", read_q_metrics)
+                .unwrap();
+            assert_eq!(cycles.len(), 2, "version {version}");
+            let quality = &cycles[&(1, 1101, 1)];
+            assert_eq!(quality.histogram, [(30, 6), (40, 2)]);
+            assert_eq!((quality.clusters(), quality.mean()), (8, Some(32.5)));
+            assert_eq!(quality.percent_at_least(30), Some(100.0));
+            assert_eq!(quality.percent_at_least(31), Some(25.0));
+            assert_eq!(cycles[&(1, 1101, 2)].histogram, [(10, 1), (35, 3)]);
+        }
+    }
+
+    #[test]
+    fn test_q_metrics_binned() {
+        // three bins [1, 14], [15, 29] and [30, 50] reported as 12, 23 and 37
+        let binned = |version: u8, record_size: u8| Fixture::default().u8(version).u8(record_size).u8(1).u8(3)
+            .u8(1).u8(15).u8(30).u8(14).u8(29).u8(50).u8(12).u8(23).u8(37);
+
+        // version 5 keeps the 50 counts, filled at the remapped scores
+        let cycles = binned(5, 206)
+            .u16(1).u16(1101).u16(1).counts(&unbinned(&[(12, 1), (37, 4)]))
+            .read("q-v5", read_q_metrics)
+            .unwrap();
+        assert_eq!(cycles[&(1, 1101, 1)].histogram, [(12, 1), (37, 4)]);
+
+        // version 6 holds one count per bin
+        let cycles = binned(6, 18)
+            .u16(1).u16(1101).u16(1).counts(&[2, 0, 8])
+            .u16(2).u16(2101).u16(3).counts(&[0, 5, 5])
+            .read("q-v6", read_q_metrics)
+            .unwrap();
+        assert_eq!(cycles[&(1, 1101, 1)].histogram, [(12, 2), (37, 8)]);
+        assert_eq!(cycles[&(2, 2101, 3)].histogram, [(23, 5), (37, 5)]);
+        assert_eq!(cycles[&(2, 2101, 3)].mean(), Some(30.0));
+
+        // the tile takes 4 bytes when a record is 8 + 4 * bins long, 2 otherwise
+        let cycles = binned(7, 20)
+            .u16(1).u32(111101).u16(1).counts(&[0, 0, 9])
+            .read("q-v7", read_q_metrics)
+            .unwrap();
+        assert_eq!(cycles.keys().copied().collect::<Vec<_>>(), [(1, 111101, 1)]);
+        assert_eq!(cycles[&(1, 111101, 1)].histogram, [(37, 9)]);
+        let cycles = binned(7, 18)
+            .u16(1).u16(1101).u16(1).counts(&[0, 0, 9])
+            .read("q-v7-short-tile", read_q_metrics)
+            .unwrap();
+        assert_eq!(cycles.keys().copied().collect::<Vec<_>>(), [(1, 1101, 1)]);
+
+        assert!(binned(6, 17).read("q-v6-size", read_q_metrics).is_err());
+        assert!(Fixture::default().u8(6).u8(18).u8(1).u8(3).u8(1).read("q-v6-header", read_q_metrics).is_err());
+        assert!(Fixture::default().u8(8).u8(18).read("q-v8", read_q_metrics).is_err());
+    }
+
+    #[test]
+    fn test_error_metrics() {
+        // version 3 records end with 20 bytes of mismatch counts
+        let errors = Fixture::default().u8(3).u8(30)
+            .u16(1).u16(1101).u16(1).f32(0.25).counts(&[0; 5])
+            .u16(1).u16(1101).u16(2).f32(0.5).counts(&[0; 5])
+            .read("error-v3", read_error_metrics)
+            .unwrap();
+        assert_eq!(errors.into_iter().collect::<Vec<_>>(), [((1, 1101, 1), 0.25), ((1, 1101, 2), 0.5)]);
+
+        let errors = Fixture::default().u8(4).u8(12)
+            .u16(2).u32(111101).u16(7).f32(1.5)
+            .read("error-v4", read_error_metrics)
+            .unwrap();
+        assert_eq!(errors.into_iter().collect::<Vec<_>>(), [((2, 111101, 7), 1.5)]);
+
+        assert!(Fixture::default().u8(4).u8(10).read("error-v4-size", read_error_metrics).is_err());
+        assert!(Fixture::default().u8(5).u8(12).read("error-v5", read_error_metrics).is_err());
+    }
+}
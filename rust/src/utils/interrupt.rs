@@ -0,0 +1,43 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+#[cfg(feature = "bam")]
+use {super::error::AppError, std::{fs, io}, tracing::warn};
+
+/// Exit code of a run stopped by SIGINT, SIGTERM or SIGHUP
+pub const EXIT_CODE: i32 = 130;
+
+/// Incomplete files and scratch directories removed when the run is interrupted
+fn pending() -> &'static Mutex<HashSet<PathBuf>> {
+    static PENDING: OnceLock<Mutex<HashSet<PathBuf>>> = OnceLock::new();
+    PENDING.get_or_init(|| Mutex::new(HashSet::new()))
+}
+
+/// Remove `path` when the run is interrupted, a file or a whole directory
+///
+/// Paths already moved into place or cleaned up are skipped then, so they need no unregistering.
+pub fn remove_on_interrupt(path: &Path) {
+    if let Ok(mut paths) = pending().lock() {
+        paths.insert(path.to_path_buf());
+    }
+}
+
+/// Catch Ctrl-C and termination signals: remove the registered paths and exit with `EXIT_CODE`
+///
+/// Outputs written through `atomic_file` only exist as `.tmp` files until complete, so an
+/// interrupted run leaves no truncated outputs behind for later stages to misread.
+#[cfg(feature = "bam")]
+pub fn install() -> Result<(), AppError> {
+    ctrlc::set_handler(|| {
+        warn!("interrupted, removing incomplete files");
+        // a worker panicking while holding the lock must not stop the cleanup
+        let paths = pending().lock().map(|paths| paths.clone()).unwrap_or_default();
+        for path in paths {
+            let removed = if path.is_dir() { fs::remove_dir_all(&path) } else { fs::remove_file(&path) };
+            if let Err(err) = removed.or_else(|err| if err.kind() == io::ErrorKind::NotFound { Ok(()) } else { Err(err) }) {
+                warn!("{}: {}", path.display(), err);
+            }
+        }
+        std::process::exit(EXIT_CODE);
+    }).map_err(|err| AppError::IoError(io::Error::other(err)))
+}
@@ -0,0 +1,83 @@
+use super::error::AppError;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use tiff::{ColorType as TiffColorType, decoder::{Decoder, DecodingResult}};
+
+/// Segment id of every pixel of a label image (e.g. cellpose or stardist masks), 0 is background
+pub struct LabelImage {
+    width: u32,
+    height: u32,
+    labels: Vec<u32>,
+}
+
+fn image_error(path: &Path, err: impl std::fmt::Display) -> AppError {
+    AppError::ImageError(format!("{}: {}", path.display(), err))
+}
+
+impl LabelImage {
+    /// Read a single channel PNG (8 or 16 bit) or TIFF (8, 16 or 32 bit) by its extension
+    pub fn open(path: &Path) -> Result<Self, AppError> {
+        let extension = path.extension()
+            .map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+            .unwrap_or_default();
+        match extension.as_str() {
+            "png" => Self::from_png(path),
+            "tif" | "tiff" => Self::from_tiff(path),
+            _ => Err(image_error(path, "expected a .png, .tif or .tiff label image")),
+        }
+    }
+
+    fn from_png(path: &Path) -> Result<Self, AppError> {
+        let decoder = png::Decoder::new(BufReader::new(File::open(path)?));
+        let mut reader = decoder.read_info().map_err(|err| image_error(path, err))?;
+        let size = reader.output_buffer_size().ok_or_else(|| image_error(path, "image too large"))?;
+        let mut buffer = vec![0; size];
+        let info = reader.next_frame(&mut buffer).map_err(|err| image_error(path, err))?;
+        if info.color_type != png::ColorType::Grayscale {
+            return Err(image_error(path, "label image must be grayscale"));
+        }
+        let rows = buffer.chunks(info.line_size).take(info.height as usize);
+        let labels = match info.bit_depth {
+            png::BitDepth::Eight => rows
+                .flat_map(|row| row[..info.width as usize].iter().map(|&label| label as u32))
+                .collect(),
+            png::BitDepth::Sixteen => rows
+                .flat_map(|row| row[..info.width as usize * 2].chunks_exact(2))
+                .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]) as u32)
+                .collect(),
+            depth => return Err(image_error(path, format!("unsupported bit depth {depth:?}"))),
+        };
+        Ok(Self { width: info.width, height: info.height, labels })
+    }
+
+    fn from_tiff(path: &Path) -> Result<Self, AppError> {
+        let mut decoder = Decoder::new(BufReader::new(File::open(path)?)).map_err(|err| image_error(path, err))?;
+        if !matches!(decoder.colortype().map_err(|err| image_error(path, err))?, TiffColorType::Gray(_)) {
+            return Err(image_error(path, "label image must be grayscale"));
+        }
+        let (width, height) = decoder.dimensions().map_err(|err| image_error(path, err))?;
+        let labels = match decoder.read_image().map_err(|err| image_error(path, err))? {
+            DecodingResult::U8(labels) => labels.into_iter().map(u32::from).collect(),
+            DecodingResult::U16(labels) => labels.into_iter().map(u32::from).collect(),
+            DecodingResult::U32(labels) => labels,
+            _ => return Err(image_error(path, "label image must hold unsigned integers")),
+        };
+        Ok(Self { width, height, labels })
+    }
+
+    #[inline]
+    pub fn width(&self) -> u32 { self.width }
+
+    #[inline]
+    pub fn height(&self) -> u32 { self.height }
+
+    /// Label at column `x` and row `y`, `None` outside the image
+    #[inline]
+    pub fn get(&self, x: i64, y: i64) -> Option<u32> {
+        if x < 0 || y < 0 || x >= self.width as i64 || y >= self.height as i64 {
+            return None;
+        }
+        Some(self.labels[y as usize * self.width as usize + x as usize])
+    }
+}
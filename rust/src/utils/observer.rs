@@ -0,0 +1,37 @@
+/// Barcode bases of one read, sliced at the barcode position before reverse complement
+pub struct BarcodeRead<'a> {
+    /// read name, `{instrument}:{run}:{flowcell}:{lane}:{tile}:{x}:{y}` for BCL reads
+    pub id: &'a str,
+    pub seq: &'a [u8],
+    pub qual: &'a [u8],
+}
+
+/// Filter that dropped a read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    /// a barcode base below Q20, or more than two below Q30
+    Quality,
+    /// the barcode does not match the pattern
+    Sequence,
+    /// a read at the same (x, y) was already kept
+    Duplicate,
+    /// rejected by `RecordObserver::accept`
+    Observer,
+}
+
+/// Hooks called per read by the barcode extraction of `touchbarcode`
+///
+/// Tiles are processed in parallel, so the hooks take `&self` and count with atomics or a mutex.
+/// Both hooks do nothing by default.
+pub trait RecordObserver: Sync {
+    /// A read that passed the built-in filters, `false` drops it as a custom filter
+    fn accept(&self, read: &BarcodeRead<'_>) -> bool {
+        let _ = read;
+        true
+    }
+
+    /// A read dropped by `filter`
+    fn reject(&self, read: &BarcodeRead<'_>, filter: Filter) {
+        let _ = (read, filter);
+    }
+}
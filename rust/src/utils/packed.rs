@@ -0,0 +1,122 @@
+use std::fmt;
+
+/// Bases of a packed barcode, in the order of their 2-bit codes
+const BASES: [u8; 4] = *b"ACGT";
+
+/// Longest barcode held in the 64 bits of `PackedBarcode::Packed`
+pub const MAX_PACKED_LEN: usize = 32;
+
+/// Barcode key of the barcode sets and maps, 2 bits a base instead of a heap allocated string
+///
+/// Barcodes of up to 32 A/C/G/T bases are packed into one `u64`, an `N` is escaped into the
+/// mask with code 0 at its place. Longer barcodes and other letters keep their text, so any
+/// barcode has a key and two keys are equal exactly when their barcodes are.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum PackedBarcode {
+    Packed {
+        bits: u64,
+        /// bit `i` is set for an `N` at base `i`
+        n_mask: u32,
+        len: u8,
+    },
+    /// any other barcode, bytes that are not UTF-8 are replaced when it is built
+    Text(Box<str>),
+}
+
+impl PackedBarcode {
+    pub fn new(barcode: &[u8]) -> Self {
+        match Self::pack(barcode) {
+            Some((bits, n_mask)) => Self::Packed { bits, n_mask, len: barcode.len() as u8 },
+            None => Self::Text(String::from_utf8_lossy(barcode).into()),
+        }
+    }
+
+    fn pack(barcode: &[u8]) -> Option<(u64, u32)> {
+        if barcode.len() > MAX_PACKED_LEN {
+            return None;
+        }
+        let mut bits: u64 = 0;
+        let mut n_mask: u32 = 0;
+        for (i, &base) in barcode.iter().enumerate() {
+            let code = match base {
+                b'A' => 0,
+                b'C' => 1,
+                b'G' => 2,
+                b'T' => 3,
+                b'N' => {
+                    n_mask |= 1 << i;
+                    0
+                }
+                _ => return None,
+            };
+            bits = bits << 2 | code;
+        }
+        Some((bits, n_mask))
+    }
+}
+
+impl From<&str> for PackedBarcode {
+    #[inline]
+    fn from(barcode: &str) -> Self {
+        Self::new(barcode.as_bytes())
+    }
+}
+
+impl fmt::Display for PackedBarcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Packed { bits, n_mask, len } => {
+                let len = *len as usize;
+                let barcode: String = (0..len).map(|i| {
+                    if n_mask >> i & 1 == 1 {
+                        'N'
+                    } else {
+                        BASES[(bits >> (2 * (len - 1 - i)) & 3) as usize] as char
+                    }
+                }).collect();
+                f.write_str(&barcode)
+            }
+            Self::Text(barcode) => f.write_str(barcode),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let full = "ACGT".repeat(MAX_PACKED_LEN / 4);
+        for barcode in ["", "A", "AAAA", "TGCA", "GATTACA", full.as_str()] {
+            let packed = PackedBarcode::from(barcode);
+            assert!(matches!(packed, PackedBarcode::Packed { .. }), "{barcode} is not packed");
+            assert_eq!(packed.to_string(), barcode);
+        }
+    }
+
+    #[test]
+    fn test_n_mask() {
+        for barcode in ["N", "NACGT", "ACGTN", "ANNA", &"N".repeat(MAX_PACKED_LEN)] {
+            let packed = PackedBarcode::from(barcode);
+            assert!(matches!(packed, PackedBarcode::Packed { .. }), "{barcode} is not packed");
+            assert_eq!(packed.to_string(), barcode);
+        }
+        // an N packs to the code of A, only the mask tells them apart
+        assert_ne!(PackedBarcode::from("ANA"), PackedBarcode::from("AAA"));
+        // leading A codes are zeros, only the length tells them apart
+        assert_ne!(PackedBarcode::from("A"), PackedBarcode::from("AA"));
+    }
+
+    #[test]
+    fn test_text_fallback() {
+        let long = "ACGT".repeat(MAX_PACKED_LEN / 4) + "A";
+        for barcode in [long.as_str(), "acgt", "ACGU", "AC-GT"] {
+            let packed = PackedBarcode::from(barcode);
+            assert_eq!(packed, PackedBarcode::Text(barcode.into()));
+            assert_eq!(packed.to_string(), barcode);
+        }
+        assert_ne!(PackedBarcode::from("acgt"), PackedBarcode::from("ACGT"));
+        assert_eq!(PackedBarcode::new(b"AC\xffGT").to_string(), "AC\u{fffd}GT");
+    }
+}
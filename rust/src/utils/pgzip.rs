@@ -0,0 +1,179 @@
+use flate2::{bufread::MultiGzDecoder, read::DeflateDecoder, Crc};
+use rayon::prelude::*;
+use std::io::{self, BufRead, Read};
+use std::num::NonZeroUsize;
+use std::sync::OnceLock;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread;
+
+/// Thread count of the global `--decompress-threads`
+static THREADS: OnceLock<usize> = OnceLock::new();
+
+/// BGZF blocks inflated per thread at once, each up to 64 KiB of output
+const BLOCKS_PER_THREAD: usize = 16;
+/// Output of plain gzip handed over at once by the decoding thread
+const CHUNK_BYTES: usize = 1 << 20;
+/// Chunks decoded ahead of the reader
+const CHUNKS_AHEAD: usize = 4;
+
+/// Set the threads decompressing gzip inputs, once before any input is opened
+pub fn init(threads: NonZeroUsize) {
+    let _ = THREADS.set(threads.get());
+}
+
+/// Decoder of a gzip stream: inline with one thread, otherwise on worker threads
+///
+/// BGZF input (bgzip, htslib) is inflated block by block on `--decompress-threads` workers.
+/// Other gzip, single or multi member, cannot be split without decoding it, so it is decoded on
+/// one thread ahead of the caller, overlapping the decompression with the parsing.
+pub fn decoder<R>(mut reader: R) -> io::Result<Box<dyn Read + Send>>
+where
+    R: BufRead + Send + 'static,
+{
+    let threads = THREADS.get().copied().unwrap_or(1);
+    if threads <= 1 {
+        return Ok(Box::new(MultiGzDecoder::new(reader)));
+    }
+    let (sender, receiver) = sync_channel(CHUNKS_AHEAD);
+    if is_bgzf(reader.fill_buf()?) {
+        thread::spawn(move || inflate_blocks(reader, threads, sender));
+    } else {
+        thread::spawn(move || decode_ahead(MultiGzDecoder::new(reader), sender));
+    }
+    Ok(Box::new(ChannelReader { receiver, chunk: Vec::new(), offset: 0 }))
+}
+
+/// Whether `header` starts a BGZF block: gzip with the `BC` extra subfield holding the block size
+#[inline]
+fn is_bgzf(header: &[u8]) -> bool {
+    header.len() >= 18 && header[..4] == [0x1f, 0x8b, 8, 4] && header[12..16] == [b'B', b'C', 2, 0]
+}
+
+/// One whole BGZF block, `None` at the end of the input
+fn read_block(reader: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut header = [0u8; 18];
+    let mut filled = 0;
+    while filled < header.len() {
+        match reader.read(&mut header[filled..])? {
+            0 if filled == 0 => return Ok(None),
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => filled += n,
+        }
+    }
+    if !is_bgzf(&header) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "BGZF input continues with a block that is not BGZF"));
+    }
+    let size = u16::from_le_bytes([header[16], header[17]]) as usize + 1;
+    if size < header.len() + 8 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid BGZF block size"));
+    }
+    let mut block = vec![0; size];
+    block[..header.len()].copy_from_slice(&header);
+    reader.read_exact(&mut block[header.len()..])?;
+    Ok(Some(block))
+}
+
+/// Inflate one BGZF block and check its CRC32 and length
+fn inflate(block: &[u8]) -> io::Result<Vec<u8>> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    let extra = u16::from_le_bytes([block[10], block[11]]) as usize;
+    let (payload, trailer) = block.split_at(block.len() - 8);
+    let payload = payload.get(12 + extra..).ok_or_else(|| invalid("invalid BGZF extra field"))?;
+    let crc = u32::from_le_bytes(trailer[..4].try_into().unwrap());
+    let len = u32::from_le_bytes(trailer[4..].try_into().unwrap()) as usize;
+    let mut data = Vec::with_capacity(len);
+    DeflateDecoder::new(payload).read_to_end(&mut data)?;
+    let mut check = Crc::new();
+    check.update(&data);
+    if data.len() != len || check.sum() != crc {
+        return Err(invalid("corrupt BGZF block"));
+    }
+    Ok(data)
+}
+
+/// Read batches of blocks and inflate each batch on a pool of `threads`, sending the output in order
+fn inflate_blocks(mut reader: impl Read, threads: usize, sender: SyncSender<Chunk>) {
+    let pool = match rayon::ThreadPoolBuilder::new().num_threads(threads).build() {
+        Ok(pool) => pool,
+        Err(err) => {
+            let _ = sender.send(Err(io::Error::other(err)));
+            return;
+        }
+    };
+    loop {
+        let mut blocks = Vec::with_capacity(threads * BLOCKS_PER_THREAD);
+        while blocks.len() < blocks.capacity() {
+            match read_block(&mut reader) {
+                Ok(Some(block)) => blocks.push(block),
+                Ok(None) => break,
+                Err(err) => {
+                    let _ = sender.send(Err(err));
+                    return;
+                }
+            }
+        }
+        if blocks.is_empty() {
+            let _ = sender.send(Ok(None));
+            return;
+        }
+        let inflated = pool.install(|| blocks.par_iter().map(|block| inflate(block)).collect::<io::Result<Vec<_>>>());
+        let stop = inflated.is_err();
+        // a closed channel means the reader was dropped
+        if sender.send(inflated.map(|chunks| Some(chunks.concat()))).is_err() || stop {
+            return;
+        }
+    }
+}
+
+/// Decode a gzip stream into chunks ahead of the reader
+fn decode_ahead(mut decoder: impl Read, sender: SyncSender<Chunk>) {
+    loop {
+        let mut chunk = Vec::with_capacity(CHUNK_BYTES);
+        match (&mut decoder).take(CHUNK_BYTES as u64).read_to_end(&mut chunk) {
+            Ok(0) => {
+                let _ = sender.send(Ok(None));
+                return;
+            }
+            Ok(_) => {
+                if sender.send(Ok(Some(chunk))).is_err() {
+                    return;
+                }
+            }
+            Err(err) => {
+                let _ = sender.send(Err(err));
+                return;
+            }
+        }
+    }
+}
+
+/// Output of a worker thread, `None` once the input is complete
+type Chunk = io::Result<Option<Vec<u8>>>;
+
+/// Decompressed chunks from a worker thread as one stream
+struct ChannelReader {
+    receiver: Receiver<Chunk>,
+    chunk: Vec<u8>,
+    offset: usize,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.offset == self.chunk.len() {
+            match self.receiver.recv() {
+                Ok(Ok(Some(chunk))) => {
+                    self.chunk = chunk;
+                    self.offset = 0;
+                }
+                Ok(Ok(None)) => return Ok(0),
+                Ok(Err(err)) => return Err(err),
+                // the worker stopped without reaching the end, e.g. it panicked
+                Err(_) => return Err(io::Error::other("decompression thread stopped")),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.offset);
+        buf[..n].copy_from_slice(&self.chunk[self.offset..self.offset + n]);
+        self.offset += n;
+        Ok(n)
+    }
+}
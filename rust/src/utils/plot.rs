@@ -0,0 +1,181 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const WIDTH: f64 = 640.0;
+const HEIGHT: f64 = 480.0;
+const MARGIN_LEFT: f64 = 72.0;
+const MARGIN_RIGHT: f64 = 24.0;
+const MARGIN_TOP: f64 = 40.0;
+const MARGIN_BOTTOM: f64 = 56.0;
+
+/// A labelled point drawn on top of the series, e.g. the knee of a rank curve
+struct Marker {
+    label: String,
+    x: f64,
+    y: f64,
+}
+
+/// Minimal SVG line plot for QC figures, no plotting dependency needed
+pub struct LinePlot {
+    title: String,
+    x_label: String,
+    y_label: String,
+    log_x: bool,
+    log_y: bool,
+    series: Vec<(Vec<(f64, f64)>, &'static str)>,
+    markers: Vec<Marker>,
+}
+
+impl LinePlot {
+    pub fn new(title: &str, x_label: &str, y_label: &str) -> Self {
+        Self {
+            title: title.to_string(),
+            x_label: x_label.to_string(),
+            y_label: y_label.to_string(),
+            log_x: false,
+            log_y: false,
+            series: Vec::new(),
+            markers: Vec::new(),
+        }
+    }
+
+    /// Use log10 axes, points with non-positive values are skipped on a log axis
+    pub fn log_scale(mut self, log_x: bool, log_y: bool) -> Self {
+        self.log_x = log_x;
+        self.log_y = log_y;
+        self
+    }
+
+    pub fn add_series(&mut self, points: Vec<(f64, f64)>, color: &'static str) {
+        self.series.push((points, color));
+    }
+
+    pub fn add_marker(&mut self, label: &str, x: f64, y: f64) {
+        self.markers.push(Marker { label: label.to_string(), x, y });
+    }
+
+    /// Value on the drawn axis, `None` if it can not be drawn
+    #[inline]
+    fn axis_value(value: f64, log: bool) -> Option<f64> {
+        match log {
+            true if value > 0.0 => Some(value.log10()),
+            true => None,
+            false if value.is_finite() => Some(value),
+            false => None,
+        }
+    }
+
+    fn range(&self, log: bool, pick: fn(&(f64, f64)) -> f64) -> (f64, f64) {
+        let (min, max) = self.series.iter()
+            .flat_map(|(points, _)| points.iter())
+            .filter_map(|point| Self::axis_value(pick(point), log))
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), v| (min.min(v), max.max(v)));
+        match (min.is_finite(), min < max) {
+            (false, _) => (0.0, 1.0),
+            (true, false) => (min - 0.5, max + 0.5),
+            (true, true) if log => (min.floor(), max.ceil()),
+            (true, true) => (min, max),
+        }
+    }
+
+    /// Tick positions on the drawn axis with their labels
+    fn ticks(min: f64, max: f64, log: bool) -> Vec<(f64, String)> {
+        if log {
+            return (min.ceil() as i32..=max.floor() as i32)
+                .map(|exp| (exp as f64, format!("1e{exp}")))
+                .collect();
+        }
+        let raw = (max - min) / 5.0;
+        let magnitude = 10f64.powf(raw.log10().floor());
+        let step = [1.0, 2.0, 5.0, 10.0].iter()
+            .map(|scale| scale * magnitude)
+            .find(|step| *step >= raw)
+            .unwrap_or(magnitude * 10.0);
+        let mut ticks = Vec::new();
+        let mut tick = (min / step).ceil() * step;
+        while tick <= max + step * 1e-9 {
+            ticks.push((tick, format!("{}", (tick / step).round() * step)));
+            tick += step;
+        }
+        ticks
+    }
+
+    pub fn to_svg(&self) -> String {
+        let (x_min, x_max) = self.range(self.log_x, |point| point.0);
+        let (y_min, y_max) = self.range(self.log_y, |point| point.1);
+        let plot_width = WIDTH - MARGIN_LEFT - MARGIN_RIGHT;
+        let plot_height = HEIGHT - MARGIN_TOP - MARGIN_BOTTOM;
+        let sx = |v: f64| MARGIN_LEFT + (v - x_min) / (x_max - x_min) * plot_width;
+        let sy = |v: f64| MARGIN_TOP + (1.0 - (v - y_min) / (y_max - y_min)) * plot_height;
+
+        let mut svg = String::new();
+        // writing into a String never fails
+        let _ = writeln!(
+            svg,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{WIDTH}" height="{HEIGHT}" font-family="sans-serif" font-size="12">"#
+        );
+        let _ = writeln!(svg, r#"<rect width="100%" height="100%" fill="white"/>"#);
+        let _ = writeln!(
+            svg, r#"<text x="{}" y="24" text-anchor="middle" font-size="15">{}</text>"#,
+            WIDTH / 2.0, escape(&self.title)
+        );
+        let _ = writeln!(
+            svg, r#"<rect x="{MARGIN_LEFT}" y="{MARGIN_TOP}" width="{plot_width}" height="{plot_height}" fill="none" stroke="black"/>"#
+        );
+        for (tick, label) in Self::ticks(x_min, x_max, self.log_x) {
+            let x = sx(tick);
+            let _ = writeln!(
+                svg, r##"<line x1="{x:.1}" y1="{MARGIN_TOP}" x2="{x:.1}" y2="{:.1}" stroke="#ddd"/><text x="{x:.1}" y="{:.1}" text-anchor="middle">{label}</text>"##,
+                HEIGHT - MARGIN_BOTTOM, HEIGHT - MARGIN_BOTTOM + 16.0
+            );
+        }
+        for (tick, label) in Self::ticks(y_min, y_max, self.log_y) {
+            let y = sy(tick);
+            let _ = writeln!(
+                svg, r##"<line x1="{MARGIN_LEFT}" y1="{y:.1}" x2="{:.1}" y2="{y:.1}" stroke="#ddd"/><text x="{:.1}" y="{:.1}" text-anchor="end">{label}</text>"##,
+                WIDTH - MARGIN_RIGHT, MARGIN_LEFT - 6.0, y + 4.0
+            );
+        }
+        let _ = writeln!(
+            svg, r#"<text x="{:.1}" y="{:.1}" text-anchor="middle">{}</text>"#,
+            MARGIN_LEFT + plot_width / 2.0, HEIGHT - 14.0, escape(&self.x_label)
+        );
+        let _ = writeln!(
+            svg, r#"<text transform="translate(18,{:.1}) rotate(-90)" text-anchor="middle">{}</text>"#,
+            MARGIN_TOP + plot_height / 2.0, escape(&self.y_label)
+        );
+        for (points, color) in &self.series {
+            let path: Vec<String> = points.iter()
+                .filter_map(|&(x, y)| Some((Self::axis_value(x, self.log_x)?, Self::axis_value(y, self.log_y)?)))
+                .map(|(x, y)| format!("{:.1},{:.1}", sx(x), sy(y)))
+                .collect();
+            let _ = writeln!(
+                svg, r#"<polyline fill="none" stroke="{color}" stroke-width="1.5" points="{}"/>"#,
+                path.join(" ")
+            );
+        }
+        for marker in &self.markers {
+            let (Some(x), Some(y)) = (Self::axis_value(marker.x, self.log_x), Self::axis_value(marker.y, self.log_y)) else {
+                continue;
+            };
+            let (x, y) = (sx(x), sy(y));
+            let _ = writeln!(
+                svg, r#"<circle cx="{x:.1}" cy="{y:.1}" r="4" fill="none" stroke="crimson" stroke-width="1.5"/><text x="{:.1}" y="{:.1}" fill="crimson">{}</text>"#,
+                x + 6.0, y - 6.0, escape(&marker.label)
+            );
+        }
+        svg.push_str("</svg>\n");
+        svg
+    }
+
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_svg())
+    }
+}
+
+/// Escape text for SVG and HTML
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
@@ -11,9 +11,9 @@ pub enum PositionError {
     InvalidRead,
     #[error("Invalid strand, must be '+' or '-'")]
     InvalidStrand,
-    #[error("Invalid start position, must be integer 0..150")]
+    #[error("Invalid start position, must be integer 0..150, or 'end-N'/'-N' from the read end")]
     InvalidStart,
-    #[error("Invalid end position, must be integer 0..150 or 'end'")]
+    #[error("Invalid end position, must be integer 0..150, 'end', or 'end-N'/'-N' from the read end")]
     InvalidEnd,
     #[error("End position must be >= start position")]
     EndBeforeStart,
@@ -31,13 +31,28 @@ pub struct Position {
     /// Range in 0..150, must larger than start
     end: usize,
     /// The len of sequence
-    len: usize
+    len: usize,
+    /// `start` counts back from the 3' end of each read
+    start_from_end: bool,
+    /// `end` counts back from the 3' end of each read
+    end_from_end: bool,
 }
 
 impl Position {
     pub fn new(read: bool, strand: bool, start: usize, end: usize) -> Self {
         let len = end - start;
-        Self { read, strand, start, end, len }
+        Self { read, strand, start, end, len, start_from_end: false, end_from_end: false }
+    }
+
+    /// Position with `start` and/or `end` counted back from the 3' end, resolved against each read
+    pub fn from_end(read: bool, strand: bool, (start, start_from_end): (usize, bool), (end, end_from_end): (usize, bool)) -> Self {
+        let len = match (start_from_end, end_from_end) {
+            (false, false) => end - start,
+            (true, true) => start - end,
+            // depends on the read length
+            _ => 0,
+        };
+        Self { read, strand, start, end, len, start_from_end, end_from_end }
     }
 
     #[inline]
@@ -55,17 +70,72 @@ impl Position {
     #[inline]
     pub fn len(&self) -> usize {self.len}
 
+    #[inline]
+    pub fn is_empty(&self) -> bool {self.len == 0}
+
+    #[inline]
+    pub fn is_start_from_end(&self) -> bool {self.start_from_end}
+
+    #[inline]
+    pub fn is_end_from_end(&self) -> bool {self.end_from_end}
+
+    /// Whether either end is counted from the 3' end, so only `resolve` gives the range
+    #[inline]
+    pub fn is_relative(&self) -> bool {self.start_from_end || self.end_from_end}
+
     #[inline]
     pub fn range(&self) -> Range<usize> {self.start..self.end}
 
+    /// Offsets from the 5' end in a read of `read_len` bases, `None` when the read is shorter than an offset from the 3' end
+    #[inline]
+    fn anchored(&self, read_len: usize) -> (Option<usize>, Option<usize>) {
+        let anchor = |offset: usize, from_end: bool| if from_end { read_len.checked_sub(offset) } else { Some(offset) };
+        (anchor(self.start, self.start_from_end), anchor(self.end, self.end_from_end))
+    }
+
+    /// Range in a read of `read_len` bases, clamped to the read
+    pub fn resolve(&self, read_len: usize) -> Range<usize> {
+        let (start, end) = self.anchored(read_len);
+        let start = start.unwrap_or(0).min(read_len);
+        let end = end.unwrap_or(0).min(read_len).max(start);
+        start..end
+    }
+
+    /// Whether a read of `read_len` bases holds the whole position
+    pub fn fits(&self, read_len: usize) -> bool {
+        match self.anchored(read_len) {
+            (Some(start), Some(end)) => start <= end && end <= read_len,
+            _ => false,
+        }
+    }
+
     #[inline]
     pub fn safe_slice<'a, T>(&self, data: &'a [T]) -> &'a [T] {
-        let start = std::cmp::min(self.start, data.len());
-        let end = std::cmp::min(self.end, data.len());
-        &data[start..end] // 自动处理越界
+        &data[self.resolve(data.len())] // 自动处理越界
+    }
+}
+
+/// `N`, or `end-N`/`-N` counted from the 3' end, `None` for anything else
+fn parse_offset(s: &str) -> Option<(usize, bool)> {
+    let (digits, from_end) = match s.strip_prefix("end-").or_else(|| s.strip_prefix('-')) {
+        Some(digits) => (digits, true),
+        None => (s, false),
+    };
+    match digits.parse::<usize>() {
+        Ok(v) if v <= 150 && digits.bytes().all(|b| b.is_ascii_digit()) => Some((v, from_end)),
+        _ => None,
     }
 }
 
+/// Split "start-end" at the dash between the two, either may be `end-N` or `-N`
+fn split_range(s: &str) -> Option<(&str, &str)> {
+    let start_len = match s.strip_prefix("end-").or_else(|| s.strip_prefix('-')) {
+        Some(rest) => s.len() - rest.len() + rest.find('-')?,
+        None => s.find('-')?,
+    };
+    Some((&s[..start_len], &s[start_len + 1..]))
+}
+
 impl FromStr for Position {
     type Err = PositionError;
 
@@ -76,10 +146,9 @@ impl FromStr for Position {
         if parts.len() != 3 {
             return Err(PositionError::InvalidFormat);
         }
-        let range_parts: Vec<&str> = parts[2].split('-').collect();
-        if range_parts.len() != 2 {
+        let Some((start_part, end_part)) = split_range(parts[2]) else {
             return Err(PositionError::InvalidFormat);
-        }
+        };
 
         // parse the part of read in position string ( read1 or read2 )
         let read = match parts[0] {
@@ -95,28 +164,110 @@ impl FromStr for Position {
             _ => return Err(PositionError::InvalidStrand),
         };
 
-        // parse the part of range in position string ( start-end )
-        let start = match range_parts[0].parse::<usize>() {
-            Err(_) => return Err(PositionError::InvalidStart),
-            Ok(v) if v > 150 => return Err(PositionError::InvalidStart),
-            Ok(v) => v,
-        };
-        let end = match range_parts[1].parse::<usize>() {
-            Err(_) if range_parts[1].eq_ignore_ascii_case("end") => 150,
-            Err(_) => return Err(PositionError::InvalidFormat),
-            Ok(v) if v > 150 => return Err(PositionError::InvalidEnd),
-            Ok(v) if v < start => return Err(PositionError::EndBeforeStart),
-            Ok(v) => v,
+        // parse the part of range in position string ( start-end ), `end-N` counts from the read end
+        let (start, start_from_end) = parse_offset(start_part).ok_or(PositionError::InvalidStart)?;
+        let (end, end_from_end) = match parse_offset(end_part) {
+            Some(end) => end,
+            // a start from the read end makes `end` the read end, otherwise it is 150 as before
+            None if end_part.eq_ignore_ascii_case("end") => if start_from_end { (0, true) } else { (150, false) },
+            None if end_part.bytes().all(|b| b.is_ascii_digit()) && !end_part.is_empty() => return Err(PositionError::InvalidEnd),
+            None if end_part.starts_with('-') || end_part.starts_with("end-") => return Err(PositionError::InvalidEnd),
+            None => return Err(PositionError::InvalidFormat),
         };
+        match (start_from_end, end_from_end) {
+            (false, false) if end < start => return Err(PositionError::EndBeforeStart),
+            (true, true) if end > start => return Err(PositionError::EndBeforeStart),
+            _ => {}
+        }
 
-        Ok(Position::new(read, strand, start, end))
+        Ok(Position::from_end(read, strand, (start, start_from_end), (end, end_from_end)))
     }
 }
 
 impl std::fmt::Display for Position {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let read = if self.read { b'2' } else { b'1' };
-        let strand = if self.strand { b'-' } else { b'+' };
-        write!(f, "read{}:{}:{}-{}", read, strand, self.start, self.end)
+        let read = if self.read { '2' } else { '1' };
+        let strand = if self.strand { '-' } else { '+' };
+        let offset = |offset: usize, from_end: bool| match (offset, from_end) {
+            (0, true) => "end".to_string(),
+            (offset, true) => format!("end-{offset}"),
+            (offset, false) => offset.to_string(),
+        };
+        write!(f, "read{}:{}:{}-{}", read, strand, offset(self.start, self.start_from_end), offset(self.end, self.end_from_end))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(s: &str) -> Position {
+        s.parse().unwrap_or_else(|err| panic!("{s}: {err}"))
+    }
+
+    #[test]
+    fn test_absolute() {
+        let pos = position("read1:+:0-12");
+        assert!(!pos.is_relative() && !pos.is_read2() && !pos.is_revcomp());
+        assert_eq!(pos.len(), 12);
+        assert_eq!(pos.resolve(150), 0..12);
+        assert_eq!(pos.resolve(10), 0..10);
+        assert!(pos.fits(12) && !pos.fits(11));
+        assert_eq!(position("read2:-:5-end").range(), 5..150);
+    }
+
+    #[test]
+    fn test_from_end() {
+        for s in ["read2:-:end-8-end", "read2:-:-8-end"] {
+            let pos = position(s);
+            assert!(pos.is_relative() && pos.is_read2() && pos.is_revcomp());
+            assert_eq!(pos.len(), 8);
+            assert_eq!(pos.resolve(100), 92..100);
+            assert_eq!(pos.resolve(5), 0..5);
+            assert!(pos.fits(8) && !pos.fits(7));
+            assert_eq!(pos.to_string(), "read2:-:end-8-end");
+        }
+        for s in ["read1:+:end-10-end-2", "read1:+:-10--2", "read1:+:-10-end-2"] {
+            let pos = position(s);
+            assert_eq!(pos.len(), 8);
+            assert_eq!(pos.resolve(50), 40..48);
+            assert_eq!(pos.resolve(1), 0..0);
+            assert!(pos.fits(10) && !pos.fits(9));
+            assert_eq!(pos.to_string(), "read1:+:end-10-end-2");
+        }
+    }
+
+    #[test]
+    fn test_mixed() {
+        // absolute start, end from the read end
+        let pos = position("read1:+:2-end-3");
+        assert!(pos.is_relative() && !pos.is_start_from_end() && pos.is_end_from_end());
+        assert_eq!(pos.resolve(20), 2..17);
+        assert_eq!(pos.resolve(4), 2..2);
+        assert!(pos.fits(5) && !pos.fits(4));
+
+        // start from the read end, absolute end
+        let pos = position("read1:+:-20-30");
+        assert!(pos.is_start_from_end() && !pos.is_end_from_end());
+        assert_eq!(pos.resolve(40), 20..30);
+        assert_eq!(pos.resolve(100), 80..80);
+        assert!(pos.fits(40) && pos.fits(50) && !pos.fits(51) && !pos.fits(19));
+        assert_eq!(pos.to_string(), "read1:+:end-20-30");
+    }
+
+    #[test]
+    fn test_invalid() {
+        let error = |s: &str| s.parse::<Position>().unwrap_err();
+        assert_eq!(error("read1:+:10-5"), PositionError::EndBeforeStart);
+        assert_eq!(error("read1:+:end-2-end-8"), PositionError::EndBeforeStart);
+        assert_eq!(error("read1:+:end-151-end"), PositionError::InvalidStart);
+        assert_eq!(error("read1:+:x-5"), PositionError::InvalidStart);
+        assert_eq!(error("read1:+:0-151"), PositionError::InvalidEnd);
+        assert_eq!(error("read1:+:0-end-x"), PositionError::InvalidEnd);
+        assert_eq!(error("read1:+:0-"), PositionError::InvalidFormat);
+        assert_eq!(error("read1:+:-"), PositionError::InvalidFormat);
+        assert_eq!(error("read3:+:0-5"), PositionError::InvalidRead);
+        assert_eq!(error("read1:*:0-5"), PositionError::InvalidStrand);
+        assert_eq!(error("read1:0-5"), PositionError::InvalidFormat);
     }
 }
@@ -0,0 +1,101 @@
+use super::error::AppError;
+use serde::Serialize;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Sink of the `--progress jsonl` events, unset without it
+static SINK: OnceLock<Mutex<Box<dyn Write + Send>>> = OnceLock::new();
+
+/// File of `--progress-file`, shared with the steps of `pipeline`
+static FILE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Write progress events to `path`, e.g. a named pipe read by a workflow manager, or to stderr
+pub fn init(path: Option<&Path>) -> Result<(), AppError> {
+    let sink: Box<dyn Write + Send> = match path {
+        Some(path) => {
+            let _ = FILE.set(path.to_path_buf());
+            Box::new(fs::OpenOptions::new().append(true).create(true).open(path)?)
+        }
+        None => Box::new(io::stderr()),
+    };
+    SINK.set(Mutex::new(sink)).map_err(|_| AppError::IoError(io::Error::other("progress output is already set")))
+}
+
+#[inline]
+pub fn enabled() -> bool {
+    SINK.get().is_some()
+}
+
+/// File the events go to, `None` for stderr or without `--progress`
+#[inline]
+pub fn file() -> Option<&'static Path> {
+    FILE.get().map(PathBuf::as_path)
+}
+
+/// One progress event, a JSON object per line
+#[derive(Serialize, Debug)]
+pub struct Event<'a> {
+    /// seconds since the Unix epoch
+    pub time: f64,
+    /// subcommand or pipeline step
+    pub stage: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tile: Option<&'a str>,
+    /// records of the tile or step just finished
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub records: Option<u64>,
+    /// finished share of the stage, 0 to 100
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub percent: Option<f64>,
+}
+
+impl<'a> Event<'a> {
+    pub fn new(stage: &'a str) -> Self {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0.0, |elapsed| elapsed.as_secs_f64());
+        Self { time, stage, tile: None, records: None, percent: None }
+    }
+
+    /// Write the event when `--progress jsonl` is on, errors of the sink never stop the run
+    pub fn emit(&self) {
+        let Some(sink) = SINK.get() else {
+            return;
+        };
+        // one write per line, so events of concurrent processes sharing a pipe do not interleave
+        let mut line = serde_json::to_vec(self).expect("event is serializable");
+        line.push(b'\n');
+        if let Ok(mut sink) = sink.lock() {
+            let _ = sink.write_all(&line).and_then(|_| sink.flush());
+        }
+    }
+}
+
+/// Progress over a known number of tiles or steps finished in any order
+pub struct Tracker<'a> {
+    stage: &'a str,
+    total: usize,
+    done: AtomicUsize,
+}
+
+impl<'a> Tracker<'a> {
+    pub fn new(stage: &'a str, total: usize) -> Self {
+        Self { stage, total, done: AtomicUsize::new(0) }
+    }
+
+    /// One more finished, with its tile and record count when known
+    pub fn finish(&self, tile: Option<&str>, records: Option<u64>) {
+        let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+        if !enabled() {
+            return;
+        }
+        let mut event = Event::new(self.stage);
+        event.tile = tile;
+        event.records = records;
+        let percent = if self.total == 0 { 100.0 } else { done as f64 * 100.0 / self.total as f64 };
+        event.percent = Some((percent * 100.0).round() / 100.0);
+        event.emit();
+    }
+}
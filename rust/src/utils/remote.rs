@@ -0,0 +1,71 @@
+use std::io::{self, Read};
+use std::path::Path;
+use object_store::{parse_url_opts, path::Path as ObjectPath, ObjectStore, ObjectStoreExt};
+use tokio::runtime::Runtime;
+use url::Url;
+
+/// Bytes fetched by one range request
+const CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+
+/// URL schemes of remote inputs
+const SCHEMES: [&str; 4] = ["s3://", "gs://", "http://", "https://"];
+
+/// Whether the path is an `s3://`, `gs://` or `http(s)://` URL
+#[inline]
+pub fn is_remote<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref().to_str().is_some_and(|path| SCHEMES.iter().any(|scheme| path.starts_with(scheme)))
+}
+
+fn remote_error(url: &str, err: impl std::fmt::Display) -> io::Error {
+    io::Error::other(format!("{url}: {err}"))
+}
+
+/// Sequential reader of a remote object by consecutive range requests
+///
+/// Credentials and endpoints come from the environment the way the object_store builders take them
+/// (e.g. `AWS_ACCESS_KEY_ID`, `AWS_REGION`, `GOOGLE_SERVICE_ACCOUNT`),
+/// failed requests are retried with backoff by object_store
+pub struct RemoteReader {
+    runtime: Runtime,
+    store: Box<dyn ObjectStore>,
+    location: ObjectPath,
+    url: String,
+    size: u64,
+    offset: u64,
+    chunk: Vec<u8>,
+    /// bytes of `chunk` already read
+    consumed: usize,
+}
+
+impl RemoteReader {
+    pub fn open(url: &str) -> io::Result<Self> {
+        let parsed = Url::parse(url).map_err(|err| remote_error(url, err))?;
+        let (store, location) = parse_url_opts(&parsed, std::env::vars()).map_err(|err| remote_error(url, err))?;
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        let size = runtime.block_on(store.head(&location)).map_err(|err| remote_error(url, err))?.size;
+        Ok(Self { runtime, store, location, url: url.to_string(), size, offset: 0, chunk: Vec::new(), consumed: 0 })
+    }
+}
+
+impl Read for RemoteReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.consumed == self.chunk.len() {
+            if self.offset >= self.size {
+                return Ok(0);
+            }
+            let range = self.offset..self.size.min(self.offset + CHUNK_SIZE);
+            let bytes = self.runtime.block_on(self.store.get_range(&self.location, range))
+                .map_err(|err| remote_error(&self.url, err))?;
+            if bytes.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("{}: empty range response", self.url)));
+            }
+            self.offset += bytes.len() as u64;
+            self.chunk = bytes.to_vec();
+            self.consumed = 0;
+        }
+        let len = buf.len().min(self.chunk.len() - self.consumed);
+        buf[..len].copy_from_slice(&self.chunk[self.consumed..self.consumed + len]);
+        self.consumed += len;
+        Ok(len)
+    }
+}
@@ -0,0 +1,21 @@
+use std::hash::{DefaultHasher, Hash};
+use std::sync::OnceLock;
+
+/// Seed of the global `--seed`
+static SEED: OnceLock<u64> = OnceLock::new();
+
+/// Set the seed of all sampling, once before any work starts
+pub fn init(seed: u64) {
+    let _ = SEED.set(seed);
+}
+
+/// Hasher of the sampling decisions, keyed by the global `--seed` when given
+///
+/// Without a seed it is the plain `DefaultHasher`, so the samples match runs from before `--seed`
+pub fn hasher() -> DefaultHasher {
+    let mut hasher = DefaultHasher::new();
+    if let Some(seed) = SEED.get() {
+        seed.hash(&mut hasher);
+    }
+    hasher
+}
@@ -0,0 +1,314 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fmt;
+use std::fs;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use super::interrupt::remove_on_interrupt;
+use tracing::warn;
+
+/// Scratch directory of the global `--tmpdir`
+static TMPDIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Set the scratch directory of the global `--tmpdir`, once before any work starts
+pub fn set_tmpdir(dir: PathBuf) {
+    let _ = TMPDIR.set(dir);
+}
+
+/// Directory for the temporary files of a command: `{tmpdir}/opentools-{name}-{pid}` with the global
+/// `--tmpdir`, so concurrent runs sharing a scratch disk do not collide, otherwise `default`
+///
+/// The directory is removed when the run is interrupted
+pub fn scratch_dir(default: PathBuf, name: &str) -> PathBuf {
+    let dir = match TMPDIR.get() {
+        Some(dir) => dir.join(format!("opentools-{}-{}", name, std::process::id())),
+        None => default,
+    };
+    remove_on_interrupt(&dir);
+    dir
+}
+
+/// Scratch directory removed when dropped, so a run failing half way leaves no spill files behind
+pub struct ScratchGuard {
+    dir: PathBuf,
+}
+
+impl ScratchGuard {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// Remove the directory now, reporting the error `drop` can only log
+    pub fn remove(mut self) -> io::Result<()> {
+        let dir = std::mem::take(&mut self.dir);
+        match fs::remove_dir_all(&dir) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl std::ops::Deref for ScratchGuard {
+    type Target = Path;
+
+    #[inline]
+    fn deref(&self) -> &Path {
+        &self.dir
+    }
+}
+
+impl Drop for ScratchGuard {
+    fn drop(&mut self) {
+        if self.dir.as_os_str().is_empty() {
+            return;
+        }
+        if let Err(err) = fs::remove_dir_all(&self.dir) && err.kind() != io::ErrorKind::NotFound {
+            warn!("{}: {}", self.dir.display(), err);
+        }
+    }
+}
+
+/// Parse memory size like `512M`, `4G` or plain bytes
+pub fn parse_memory_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (number, unit) = match value.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(index) => value.split_at(index),
+        None => (value, ""),
+    };
+    let number: f64 = number.parse()
+        .map_err(|_| format!("`{}` is not valid memory size (e.g. 512M, 4G)", value))?;
+    let scale: u64 = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        "T" | "TB" => 1 << 40,
+        _ => return Err(format!("`{}` is not valid memory unit, expected K, M, G or T", unit)),
+    };
+    Ok((number * scale as f64) as u64)
+}
+
+/// Bucket of a key among `n` buckets, the same in every thread and pass, unlike the `--seed` hasher
+#[inline]
+pub fn bucket_of<K: Hash + ?Sized>(key: &K, n: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() % n as u64) as usize
+}
+
+/// Temporary files partitioned by key hash, written by one thread
+pub struct SpillBuckets {
+    dir: PathBuf,
+    writers: Vec<BufWriter<fs::File>>,
+}
+
+impl SpillBuckets {
+    pub fn create(dir: &Path, n: usize) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let writers = (0..n)
+            .map(|index| Ok(BufWriter::new(fs::File::create(Self::bucket_path(dir, index))?)))
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(Self { dir: dir.to_path_buf(), writers })
+    }
+
+    #[inline]
+    fn bucket_path(dir: &Path, index: usize) -> PathBuf {
+        dir.join(format!("bucket_{index:04}.tsv"))
+    }
+
+    /// Append one line to the bucket `index`
+    #[inline]
+    pub fn write_line(&mut self, index: usize, line: fmt::Arguments<'_>) -> io::Result<()> {
+        writeln!(self.writers[index], "{line}")
+    }
+
+    /// Replace every bucket by `rewrite` of its lines, later lines are appended to the rewritten ones
+    pub fn rewrite<F, E>(&mut self, mut rewrite: F) -> Result<(), E>
+    where
+        F: FnMut(io::Lines<BufReader<fs::File>>, &mut BufWriter<fs::File>) -> Result<(), E>,
+        E: From<io::Error>,
+    {
+        for (index, writer) in self.writers.iter_mut().enumerate() {
+            writer.flush()?;
+            let path = Self::bucket_path(&self.dir, index);
+            let temp = path.with_extension("tsv.tmp");
+            let mut rewritten = BufWriter::new(fs::File::create(&temp)?);
+            rewrite(BufReader::new(fs::File::open(&path)?).lines(), &mut rewritten)?;
+            rewritten.flush()?;
+            fs::rename(&temp, &path)?;
+            *writer = BufWriter::new(fs::OpenOptions::new().append(true).open(&path)?);
+        }
+        Ok(())
+    }
+
+    /// Flush all buckets and return their paths for reading
+    pub fn finish(self) -> io::Result<Vec<PathBuf>> {
+        let n = self.writers.len();
+        for mut writer in self.writers {
+            writer.flush()?;
+        }
+        Ok((0..n).map(|index| Self::bucket_path(&self.dir, index)).collect())
+    }
+}
+
+/// Lines sorted in memory up to `max_memory` bytes, beyond that spilled as sorted runs, shared by worker threads
+pub struct SortedRuns {
+    dir: PathBuf,
+    max_memory: u64,
+    state: Mutex<RunState>,
+}
+
+#[derive(Default)]
+struct RunState {
+    lines: Vec<String>,
+    bytes: u64,
+    runs: Vec<PathBuf>,
+}
+
+impl RunState {
+    /// Sort the buffered lines and write them as the next run
+    fn spill(&mut self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)?;
+        self.lines.sort_unstable();
+        let path = dir.join(format!("run_{:04}.tsv", self.runs.len()));
+        let mut writer = BufWriter::new(fs::File::create(&path)?);
+        for line in self.lines.drain(..) {
+            writeln!(writer, "{line}")?;
+        }
+        writer.flush()?;
+        self.lines.shrink_to_fit();
+        self.bytes = 0;
+        self.runs.push(path);
+        Ok(())
+    }
+}
+
+impl SortedRuns {
+    /// Runs go to `dir`, created only once the first run is spilled
+    pub fn new(dir: &Path, max_memory: u64) -> Self {
+        Self { dir: dir.to_path_buf(), max_memory, state: Mutex::new(RunState::default()) }
+    }
+
+    /// Add lines, sorting and spilling the buffered ones as a run once they exceed `max_memory`
+    pub fn push(&self, lines: Vec<String>) -> io::Result<()> {
+        let mut state = self.state.lock().map_err(|_| io::Error::other("run lock poisoned"))?;
+        state.bytes += lines.iter().map(|line| (line.len() + size_of::<String>()) as u64).sum::<u64>();
+        state.lines.extend(lines);
+        if state.bytes >= self.max_memory {
+            state.spill(&self.dir)?;
+        }
+        Ok(())
+    }
+
+    /// All lines in sorted order, a k-way merge of the spilled runs or the lines kept in memory
+    pub fn finish(self) -> io::Result<MergedRuns> {
+        let mut state = self.state.into_inner().map_err(|_| io::Error::other("run lock poisoned"))?;
+        if state.runs.is_empty() {
+            state.lines.sort_unstable();
+            return Ok(MergedRuns { readers: Vec::new(), heap: BinaryHeap::new(), memory: state.lines.into_iter(), runs: 0 });
+        }
+        if !state.lines.is_empty() {
+            state.spill(&self.dir)?;
+        }
+        let mut readers = state.runs.iter()
+            .map(|path| Ok(io::BufReader::new(fs::File::open(path)?).lines()))
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut heap = BinaryHeap::new();
+        for (index, reader) in readers.iter_mut().enumerate() {
+            if let Some(line) = reader.next() {
+                heap.push(Reverse((line?, index)));
+            }
+        }
+        Ok(MergedRuns { readers, heap, memory: Vec::new().into_iter(), runs: state.runs.len() })
+    }
+}
+
+/// Sorted lines of `SortedRuns::finish`
+pub struct MergedRuns {
+    readers: Vec<io::Lines<io::BufReader<fs::File>>>,
+    heap: BinaryHeap<Reverse<(String, usize)>>,
+    memory: std::vec::IntoIter<String>,
+    runs: usize,
+}
+
+impl MergedRuns {
+    /// Runs spilled to disk, 0 when all lines fit in memory
+    #[inline]
+    pub fn runs(&self) -> usize { self.runs }
+}
+
+impl Iterator for MergedRuns {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.readers.is_empty() {
+            return self.memory.next().map(Ok);
+        }
+        let Reverse((line, index)) = self.heap.pop()?;
+        match self.readers[index].next() {
+            Some(Ok(next)) => self.heap.push(Reverse((next, index))),
+            Some(Err(err)) => return Some(Err(err)),
+            None => {}
+        }
+        Some(Ok(line))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Shuffled batches of `0..n` written twice, zero padded so text order is number order
+    fn batches(n: u64) -> Vec<Vec<String>> {
+        let mut lines: Vec<String> = (0..n).chain(0..n).map(|i| format!("{:06}", i * 7919 % n)).collect();
+        lines.reverse();
+        lines.chunks(13).map(<[String]>::to_vec).collect()
+    }
+
+    fn merge(dir: &Path, max_memory: u64) -> (Vec<String>, usize) {
+        let runs = SortedRuns::new(dir, max_memory);
+        for batch in batches(500) {
+            runs.push(batch).unwrap();
+        }
+        let merged = runs.finish().unwrap();
+        let count = merged.runs();
+        (merged.collect::<io::Result<Vec<_>>>().unwrap(), count)
+    }
+
+    #[test]
+    fn test_sorted_runs_merge() {
+        let dir = std::env::temp_dir().join(format!("opentools-test-sorted-runs-{}", std::process::id()));
+        let mut expected: Vec<String> = batches(500).concat();
+        expected.sort_unstable();
+
+        let (lines, runs) = merge(&dir, u64::MAX);
+        assert_eq!(runs, 0);
+        assert!(!dir.exists());
+        assert_eq!(lines, expected);
+
+        // every batch of 13 lines is spilled as a run of its own
+        let (lines, runs) = merge(&dir, 1);
+        assert_eq!(runs, 1000usize.div_ceil(13));
+        assert_eq!(lines, expected);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_scratch_guard() {
+        let dir = std::env::temp_dir().join(format!("opentools-test-scratch-guard-{}", std::process::id()));
+        let runs = SortedRuns::new(&dir.join("rows"), 1);
+        runs.push(batches(50).concat()).unwrap();
+        assert!(dir.join("rows").exists());
+        // an error on the way drops the guard
+        drop(ScratchGuard::new(dir.clone()));
+        assert!(!dir.exists());
+
+        fs::create_dir_all(&dir).unwrap();
+        ScratchGuard::new(dir.clone()).remove().unwrap();
+        assert!(!dir.exists());
+        // a directory never created is no error
+        ScratchGuard::new(dir.clone()).remove().unwrap();
+    }
+}
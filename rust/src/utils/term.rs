@@ -0,0 +1,75 @@
+use anstyle::{AnsiColor, Style};
+use std::io::IsTerminal;
+use std::sync::OnceLock;
+
+/// Whether `--no-color` or `NO_COLOR` turned colors off
+static NO_COLOR: OnceLock<bool> = OnceLock::new();
+
+pub const HEADER: Style = Style::new().bold();
+pub const PASS: Style = AnsiColor::Green.on_default();
+pub const ERROR: Style = AnsiColor::Red.on_default().bold();
+
+/// Turn colors off for the whole run by `--no-color` or any non-empty `NO_COLOR`, once before any output
+pub fn init(no_color: bool) {
+    let no_color = no_color || std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    let _ = NO_COLOR.set(no_color);
+}
+
+/// Colors and aligned tables on stdout, only for a terminal
+#[inline]
+pub fn stdout() -> bool {
+    !NO_COLOR.get().copied().unwrap_or(false) && std::io::stdout().is_terminal()
+}
+
+/// Colors of the log lines and errors on stderr, only for a terminal
+#[inline]
+pub fn stderr() -> bool {
+    !NO_COLOR.get().copied().unwrap_or(false) && std::io::stderr().is_terminal()
+}
+
+/// `text` in `style`, unchanged when `color` is off
+pub fn paint(text: &str, style: Style, color: bool) -> String {
+    if color {
+        format!("{style}{text}{style:#}")
+    } else {
+        text.to_string()
+    }
+}
+
+/// Table of tab separated lines, printed with the columns padded to the widest cell on a terminal
+pub struct Table {
+    rows: Vec<(Vec<String>, Style)>,
+}
+
+impl Table {
+    pub fn new(header: &str) -> Self {
+        let mut table = Self { rows: Vec::new() };
+        table.push(header, HEADER);
+        table
+    }
+
+    /// Add one tab separated line, the padding of the line is trimmed from its cells
+    pub fn push(&mut self, line: &str, style: Style) {
+        self.rows.push((line.split('\t').map(|cell| cell.trim().to_string()).collect(), style));
+    }
+
+    /// Lines with the columns aligned by two spaces, colored by row when `color` is on
+    pub fn render(&self, color: bool) -> String {
+        let columns = self.rows.iter().map(|(cells, _)| cells.len()).max().unwrap_or(0);
+        let widths: Vec<usize> = (0..columns)
+            .map(|column| self.rows.iter()
+                .filter_map(|(cells, _)| cells.get(column))
+                .map(|cell| cell.chars().count())
+                .max()
+                .unwrap_or(0))
+            .collect();
+        let lines: Vec<String> = self.rows.iter().map(|(cells, style)| {
+            let line = cells.iter().zip(&widths)
+                .map(|(cell, &width)| format!("{:<width$}", cell))
+                .collect::<Vec<_>>()
+                .join("  ");
+            paint(line.trim_end(), *style, color)
+        }).collect();
+        lines.join("\n")
+    }
+}
@@ -0,0 +1,28 @@
+use super::error::AppError;
+use std::io;
+use std::num::NonZeroUsize;
+use std::sync::OnceLock;
+
+/// Thread count of the global `--threads`
+static GLOBAL: OnceLock<usize> = OnceLock::new();
+
+/// Set the global thread count and size the rayon global pool with it, once before any work starts
+pub fn init(threads: NonZeroUsize) -> Result<(), AppError> {
+    GLOBAL.set(threads.get()).map_err(|_| AppError::IoError(io::Error::other("thread count is already set")))?;
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads.get())
+        .build_global()
+        .map_err(|err| AppError::IoError(io::Error::other(err)))
+}
+
+/// Threads of a command: its own option, else the global `--threads`, else `default`
+#[inline]
+pub fn resolve(own: Option<usize>, default: usize) -> usize {
+    own.or(GLOBAL.get().copied()).unwrap_or(default)
+}
+
+/// All available cores, the default of the commands that scale with them
+#[inline]
+pub fn available() -> usize {
+    std::thread::available_parallelism().map_or(1, NonZeroUsize::get)
+}